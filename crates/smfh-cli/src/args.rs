@@ -2,14 +2,44 @@ use clap::{
     Parser,
     Subcommand,
 };
+use simplelog::LevelFilter;
+use smfh_core::manifest::{
+    Duplicates,
+    Format,
+};
 use std::path::PathBuf;
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Clone, Debug)]
 #[command(version, about)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct Args {
-    #[arg(short, long)]
+    #[arg(short, long, help = "Same as `--log-level info`")]
     pub verbose: bool,
 
+    #[arg(
+        long,
+        value_name = "LEVEL",
+        value_parser = parse_log_level,
+        conflicts_with = "verbose",
+        help = "Log messages of this level and worse: error, warn, info, debug, or trace. Defaults to warn, or info for dry runs"
+    )]
+    pub log_level: Option<LevelFilter>,
+
+    #[arg(
+        short,
+        long,
+        conflicts_with_all = ["verbose", "log_level"],
+        help = "Log errors only"
+    )]
+    pub quiet: bool,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Also append timestamped log records to this file"
+    )]
+    pub log_file: Option<PathBuf>,
+
     #[arg(
         long,
         default_value = "false",
@@ -17,10 +47,319 @@ pub struct Args {
     )]
     pub impure: bool,
 
+    #[arg(
+        long,
+        requires = "impure",
+        value_name = "NAME,...",
+        value_delimiter = ',',
+        help = "Only substitute these environment variables in paths, failing on any other"
+    )]
+    pub allow_env: Option<Vec<String>>,
+
+    #[arg(
+        long,
+        value_name = "NAME",
+        help = "Expand ~ and $HOME in paths to the home of this user, and make it own entries without an owner"
+    )]
+    pub user: Option<String>,
+
+    #[arg(
+        long,
+        default_value = "false",
+        help = "Log what would be done without modifying the filesystem"
+    )]
+    pub dry_run: bool,
+
+    #[arg(
+        long,
+        global = true,
+        default_value = "false",
+        help = "Exit with 6 if anything would be changed, with verify, plan, or --dry-run"
+    )]
+    pub check: bool,
+
+    #[arg(
+        short,
+        long,
+        default_value = "false",
+        help = "Ask before clobbering, backing up, or deleting an existing file that doesn't match the manifest"
+    )]
+    pub interactive: bool,
+
+    #[arg(
+        long,
+        default_value = "false",
+        help = "Clobber every target regardless of clobber and clobber_by_default"
+    )]
+    pub force: bool,
+
+    #[arg(
+        long,
+        default_value = "false",
+        help = "Refuse to delete or clobber directories that are or contain mountpoints"
+    )]
+    pub one_file_system: bool,
+
+    #[arg(
+        long,
+        default_value = "false",
+        overrides_with = "keep_going",
+        help = "Stop at the first entry that fails"
+    )]
+    pub fail_fast: bool,
+
+    #[arg(
+        long,
+        default_value = "false",
+        overrides_with = "fail_fast",
+        help = "Carry on with the other entries when one fails (default)"
+    )]
+    pub keep_going: bool,
+
+    #[arg(
+        long,
+        help = "Wait for this path, e.g. one on a network mount, before doing anything (repeatable)"
+    )]
+    pub wait_for: Vec<PathBuf>,
+
+    #[arg(
+        long,
+        default_value = "30s",
+        help = "How long to wait for each of --wait-for and the wait_for of entries"
+    )]
+    pub wait_timeout: humantime::Duration,
+
+    #[arg(
+        long,
+        default_value = "false",
+        help = "Refuse to change targets with a symlink anywhere in the path to their directory (Linux 5.6+)"
+    )]
+    pub no_symlinks: bool,
+
+    #[arg(
+        long,
+        default_value = "false",
+        help = "Flush written files and their directories to disk, e.g. before a reboot"
+    )]
+    pub fsync: bool,
+
+    #[arg(
+        long,
+        value_name = "OCTAL",
+        value_parser = parse_umask,
+        help = "Umask to create files with, overriding the manifest's umask (e.g. 022)"
+    )]
+    pub umask: Option<u32>,
+
+    #[arg(
+        long,
+        default_value = "false",
+        help = "Suffix backups with a timestamp instead of replacing an earlier backup"
+    )]
+    pub timestamped_backups: bool,
+
+    #[arg(
+        long,
+        default_value = "false",
+        help = "Log a unified diff of the old and new contents of copies before replacing them"
+    )]
+    pub show_diff: bool,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Manifest format (json, toml, yaml, or cbor), guessed from the file extension by default"
+    )]
+    pub format: Option<Format>,
+
+    #[arg(
+        long = "var",
+        global = true,
+        value_name = "NAME=VALUE",
+        value_parser = parse_variable,
+        help = "Set a template variable, overriding the manifest's variables"
+    )]
+    pub variables: Vec<(String, String)>,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        help = "Write a JSON report of the action taken on every target to this file"
+    )]
+    pub report_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        global = true,
+        default_value = "false",
+        help = "Print statistics of the run on stderr, and list the failed targets if any entry fails"
+    )]
+    pub summary: bool,
+
+    #[arg(
+        short,
+        long,
+        global = true,
+        default_value = "1",
+        help = "Number of threads to activate and check files with, 0 for one per CPU"
+    )]
+    pub jobs: usize,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        help = "Lock this file for the duration of the run instead of one derived from the manifest path"
+    )]
+    pub lock_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "DIR",
+        help = "Place every target under this directory, e.g. a mounted image or chroot"
+    )]
+    pub root: Option<PathBuf>,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "DIR",
+        help = "Archive every successfully applied manifest as a generation in this directory"
+    )]
+    pub state_dir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        help = "Record every change to the filesystem in this journal, to be reverted with `smfh undo`"
+    )]
+    pub journal: Option<PathBuf>,
+
+    #[arg(
+        long,
+        global = true,
+        default_value = "false",
+        help = "Only let smfh write under the directories of the manifest's targets, with Landlock"
+    )]
+    pub landlock: bool,
+
+    #[arg(
+        long,
+        global = true,
+        default_value = "false",
+        help = "Only let smfh make the system calls it needs for files, with seccomp"
+    )]
+    pub seccomp: bool,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "POLICY",
+        default_value = "error",
+        help = "What to do with several entries of a manifest sharing a target (error or last-wins)"
+    )]
+    pub allow_duplicates: Duplicates,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        help = "Only apply the entries whose target is or is under this path, may be repeated"
+    )]
+    pub only: Vec<PathBuf>,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        help = "Leave the entries whose target is or is under this path alone, may be repeated"
+    )]
+    pub skip: Vec<PathBuf>,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        help = "Refuse to replace or remove this path, on top of the built-in ones, may be repeated"
+    )]
+    pub protect: Vec<PathBuf>,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        help = "Decrypt secrets with this age identity instead of the standard ones, may be repeated"
+    )]
+    pub identity: Vec<PathBuf>,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        help = "Download URL sources into this directory [default: /var/cache/smfh for root, ~/.cache/smfh otherwise]"
+    )]
+    pub cache_dir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        global = true,
+        default_value = "false",
+        help = "Copy sources through a content-addressed cache in the cache directory"
+    )]
+    pub content_cache: bool,
+
+    #[arg(
+        long = "tag",
+        global = true,
+        value_name = "TAG",
+        help = "Only apply the entries with this tag, or any other given with --tag"
+    )]
+    pub tags: Vec<String>,
+
+    #[arg(
+        long = "skip-tag",
+        global = true,
+        value_name = "TAG",
+        help = "Leave the entries with this tag alone, may be repeated"
+    )]
+    pub skip_tags: Vec<String>,
+
     #[command(subcommand)]
     pub sub_command: Subcommands,
 }
 
+fn parse_variable(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(name, value)| (name.to_owned(), value.to_owned()))
+        .ok_or_else(|| format!("expected NAME=VALUE, got '{s}'"))
+}
+
+fn parse_log_level(s: &str) -> Result<LevelFilter, String> {
+    s.parse()
+        .ok()
+        .filter(|x| *x != LevelFilter::Off)
+        .ok_or_else(|| format!("expected error, warn, info, debug, or trace, got '{s}'"))
+}
+
+fn parse_umask(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s, 8)
+        .ok()
+        .filter(|x| *x <= 0o777)
+        .ok_or_else(|| format!("expected an octal umask like 022, got '{s}'"))
+}
+
+/// Rejects an empty backup prefix, with which every file would look like a
+/// backup of itself.
+fn parse_prefix(s: &str) -> Result<String, String> {
+    if s.is_empty() {
+        return Err(String::from("the prefix must not be empty"));
+    }
+    Ok(s.to_owned())
+}
+
 #[derive(Subcommand, Clone, Debug)]
 pub enum Subcommands {
     Activate {
@@ -33,6 +372,16 @@ pub enum Subcommands {
     Deactivate {
         #[arg()]
         manifest: PathBuf,
+
+        #[clap(long, short, action, default_value = ".backup-")]
+        prefix: String,
+
+        #[arg(
+            long,
+            default_value = "false",
+            help = "Move backups made with the prefix back into place"
+        )]
+        restore_backups: bool,
     },
     Diff {
         #[clap(long, short, action, default_value = ".backup-")]
@@ -45,18 +394,214 @@ pub enum Subcommands {
         )]
         fallback: bool,
 
+        #[arg(
+            long,
+            default_value = "false",
+            help = "Move backups made with the prefix back into place when removing old files"
+        )]
+        restore_backups: bool,
+
+        #[arg(
+            long,
+            default_value = "false",
+            help = "Print what will be done as a JSON array, as `plan --json` does, before doing it"
+        )]
+        json: bool,
+
         #[arg()]
         manifest: PathBuf,
 
         #[arg()]
         old_manifest: PathBuf,
     },
+    /// Validate the manifest and check that every target matches it, without
+    /// modifying anything
     Verify {
         #[arg()]
         manifest: PathBuf,
     },
+    /// Check a manifest for structural errors, unknown fields, and invalid
+    /// paths, without reading any of its sources or targets
+    Validate {
+        #[arg()]
+        manifest: PathBuf,
+    },
+    /// Print the JSON Schema of the manifest format
+    Schema,
+    /// Show what `diff` would do to every target, without modifying anything
+    Plan {
+        #[arg(
+            long,
+            default_value = "false",
+            help = "Plan a full activation if old_manifest doesn't exist"
+        )]
+        fallback: bool,
+
+        #[arg(
+            long,
+            default_value = "false",
+            help = "Print a JSON array instead of a table"
+        )]
+        json: bool,
+
+        #[arg()]
+        manifest: PathBuf,
+
+        #[arg()]
+        old_manifest: PathBuf,
+    },
+    /// Print the state of every target, and whether a backup of it exists
+    Status {
+        #[arg()]
+        manifest: PathBuf,
+
+        #[clap(long, short, action, default_value = ".backup-")]
+        prefix: String,
+
+        #[arg(
+            long,
+            default_value = "false",
+            help = "Print a JSON array instead of a table"
+        )]
+        json: bool,
+    },
+    /// List what is inside the manifest's directories without being
+    /// accounted for by any entry, without deleting anything
+    Orphans {
+        #[arg()]
+        manifest: PathBuf,
+
+        #[clap(long, short, action, default_value = ".backup-")]
+        prefix: String,
+
+        #[arg(
+            long,
+            default_value = "false",
+            help = "Print a JSON array instead of one path per line"
+        )]
+        json: bool,
+    },
+    /// Revert from the current generation in `--state-dir` to the previous
+    /// one
+    Rollback {
+        #[clap(long, short, action, default_value = ".backup-")]
+        prefix: String,
+
+        #[arg(
+            long,
+            default_value = "false",
+            help = "Move backups made with the prefix back into place when removing files"
+        )]
+        restore_backups: bool,
+    },
+    /// Activate the manifest of every user in a directory, named after
+    /// them, each in a run of smfh as that user and all at once
+    ActivateUsers {
+        #[arg()]
+        users_dir: PathBuf,
+
+        #[clap(long, short, action, default_value = ".backup-")]
+        prefix: String,
+    },
+    /// Revert the changes recorded in a journal written with `--journal`,
+    /// latest first, and delete it
+    Undo {
+        #[arg(value_name = "JOURNAL")]
+        path: PathBuf,
+    },
+    /// Print a manifest that recreates the symlinks and files under a
+    /// directory
+    Generate {
+        #[arg()]
+        dir: PathBuf,
+
+        #[arg(
+            long,
+            help = "Directory the entries are placed in, the scanned directory by default"
+        )]
+        target: Option<PathBuf>,
+
+        #[arg(
+            long,
+            short,
+            help = "Write the manifest to this file, in the format matching its extension, instead of stdout"
+        )]
+        out: Option<PathBuf>,
+    },
     Clean {
         #[arg()]
         manifest: PathBuf,
     },
+    /// Activate the manifest, then activate again the entries whose sources
+    /// change, until killed. Linux only
+    Watch {
+        #[arg()]
+        manifest: PathBuf,
+
+        #[clap(long, short, action, default_value = ".backup-")]
+        prefix: String,
+
+        #[arg(
+            long = "manifest",
+            default_value = "false",
+            help = "Also watch the manifest, and diff against the previous one when it changes"
+        )]
+        watch_manifest: bool,
+    },
+    /// Serve activation, diffing, and status over D-Bus as co.feel.Smfh,
+    /// until killed
+    #[cfg(all(unix, feature = "dbus"))]
+    Dbus {
+        #[arg(
+            long,
+            default_value = "false",
+            help = "Use the session bus instead of the system bus"
+        )]
+        session: bool,
+    },
+    /// Copy what is at the manifest's targets into a directory, and print
+    /// the manifest pointed at the copies
+    Adopt {
+        #[arg()]
+        manifest: PathBuf,
+
+        #[arg(long, help = "Directory the targets are copied into")]
+        out_dir: PathBuf,
+
+        #[arg(
+            long,
+            short,
+            help = "Write the manifest to this file, in the format matching its extension, instead of stdout"
+        )]
+        out: Option<PathBuf>,
+    },
+    /// Delete backups of the manifest's targets, or of every file under
+    /// `--dir`
+    CleanBackups {
+        #[arg(required_unless_present = "dir")]
+        manifest: Option<PathBuf>,
+
+        #[arg(
+            long,
+            conflicts_with = "manifest",
+            help = "Scan this directory tree instead of the manifest's targets"
+        )]
+        dir: Option<PathBuf>,
+
+        #[clap(long, short, action, default_value = ".backup-", value_parser = parse_prefix)]
+        prefix: String,
+
+        #[arg(
+            long,
+            help = "Only delete backups last modified longer ago than this, e.g. 30d"
+        )]
+        older_than: Option<humantime::Duration>,
+
+        #[arg(
+            long,
+            default_value = "0",
+            help = "Keep this many of the newest backups"
+        )]
+        keep: usize,
+    },
 }
@@ -0,0 +1,324 @@
+//! `smfh dbus`: a D-Bus service through which other programs activate
+//! manifests and follow their progress, instead of running smfh and scraping
+//! its stderr.
+
+use crate::{
+    args::Args,
+    read,
+};
+use log::{
+    error,
+    info,
+};
+use smfh_core::{
+    file_util::{
+        self,
+        lock_path,
+    },
+    manifest::DiffError,
+    options::Options,
+    report::{
+        Action,
+        Report,
+    },
+};
+use std::{
+    fs,
+    path::{
+        Path,
+        PathBuf,
+    },
+    process,
+    sync::mpsc::{
+        self,
+        RecvTimeoutError,
+    },
+    thread,
+    time::Duration,
+};
+use zbus::{
+    Connection,
+    blocking::connection::Builder,
+    fdo,
+    interface,
+    message::Header,
+    object_server::SignalEmitter,
+};
+
+const NAME: &str = "co.feel.Smfh";
+const PATH: &str = "/co/feel/Smfh";
+const INTERVAL: Duration = Duration::from_secs(1);
+
+/// Targets that failed, each with why.
+type Failures = Vec<(String, String)>;
+
+struct Service {
+    options: Options,
+    args: Args,
+    system: bool,
+}
+
+#[interface(name = "co.feel.Smfh1")]
+impl Service {
+    /// Activates `manifest`, backing up what is in the way with `prefix`.
+    async fn activate(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+        manifest: String,
+        prefix: String,
+    ) -> fdo::Result<Failures> {
+        self.authorize(&header, connection).await?;
+        let (options, args) = self.per_call(prefix);
+        let emitter = emitter.into_owned();
+        blocking::unblock(move || {
+            let path = PathBuf::from(manifest);
+            let _lock = lock(&path, &args)?;
+            let mut manifest = read(&path, &args).map_err(failed)?;
+            let protected = manifest.check_protected(&options, false);
+            if !protected.is_empty() {
+                return Err(failed(
+                    protected
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                ));
+            }
+            let failures = with_progress(
+                &emitter,
+                &path,
+                "activate",
+                &options.report,
+                manifest.files.len(),
+                || manifest.activate(&options),
+            );
+            Ok(failures
+                .into_iter()
+                .map(|(target, err)| (target.display().to_string(), format!("{err:?}")))
+                .collect())
+        })
+        .await
+    }
+
+    /// Applies the changes from `old_manifest` to `manifest`, as `smfh diff`
+    /// does.
+    #[allow(clippy::too_many_arguments)]
+    async fn diff(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+        manifest: String,
+        old_manifest: String,
+        prefix: String,
+        fallback: bool,
+    ) -> fdo::Result<Failures> {
+        self.authorize(&header, connection).await?;
+        let (options, args) = self.per_call(prefix);
+        let emitter = emitter.into_owned();
+        blocking::unblock(move || {
+            let path = PathBuf::from(manifest);
+            let old_path = PathBuf::from(old_manifest);
+            let _lock = lock(&old_path, &args)?;
+            let manifest = read(&path, &args).map_err(failed)?;
+            let total = manifest.files.len();
+            let result = with_progress(&emitter, &path, "diff", &options.report, total, || {
+                manifest.diff(&old_path, &options, fallback)
+            });
+            match result {
+                Ok(()) => Ok(Vec::new()),
+                Err(DiffError::ActivationFailed(failures)) => Ok(failures
+                    .into_iter()
+                    .map(|(target, err)| (target.display().to_string(), err))
+                    .collect()),
+                Err(e) => Err(failed(e)),
+            }
+        })
+        .await
+    }
+
+    /// Returns the state of every target of `manifest`, as `smfh status`
+    /// prints it.
+    async fn status(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+        manifest: String,
+    ) -> fdo::Result<Vec<(String, String)>> {
+        self.authorize(&header, connection).await?;
+        let options = self.options.clone();
+        let args = self.args.clone();
+        blocking::unblock(move || {
+            let manifest = read(Path::new(&manifest), &args).map_err(failed)?;
+            Ok(manifest
+                .states(&options)
+                .into_iter()
+                .map(|(target, state)| {
+                    let state = state.map_or_else(|_| String::from("error"), |x| x.to_string());
+                    (target.display().to_string(), state)
+                })
+                .collect())
+        })
+        .await
+    }
+
+    /// Sent every second while `manifest` is being applied.
+    #[zbus(signal)]
+    async fn progress(
+        emitter: &SignalEmitter<'_>,
+        manifest: &str,
+        action: &str,
+        done: u32,
+        total: u32,
+    ) -> zbus::Result<()>;
+
+    /// Sent once applying `manifest` is done, with how many entries failed.
+    #[zbus(signal)]
+    async fn finished(
+        emitter: &SignalEmitter<'_>,
+        manifest: &str,
+        action: &str,
+        total: u32,
+        failed: u32,
+    ) -> zbus::Result<()>;
+}
+
+impl Service {
+    /// On the system bus, refuses callers other than root, as everything
+    /// runs with the service's privileges. The session bus only lets the
+    /// user in anyway.
+    async fn authorize(&self, header: &Header<'_>, connection: &Connection) -> fdo::Result<()> {
+        if !self.system {
+            return Ok(());
+        }
+        let sender = header
+            .sender()
+            .ok_or_else(|| fdo::Error::AccessDenied(String::from("no sender")))?;
+        let uid = fdo::DBusProxy::new(connection)
+            .await?
+            .get_connection_unix_user(sender.clone().into())
+            .await?;
+        if uid == 0 {
+            Ok(())
+        } else {
+            Err(fdo::Error::AccessDenied(format!("uid {uid} is not root")))
+        }
+    }
+
+    /// Options for one call, with its own report so that progress counts
+    /// only its entries.
+    fn per_call(&self, prefix: String) -> (Options, Args) {
+        let options = Options {
+            prefix,
+            report: Report::default(),
+            ..self.options.clone()
+        };
+        (options, self.args.clone())
+    }
+}
+
+#[allow(clippy::needless_pass_by_value)]
+fn failed(e: impl ToString) -> fdo::Error {
+    fdo::Error::Failed(e.to_string())
+}
+
+/// Takes the same lock as the other subcommands do for `manifest`.
+fn lock(manifest: &Path, args: &Args) -> fdo::Result<Option<fs::File>> {
+    if args.dry_run {
+        return Ok(None);
+    }
+    let path = args
+        .lock_file
+        .clone()
+        .unwrap_or_else(|| lock_path(manifest));
+    file_util::lock(&path)
+        .map(Some)
+        .map_err(|e| failed(format!("{e:?}")))
+}
+
+/// Runs `work`, sending `Progress` every second from what `report` recorded
+/// meanwhile, then `Finished`.
+fn with_progress<T>(
+    emitter: &SignalEmitter<'_>,
+    manifest: &Path,
+    action: &str,
+    report: &Report,
+    total: usize,
+    work: impl FnOnce() -> T,
+) -> T {
+    let manifest = &manifest.display().to_string();
+    let total = u32::try_from(total).unwrap_or(u32::MAX);
+    let count = |action: Option<Action>| {
+        let entries = report.entries();
+        let count = entries
+            .iter()
+            .filter(|x| action.is_none_or(|action| x.action == action))
+            .count();
+        u32::try_from(count).unwrap_or(u32::MAX).min(total)
+    };
+    let (stop, stopped) = mpsc::channel::<()>();
+    let result = thread::scope(|scope| {
+        scope.spawn(move || {
+            loop {
+                let signal = Service::progress(emitter, manifest, action, count(None), total);
+                if let Err(e) = zbus::block_on(signal) {
+                    error!("Failed to send progress: {e}");
+                }
+                if !matches!(
+                    stopped.recv_timeout(INTERVAL),
+                    Err(RecvTimeoutError::Timeout)
+                ) {
+                    break;
+                }
+            }
+        });
+        let result = work();
+        drop(stop);
+        result
+    });
+    let signal = Service::finished(
+        emitter,
+        manifest,
+        action,
+        total,
+        count(Some(Action::Failed)),
+    );
+    if let Err(e) = zbus::block_on(signal) {
+        error!("Failed to send progress: {e}");
+    }
+    result
+}
+
+/// Serves [`NAME`] on the system bus, or the session bus if `session`, until
+/// killed.
+pub fn run(session: bool, mut options: Options, args: &Args) -> ! {
+    // Nobody is there to answer
+    options.confirm = None;
+    // They would stay on the thread for every later call
+    options.landlock = None;
+    options.seccomp = false;
+    let service = Service {
+        options,
+        args: args.clone(),
+        system: !session,
+    };
+    let builder = if session {
+        Builder::session()
+    } else {
+        Builder::system()
+    };
+    let connection = builder
+        .and_then(|x| x.name(NAME))
+        .and_then(|x| x.serve_at(PATH, service))
+        .and_then(Builder::build);
+    let _connection = connection.unwrap_or_else(|e| {
+        error!("Failed to serve {NAME}: {e}");
+        process::exit(1);
+    });
+    info!("Serving {NAME} at {PATH}");
+    loop {
+        thread::park();
+    }
+}
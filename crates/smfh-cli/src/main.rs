@@ -1,32 +1,90 @@
 mod args;
+#[cfg(all(unix, feature = "dbus"))]
+mod dbus;
+mod notify;
+mod users;
+mod watch;
 
 use args::{
     Args,
     Subcommands,
 };
 use clap::Parser as _;
+use color_eyre::Report;
 use log::{
     error,
     info,
 };
 use simplelog::{
     ColorChoice,
+    CombinedLogger,
     Config,
+    ConfigBuilder,
     LevelFilter,
+    SharedLogger,
     TermLogger,
     TerminalMode,
+    WriteLogger,
 };
 use smfh_core::{
+    Generations,
     VERSION,
+    cas::ContentCache,
+    confirm::{
+        Confirm,
+        Prompt,
+    },
+    file_util::{
+        FileState,
+        backups_of,
+        find_backups,
+        lock,
+        lock_path,
+        prune_backups,
+        write_atomic,
+    },
+    filter::Filter,
+    installed::Installed,
+    journal::{
+        self,
+        Journal,
+    },
     manifest::{
         DiffError,
+        Format,
+        Impure,
         Manifest,
         ReadError,
     },
+    options::Options,
+    plan::PlanEntry,
+    protect::{
+        self,
+        ProtectedError,
+    },
+    report::Stats,
+    schema,
+    sys::{
+        self,
+        User,
+    },
 };
 use std::{
-    path::Path,
+    fs,
+    io::{
+        self,
+        Write as _,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
     process,
+    sync::{
+        Mutex,
+        PoisonError,
+    },
+    time::Duration,
 };
 
 fn handle_read_error(err: ReadError) -> ! {
@@ -41,6 +99,12 @@ fn handle_read_error(err: ReadError) -> ! {
             error!("{e:?}");
             process::exit(3);
         }
+        ReadError::IncludeCycle(_)
+        | ReadError::DuplicateTarget { .. }
+        | ReadError::DependencyCycle(_) => {
+            error!("{err}");
+            process::exit(3);
+        }
         ReadError::ExpandFailed(e) => {
             error!("{e:?}");
             process::exit(4);
@@ -48,14 +112,229 @@ fn handle_read_error(err: ReadError) -> ! {
     }
 }
 
-fn read_or_exit(path: &Path, impure: bool) -> Manifest {
-    match Manifest::read(path, impure) {
-        Ok(m) => m,
-        Err(e) => handle_read_error(e),
+fn handle_diff_error(err: DiffError, old_manifest: &Path, args: &Args) -> ! {
+    match err {
+        DiffError::OldManifestMissing => {
+            error!(
+                "Old manifest {} does not exist and `--fallback` is not set",
+                old_manifest.display()
+            );
+            process::exit(3);
+        }
+        DiffError::OldManifestRead(e) => handle_read_error(e),
+        DiffError::ActivationFailed(failures) => {
+            for (path, err) in &failures {
+                error!("Failed to activate {}: {err}", path.display());
+            }
+            exit_partial_failure(failures.iter().map(|(path, _)| path.as_path()), args);
+        }
+        DiffError::Protected(errors) => exit_protected(&errors),
+        DiffError::Other(e) => {
+            error!("{e:?}");
+            process::exit(1);
+        }
+    }
+}
+
+fn exit_protected(errors: &[ProtectedError]) -> ! {
+    for err in errors {
+        error!("{err}");
+    }
+    error!("Refusing to touch protected paths, nothing was done");
+    process::exit(1);
+}
+
+fn exit_on_failures(failures: &[(PathBuf, Report)], action: &str, args: &Args) {
+    if !failures.is_empty() {
+        for (path, err) in failures {
+            error!("Failed to {action} {}: {err:?}", path.display());
+        }
+        exit_partial_failure(failures.iter().map(|(path, _)| path.as_path()), args);
+    }
+}
+
+/// Exit code of `--check` runs that would change something.
+const EXIT_CHANGES: i32 = 6;
+
+/// With `--check`, whether `plan` would change any target. Without
+/// `--dry-run` the changes would be made, so that is refused.
+fn would_change(plan: impl FnOnce() -> Vec<PlanEntry>, args: &Args) -> bool {
+    if !args.check {
+        return false;
+    }
+    if !args.dry_run {
+        error!("`--check` requires `--dry-run`, except with `verify` and `plan`");
+        process::exit(1);
+    }
+    plan().iter().any(|x| x.step.changes())
+}
+
+fn exit_on_changes(changes: bool) {
+    if changes {
+        info!("Some targets would be changed");
+        process::exit(EXIT_CHANGES);
+    }
+}
+
+/// Exits with the code reserved for runs in which some entries failed, after
+/// listing them if `--summary` is set.
+fn exit_partial_failure<'a>(targets: impl ExactSizeIterator<Item = &'a Path>, args: &Args) -> ! {
+    if args.summary {
+        eprintln!("{} entries failed:", targets.len());
+        for target in targets {
+            eprintln!("  {}", target.display());
+        }
+    }
+    process::exit(5);
+}
+
+/// Writes the report to `--report-file` and the checksums of installed
+/// copies to `--state-dir`, and prints its statistics if `--summary` is set.
+fn write_report(options: &Options, args: &Args) {
+    if let Some(ref installed) = options.installed
+        && !args.dry_run
+        && let Err(e) = installed.save()
+    {
+        error!("Failed to save checksums of installed copies: {e:?}");
     }
+    if let Some(ref path) = args.report_file
+        && let Err(e) = options.report.write(path)
+    {
+        error!("Failed to write report to {}: {e:?}", path.display());
+    }
+    if args.summary {
+        print_stats(&options.report.stats());
+    }
+}
+
+fn print_stats(stats: &Stats) {
+    eprintln!(
+        "{} created, {} correct, {} clobbered, {} backed up, {} modified, {} deleted, {} renamed, {} restored, {} skipped, {} failed, {} bytes copied",
+        stats.created,
+        stats.correct,
+        stats.clobbered,
+        stats.backed_up,
+        stats.modified,
+        stats.deleted,
+        stats.renamed,
+        stats.restored,
+        stats.skipped,
+        stats.failed,
+        stats.bytes_copied,
+    );
+    let phases: Vec<_> = stats
+        .phases
+        .iter()
+        .map(|x| format!("{} {:.2?}", x.name, Duration::from_secs_f64(x.seconds)))
+        .collect();
+    if !phases.is_empty() {
+        eprintln!("{}", phases.join(", "));
+    }
+}
+
+/// Waits for an exclusive lock so that concurrent runs on the same manifest
+/// don't race each other. Not taken for dry runs, which don't write anything.
+fn lock_or_exit(manifest: &Path, args: &Args) -> Option<fs::File> {
+    if args.dry_run {
+        return None;
+    }
+    let path = args
+        .lock_file
+        .clone()
+        .unwrap_or_else(|| lock_path(manifest));
+    match lock(&path) {
+        Ok(file) => Some(file),
+        Err(e) => {
+            error!("{e:?}");
+            process::exit(1);
+        }
+    }
+}
+
+/// Takes the lock for `manifest`, then reads the checksums kept in
+/// `--state-dir` into `options`, so that another run can't save them in
+/// between.
+fn lock_and_load(manifest: &Path, options: &mut Options, args: &Args) -> Option<fs::File> {
+    let lock = lock_or_exit(manifest, args);
+    load_installed(options, args);
+    lock
 }
-fn verify(manifest: &Path, impure: bool) -> smfh_core::manifest::Manifest {
-    let m = read_or_exit(manifest, impure);
+
+fn read_or_exit(path: &Path, args: &Args) -> Manifest {
+    read(path, args).unwrap_or_else(|e| handle_read_error(e))
+}
+
+fn read(path: &Path, args: &Args) -> Result<Manifest, ReadError> {
+    let mut manifest = read_unrooted(path, args)?;
+    if let Some(ref root) = args.root {
+        manifest.reroot(root);
+    }
+    Ok(manifest)
+}
+
+fn read_unrooted_or_exit(path: &Path, args: &Args) -> Manifest {
+    read_unrooted(path, args).unwrap_or_else(|e| handle_read_error(e))
+}
+
+fn read_unrooted(path: &Path, args: &Args) -> Result<Manifest, ReadError> {
+    let format = args.format.unwrap_or_else(|| Format::from_path(path));
+    let impure = match args.allow_env {
+        _ if !args.impure => Impure::No,
+        Some(ref names) => Impure::Variables(names.clone()),
+        None => Impure::AnyVariable,
+    };
+    let user = args.user.as_deref().map(user_or_exit);
+    let mut manifest =
+        Manifest::read_as(path, format, impure, args.allow_duplicates, user.as_ref())?;
+    manifest.variables.extend(args.variables.iter().cloned());
+    Ok(manifest)
+}
+
+/// Looks up the user given with `--user`, exiting if there is no such user.
+fn user_or_exit(name: &str) -> User {
+    sys::user(name)
+        .and_then(|x| x.ok_or_else(|| io::Error::other("no such user")))
+        .unwrap_or_else(|e| {
+            error!("Failed to look up user '{name}': {e}");
+            process::exit(1);
+        })
+}
+
+/// Reads the manifest to apply, along with a copy to archive as a generation
+/// if `--state-dir` is set. The copy is taken before `--root` is applied, so
+/// that rolling back to it with the same `--root` moves its targets only once.
+/// Nothing is archived when a filter such as `--only` leaves part of it
+/// unapplied.
+fn read_to_apply(path: &Path, options: &Options, args: &Args) -> (Manifest, Option<Manifest>) {
+    let _phase = options.report.phase("read");
+    let mut manifest = read_unrooted_or_exit(path, args);
+    let archive = args
+        .state_dir
+        .as_ref()
+        .filter(|_| !args.dry_run && options.filter.is_empty())
+        .map(|_| manifest.clone());
+    if let Some(ref root) = args.root {
+        manifest.reroot(root);
+    }
+    (manifest, archive)
+}
+
+fn record_generation(archive: Option<&Manifest>, args: &Args) {
+    if let Some(manifest) = archive
+        && let Some(ref dir) = args.state_dir
+    {
+        match Generations::new(dir).record(manifest) {
+            Ok(path) => info!("Recorded generation '{}'", path.display()),
+            Err(e) => {
+                error!("{e:?}");
+                process::exit(1);
+            }
+        }
+    }
+}
+
+fn verify(manifest: &Path, args: &Args) -> smfh_core::manifest::Manifest {
+    let m = read_or_exit(manifest, args);
     let errors = m.verify();
     if !errors.is_empty() {
         for e in &errors {
@@ -66,90 +345,546 @@ fn verify(manifest: &Path, impure: bool) -> smfh_core::manifest::Manifest {
     m
 }
 
-fn main() {
-    color_eyre::install().expect("Failed to setup color_eyre");
+fn verify_applied(manifest: &Path, args: &Args) {
+    let mut failed = false;
+    let mut drifted = false;
+    for (target, state) in verify(manifest, args).states(&options(args)) {
+        match state {
+            Ok(FileState::Correct) => {}
+            Ok(state) => {
+                error!("File '{}' is {state}", target.display());
+                drifted = true;
+            }
+            Err(err) => {
+                error!("Failed to check {}: {err:?}", target.display());
+                failed = true;
+            }
+        }
+    }
+    if failed || (drifted && !args.check) {
+        process::exit(1);
+    }
+    exit_on_changes(drifted);
+    info!("Manifest '{}' is valid and applied", manifest.display());
+}
 
-    let args = Args::parse();
+fn validate(manifest: &Path, args: &Args) {
+    let format = args.format.unwrap_or_else(|| Format::from_path(manifest));
+    let value = fs::File::open(manifest)
+        .map_err(Report::from)
+        .and_then(|file| format.parse(&file))
+        .unwrap_or_else(|e| {
+            error!("Failed to read manifest '{}': {e:?}", manifest.display());
+            process::exit(3);
+        });
+    let errors = schema::validate(&value, args.impure);
+    if !errors.is_empty() {
+        for e in &errors {
+            error!("{e}");
+        }
+        process::exit(3);
+    }
+    info!("Manifest '{}' is valid", manifest.display());
+}
+
+fn status(manifest: &Path, prefix: &str, json: bool, args: &Args) {
+    let rows: Vec<(PathBuf, String, Option<PathBuf>)> = read_or_exit(manifest, args)
+        .states(&options(args))
+        .into_iter()
+        .map(|(target, state)| {
+            let state = state.map_or_else(|_| String::from("error"), |x| x.to_string());
+            let backup = backups_of(&target, prefix).ok().and_then(|mut x| x.pop());
+            (target, state, backup)
+        })
+        .collect();
 
-    let level = if args.verbose {
+    if json {
+        let value: Vec<_> = rows
+            .iter()
+            .map(|(target, state, backup)| {
+                serde_json::json!({
+                    "target": target,
+                    "state": state,
+                    "backup": backup,
+                })
+            })
+            .collect();
+        print_json(serde_json::to_string_pretty(&value));
+        return;
+    }
+
+    let width = rows
+        .iter()
+        .map(|(_, state, _)| state.len())
+        .max()
+        .unwrap_or(0);
+    for (target, state, backup) in rows {
+        match backup {
+            Some(backup) => println!(
+                "{state:width$}  {} (backup-exists: {})",
+                target.display(),
+                backup.display()
+            ),
+            None => println!("{state:width$}  {}", target.display()),
+        }
+    }
+}
+
+fn orphans(manifest: &Path, prefix: &str, json: bool, args: &Args) {
+    let orphans = read_or_exit(manifest, args).orphans(prefix);
+    if json {
+        print_json(serde_json::to_string_pretty(&orphans));
+        return;
+    }
+    for orphan in orphans {
+        println!("{}", orphan.display());
+    }
+}
+
+fn print_json(json: serde_json::Result<String>) {
+    match json {
+        Ok(s) => println!("{s}"),
+        Err(e) => {
+            error!("{e:?}");
+            process::exit(1);
+        }
+    }
+}
+
+/// Prints what diffing `old_manifest` against `manifest` would do.
+fn plan(
+    manifest: &Path,
+    old_manifest: &Path,
+    fallback: bool,
+    json: bool,
+    options: &Options,
+    args: &Args,
+) {
+    let plan = read_or_exit(manifest, args)
+        .plan(old_manifest, options, fallback)
+        .unwrap_or_else(|e| handle_diff_error(e, old_manifest, args));
+    if json {
+        print_json(serde_json::to_string_pretty(&plan));
+    } else {
+        print_plan(&plan);
+    }
+    exit_on_changes(args.check && plan.iter().any(|x| x.step.changes()));
+}
+
+fn print_plan(plan: &[PlanEntry]) {
+    for entry in plan {
+        println!(
+            "{:9}  {:18}  {}",
+            entry.change.to_string(),
+            entry.step.to_string(),
+            entry.target.display()
+        );
+    }
+}
+
+fn find_backups_or_exit(
+    manifest: Option<&Path>,
+    dir: Option<&Path>,
+    prefix: &str,
+    args: &Args,
+) -> Vec<PathBuf> {
+    match (manifest, dir) {
+        (_, Some(dir)) => find_backups(dir, prefix).unwrap_or_else(|e| {
+            error!("{e:?}");
+            process::exit(3);
+        }),
+        (Some(manifest), None) => read_or_exit(manifest, args).backups(prefix),
+        (None, None) => unreachable!("clap requires a manifest or --dir"),
+    }
+}
+
+/// Number of entries of `manifest` that the filters let through.
+fn selected(manifest: &Manifest, options: &Options) -> usize {
+    manifest
+        .files
+        .iter()
+        .filter(|file| options.filter.matches(file))
+        .count()
+}
+
+fn activate(manifest: &Path, options: &Options, args: &Args) {
+    let (mut manifest, archive) = read_to_apply(manifest, options, args);
+    let protected = manifest.check_protected(options, false);
+    if !protected.is_empty() {
+        exit_protected(&protected);
+    }
+    let changes = would_change(|| manifest.plan_activation(options), args);
+    let progress =
+        notify::Progress::start(&options.report, selected(&manifest, options), "activated");
+    let failures = manifest.activate(options);
+    progress.finish();
+    write_report(options, args);
+    exit_on_failures(&failures, "activate", args);
+    record_generation(archive.as_ref(), args);
+    exit_on_changes(changes);
+}
+
+fn deactivate(manifest: &Path, options: &Options, args: &Args) {
+    let phase = options.report.phase("read");
+    let mut manifest = read_or_exit(manifest, args);
+    drop(phase);
+    let protected = manifest.check_protected(options, true);
+    if !protected.is_empty() {
+        exit_protected(&protected);
+    }
+    let changes = would_change(|| manifest.plan_deactivation(options), args);
+    let progress =
+        notify::Progress::start(&options.report, selected(&manifest, options), "deactivated");
+    let failures = manifest.deactivate(options);
+    progress.finish();
+    write_report(options, args);
+    exit_on_failures(&failures, "deactivate", args);
+    exit_on_changes(changes);
+}
+
+fn diff(
+    manifest: Manifest,
+    old_manifest: &Path,
+    fallback: bool,
+    json: bool,
+    options: &Options,
+    args: &Args,
+) {
+    // Errors reading the old manifest are left to the diff itself
+    let plan = if json || args.check {
+        manifest
+            .plan(old_manifest, options, fallback)
+            .unwrap_or_default()
+    } else {
+        Vec::new()
+    };
+    let changes = would_change(|| plan.clone(), args);
+    if json {
+        print_json(serde_json::to_string_pretty(&plan));
+    }
+    let progress =
+        notify::Progress::start(&options.report, selected(&manifest, options), "activated");
+    let result = manifest.diff(old_manifest, options, fallback);
+    progress.finish();
+    write_report(options, args);
+    if let Err(e) = result {
+        handle_diff_error(e, old_manifest, args);
+    }
+    exit_on_changes(changes);
+}
+
+/// Reverts the changes recorded in the journal at `path`.
+fn undo(path: &Path, args: &Args) {
+    let failures = journal::undo(path, args.dry_run).unwrap_or_else(|e| {
+        error!("{e:?}");
+        process::exit(1);
+    });
+    exit_on_failures(&failures, "undo the change to", args);
+}
+
+/// Diffs the previous generation against the current one, then drops the
+/// current one so that the previous one becomes current.
+fn rollback(options: &mut Options, args: &Args) {
+    let Some(ref dir) = args.state_dir else {
+        error!("`rollback` requires `--state-dir`");
+        process::exit(1);
+    };
+    let _lock = lock_and_load(dir, options, args);
+    let generations = Generations::new(dir).list().unwrap_or_else(|e| {
+        error!("{e:?}");
+        process::exit(3);
+    });
+    let [.., (_, previous), (_, current)] = generations.as_slice() else {
+        error!("No previous generation in '{}'", dir.display());
+        process::exit(1);
+    };
+    diff(
+        read_or_exit(previous, args),
+        current,
+        false,
+        false,
+        options,
+        args,
+    );
+    if !args.dry_run
+        && let Err(e) = fs::remove_file(current)
+    {
+        error!("Failed to remove generation '{}': {e}", current.display());
+        process::exit(1);
+    }
+}
+
+fn generate(dir: &Path, target: Option<&Path>, out: Option<&Path>, args: &Args) {
+    let manifest = Manifest::generate(dir, target.unwrap_or(dir)).unwrap_or_else(|e| {
+        error!("{e:?}");
+        process::exit(3);
+    });
+    write_manifest(&manifest, out, args);
+}
+
+fn adopt(manifest: &Path, out_dir: &Path, out: Option<&Path>, args: &Args) {
+    let adopted = read_or_exit(manifest, args)
+        .adopt(out_dir)
+        .unwrap_or_else(|e| {
+            error!("{e:?}");
+            process::exit(1);
+        });
+    write_manifest(&adopted, out, args);
+}
+
+/// Prints `manifest`, or writes it to `out`, in the format of `--format` or
+/// else the one matching `out`'s extension.
+fn write_manifest(manifest: &Manifest, out: Option<&Path>, args: &Args) {
+    let format = args
+        .format
+        .or_else(|| out.map(Format::from_path))
+        .unwrap_or_default();
+    let result = format.serialize(manifest).and_then(|s| {
+        out.map_or_else(
+            || {
+                let mut stdout = io::stdout().lock();
+                stdout.write_all(&s)?;
+                if format.is_text() {
+                    stdout.write_all(b"\n")?;
+                }
+                Ok(())
+            },
+            |path| write_atomic(path, &s),
+        )
+    });
+    if let Err(e) = result {
+        error!("{e:?}");
+        process::exit(1);
+    }
+}
+
+/// Builds the run-wide options shared by every subcommand.
+fn options(args: &Args) -> Options {
+    let mut options = Options {
+        dry_run: args.dry_run,
+        jobs: args.jobs,
+        root: args.root.clone(),
+        force: args.force,
+        one_file_system: args.one_file_system,
+        fail_fast: args.fail_fast,
+        wait_for: args.wait_for.clone(),
+        wait_timeout: args.wait_timeout.into(),
+        no_symlinks: args.no_symlinks,
+        fsync: args.fsync,
+        umask: args.umask,
+        timestamped_backups: args.timestamped_backups,
+        show_diff: args.show_diff,
+        seccomp: args.seccomp,
+        identities: args.identity.clone(),
+        filter: Filter {
+            only: args.only.clone(),
+            skip: args.skip.clone(),
+            tags: args.tags.clone(),
+            skip_tags: args.skip_tags.clone(),
+        },
+        ..Options::default()
+    };
+    options.protected.extend(args.protect.iter().cloned());
+    if let Some(ref cache) = args.cache_dir {
+        options.cache.clone_from(cache);
+    }
+    if args.content_cache {
+        options.content_cache = Some(ContentCache::default());
+    }
+    if let Some(ref root) = args.root {
+        options.filter.reroot(root);
+        protect::reroot(&mut options.protected, root);
+        protect::reroot(&mut options.wait_for, root);
+    }
+    if args.interactive {
+        options.confirm = Some(Confirm::new(ask));
+    }
+    if let Some(ref dir) = args.state_dir {
+        options.generations = Some(Generations::new(dir));
+    }
+    // The others that use them read them once they hold the lock
+    if matches!(
+        args.sub_command,
+        Subcommands::Plan { .. } | Subcommands::Watch { .. }
+    ) {
+        load_installed(&mut options, args);
+    }
+    if args.landlock {
+        // Written to after activation
+        let written = [&args.journal, &args.report_file]
+            .into_iter()
+            .flatten()
+            .filter_map(|x| x.parent().map(Path::to_path_buf));
+        options.landlock = Some(args.state_dir.iter().cloned().chain(written).collect());
+    }
+    if let Some(ref path) = args.journal
+        && !args.dry_run
+    {
+        options.journal = Some(Journal::open(path).unwrap_or_else(|e| {
+            error!("{e:?}");
+            process::exit(3);
+        }));
+    }
+    options
+}
+
+/// Reads the checksums kept in `--state-dir` into `options`, for the
+/// subcommands that use them.
+fn load_installed(options: &mut Options, args: &Args) {
+    if let Some(ref dir) = args.state_dir {
+        options.installed = Some(Installed::load(dir).unwrap_or_else(|e| {
+            error!("{e:?}");
+            process::exit(3);
+        }));
+    }
+}
+
+/// Asks on stderr whether `prompt` may go ahead. Anything but `y` or `yes`
+/// on stdin, including its end, declines.
+fn ask(prompt: &Prompt) -> bool {
+    // Keeps the questions of parallel jobs apart
+    static LOCK: Mutex<()> = Mutex::new(());
+    let _guard = LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+    eprint!("{prompt} [y/N] ");
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).is_ok()
+        && matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+fn init_logger(args: &Args) {
+    let level = if args.quiet {
+        LevelFilter::Error
+    } else if let Some(level) = args.log_level {
+        level
+    } else if args.verbose || args.dry_run {
         LevelFilter::Info
     } else {
         LevelFilter::Warn
     };
 
-    TermLogger::init(
+    let mut loggers: Vec<Box<dyn SharedLogger>> = vec![TermLogger::new(
         level,
         Config::default(),
         TerminalMode::Mixed,
         ColorChoice::Auto,
-    )
-    .expect("Failed to initialize logger");
+    )];
+    if let Some(ref path) = args.log_file {
+        // Appending whole lines lets logrotate's copytruncate rotate it
+        let file = fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to open log file '{}': {e}", path.display());
+                process::exit(1);
+            });
+        let config = ConfigBuilder::new().set_time_format_rfc3339().build();
+        loggers.push(WriteLogger::new(level, config, io::LineWriter::new(file)));
+    }
+    CombinedLogger::init(loggers).expect("Failed to initialize logger");
+}
 
+fn main() {
+    color_eyre::install().expect("Failed to setup color_eyre");
+
+    let args = Args::parse();
+    init_logger(&args);
     info!("Program version: '{VERSION}'");
 
-    match args.sub_command {
-        Subcommands::Deactivate { manifest } => {
-            let failures = read_or_exit(&manifest, args.impure).deactivate();
-            if !failures.is_empty() {
-                for (path, err) in &failures {
-                    error!("Failed to deactivate {}: {err:?}", path.display());
-                }
-                process::exit(1);
-            }
+    let mut options = options(&args);
+
+    match args.sub_command.clone() {
+        Subcommands::Deactivate {
+            manifest,
+            prefix,
+            restore_backups,
+        } => {
+            let _lock = lock_and_load(&manifest, &mut options, &args);
+            options.prefix = prefix;
+            options.restore_backups = restore_backups;
+            deactivate(&manifest, &options, &args);
         }
         Subcommands::Activate { manifest, prefix } => {
-            let failures = read_or_exit(&manifest, args.impure).activate(&prefix);
-            if !failures.is_empty() {
-                for (path, err) in &failures {
-                    error!("Failed to activate {}: {err:?}", path.display());
-                }
-                process::exit(1);
-            }
+            let _lock = lock_and_load(&manifest, &mut options, &args);
+            options.prefix = prefix;
+            activate(&manifest, &options, &args);
         }
         Subcommands::Diff {
             prefix,
             fallback,
+            restore_backups,
+            json,
             manifest,
             old_manifest,
         } => {
-            if let Err(e) =
-                read_or_exit(&manifest, args.impure).diff(&old_manifest, &prefix, fallback)
-            {
-                match e {
-                    DiffError::OldManifestMissing => {
-                        error!(
-                            "Old manifest {} does not exist and `--fallback` is not set",
-                            old_manifest.display()
-                        );
-                        process::exit(3);
-                    }
-                    DiffError::OldManifestRead(e) => handle_read_error(e),
-                    DiffError::ActivationFailed(failures) => {
-                        for (path, err) in &failures {
-                            error!("Failed to activate {}: {err}", path.display());
-                        }
-                        process::exit(1);
-                    }
-                    DiffError::Other(e) => {
-                        error!("{e:?}");
-                        process::exit(1);
-                    }
-                }
-            }
+            // The old manifest path stays the same across generations
+            let _lock = lock_and_load(&old_manifest, &mut options, &args);
+            options.prefix = prefix;
+            options.restore_backups = restore_backups;
+            let (manifest, archive) = read_to_apply(&manifest, &options, &args);
+            diff(manifest, &old_manifest, fallback, json, &options, &args);
+            record_generation(archive.as_ref(), &args);
+        }
+        Subcommands::Verify { manifest } => verify_applied(&manifest, &args),
+        Subcommands::Validate { manifest } => validate(&manifest, &args),
+        Subcommands::Schema => print_json(serde_json::to_string_pretty(&schema::schema())),
+        Subcommands::Plan {
+            fallback,
+            json,
+            manifest,
+            old_manifest,
+        } => plan(&manifest, &old_manifest, fallback, json, &options, &args),
+        Subcommands::Status {
+            manifest,
+            prefix,
+            json,
+        } => status(&manifest, &prefix, json, &args),
+        Subcommands::Orphans {
+            manifest,
+            prefix,
+            json,
+        } => orphans(&manifest, &prefix, json, &args),
+        Subcommands::CleanBackups {
+            manifest,
+            dir,
+            prefix,
+            older_than,
+            keep,
+        } => {
+            let backups = find_backups_or_exit(manifest.as_deref(), dir.as_deref(), &prefix, &args);
+            let failures = prune_backups(&backups, keep, older_than.map(Into::into), args.dry_run);
+            exit_on_failures(&failures, "delete backup", &args);
         }
-        Subcommands::Verify { manifest } => {
-            _ = verify(&manifest, args.impure);
-            info!("Manifest '{}' is valid", manifest.display());
+        Subcommands::Rollback {
+            prefix,
+            restore_backups,
+        } => {
+            options.prefix = prefix;
+            options.restore_backups = restore_backups;
+            rollback(&mut options, &args);
+        }
+        Subcommands::Undo { path } => undo(&path, &args),
+        Subcommands::ActivateUsers { users_dir, prefix } => users::run(&users_dir, &prefix, &args),
+        Subcommands::Generate { dir, target, out } => {
+            generate(&dir, target.as_deref(), out.as_deref(), &args);
         }
+        Subcommands::Adopt {
+            manifest,
+            out_dir,
+            out,
+        } => adopt(&manifest, &out_dir, out.as_deref(), &args),
+        Subcommands::Watch {
+            manifest,
+            prefix,
+            watch_manifest,
+        } => {
+            options.prefix = prefix;
+            watch::run(&manifest, watch_manifest, &options, &args);
+        }
+        #[cfg(all(unix, feature = "dbus"))]
+        Subcommands::Dbus { session } => dbus::run(session, options, &args),
         Subcommands::Clean { manifest } => {
-            let m = verify(&manifest, args.impure);
-            match serde_json::to_string_pretty(&m) {
-                Ok(s) => println!("{s}"),
-                Err(e) => {
-                    error!("{e:?}");
-                    process::exit(1);
-                }
-            }
+            print_json(serde_json::to_string_pretty(&verify(&manifest, &args)));
         }
     }
 }
@@ -0,0 +1,98 @@
+//! Progress and readiness notifications for units with `Type=notify`, so that
+//! a slow activation doesn't look hung. Does nothing outside of systemd.
+
+use smfh_core::report::{
+    Action,
+    Report,
+};
+use std::{
+    env,
+    sync::mpsc::{
+        self,
+        RecvTimeoutError,
+        Sender,
+    },
+    thread::{
+        self,
+        JoinHandle,
+    },
+    time::Duration,
+};
+
+const INTERVAL: Duration = Duration::from_secs(1);
+
+/// Periodically sends `STATUS=<verb> <done>/<total> files` until finished.
+pub struct Progress {
+    report: Report,
+    total: usize,
+    verb: &'static str,
+    worker: Option<(Sender<()>, JoinHandle<()>)>,
+}
+
+impl Progress {
+    pub fn start(report: &Report, total: usize, verb: &'static str) -> Self {
+        let worker = env::var_os("NOTIFY_SOCKET").map(|_| {
+            let (stop, stopped) = mpsc::channel();
+            let report = report.clone();
+            let handle = thread::spawn(move || {
+                loop {
+                    let done = report.entries().len().min(total);
+                    status(&format!("{verb} {done}/{total} files"), false);
+                    if !matches!(
+                        stopped.recv_timeout(INTERVAL),
+                        Err(RecvTimeoutError::Timeout)
+                    ) {
+                        break;
+                    }
+                }
+            });
+            (stop, handle)
+        });
+        Self {
+            report: report.clone(),
+            total,
+            verb,
+            worker,
+        }
+    }
+
+    /// Stops the progress updates and reports the final state along with
+    /// `READY=1`.
+    pub fn finish(self) {
+        let Some((stop, handle)) = self.worker else {
+            return;
+        };
+        drop(stop);
+        let _ = handle.join();
+        let failed = self
+            .report
+            .entries()
+            .iter()
+            .filter(|x| x.action == Action::Failed)
+            .count();
+        let message = if failed == 0 {
+            format!("{} {} files", self.verb, self.total)
+        } else {
+            format!("{} {} files, {failed} failed", self.verb, self.total)
+        };
+        status(&message, true);
+    }
+}
+
+#[cfg(unix)]
+fn status(message: &str, ready: bool) {
+    use sd_notify::NotifyState;
+
+    let status = NotifyState::Status(message);
+    let result = if ready {
+        sd_notify::notify(false, &[NotifyState::Ready, status])
+    } else {
+        sd_notify::notify(false, &[status])
+    };
+    if let Err(e) = result {
+        log::warn!("Failed to notify systemd: {e}");
+    }
+}
+
+#[cfg(not(unix))]
+const fn status(_: &str, _: bool) {}
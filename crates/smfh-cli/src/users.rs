@@ -0,0 +1,209 @@
+//! `smfh activate-users`: activates the manifest of every user in a
+//! directory, each in a run of smfh as that user, all at once.
+
+use crate::{
+    args::Args,
+    exit_partial_failure,
+};
+use clap::{
+    Arg,
+    CommandFactory,
+};
+use log::{
+    error,
+    info,
+};
+use smfh_core::sys;
+use std::{
+    env,
+    ffi::OsString,
+    fs,
+    io,
+    path::{
+        self,
+        Path,
+        PathBuf,
+    },
+    process::{
+        self,
+        Child,
+        Command,
+    },
+};
+
+/// Name of the subcommand, up to which the arguments of this run are passed
+/// on to those of every user, along with the global ones after it.
+const SUBCOMMAND: &str = "activate-users";
+
+pub fn run(dir: &Path, prefix: &str, args: &Args) {
+    let shared = [
+        ("--user", args.user.is_some()),
+        ("--state-dir", args.state_dir.is_some()),
+        ("--journal", args.journal.is_some()),
+        ("--report-file", args.report_file.is_some()),
+        ("--lock-file", args.lock_file.is_some()),
+    ];
+    if let Some((flag, _)) = shared.iter().find(|(_, set)| *set) {
+        error!("{flag} would be shared by every user, refusing to run");
+        process::exit(1);
+    }
+    let manifests = manifests(dir).unwrap_or_else(|e| {
+        error!("Failed to read '{}': {e}", dir.display());
+        process::exit(1);
+    });
+
+    let passed_on = passed_on(&env::args_os().skip(1).collect::<Vec<_>>());
+    let mut children = Vec::new();
+    let mut failed = Vec::new();
+    for (name, manifest) in manifests {
+        match spawn(&name, &manifest, prefix, &passed_on) {
+            Ok(child) => children.push((manifest, child)),
+            Err(e) => {
+                error!("Failed to activate '{}' as {name}: {e}", manifest.display());
+                failed.push(manifest);
+            }
+        }
+    }
+    for (manifest, mut child) in children {
+        match child.wait() {
+            Ok(status) if status.success() => {}
+            Ok(status) => {
+                error!("Activating '{}' {status}", manifest.display());
+                failed.push(manifest);
+            }
+            Err(e) => {
+                error!("Failed to wait for '{}': {e}", manifest.display());
+                failed.push(manifest);
+            }
+        }
+    }
+    if !failed.is_empty() {
+        exit_partial_failure(failed.iter().map(PathBuf::as_path), args);
+    }
+}
+
+/// Returns the manifests in `dir`, each with the name of the user it is for,
+/// its file name without the extension. Hidden files are left out.
+fn manifests(dir: &Path) -> io::Result<Vec<(String, PathBuf)>> {
+    let mut manifests = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = path::absolute(entry?.path())?;
+        if !path.is_file() {
+            continue;
+        }
+        if let Some(name) = path.file_stem().and_then(|x| x.to_str())
+            && !name.starts_with('.')
+        {
+            manifests.push((name.to_owned(), path));
+        }
+    }
+    manifests.sort();
+    Ok(manifests)
+}
+
+/// Returns the arguments of this run, without the program name, that every
+/// user's run gets: those before the subcommand and the global ones given
+/// after it, which would otherwise be lost.
+fn passed_on(args: &[OsString]) -> Vec<OsString> {
+    let Some(at) = args.iter().position(|x| x == SUBCOMMAND) else {
+        return args.to_vec();
+    };
+    let command = Args::command();
+    let own = command
+        .find_subcommand(SUBCOMMAND)
+        .into_iter()
+        .flat_map(clap::Command::get_arguments);
+    let known: Vec<_> = command.get_arguments().chain(own).collect();
+
+    let mut passed = args[..at].to_vec();
+    let mut rest = args[at + 1..].iter();
+    while let Some(arg) = rest.next() {
+        let Some(s) = arg.to_str() else {
+            continue;
+        };
+        if s == "--" {
+            break;
+        }
+        let Some((found, inline)) = find(&known, s) else {
+            continue;
+        };
+        let value = (found.get_action().takes_values() && !inline)
+            .then(|| rest.next())
+            .flatten();
+        if found.is_global_set() {
+            passed.push(arg.clone());
+            passed.extend(value.cloned());
+        }
+    }
+    passed
+}
+
+/// Returns the argument `arg` is the flag of, and whether its value is part
+/// of it, as in `--jobs=4` or `-j4`.
+fn find<'a>(known: &[&'a Arg], arg: &str) -> Option<(&'a Arg, bool)> {
+    if let Some(long) = arg.strip_prefix("--") {
+        let (long, inline) = long
+            .split_once('=')
+            .map_or((long, false), |(x, _)| (x, true));
+        let found = known.iter().find(|x| x.get_long() == Some(long))?;
+        return Some((found, inline));
+    }
+    let short = arg.strip_prefix('-')?.chars().next()?;
+    let found = known.iter().find(|x| x.get_short() == Some(short))?;
+    Some((found, arg.len() > 2))
+}
+
+/// Starts activating `manifest` with `--user name`, as that user.
+fn spawn(name: &str, manifest: &Path, prefix: &str, args: &[OsString]) -> io::Result<Child> {
+    let user = sys::user(name)?.ok_or_else(|| io::Error::other("no such user"))?;
+    let mut command = Command::new(env::current_exe()?);
+    command
+        .args(args)
+        .args(["--user", name, "activate", "--prefix", prefix])
+        .arg(manifest);
+    sys::run_as(&mut command, &user)?;
+    info!("Activating '{}' as {name}", manifest.display());
+    command.spawn()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn passed_on(args: &[&str]) -> Vec<OsString> {
+        super::passed_on(&args.iter().map(OsString::from).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn passes_on_global_arguments_after_the_subcommand() {
+        assert_eq!(
+            passed_on(&[
+                "--dry-run",
+                "--check",
+                "activate-users",
+                "--root",
+                "/mnt",
+                "-p",
+                ".old-",
+                "--prefix=.old-",
+                "/etc/smfh/users",
+                "--only=/home",
+                "-j4",
+                "--var",
+                "a=b",
+                "--seccomp",
+            ]),
+            [
+                "--dry-run",
+                "--check",
+                "--root",
+                "/mnt",
+                "--only=/home",
+                "-j4",
+                "--var",
+                "a=b",
+                "--seccomp",
+            ]
+        );
+    }
+}
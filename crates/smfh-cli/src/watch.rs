@@ -0,0 +1,134 @@
+//! `smfh watch`: activates a manifest, then activates again the entries whose
+//! sources change, until killed.
+
+use crate::{
+    args::Args,
+    lock_or_exit,
+    read,
+    read_or_exit,
+    write_report,
+};
+use log::{
+    error,
+    info,
+    warn,
+};
+use smfh_core::{
+    manifest::Manifest,
+    options::Options,
+    sys::Watcher,
+    watch::{
+        affects,
+        watched_dirs,
+    },
+};
+use std::{
+    fs,
+    io,
+    mem,
+    path::{
+        Path,
+        PathBuf,
+    },
+    process,
+    time::Duration,
+};
+
+/// How long to wait for more changes after one, so that an editor saving or a
+/// build writing several files leads to a single activation.
+const SETTLE: Duration = Duration::from_millis(100);
+
+pub fn run(path: &Path, watch_manifest: bool, options: &Options, args: &Args) -> ! {
+    let path = fs::canonicalize(path).unwrap_or_else(|e| {
+        error!("Failed to find manifest '{}': {e}", path.display());
+        process::exit(3);
+    });
+    let mut watcher = Watcher::new().unwrap_or_else(|e| {
+        error!("Failed to watch sources: {e}");
+        process::exit(1);
+    });
+    let mut manifest = read_or_exit(&path, args);
+    {
+        let _lock = lock_or_exit(&path, args);
+        activate(manifest.clone(), options);
+        write_report(options, args);
+    }
+
+    loop {
+        let manifest_dir = path.parent().filter(|_| watch_manifest);
+        for dir in watched_dirs(&manifest)
+            .iter()
+            .map(PathBuf::as_path)
+            .chain(manifest_dir)
+        {
+            if let Err(e) = watcher.watch(dir) {
+                warn!("Failed to watch '{}': {e}", dir.display());
+            }
+        }
+        let changed = changes(&mut watcher).unwrap_or_else(|e| {
+            error!("Failed to wait for changes: {e}");
+            process::exit(1);
+        });
+
+        // Changes were lost, so any entry, or the manifest, may be stale
+        let overflowed = watcher.overflowed();
+        if overflowed {
+            warn!("Missed changes, activating every entry again");
+        }
+
+        let _lock = lock_or_exit(&path, args);
+        let manifest_changed = watch_manifest && (overflowed || changed.contains(&path));
+        if manifest_changed {
+            match read(&path, args) {
+                Ok(new) => {
+                    info!("Manifest '{}' changed", path.display());
+                    let old = mem::replace(&mut manifest, new);
+                    if let Err(e) = manifest.clone().diff_with(old, options) {
+                        error!("Failed to apply the changed manifest: {e}");
+                    }
+                }
+                Err(e) => error!("Failed to read manifest '{}': {e}", path.display()),
+            }
+        }
+        if overflowed {
+            activate(manifest.clone(), options);
+        } else if !manifest_changed {
+            let mut subset = manifest.clone();
+            subset
+                .files
+                .update(|files| files.retain(|file| affects(file, &changed)));
+            if subset.files.is_empty() {
+                continue;
+            }
+            activate(subset, options);
+        }
+        write_report(options, args);
+    }
+}
+
+/// Waits for changes, then for them to settle, and returns every changed path.
+fn changes(watcher: &mut Watcher) -> io::Result<Vec<PathBuf>> {
+    let mut changed = watcher.wait(None)?;
+    loop {
+        let more = watcher.wait(Some(SETTLE))?;
+        if more.is_empty() {
+            return Ok(changed);
+        }
+        changed.extend(more);
+    }
+}
+
+/// Activates `manifest`, logging failures instead of exiting.
+fn activate(mut manifest: Manifest, options: &Options) {
+    let protected = manifest.check_protected(options, false);
+    if !protected.is_empty() {
+        for err in &protected {
+            error!("{err}");
+        }
+        error!("Refusing to touch protected paths, nothing was done");
+        return;
+    }
+    for (target, err) in manifest.activate(options) {
+        error!("Failed to activate {}: {err:?}", target.display());
+    }
+}
@@ -0,0 +1,139 @@
+//! Content-addressed copies of [`Copy`][crate::manifest::FileKind::Copy]
+//! sources, so that identical contents are only read and hashed once, and
+//! targets can be cloned from a copy on their own filesystem.
+
+use crate::{
+    checksum::Checksum,
+    fetch::cached_path,
+    file_util::{
+        copy_file,
+        mkdir,
+        random_sibling,
+    },
+    manifest::Reflink,
+    sys,
+};
+use blake3::Hash;
+use color_eyre::{
+    Result,
+    eyre::{
+        OptionExt as _,
+        WrapErr as _,
+    },
+};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::{
+        Arc,
+        Mutex,
+        MutexGuard,
+        PoisonError,
+    },
+};
+
+/// Stores sources under `blake3/` in [`Options::cache`], remembering the
+/// hash of every source it has seen. Clones share the same hashes.
+///
+/// [`Options::cache`]: crate::options::Options::cache
+#[derive(Debug, Clone, Default)]
+pub struct ContentCache(Arc<Mutex<HashMap<PathBuf, Hash>>>);
+
+impl ContentCache {
+    /// Returns the BLAKE3 hash of `source`, only reading it the first time.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `source` can't be read.
+    pub fn hash(&self, source: &Path) -> Result<Hash> {
+        let source = fs::canonicalize(source)?;
+        if let Some(hash) = self.hashes().get(&source) {
+            return Ok(*hash);
+        }
+        let hash = blake3::Hasher::new()
+            .update_mmap(&source)
+            .wrap_err_with(|| format!("Failed to hash '{}'", source.display()))?
+            .finalize();
+        self.hashes().insert(source, hash);
+        Ok(hash)
+    }
+
+    /// Copies `source` into `cache` unless its contents already are with the
+    /// same mode, and returns the cached copy and its hash. Targets copy the
+    /// mode of the cached copy, so sources that differ in it are kept apart.
+    /// The cache is only accessible by its owner, as sources may be private.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `source` can't be read or copied.
+    pub fn store(&self, source: &Path, cache: &Path) -> Result<(PathBuf, Hash)> {
+        let hash = self.hash(source)?;
+        let mode = sys::mode(&fs::metadata(source)?) & 0o7777;
+        let mut path = cached_path(cache, &Checksum::from(hash));
+        path.as_mut_os_string().push(format!("-{mode:o}"));
+        if fs::symlink_metadata(&path).is_ok() {
+            return Ok((path, hash));
+        }
+
+        let dir = path.parent().ok_or_eyre("Failed to get parent directory")?;
+        mkdir(dir)?;
+        sys::set_mode(dir, 0o700)?;
+        let temp_path = random_sibling(&path);
+        copy_file(source, &temp_path, Reflink::Auto)
+            .and_then(|()| sys::set_mode(&temp_path, mode).map_err(Into::into))
+            .and_then(|()| {
+                fs::rename(&temp_path, &path)
+                    .wrap_err_with(|| format!("Failed to rename to '{}'", path.display()))
+            })
+            .inspect_err(|_| {
+                let _ = fs::remove_file(&temp_path);
+            })?;
+        Ok((path, hash))
+    }
+
+    fn hashes(&self) -> MutexGuard<'_, HashMap<PathBuf, Hash>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn store_shares_identical_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path().join("cache");
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        fs::write(&a, b"same").unwrap();
+        fs::write(&b, b"same").unwrap();
+
+        let cas = ContentCache::default();
+        let (stored, hash) = cas.store(&a, &cache).unwrap();
+        assert_eq!(hash, blake3::hash(b"same"));
+        assert_eq!(fs::read(&stored).unwrap(), b"same");
+        assert_eq!(cas.store(&b, &cache).unwrap().0, stored);
+        assert_eq!(fs::read_dir(stored.parent().unwrap()).unwrap().count(), 1);
+
+        // Kept apart by mode, which targets copy
+        let c = dir.path().join("c");
+        fs::write(&c, b"same").unwrap();
+        sys::set_mode(&a, 0o644).unwrap();
+        sys::set_mode(&c, 0o755).unwrap();
+        let executable = cas.store(&c, &cache).unwrap().0;
+        assert_ne!(executable, stored);
+        assert_eq!(
+            sys::mode(&fs::metadata(&executable).unwrap()) & 0o777,
+            0o755
+        );
+        assert_eq!(sys::mode(&fs::metadata(&stored).unwrap()) & 0o777, 0o644);
+
+        // Hashed once, so later changes go unnoticed within a run
+        fs::write(&a, b"changed").unwrap();
+        assert_eq!(cas.hash(&a).unwrap(), hash);
+    }
+}
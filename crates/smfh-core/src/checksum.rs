@@ -0,0 +1,209 @@
+//! Expected checksums of file contents, written as `algorithm:hex`.
+
+use color_eyre::{
+    Result,
+    eyre::{
+        WrapErr as _,
+        eyre,
+    },
+};
+use core::{
+    fmt::{
+        self,
+        Display,
+        Write as _,
+    },
+    str::FromStr,
+};
+use schemars::{
+    JsonSchema,
+    Schema,
+    SchemaGenerator,
+    json_schema,
+};
+use serde::{
+    Deserialize,
+    Deserializer,
+    Serialize,
+    Serializer,
+    de,
+};
+use sha2::{
+    Digest as _,
+    Sha256,
+};
+use std::{
+    borrow::Cow,
+    fs,
+    io,
+    path::Path,
+};
+
+/// Hash function of a [`Checksum`].
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Algorithm {
+    Blake3,
+    Sha256,
+}
+
+impl Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match *self {
+            Self::Blake3 => "blake3",
+            Self::Sha256 => "sha256",
+        })
+    }
+}
+
+/// The expected hash of some contents, e.g. `sha256:e3b0c442...`.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Checksum {
+    pub algorithm: Algorithm,
+    /// Lowercase hex digest.
+    pub digest: String,
+}
+
+impl Checksum {
+    /// Hashes `bytes` with `algorithm`.
+    #[must_use]
+    pub fn of(algorithm: Algorithm, bytes: &[u8]) -> Self {
+        let digest = match algorithm {
+            Algorithm::Blake3 => blake3::hash(bytes).to_hex().to_string(),
+            Algorithm::Sha256 => hex(&Sha256::digest(bytes)),
+        };
+        Self { algorithm, digest }
+    }
+
+    /// Hashes the file at `path` with `algorithm`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file can't be read.
+    pub fn of_file(algorithm: Algorithm, path: &Path) -> Result<Self> {
+        let digest = match algorithm {
+            Algorithm::Blake3 => blake3::Hasher::new()
+                .update_mmap(path)
+                .map(|x| x.finalize().to_hex().to_string()),
+            Algorithm::Sha256 => {
+                let mut hasher = Sha256::new();
+                fs::File::open(path)
+                    .and_then(|mut x| io::copy(&mut x, &mut hasher))
+                    .map(|_| hex(&hasher.finalize()))
+            }
+        }
+        .wrap_err_with(|| format!("Failed to hash '{}'", path.display()))?;
+        Ok(Self { algorithm, digest })
+    }
+
+    /// Whether the file at `path` has this checksum.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file can't be read.
+    pub fn matches_file(&self, path: &Path) -> Result<bool> {
+        Ok(Self::of_file(self.algorithm, path)? == *self)
+    }
+}
+
+impl From<blake3::Hash> for Checksum {
+    fn from(hash: blake3::Hash) -> Self {
+        Self {
+            algorithm: Algorithm::Blake3,
+            digest: hash.to_hex().to_string(),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .fold(String::with_capacity(bytes.len() * 2), |mut hex, x| {
+            let _ = write!(hex, "{x:02x}");
+            hex
+        })
+}
+
+impl Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm, self.digest)
+    }
+}
+
+impl FromStr for Checksum {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (algorithm, digest) = s
+            .split_once(':')
+            .ok_or_else(|| eyre!("expected 'blake3:<hex>' or 'sha256:<hex>', got '{s}'"))?;
+        let algorithm = match algorithm {
+            "blake3" => Algorithm::Blake3,
+            "sha256" => Algorithm::Sha256,
+            x => return Err(eyre!("unknown hash algorithm '{x}'")),
+        };
+        if digest.len() != 64 || !digest.bytes().all(|x| x.is_ascii_hexdigit()) {
+            return Err(eyre!("expected 64 hex digits, got '{digest}'"));
+        }
+        Ok(Self {
+            algorithm,
+            digest: digest.to_ascii_lowercase(),
+        })
+    }
+}
+
+impl Serialize for Checksum {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Checksum {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+impl JsonSchema for Checksum {
+    fn schema_name() -> Cow<'static, str> {
+        "Checksum".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "type": "string",
+            "description": "blake3:<hex> or sha256:<hex>",
+            "pattern": "^(blake3|sha256):[0-9a-fA-F]{64}$",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum_round_trips_and_matches() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        fs::write(&path, b"").unwrap();
+
+        let sha256: Checksum =
+            "sha256:E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855"
+                .parse()
+                .unwrap();
+        assert_eq!(sha256, Checksum::of(Algorithm::Sha256, b""));
+        assert!(sha256.matches_file(&path).unwrap());
+        assert_eq!(
+            sha256.to_string(),
+            "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+
+        let blake3 = Checksum::of(Algorithm::Blake3, b"x");
+        assert_eq!(blake3.to_string().parse::<Checksum>().unwrap(), blake3);
+        assert!(!blake3.matches_file(&path).unwrap());
+
+        assert!("md5:00".parse::<Checksum>().is_err());
+        assert!("sha256:00".parse::<Checksum>().is_err());
+    }
+}
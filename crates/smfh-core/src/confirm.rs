@@ -0,0 +1,97 @@
+//! Confirmation of destructive steps, so that adopting an existing directory
+//! doesn't have to be all-or-nothing.
+
+use crate::report::Action;
+use core::fmt::{
+    self,
+    Debug,
+    Display,
+};
+use std::{
+    fs::{
+        self,
+        Metadata,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::Arc,
+};
+
+/// A destructive step on an existing file that doesn't match the manifest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Prompt {
+    pub target: PathBuf,
+    /// [`Clobbered`][Action::Clobbered], [`BackedUp`][Action::BackedUp], or
+    /// [`Deleted`][Action::Deleted].
+    pub action: Action,
+    /// What is at the target now, e.g. `a file of 12 bytes`.
+    pub found: String,
+    /// What the manifest wants there instead, e.g. `a symlink to '/a'`.
+    pub wanted: String,
+}
+
+impl Prompt {
+    /// Describes `metadata` of the file at `path` for [`found`][Self::found].
+    #[must_use]
+    pub fn describe(path: &Path, metadata: &Metadata) -> String {
+        if metadata.is_dir() {
+            String::from("a directory")
+        } else if metadata.is_symlink() {
+            fs::read_link(path).map_or_else(
+                |_| String::from("a symlink"),
+                |x| format!("a symlink to '{}'", x.display()),
+            )
+        } else if metadata.is_file() {
+            format!("a file of {} bytes", metadata.len())
+        } else {
+            String::from("a special file")
+        }
+    }
+}
+
+impl Display for Prompt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let target = self.target.display();
+        match self.action {
+            Action::Deleted => write!(f, "Delete '{target}', {}?", self.found),
+            Action::BackedUp => write!(
+                f,
+                "'{target}' is {}, not {}. Back it up?",
+                self.found, self.wanted
+            ),
+            _ => write!(
+                f,
+                "'{target}' is {}, not {}. Clobber it?",
+                self.found, self.wanted
+            ),
+        }
+    }
+}
+
+/// Decides whether a [`Prompt`] may go ahead.
+///
+/// See [`Options::confirm`][crate::options::Options::confirm]. Called from
+/// several threads with more than one of
+/// [`Options::jobs`][crate::options::Options::jobs].
+#[derive(Clone)]
+pub struct Confirm(Arc<dyn Fn(&Prompt) -> bool + Send + Sync>);
+
+impl Confirm {
+    pub fn new(confirm: impl Fn(&Prompt) -> bool + Send + Sync + 'static) -> Self {
+        Self(Arc::new(confirm))
+    }
+
+    /// Returns whether the step described by `prompt` may go ahead.
+    #[must_use]
+    pub fn ask(&self, prompt: &Prompt) -> bool {
+        (self.0)(prompt)
+    }
+}
+
+impl Debug for Confirm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Confirm(..)")
+    }
+}
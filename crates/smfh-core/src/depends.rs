@@ -0,0 +1,215 @@
+//! Order of entries that [depend on][File::depends_on] the targets of
+//! others, so that those are activated first.
+
+use crate::{
+    index::Files,
+    manifest::File,
+};
+use core::{
+    cmp::Reverse,
+    mem,
+};
+use std::{
+    collections::{
+        BinaryHeap,
+        HashSet,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+/// Positions of the entries each of `files` depends on. Targets that no
+/// entry has are left out.
+fn dependencies(files: &Files) -> Vec<Vec<usize>> {
+    files
+        .iter()
+        .map(|file| {
+            file.depends_on
+                .iter()
+                .filter_map(|target| files.get(target))
+                .collect()
+        })
+        .collect()
+}
+
+/// Returns the positions of the entries in an order where each comes after
+/// those it depends on, and otherwise as early as its position allows.
+/// Entries in or after a cycle are left out.
+fn topological(dependencies: &[Vec<usize>]) -> Vec<usize> {
+    let mut waiting: Vec<usize> = dependencies.iter().map(Vec::len).collect();
+    let mut dependents = vec![Vec::new(); dependencies.len()];
+    for (i, dependencies) in dependencies.iter().enumerate() {
+        for &dependency in dependencies {
+            dependents[dependency].push(i);
+        }
+    }
+
+    let mut ready: BinaryHeap<Reverse<usize>> = waiting
+        .iter()
+        .enumerate()
+        .filter(|&(_, &n)| n == 0)
+        .map(|(i, _)| Reverse(i))
+        .collect();
+    let mut order = Vec::with_capacity(dependencies.len());
+    while let Some(Reverse(i)) = ready.pop() {
+        order.push(i);
+        for &dependent in &dependents[i] {
+            waiting[dependent] -= 1;
+            if waiting[dependent] == 0 {
+                ready.push(Reverse(dependent));
+            }
+        }
+    }
+    order
+}
+
+/// Sorts `files` by their [`Ord`], then moves each entry after those it
+/// depends on. Entries in a cycle, which [`cycle`] finds when a manifest is
+/// read, are left at the end in sorted order.
+pub fn sort(files: &mut Files) {
+    let independent = files.iter().all(|file| file.depends_on.is_empty());
+    if independent && files.is_sorted() {
+        return;
+    }
+    files.update(|x| x.sort());
+    if independent {
+        return;
+    }
+    let mut order = topological(&dependencies(files));
+    if order.len() < files.len() {
+        let mut placed = vec![false; files.len()];
+        for &i in &order {
+            placed[i] = true;
+        }
+        order.extend((0..files.len()).filter(|&i| !placed[i]));
+    }
+    files.update(|files| {
+        let mut unsorted: Vec<Option<File>> = mem::take(files).into_iter().map(Some).collect();
+        files.extend(order.into_iter().filter_map(|i| unsorted[i].take()));
+    });
+}
+
+/// Returns the targets of entries that depend on each other in a cycle, each
+/// depending on the next and the last being the first again, if any.
+#[must_use]
+pub fn cycle(files: &Files) -> Option<Vec<PathBuf>> {
+    let dependencies = dependencies(files);
+    let order = topological(&dependencies);
+    if order.len() == files.len() {
+        return None;
+    }
+    let mut placed = vec![false; files.len()];
+    for i in order {
+        placed[i] = true;
+    }
+
+    // Every entry left waits on another one left, so following them must
+    // come back around
+    let mut path = Vec::new();
+    let mut seen = vec![None; files.len()];
+    let mut i = placed.iter().position(|&x| !x)?;
+    while seen[i].is_none() {
+        seen[i] = Some(path.len());
+        path.push(i);
+        i = *dependencies[i].iter().find(|&&x| !placed[x])?;
+    }
+    let start = seen[i]?;
+    path.push(i);
+    Some(
+        path[start..]
+            .iter()
+            .map(|&i| files[i].target.clone())
+            .collect(),
+    )
+}
+
+/// Splits sorted `files` into runs of the same [`File::order`] whose entries
+/// don't depend on each other, so that each run can be activated
+/// concurrently once the ones before it are done.
+#[must_use]
+pub fn batches(files: &[File]) -> Vec<&[File]> {
+    let mut batches = Vec::new();
+    let mut start = 0;
+    let mut targets: HashSet<&Path> = HashSet::new();
+    for (i, file) in files.iter().enumerate() {
+        if files[start].order.unwrap_or(0) != file.order.unwrap_or(0)
+            || file
+                .depends_on
+                .iter()
+                .any(|target| targets.contains(target.as_path()))
+        {
+            batches.push(&files[start..i]);
+            start = i;
+            targets.clear();
+        }
+        targets.insert(&file.target);
+    }
+    if start < files.len() {
+        batches.push(&files[start..]);
+    }
+    batches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::FileKind;
+
+    fn file(kind: FileKind, target: &str, depends_on: &[&str]) -> File {
+        let mut file = File::new(kind, target.into());
+        file.depends_on = depends_on.iter().map(PathBuf::from).collect();
+        file
+    }
+
+    fn targets(files: &[File]) -> Vec<&str> {
+        files.iter().map(|x| x.target.to_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn sorts_dependencies_first() {
+        let mut files = Files::from(vec![
+            file(FileKind::Modify, "/etc/app", &[]),
+            file(FileKind::Copy, "/etc/app/a", &["/etc/app"]),
+            file(FileKind::Copy, "/etc/b", &[]),
+            file(FileKind::Touch, "/etc/generated", &["/etc/missing"]),
+        ]);
+        sort(&mut files);
+        assert_eq!(
+            targets(&files),
+            ["/etc/b", "/etc/generated", "/etc/app", "/etc/app/a"]
+        );
+        assert_eq!(
+            batches(&files)
+                .iter()
+                .map(|x| targets(x))
+                .collect::<Vec<_>>(),
+            [
+                vec!["/etc/b", "/etc/generated", "/etc/app"],
+                vec!["/etc/app/a"]
+            ]
+        );
+        assert_eq!(cycle(&files), None);
+    }
+
+    #[test]
+    fn finds_cycles() {
+        let mut files = Files::from(vec![
+            file(FileKind::Copy, "/a", &[]),
+            file(FileKind::Copy, "/b", &["/c"]),
+            file(FileKind::Copy, "/c", &["/d", "/a"]),
+            file(FileKind::Copy, "/d", &["/b"]),
+            file(FileKind::Copy, "/e", &["/d"]),
+        ]);
+        assert_eq!(
+            cycle(&files),
+            Some(["/b", "/c", "/d", "/b"].map(PathBuf::from).to_vec())
+        );
+        sort(&mut files);
+        assert_eq!(targets(&files), ["/a", "/b", "/c", "/d", "/e"]);
+
+        let files = Files::from(vec![file(FileKind::Copy, "/a", &["/a"])]);
+        assert_eq!(cycle(&files), Some(vec!["/a".into(), "/a".into()]));
+    }
+}
@@ -0,0 +1,141 @@
+//! Downloading of `http(s)://` sources into a local cache, so that remote
+//! assets can be managed like any other copy.
+
+use crate::{
+    checksum::Checksum,
+    file_util::{
+        mkdir,
+        random_sibling,
+    },
+    sys,
+};
+use color_eyre::{
+    Result,
+    eyre::{
+        OptionExt as _,
+        WrapErr as _,
+        eyre,
+    },
+};
+use log::info;
+use std::{
+    env,
+    fs,
+    path::{
+        Path,
+        PathBuf,
+    },
+    process::{
+        Command,
+        Stdio,
+    },
+};
+
+/// Whether `source` is an `http://` or `https://` URL rather than a path.
+#[must_use]
+pub fn is_url(source: &Path) -> bool {
+    source
+        .to_str()
+        .is_some_and(|x| x.starts_with("http://") || x.starts_with("https://"))
+}
+
+/// Returns `/var/cache/smfh` for root, and `$XDG_CACHE_HOME/smfh` or
+/// `~/.cache/smfh` for anyone else.
+#[must_use]
+pub fn default_cache() -> PathBuf {
+    if sys::is_root() {
+        return PathBuf::from("/var/cache/smfh");
+    }
+    env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|x| PathBuf::from(x).join(".cache")))
+        .unwrap_or_else(env::temp_dir)
+        .join("smfh")
+}
+
+/// Returns where the contents with `checksum` are kept under `cache`.
+#[must_use]
+pub fn cached_path(cache: &Path, checksum: &Checksum) -> PathBuf {
+    cache
+        .join(checksum.algorithm.to_string())
+        .join(&checksum.digest)
+}
+
+/// Returns the cached copy of `url`, downloading it with `curl` first unless
+/// it is already cached. A download is only cached if it has `checksum`.
+///
+/// # Errors
+///
+/// Returns an error if the download fails or doesn't have `checksum`.
+pub fn fetch(url: &str, checksum: &Checksum, cache: &Path) -> Result<PathBuf> {
+    let path = cached_path(cache, checksum);
+    if path.is_file() && checksum.matches_file(&path)? {
+        return Ok(path);
+    }
+    mkdir(path.parent().ok_or_eyre("Failed to get parent directory")?)?;
+
+    let temp_path = random_sibling(&path);
+    let result = download(url, &temp_path).and_then(|()| {
+        let actual = Checksum::of_file(checksum.algorithm, &temp_path)?;
+        if actual != *checksum {
+            return Err(eyre!(
+                "'{url}' has checksum {actual}, but {checksum} was expected"
+            ));
+        }
+        fs::rename(&temp_path, &path)
+            .wrap_err_with(|| format!("Failed to rename to '{}'", path.display()))
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result?;
+    info!("Fetched '{url}' into '{}'", path.display());
+    Ok(path)
+}
+
+fn download(url: &str, path: &Path) -> Result<()> {
+    let output = Command::new("curl")
+        .args([
+            "--fail",
+            "--location",
+            "--silent",
+            "--show-error",
+            "--output",
+        ])
+        .arg(path)
+        .arg(url)
+        .stdin(Stdio::null())
+        .output()
+        .wrap_err("Failed to run curl")?;
+    if !output.status.success() {
+        return Err(eyre!(
+            "Failed to download '{url}': {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum::Algorithm;
+
+    #[test]
+    fn fetch_uses_cached_copy() {
+        let cache = tempfile::tempdir().unwrap();
+        let checksum = Checksum::of(Algorithm::Sha256, b"asset");
+        let path = cached_path(cache.path(), &checksum);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, b"asset").unwrap();
+
+        // Unresolvable, so only the cache can satisfy it
+        let url = "https://smfh.invalid/asset";
+        assert_eq!(fetch(url, &checksum, cache.path()).unwrap(), path);
+
+        fs::write(&path, b"corrupted").unwrap();
+        assert!(fetch(url, &checksum, cache.path()).is_err());
+        assert!(is_url(Path::new(url)));
+        assert!(!is_url(Path::new("/nix/store/asset")));
+    }
+}
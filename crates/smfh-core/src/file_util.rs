@@ -1,6 +1,30 @@
 use crate::{
-    file_util,
+    checksum::{
+        Algorithm,
+        Checksum,
+    },
+    confirm::Prompt,
+    fetch,
     manifest,
+    memo::Memo,
+    options::Options,
+    patch,
+    report::{
+        ALREADY_CORRECT,
+        Action,
+    },
+    secret::{
+        self,
+        Decrypt,
+    },
+    sys::{
+        self,
+        Resolve,
+    },
+};
+use base64::{
+    Engine as _,
+    prelude::BASE64_STANDARD,
 };
 use blake3::Hash;
 use color_eyre::{
@@ -11,6 +35,7 @@ use color_eyre::{
         eyre,
     },
 };
+use core::mem;
 use log::{
     info,
     warn,
@@ -18,25 +43,34 @@ use log::{
 use manifest::{
     File,
     FileKind,
+    IfMissingSource,
+    Mtime,
+    Reflink,
 };
 use rand::distr::{
     Alphanumeric,
     SampleString,
 };
 use std::{
-    ffi::OsString,
+    borrow::Cow,
+    collections::{
+        BTreeMap,
+        BTreeSet,
+    },
+    env,
+    ffi::{
+        OsStr,
+        OsString,
+    },
+    fmt,
     fs::{
         self,
         Metadata,
         read_link,
     },
-    io::ErrorKind,
-    os::unix::fs::{
-        MetadataExt as _,
-        PermissionsExt as _,
-        chown,
-        lchown,
-        symlink,
+    io::{
+        ErrorKind,
+        Write as _,
     },
     path::{
         self,
@@ -44,7 +78,58 @@ use std::{
         PathBuf,
     },
     result::Result::Ok,
+    thread,
+    time::{
+        Duration,
+        Instant,
+        SystemTime,
+        UNIX_EPOCH,
+    },
 };
+/// How a target on disk compares to its manifest entry, as reported by
+/// [`FileWithMetadata::state`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileState {
+    /// The target matches the manifest entry.
+    Correct,
+    /// The target does not exist.
+    Missing,
+    /// The target of a [`Delete`][FileKind::Delete] entry still exists.
+    Present,
+    /// The target exists but is not the expected file type.
+    WrongKind,
+    /// The target's permission bits differ from the manifest.
+    WrongPermissions,
+    /// The target's uid or gid differ from the manifest.
+    WrongOwner,
+    /// One of the target's extended attributes differs from the manifest.
+    WrongXattrs,
+    /// The target's modification time differs from the manifest.
+    WrongMtime,
+    /// The target lacks the immutable flag the manifest asks for.
+    WrongFlags,
+    /// The target's content or symlink destination differs from the source.
+    Modified,
+}
+
+impl fmt::Display for FileState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Self::Correct => "correct",
+            Self::Missing => "missing",
+            Self::Present => "present",
+            Self::WrongKind => "wrong-kind",
+            Self::WrongPermissions => "wrong-permissions",
+            Self::WrongOwner => "wrong-owner",
+            Self::WrongXattrs => "wrong-xattrs",
+            Self::WrongMtime => "wrong-mtime",
+            Self::WrongFlags => "wrong-flags",
+            Self::Modified => "modified",
+        };
+        write!(f, "{name}")
+    }
+}
+
 /// A manifest [`File`] paired with its live filesystem metadata.
 pub struct FileWithMetadata {
     pub source: Option<PathBuf>,
@@ -55,34 +140,124 @@ pub struct FileWithMetadata {
     pub permissions: Option<u32>,
     pub uid: Option<u32>,
     pub gid: Option<u32>,
+    /// Resolved into [`uid`][Self::uid] by
+    /// [`set_metadata`][Self::set_metadata].
+    pub owner: Option<String>,
+    /// Resolved into [`gid`][Self::gid] by
+    /// [`set_metadata`][Self::set_metadata].
+    pub group: Option<String>,
     pub deactivate: Option<bool>,
     pub follow_symlinks: Option<bool>,
     pub ignore_modification: Option<bool>,
+    pub copy_fallback: Option<bool>,
+    pub text: Option<String>,
+    pub content_base64: Option<Vec<u8>>,
+    pub restore_backup: Option<bool>,
+    pub xattrs: BTreeMap<String, String>,
+    pub on_activate: Option<String>,
+    pub on_deactivate: Option<String>,
+    pub reflink: Option<Reflink>,
+    pub mtime: Option<Mtime>,
+    pub decrypt: Option<Decrypt>,
+    pub source_hash: Option<Checksum>,
+    pub target_hash: Option<Checksum>,
+    pub wait_for: Option<PathBuf>,
+    pub parents: Option<manifest::Parents>,
+    pub major: Option<u32>,
+    pub minor: Option<u32>,
+    pub immutable: Option<bool>,
+    pub as_owner: Option<bool>,
+    pub if_missing_source: Option<IfMissingSource>,
+    /// BLAKE3 hash of the source if already known, so that
+    /// [`state`][Self::state] doesn't read it again.
+    pub source_digest: Option<Hash>,
+    /// How the parent directory of the target is resolved, see
+    /// [`Options::no_symlinks`].
+    pub resolve: Resolve,
+    /// Whether changes are flushed to disk, see [`Options::fsync`].
+    pub fsync: bool,
+    /// Where source hashes and canonical paths are remembered, see
+    /// [`Options::memo`].
+    pub memo: Memo,
+    /// Rendered contents of a [`Template`][FileKind::Template] file, or
+    /// decrypted ones of a [`Copy`][FileKind::Copy] with
+    /// [`decrypt`][Self::decrypt], see
+    /// [`Manifest::prepare`][crate::manifest::Manifest::prepare] and
+    /// [`load`][Self::load].
+    pub rendered: Option<Vec<u8>>,
+    /// Whether [`load`][Self::load] has fetched and decrypted the source.
+    pub loaded: bool,
 
     pub metadata: Option<Metadata>,
 }
 
-impl From<&File> for FileWithMetadata {
-    fn from(file: &File) -> Self {
+/// Extended attribute holding the `SELinux` context of a file.
+const SELINUX_XATTR: &str = "security.selinux";
+
+impl From<File> for FileWithMetadata {
+    /// The [`selinux_context`][File::selinux_context] of `file` ends up in
+    /// [`xattrs`][Self::xattrs], NUL-terminated like libselinux stores it.
+    fn from(file: File) -> Self {
+        let mut xattrs = file.xattrs;
+        if let Some(context) = file.selinux_context {
+            xattrs.insert(String::from(SELINUX_XATTR), format!("{context}\0"));
+        }
         Self {
-            source: file.source.clone(),
-            target: file.target.clone(),
+            source: file.source,
+            target: file.target,
             kind: file.kind,
             clobber: file.clobber,
             permissions: file.permissions,
             uid: file.uid,
             gid: file.gid,
+            owner: file.owner,
+            group: file.group,
             deactivate: file.deactivate,
             follow_symlinks: file.follow_symlinks,
             ignore_modification: file.ignore_modification,
+            copy_fallback: file.copy_fallback,
+            text: file.text,
+            content_base64: file.content_base64,
+            restore_backup: file.restore_backup,
+            xattrs,
+            on_activate: file.on_activate,
+            on_deactivate: file.on_deactivate,
+            reflink: file.reflink,
+            mtime: file.mtime,
+            decrypt: file.decrypt,
+            source_hash: file.source_hash,
+            target_hash: file.target_hash,
+            wait_for: file.wait_for,
+            parents: file.parents,
+            major: file.major,
+            minor: file.minor,
+            immutable: file.immutable,
+            as_owner: file.as_owner,
+            if_missing_source: file.if_missing_source,
+            source_digest: None,
+            resolve: Resolve::Follow,
+            fsync: false,
+            memo: Memo::default(),
+            rendered: None,
+            loaded: false,
             metadata: None,
         }
     }
 }
+
+/// Clones `file`, for when the manifest keeps it.
+impl From<&File> for FileWithMetadata {
+    fn from(file: &File) -> Self {
+        Self::from(file.clone())
+    }
+}
+
 impl FileWithMetadata {
     /// Activates the file at [`target`][Self::target] by performing the
     /// operation described by [`kind`][Self::kind]. Handles clobber and
-    /// backup (via `prefix`) before writing.
+    /// backup (via [`Options::prefix`]) before writing, and records what was
+    /// done in [`Options::report`]. With [`Options::dry_run`], only logs what
+    /// would be done.
     ///
     /// # Errors
     ///
@@ -98,32 +273,344 @@ impl FileWithMetadata {
     ///
     /// Does not panic under correct use; internal guards ensure `metadata` is
     /// `Some` before every `.unwrap()` site is reached.
-    pub fn activate(&mut self, clobber_by_default: Option<bool>, prefix: &str) -> Result<()> {
+    pub fn activate(&mut self, clobber_by_default: Option<bool>, options: &Options) -> Result<()> {
+        let clobber = options.clobber(self.clobber, clobber_by_default);
+
+        if options.dry_run {
+            if self.creates_empty() {
+                info!(
+                    "Would create '{}' empty, as its source is missing",
+                    self.target.display()
+                );
+                return Ok(());
+            }
+            if self.check_source() {
+                return Ok(());
+            }
+            self.verify_source()?;
+            self.set_metadata()?;
+            return self.dry_run_activate(clobber, options);
+        }
+
+        match self.apply(clobber, options).and_then(|(action, reason)| {
+            if action != Action::Skipped
+                && let Some(ref command) = self.on_activate
+            {
+                run_hook(command, Some(&self.target), "on_activate")?;
+            }
+            Ok((action, reason))
+        }) {
+            Ok((action, reason)) => {
+                if matches!(action, Action::Created | Action::Clobbered) {
+                    options.report.add_copied(self.copied_bytes());
+                }
+                self.remember(action, reason.as_deref(), options);
+                options.report.record(&self.target, action, reason);
+                Ok(())
+            }
+            Err(err) => {
+                options
+                    .report
+                    .record(&self.target, Action::Failed, Some(format!("{err:#}")));
+                Err(err)
+            }
+        }
+    }
+
+    /// Does the work of [`activate`][Self::activate], returning the action
+    /// taken and why.
+    fn apply(&mut self, clobber: bool, options: &Options) -> Result<(Action, Option<String>)> {
+        if let Some(ref path) = self.wait_for {
+            wait_for(path, options.wait_timeout)?;
+        }
+        let declined = || Ok((Action::Skipped, Some(String::from("declined"))));
+        self.load(options);
+        if self.creates_empty() {
+            self.set_metadata()?;
+            let _owner = self.impersonate()?;
+            return self.create_empty(clobber);
+        }
         if self.check_source() {
-            return Ok(());
+            return Ok((
+                Action::Skipped,
+                Some(String::from("source is missing or invalid")),
+            ));
         }
+        self.verify_source()?;
 
         self.set_metadata()?;
+        let _owner = self.impersonate()?;
+        self.show_diff(options);
 
-        let clobber = self
-            .clobber
-            .unwrap_or_else(|| clobber_by_default.unwrap_or(false));
+        match self.state() {
+            Ok(FileState::Correct) => {
+                info!("File '{}' already correct", self.target.display());
+                return Ok((Action::Skipped, Some(String::from(ALREADY_CORRECT))));
+            }
+            Ok(FileState::WrongFlags) => {
+                self.journal_change(options)?;
+                self.lock()?;
+                return Ok((Action::Modified, Some(String::from("made immutable"))));
+            }
+            _ => {}
+        }
 
-        if clobber
-            && self.metadata.is_some()
-            && self
-                .atomic_activate()
-                .wrap_err("While attempting atomic activation")?
+        if clobber && self.metadata.is_some() {
+            if !self.confirm(Action::Clobbered, options) {
+                return declined();
+            }
+            if self.swappable()? {
+                self.swap(options)?;
+                return Ok((Action::Clobbered, Some(String::from("replaced atomically"))));
+            }
+        }
+        if self.edited()? {
+            if !self.confirm(Action::BackedUp, options) {
+                return declined();
+            }
+            return self.back_up_edited(options);
+        }
+        if self.kind == FileKind::Delete && !self.confirm(Action::Deleted, options)
+            || !clobber && self.in_the_way() && !self.confirm(Action::BackedUp, options)
         {
-            return Ok(());
+            return declined();
         }
 
-        if self.check().unwrap_or(false) {
-            info!("File '{}' already correct", self.target.display());
-            return Ok(());
+        let (mut action, mut reason) = match self.kind {
+            FileKind::Modify => (Action::Modified, None),
+            FileKind::Delete => (Action::Deleted, None),
+            FileKind::Touch if self.metadata.is_some() => (Action::Modified, None),
+            _ => (Action::Created, None),
+        };
+        self.unlock()?;
+        if self.in_the_way() && !clobber {
+            action = Action::BackedUp;
+            reason = self
+                .back_up(options)?
+                .map(|x| format!("moved to '{}'", x.display()));
+        }
+        self.journal_change(options)?;
+        if self.in_the_way() && clobber {
+            delete_within(&self.target, self.metadata.as_ref().unwrap(), options)?;
+            action = Action::Clobbered;
+        }
+
+        match self.kind {
+            FileKind::Directory => self.directory(),
+            FileKind::Copy | FileKind::Template => self.copy(),
+            FileKind::Hardlink => self.hardlink(),
+            FileKind::RecursiveCopy => self.recursive_copy(),
+            FileKind::Symlink => self.symlink(),
+            FileKind::LinkFarm => self.link_farm(clobber, options),
+            FileKind::Fifo | FileKind::Socket | FileKind::CharDevice | FileKind::BlockDevice => {
+                self.mknod()
+            }
+            FileKind::Touch => self.touch(),
+            FileKind::Modify => self.chmod_chown(),
+            FileKind::Delete => {
+                delete_within(&self.target, self.metadata.as_ref().unwrap(), options)
+            }
+        }?;
+        self.lock()?;
+        self.sync()?;
+        self.verify_target()?;
+        Ok((action, reason))
+    }
+
+    /// Replaces the target with [`atomic_activate`][Self::atomic_activate],
+    /// clearing its immutable flag only for the swap. If that fails, the old
+    /// target is left in place, and immutable again if it was.
+    fn swap(&mut self, options: &Options) -> Result<()> {
+        let locked = self.lockable() && self.locked();
+        self.unlock()?;
+        if let Err(err) = self.journal_change(options).and_then(|()| {
+            self.atomic_activate()
+                .wrap_err("While attempting atomic activation")
+        }) {
+            if locked {
+                self.relock();
+            }
+            return Err(err);
+        }
+        self.lock()?;
+        self.verify_target()
+    }
+
+    /// Moves the [edited][Self::edited] target of a
+    /// [`Delete`][FileKind::Delete] aside with [`Options::prefix`] instead of
+    /// deleting it.
+    fn back_up_edited(&self, options: &Options) -> Result<(Action, Option<String>)> {
+        self.unlock()?;
+        let backup = self.back_up(options)?;
+        self.sync_parent()?;
+        Ok((
+            Action::BackedUp,
+            backup.map(|x| {
+                format!(
+                    "modified since it was installed, moved to '{}'",
+                    x.display()
+                )
+            }),
+        ))
+    }
+
+    /// Moves the target aside with [`Options::prefix`], recording the move in
+    /// [`Options::journal`], and returns where to.
+    pub(crate) fn back_up(&self, options: &Options) -> Result<Option<PathBuf>> {
+        let backup = prefix_move(
+            &self.target,
+            &options.prefix,
+            options.timestamped_backups,
+            self.resolve,
+        )?;
+        if let Some(ref journal) = options.journal
+            && let Some(ref backup) = backup
+        {
+            journal.rename(&self.target, backup)?;
+        }
+        Ok(backup)
+    }
+
+    /// Records in [`Options::journal`] that the target is about to change.
+    pub(crate) fn journal_change(&self, options: &Options) -> Result<()> {
+        options
+            .journal
+            .as_ref()
+            .map_or(Ok(()), |journal| journal.change(&self.target))
+    }
+
+    /// Fetches a URL source into [`Options::cache`] and replaces it by the
+    /// cached copy, as is the source of a [`Copy`][FileKind::Copy] with
+    /// [`Options::content_cache`], then decrypts it with
+    /// [`Options::identities`] if [`decrypt`][Self::decrypt] is set. Only
+    /// done once, and not at all with [`Options::dry_run`], as inspecting the
+    /// target doesn't need it. A source that fails to be fetched or decrypted
+    /// is left for [`check_source`][Self::check_source] to skip.
+    pub fn load(&mut self, options: &Options) {
+        if self.loaded || options.dry_run {
+            return;
+        }
+        self.loaded = true;
+        if let Some(url) = self.source.as_deref().filter(|x| fetch::is_url(x))
+            && let Some(ref checksum) = self.source_hash
+        {
+            let url = url.to_string_lossy();
+            match fetch::fetch(&url, checksum, &options.cache) {
+                Ok(path) => self.source = Some(path),
+                Err(err) => warn!("Failed to fetch '{url}'\n{err:?}"),
+            }
+        }
+        if let Some(ref cache) = options.content_cache
+            && self.kind == FileKind::Copy
+            && self.decrypt.is_none()
+            && self.follow_symlinks.unwrap_or(true)
+            && self.mtime != Some(Mtime::Source)
+            && let Some(ref source) = self.source
+            && fs::metadata(source).is_ok_and(|x| x.is_file())
+        {
+            match cache.store(source, &options.cache) {
+                Ok((path, hash)) => {
+                    self.source = Some(path);
+                    self.source_digest = Some(hash);
+                }
+                Err(err) => warn!("Failed to cache '{}'\n{err:?}", source.display()),
+            }
+        }
+        if let Some(tool) = self.decrypt
+            && let Some(ref source) = self.source
+        {
+            self.rendered = secret::decrypt(source, tool, &options.identities)
+                .inspect_err(|err| {
+                    warn!("Failed to decrypt '{}'\n{:?}", source.display(), err);
+                })
+                .ok();
+        }
+    }
+
+    /// Checks the source against [`source_hash`][Self::source_hash], if set.
+    /// A URL source not [fetched][Self::load] yet is left to the fetch to
+    /// check.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the source can't be read or has another checksum.
+    pub fn verify_source(&self) -> Result<()> {
+        if let (Some(expected), Some(source)) = (&self.source_hash, &self.source)
+            && !fetch::is_url(source)
+        {
+            let actual = Checksum::of_file(expected.algorithm, source)?;
+            if actual != *expected {
+                return Err(eyre!(
+                    "Source '{}' has checksum {actual}, but {expected} was expected",
+                    source.display()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the source is a path that doesn't exist.
+    fn source_missing(&self) -> bool {
+        self.source.as_deref().is_some_and(|source| {
+            fs::symlink_metadata(source).is_err_and(|err| err.kind() == ErrorKind::NotFound)
+        })
+    }
+
+    /// Checks what was written to the target against
+    /// [`target_hash`][Self::target_hash], if set.
+    fn verify_target(&self) -> Result<()> {
+        if let Some(ref expected) = self.target_hash
+            && self.kind != FileKind::Delete
+        {
+            let actual = Checksum::of_file(expected.algorithm, &self.target)?;
+            if actual != *expected {
+                return Err(eyre!(
+                    "Wrote '{}' with checksum {actual}, but {expected} was expected",
+                    self.target.display()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `action` may be done to the existing target, as decided by
+    /// [`Options::confirm`]. Targets that already match aren't asked about.
+    pub(crate) fn confirm(&self, action: Action, options: &Options) -> bool {
+        let (Some(confirm), Some(metadata)) = (&options.confirm, &self.metadata) else {
+            return true;
+        };
+        self.check().unwrap_or(false)
+            || confirm.ask(&Prompt {
+                target: self.target.clone(),
+                action,
+                found: Prompt::describe(&self.target, metadata),
+                wanted: self.wanted(),
+            })
+    }
+
+    /// Describes what the manifest wants at the target, for a [`Prompt`].
+    fn wanted(&self) -> String {
+        let source = self.source.as_deref().map(Path::display);
+        match (self.kind, source) {
+            (FileKind::Directory, _) => String::from("a directory"),
+            (FileKind::Copy, None) => String::from("a file with inline content"),
+            (FileKind::Copy | FileKind::RecursiveCopy, Some(source)) => {
+                format!("a copy of '{source}'")
+            }
+            (FileKind::Template, Some(source)) => format!("a rendering of '{source}'"),
+            (FileKind::Hardlink, Some(source)) => format!("a hard link to '{source}'"),
+            (FileKind::Symlink, Some(source)) => format!("a symlink to '{source}'"),
+            (FileKind::LinkFarm, Some(source)) => format!("links to the files in '{source}'"),
+            (FileKind::Touch, _) => String::from("a file"),
+            (FileKind::Delete, _) => String::from("nothing"),
+            _ => self.kind.to_string(),
         }
+    }
 
-        if match *self {
+    /// Returns `true` if an existing file at [`target`][Self::target] has to
+    /// be clobbered or backed up before activation.
+    pub(crate) fn in_the_way(&self) -> bool {
+        match *self {
             Self { metadata: None, .. }
             | Self {
                 kind: FileKind::Modify | FileKind::Delete,
@@ -133,30 +620,93 @@ impl FileWithMetadata {
             // If they're supposed to be
             // directories
             Self {
-                kind: FileKind::Directory,
+                kind: FileKind::Directory | FileKind::LinkFarm,
                 metadata: Some(ref metadata),
                 ..
             } => !metadata.is_dir(),
+            Self {
+                kind: FileKind::Touch,
+                metadata: Some(ref metadata),
+                ..
+            } => !metadata.is_file(),
             _ => true,
-        } {
+        }
+    }
+
+    /// Logs the steps [`activate`][Self::activate] would take without
+    /// performing any of them.
+    fn dry_run_activate(&self, clobber: bool, options: &Options) -> Result<()> {
+        if self.check().unwrap_or(false) {
+            info!("File '{}' already correct", self.target.display());
+            return Ok(());
+        }
+
+        if self.in_the_way() {
+            self.show_diff(options);
             if clobber {
-                delete(&self.target, self.metadata.as_ref().unwrap())?;
+                info!("Would clobber '{}'", self.target.display());
             } else {
-                prefix_move(&self.target, prefix)?;
+                info!(
+                    "Would back up '{}' -> '{}'",
+                    self.target.display(),
+                    backup_path(&self.target, &options.prefix, options.timestamped_backups)?
+                        .display()
+                );
             }
         }
 
-        match self.kind {
-            FileKind::Directory => self.directory(),
-            FileKind::Copy => self.copy(),
-            FileKind::Symlink => self.symlink(),
-            FileKind::Modify => self.chmod_chown(),
-            FileKind::Delete => delete(&self.target, self.metadata.as_ref().unwrap()),
+        match (self.kind, self.source.as_ref()) {
+            (FileKind::Copy, Some(source)) => info!(
+                "Would copy '{}' -> '{}'",
+                source.display(),
+                self.target.display()
+            ),
+            (FileKind::Copy, None) => {
+                info!("Would write inline content to '{}'", self.target.display());
+            }
+            (FileKind::Template, Some(source)) => info!(
+                "Would render template '{}' -> '{}'",
+                source.display(),
+                self.target.display()
+            ),
+            (FileKind::Hardlink, Some(source)) => info!(
+                "Would hard link '{}' -> '{}'",
+                source.display(),
+                self.target.display()
+            ),
+            (FileKind::RecursiveCopy, Some(source)) => info!(
+                "Would recursively copy '{}' -> '{}'",
+                source.display(),
+                self.target.display()
+            ),
+            (FileKind::Symlink, Some(source)) => info!(
+                "Would symlink '{}' -> '{}'",
+                source.display(),
+                self.target.display()
+            ),
+            (FileKind::LinkFarm, Some(source)) => info!(
+                "Would symlink every file in '{}' -> '{}'",
+                source.display(),
+                self.target.display()
+            ),
+            (FileKind::Directory, _) => {
+                info!("Would create directory '{}'", self.target.display());
+            }
+            (FileKind::Delete, _) if self.edited()? => info!(
+                "Would back up '{}' -> '{}', as it was modified since it was installed",
+                self.target.display(),
+                backup_path(&self.target, &options.prefix, options.timestamped_backups)?.display()
+            ),
+            (FileKind::Delete, _) => info!("Would delete '{}'", self.target.display()),
+            _ => info!("Would modify '{}'", self.target.display()),
         }
+        Ok(())
     }
 
     /// Attempts an atomic replacement of an existing
-    /// [`Symlink`][FileKind::Symlink] or [`Copy`][FileKind::Copy] target by
+    /// [`Symlink`][FileKind::Symlink], [`Copy`][FileKind::Copy],
+    /// [`Template`][FileKind::Template], or [`Hardlink`][FileKind::Hardlink]
+    /// target by
     /// writing to a random temporary name in the same directory, then
     /// renaming into place. Returns `true` if the swap succeeded, `false` if
     /// the kind does not support atomic replacement or the target and
@@ -173,61 +723,83 @@ impl FileWithMetadata {
     ///
     /// # Panics
     ///
-    /// Panics if called on a `Symlink` or `Copy` file with `metadata` or
-    /// `source` being `None`.
+    /// Panics if called on a `Symlink` or `Hardlink` file with `metadata` or
+    /// `source` being `None`, or on a `Copy` file with `metadata` being
+    /// `None`.
     pub fn atomic_activate(&mut self) -> Result<bool> {
-        match self.kind {
-            FileKind::Symlink | FileKind::Copy => {
-                fn randomize_filename(file: &mut FileWithMetadata) {
-                    let string = Alphanumeric.sample_string(&mut rand::rng(), 16);
-                    file.target.set_file_name(string);
-                    if file.target.exists() {
-                        randomize_filename(file);
-                    }
-                }
-
-                let target_is_dir = self.metadata.as_ref().unwrap().is_dir();
-                let source_is_dir = fs::symlink_metadata(self.source.as_ref().unwrap())?.is_dir();
-
-                if target_is_dir != source_is_dir
-                    || target_is_dir
-                        && source_is_dir
-                        && self.source.as_ref().unwrap().read_dir()?.next().is_some()
-                {
-                    return Ok(false);
-                }
+        if !self.swappable()? {
+            return Ok(false);
+        }
+        let target = self.target.clone();
+        let (dir, name) = self.parent()?;
 
-                let target = self.target.clone();
+        self.target = random_sibling(&target);
+        let temp_path = self.target.clone();
+        let temp_name = temp_path
+            .file_name()
+            .ok_or_eyre("Failed to get file name")?;
 
-                randomize_filename(self);
-                let temp_path = self.target.clone();
+        let result = match self.kind {
+            FileKind::Symlink => self.symlink(),
+            FileKind::Copy | FileKind::Template => self.copy(),
+            FileKind::Hardlink => self.hardlink(),
+            _ => panic!("This should never happen"),
+        }
+        .and_then(|()| {
+            info!(
+                "Renaming '{}' -> '{}'",
+                temp_path.display(),
+                target.display()
+            );
+            dir.rename(temp_name, &name)?;
+            // Renaming over a link to the same inode does nothing, leaving
+            // the temporary link behind
+            match dir.remove_file(temp_name) {
+                Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+                result => result.map_err(Into::into),
+            }
+        })
+        .inspect_err(|_| {
+            let _ = dir.remove_file(temp_name);
+        });
+        self.target = target;
+        result?;
+        self.sync()?;
 
-                match self.kind {
-                    FileKind::Symlink => self.symlink(),
-                    FileKind::Copy => self.copy(),
-                    _ => panic!("This should never happen"),
-                }
-                .and_then(|()| {
-                    info!(
-                        "Renaming '{}' -> '{}'",
-                        temp_path.display(),
-                        target.display()
-                    );
-                    fs::rename(&temp_path, &target).map_err(Into::into)
-                })
-                .inspect_err(|_| {
-                    let _ = fs::remove_file(&temp_path);
-                })?;
+        Ok(true)
+    }
 
-                Ok(true)
-            }
-            _ => Ok(false),
+    /// Whether [`atomic_activate`][Self::atomic_activate] can replace the
+    /// existing target, so that nothing is recorded for a swap that won't
+    /// happen.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the source can't be inspected.
+    pub(crate) fn swappable(&self) -> Result<bool> {
+        if !matches!(
+            self.kind,
+            FileKind::Symlink | FileKind::Copy | FileKind::Template | FileKind::Hardlink
+        ) {
+            return Ok(false);
         }
+        let target_is_dir = self.metadata.as_ref().unwrap().is_dir();
+        // Inline content is always a file
+        let source_is_dir = match self.source {
+            Some(ref source) => fs::symlink_metadata(source)?.is_dir(),
+            None => false,
+        };
+        Ok(target_is_dir == source_is_dir
+            && !(target_is_dir && self.source.as_ref().unwrap().read_dir()?.next().is_some()))
     }
 
     /// Removes the file at [`target`][Self::target] if it still matches the
     /// expected state. No-op for [`Delete`][FileKind::Delete] and
-    /// [`Modify`][FileKind::Modify] kinds.
+    /// [`Modify`][FileKind::Modify] kinds. With [`Options::restore_backups`]
+    /// or [`restore_backup`][Self::restore_backup], a backup left by
+    /// [`prefix_move`] is then moved back into place. What was done is
+    /// recorded in [`Options::report`]. With [`Options::dry_run`], only logs
+    /// what would be removed.
     ///
     /// # Errors
     ///
@@ -235,43 +807,153 @@ impl FileWithMetadata {
     /// - the file has been modified since activation
     /// - the target is not the expected type
     /// - filesystem removal fails
+    /// - restoring the backup fails
     ///
     /// # Panics
     ///
     /// Does not panic under correct use; `metadata` is verified to be `Some`
     /// before every `.unwrap()` site is reached.
-    pub fn deactivate(&mut self) -> Result<()> {
+    pub fn deactivate(&mut self, options: &Options) -> Result<()> {
+        match self.undo(options).and_then(|done| {
+            if let Some((action, _)) = done
+                && action != Action::Skipped
+                && let Some(ref command) = self.on_deactivate
+            {
+                run_hook(command, Some(&self.target), "on_deactivate")?;
+            }
+            Ok(done)
+        }) {
+            Ok(Some((action, reason))) => {
+                if matches!(action, Action::Deleted | Action::Restored)
+                    && let Some(ref installed) = options.installed
+                {
+                    installed.remove(&self.target);
+                }
+                options.report.record(&self.target, action, reason);
+                Ok(())
+            }
+            Ok(None) => Ok(()),
+            Err(err) => {
+                options
+                    .report
+                    .record(&self.target, Action::Failed, Some(format!("{err:#}")));
+                Err(err)
+            }
+        }
+    }
+
+    /// Does the work of [`deactivate`][Self::deactivate], returning the
+    /// action taken and why, or `None` with [`Options::dry_run`].
+    fn undo(&mut self, options: &Options) -> Result<Option<(Action, Option<String>)>> {
         if !self.deactivate.unwrap_or(true) {
-            return Ok(());
+            return Ok(Some((
+                Action::Skipped,
+                Some(String::from("deactivate is false")),
+            )));
+        }
+        if let Some(ref path) = self.wait_for {
+            wait_for(path, options.wait_timeout)?;
         }
 
+        self.load(options);
         self.set_metadata()?;
+        let _owner = self.impersonate()?;
 
         if self.metadata.is_none() {
             info!("File already deleted '{}'", self.target.display());
-            return Ok(());
+            return Ok(Some((
+                Action::Skipped,
+                Some(String::from("already deleted")),
+            )));
+        }
+
+        if self.written_to() {
+            return Ok(Some((
+                Action::Skipped,
+                Some(String::from("no longer empty")),
+            )));
         }
 
-        if !self.check()? {
+        // A cleared immutable flag doesn't make it any less ours
+        if !matches!(self.state()?, FileState::Correct | FileState::WrongFlags) {
             return Err(eyre!("File is not the same as expected"));
         }
 
+        let restore = (options.restore_backups || self.restore_backup.unwrap_or(false))
+            && !matches!(self.kind, FileKind::Delete | FileKind::Modify);
+
+        if options.dry_run {
+            if !matches!(self.kind, FileKind::Delete | FileKind::Modify) {
+                info!("Would delete '{}'", self.target.display());
+            }
+            if restore && let Some(backup) = backups_of(&self.target, &options.prefix)?.pop() {
+                info!(
+                    "Would restore '{}' -> '{}'",
+                    backup.display(),
+                    self.target.display()
+                );
+            }
+            return Ok(None);
+        }
+
+        self.unlock()?;
+        if matches!(self.kind, FileKind::Delete | FileKind::Modify) {
+            return Ok(Some((
+                Action::Skipped,
+                Some(format!("nothing to undo for {}", self.kind)),
+            )));
+        }
+        self.journal_change(options)?;
+        self.remove(options)?;
+        let backup = if restore {
+            backups_of(&self.target, &options.prefix)?.pop()
+        } else {
+            None
+        };
+        let restored = restore && restore_prefixed(&self.target, &options.prefix, self.resolve)?;
+        if restored
+            && let Some(ref journal) = options.journal
+            && let Some(ref backup) = backup
+        {
+            journal.rename(backup, &self.target)?;
+        }
+        self.sync_parent()?;
+        if restored {
+            return Ok(Some((Action::Restored, None)));
+        }
+        Ok(Some((Action::Deleted, None)))
+    }
+
+    /// Removes the target according to [`kind`][Self::kind], see
+    /// [`deactivate`][Self::deactivate].
+    fn remove(&self, options: &Options) -> Result<()> {
         match self.kind {
             // no-op on deactivation
             FileKind::Delete | FileKind::Modify => Ok(()),
             // delete only if directory is empty
             FileKind::Directory => match self.metadata.as_ref() {
                 Some(x) if x.is_dir() => {
-                    fs::remove_dir(&self.target)?;
+                    let (dir, name) = self.parent()?;
+                    dir.remove_dir(&name)?;
                     info!("Deleting directory '{}'", self.target.display());
                     Ok(())
                 }
                 Some(_) => Err(eyre!("File is not directory")),
                 None => Err(eyre!("Cannot access file")),
             },
+            FileKind::LinkFarm => self.remove_link_farm(),
             // delete only if types match
-            FileKind::Symlink | FileKind::Copy => {
-                delete(&self.target, self.metadata.as_ref().unwrap())
+            FileKind::Symlink
+            | FileKind::Copy
+            | FileKind::Template
+            | FileKind::Hardlink
+            | FileKind::RecursiveCopy
+            | FileKind::Fifo
+            | FileKind::Socket
+            | FileKind::CharDevice
+            | FileKind::BlockDevice
+            | FileKind::Touch => {
+                delete_within(&self.target, self.metadata.as_ref().unwrap(), options)
             }
         }
     }
@@ -281,107 +963,327 @@ impl FileWithMetadata {
     ///
     /// # Errors
     ///
+    /// Returns an error if [`state`][Self::state] fails.
+    pub fn check(&self) -> Result<bool> {
+        Ok(self.state()? == FileState::Correct)
+    }
+
+    /// Classifies how the file at [`target`][Self::target] differs from the
+    /// expected kind, permissions, ownership, and content.
+    ///
+    /// # Errors
+    ///
     /// Returns an error if:
     ///
     /// - a `Symlink` or `Copy` file has no `source`
+    /// - the source doesn't match [`source_hash`][Self::source_hash]
     /// - canonicalization, symlink resolution, or stat calls fail
-    pub fn check(&self) -> Result<bool> {
+    pub fn state(&self) -> Result<FileState> {
+        let state = self.content_state()?;
+        Ok(if state == FileState::Correct && !self.locked() {
+            FileState::WrongFlags
+        } else {
+            state
+        })
+    }
+
+    /// Does the work of [`state`][Self::state], except for the immutable
+    /// flag.
+    #[allow(clippy::too_many_lines)]
+    fn content_state(&self) -> Result<FileState> {
+        // A source that is gone, e.g. with its generation garbage-collected,
+        // has nothing left to verify, and the target is compared against
+        // its checksum instead
+        if !self.source_missing() {
+            self.verify_source()?;
+        }
         match *self {
             Self {
                 metadata: None,
-                kind,
+                kind: FileKind::Delete,
                 ..
-            } => Ok(kind == FileKind::Delete),
+            } => Ok(FileState::Correct),
+            Self { metadata: None, .. } => Ok(FileState::Missing),
             Self {
                 metadata: Some(_),
                 kind: FileKind::Delete,
                 ..
-            } => Ok(false),
+            } => Ok(FileState::Present),
             // This should never happen
             // as it's checked before this
             // function is ever called
             Self {
                 source: None,
-                kind: FileKind::Symlink | FileKind::Copy,
+                text: None,
+                content_base64: None,
+                kind: FileKind::Copy,
+                ref target,
+                ..
+            }
+            | Self {
+                source: None,
+                kind:
+                    FileKind::Symlink
+                    | FileKind::Template
+                    | FileKind::Hardlink
+                    | FileKind::RecursiveCopy
+                    | FileKind::LinkFarm,
                 ref target,
                 ..
             } => Err(eyre!("File '{}' missing_source", target.display())),
             Self {
-                kind: FileKind::Copy,
-                metadata: Some(ref metadata),
+                kind: FileKind::CharDevice | FileKind::BlockDevice,
+                major: None,
+                ref target,
+                ..
+            }
+            | Self {
+                kind: FileKind::CharDevice | FileKind::BlockDevice,
+                minor: None,
+                ref target,
                 ..
-            } if !metadata.is_file() => Ok(false),
+            } => Err(eyre!(
+                "Device '{}' needs a major and minor",
+                target.display()
+            )),
             Self {
-                kind: FileKind::Symlink,
+                kind:
+                    FileKind::Fifo | FileKind::Socket | FileKind::CharDevice | FileKind::BlockDevice,
                 metadata: Some(ref metadata),
                 ..
-            } if !metadata.is_symlink() => Ok(false),
+            } if sys::node(metadata).map(node_kind) != Some(self.kind) => Ok(FileState::WrongKind),
             Self {
-                metadata: Some(_),
-                kind: FileKind::Copy | FileKind::Symlink,
-                ignore_modification: Some(x),
+                kind: FileKind::CharDevice | FileKind::BlockDevice,
+                metadata: Some(ref metadata),
                 ..
-            } if x => Ok(true),
+            } if sys::node(metadata) != self.node() => Ok(FileState::Modified),
             Self {
-                kind: FileKind::Copy | FileKind::Directory | FileKind::Modify,
-                permissions: Some(perms),
+                kind: FileKind::Copy | FileKind::Template | FileKind::Hardlink | FileKind::Touch,
                 metadata: Some(ref metadata),
                 ..
-            } if perms != (metadata.mode() & 0o7_777) => Ok(false),
+            } if !metadata.is_file() => Ok(FileState::WrongKind),
             Self {
-                uid: Some(uid),
+                kind: FileKind::Symlink,
                 metadata: Some(ref metadata),
                 ..
-            } if uid != metadata.uid() => Ok(false),
+            } if !metadata.is_symlink() => Ok(FileState::WrongKind),
             Self {
-                gid: Some(gid),
+                kind: FileKind::Directory | FileKind::RecursiveCopy | FileKind::LinkFarm,
                 metadata: Some(ref metadata),
                 ..
-            } if gid != metadata.gid() => Ok(false),
+            } if !metadata.is_dir() => Ok(FileState::WrongKind),
+            // The target directory itself isn't managed,
+            // only the links inside of it
             Self {
-                kind: FileKind::Symlink,
+                kind: FileKind::LinkFarm,
                 ref target,
                 source: Some(ref source),
-                follow_symlinks: canonicalize,
+                uid,
+                gid,
                 ..
-            } => {
-                // This will fail if target
-                // is a dead symlink
-                // which should only happen
-                // if source does not exist
-                // which should never happen
-                if canonicalize.unwrap_or(true) {
-                    Ok(fs::canonicalize(target)? == fs::canonicalize(source)?)
-                } else {
-                    Ok(read_link(target)? == std::path::absolute(source)?)
-                }
-            }
+            } => link_farm_state(source, target, uid, gid),
             Self {
-                kind: FileKind::Directory,
-                metadata: Some(ref metadata),
+                metadata: Some(_),
+                kind:
+                    FileKind::Copy
+                    | FileKind::Template
+                    | FileKind::Hardlink
+                    | FileKind::RecursiveCopy
+                    | FileKind::Symlink,
+                ignore_modification: Some(x),
                 ..
-            } => Ok(metadata.is_dir()),
-
+            } if x => Ok(FileState::Correct),
             Self {
-                kind: FileKind::Copy,
-                ref target,
+                kind: FileKind::Hardlink,
                 source: Some(ref source),
                 metadata: Some(ref metadata),
                 ..
-            } => {
-                if metadata.len() != fs::symlink_metadata(source)?.len() {
-                    return Ok(false);
+            } if fs::metadata(source).is_ok_and(|x| sys::same_file(metadata, &x)) => {
+                Ok(FileState::Correct)
+            }
+            // Anything but the same inode is only
+            // acceptable if it may be a copy
+            Self {
+                kind: FileKind::Hardlink,
+                copy_fallback,
+                ..
+            } if !copy_fallback.unwrap_or(false) => Ok(FileState::Modified),
+            Self {
+                kind:
+                    FileKind::Copy
+                    | FileKind::Template
+                    | FileKind::Hardlink
+                    | FileKind::Directory
+                    | FileKind::Fifo
+                    | FileKind::Socket
+                    | FileKind::CharDevice
+                    | FileKind::BlockDevice
+                    | FileKind::Touch
+                    | FileKind::Modify,
+                permissions: Some(perms),
+                metadata: Some(ref metadata),
+                ..
+            } if !sys::mode_matches(metadata, perms) => Ok(FileState::WrongPermissions),
+            Self {
+                uid: Some(uid),
+                metadata: Some(ref metadata),
+                ..
+            } if sys::owner(metadata).is_some_and(|(x, _)| x != uid) => Ok(FileState::WrongOwner),
+            Self {
+                gid: Some(gid),
+                metadata: Some(ref metadata),
+                ..
+            } if sys::owner(metadata).is_some_and(|(_, x)| x != gid) => Ok(FileState::WrongOwner),
+            Self {
+                ref xattrs,
+                metadata: Some(_),
+                ..
+            } if !xattrs.is_empty() && !self.xattrs_match() => Ok(FileState::WrongXattrs),
+            Self {
+                mtime: Some(_),
+                metadata: Some(ref metadata),
+                ..
+            } if !self.mtime_matches(metadata) => Ok(FileState::WrongMtime),
+            // Whatever was written to it since isn't ours
+            Self {
+                metadata: Some(ref metadata),
+                ..
+            } if self.creates_empty() => Ok(if metadata.len() == 0 {
+                FileState::Correct
+            } else {
+                FileState::Modified
+            }),
+            // Exactly the expected contents, whatever the source
+            Self {
+                kind: FileKind::Copy | FileKind::Template | FileKind::Hardlink,
+                ref target,
+                target_hash: Some(ref expected),
+                ..
+            } => Ok(if expected.matches_file(target)? {
+                FileState::Correct
+            } else {
+                FileState::Modified
+            }),
+            Self {
+                kind: FileKind::Symlink,
+                ref target,
+                source: Some(ref source),
+                follow_symlinks: canonicalize,
+                ..
+            } => {
+                // A source that is gone, such as one a glob no longer
+                // matches, can only be compared with where the link points
+                let same = if canonicalize.unwrap_or(true) && source.exists() {
+                    match follow_chain(target)? {
+                        Chain::Ends(x) => x == self.memo.canonicalize(source)?,
+                        Chain::Loops => {
+                            warn!(
+                                "'{}' is a symlink loop, treating it as modified",
+                                target.display()
+                            );
+                            false
+                        }
+                        Chain::TooDeep => {
+                            warn!(
+                                "'{}' is a chain of more than {MAX_SYMLINK_DEPTH} symlinks, \
+                                 treating it as modified",
+                                target.display()
+                            );
+                            false
+                        }
+                    }
+                } else {
+                    read_link(target)? == std::path::absolute(source)?
+                };
+                Ok(if same {
+                    FileState::Correct
+                } else {
+                    FileState::Modified
+                })
+            }
+            Self {
+                kind:
+                    FileKind::Directory
+                    | FileKind::Fifo
+                    | FileKind::Socket
+                    | FileKind::CharDevice
+                    | FileKind::BlockDevice
+                    | FileKind::Touch
+                    | FileKind::Modify,
+                ..
+            }
+            // Its contents can't be compared without decrypting the source
+            | Self {
+                kind: FileKind::Copy,
+                decrypt: Some(_),
+                rendered: None,
+                loaded: false,
+                ..
+            } => Ok(FileState::Correct),
+            Self {
+                kind: FileKind::RecursiveCopy,
+                ref target,
+                source: Some(ref source),
+                permissions,
+                uid,
+                gid,
+                ..
+            } => tree_state(source, target, permissions, uid, gid),
+            Self {
+                kind: FileKind::Copy,
+                ref target,
+                source: None,
+                metadata: Some(ref metadata),
+                ..
+            }
+            | Self {
+                kind: FileKind::Copy,
+                ref target,
+                decrypt: Some(_),
+                metadata: Some(ref metadata),
+                ..
+            }
+            | Self {
+                kind: FileKind::Template,
+                ref target,
+                metadata: Some(ref metadata),
+                ..
+            } => {
+                let content = self.content().unwrap_or_default();
+                if metadata.len() != content.len() as u64 {
+                    return Ok(FileState::Modified);
                 }
 
-                match (hash_file(target), hash_file(source)) {
-                    (Some(left), Some(right)) => Ok(left == right),
-                    _ => Ok(false),
+                match hash_file(target) {
+                    Some(hash) if hash == blake3::hash(content) => Ok(FileState::Correct),
+                    _ => Ok(FileState::Modified),
                 }
             }
             Self {
-                kind: FileKind::Modify,
+                kind: FileKind::Copy | FileKind::Hardlink,
+                ref target,
+                source: Some(ref source),
+                metadata: Some(ref metadata),
                 ..
-            } => Ok(true),
+            } => {
+                if self.source_missing() {
+                    return Ok(match self.source_hash {
+                        Some(ref expected) if expected.matches_file(target)? => FileState::Correct,
+                        _ => FileState::Modified,
+                    });
+                }
+                if metadata.len() != fs::symlink_metadata(source)?.len() {
+                    return Ok(FileState::Modified);
+                }
+
+                match (
+                    hash_file(target),
+                    self.source_digest.or_else(|| self.memo.hash(source)),
+                ) {
+                    (Some(left), Some(right)) if left == right => Ok(FileState::Correct),
+                    _ => Ok(FileState::Modified),
+                }
+            }
         }
     }
 
@@ -392,9 +1294,17 @@ impl FileWithMetadata {
     /// # Errors
     ///
     /// Returns an error if the filesystem returns anything other than
-    /// `NotFound`. `NotFound` is silently treated as `None`.
+    /// `NotFound`. `NotFound` is silently treated as `None`. Also returns an
+    /// error if [`owner`][Self::owner] or [`group`][Self::group] name no
+    /// known user or group.
     pub fn set_metadata(&mut self) -> Result<()> {
-        match fs::symlink_metadata(&self.target) {
+        self.resolve_owner()?;
+        let metadata = if self.follows_target() {
+            fs::metadata(&self.target)
+        } else {
+            fs::symlink_metadata(&self.target)
+        };
+        match metadata {
             Ok(metadata) => {
                 self.metadata = Some(metadata);
                 Ok(())
@@ -407,15 +1317,71 @@ impl FileWithMetadata {
         }
     }
 
+    /// Replaces [`owner`][Self::owner] and [`group`][Self::group] with the
+    /// [`uid`][Self::uid] and [`gid`][Self::gid] they name, so they are only
+    /// looked up once. Without NSS, as on Windows, they are ignored like
+    /// numeric IDs.
+    fn resolve_owner(&mut self) -> Result<()> {
+        if let Some(name) = self.owner.take() {
+            match sys::user_id(&name) {
+                Ok(Some(uid)) => self.uid = Some(uid),
+                Ok(None) => return Err(eyre!("No such user '{name}'")),
+                Err(err) if err.kind() == ErrorKind::Unsupported => {
+                    warn!("Ignoring owner '{name}', user names are not supported");
+                }
+                Err(err) => {
+                    return Err(err).wrap_err_with(|| format!("Failed to look up user '{name}'"));
+                }
+            }
+        }
+        if let Some(name) = self.group.take() {
+            match sys::group_id(&name) {
+                Ok(Some(gid)) => self.gid = Some(gid),
+                Ok(None) => return Err(eyre!("No such group '{name}'")),
+                Err(err) if err.kind() == ErrorKind::Unsupported => {
+                    warn!("Ignoring group '{name}', group names are not supported");
+                }
+                Err(err) => {
+                    return Err(err).wrap_err_with(|| format!("Failed to look up group '{name}'"));
+                }
+            }
+        }
+        Ok(())
+    }
+
     /// Returns `true` if the source is absent or invalid for a
-    /// [`Copy`][FileKind::Copy] or [`Symlink`][FileKind::Symlink] file,
-    /// logging a warning. When `true`, the caller should skip activation.
+    /// [`Copy`][FileKind::Copy] or [`Symlink`][FileKind::Symlink] file, or a
+    /// [`Template`][FileKind::Template] failed to render, logging a warning.
+    /// A `Copy` with inline content needs no source. A URL source or one to
+    /// decrypt only counts as absent once [`load`][Self::load] failed to
+    /// fetch or decrypt it. When `true`, the caller should skip activation.
     #[must_use]
+    #[allow(clippy::too_many_lines)]
     pub fn check_source(&self) -> bool {
         match *self {
+            Self {
+                source: Some(ref source),
+                ..
+            } if fetch::is_url(source) => {
+                if self.loaded {
+                    warn!(
+                        "{} with target '{}' source '{}' was not fetched, skipping...",
+                        self.kind,
+                        self.target.display(),
+                        source.display()
+                    );
+                }
+                self.loaded
+            }
             Self {
                 source: Some(ref metadata),
-                kind: FileKind::Copy | FileKind::Symlink,
+                kind:
+                    FileKind::Copy
+                    | FileKind::Template
+                    | FileKind::Hardlink
+                    | FileKind::RecursiveCopy
+                    | FileKind::Symlink
+                    | FileKind::LinkFarm,
                 ..
             } if fs::symlink_metadata(metadata)
                 .is_err_and(|err| err.kind() == ErrorKind::NotFound) =>
@@ -430,9 +1396,15 @@ impl FileWithMetadata {
             }
             Self {
                 source: None,
-                kind: FileKind::Copy | FileKind::Symlink,
+                kind:
+                    FileKind::Copy
+                    | FileKind::Template
+                    | FileKind::Hardlink
+                    | FileKind::RecursiveCopy
+                    | FileKind::Symlink
+                    | FileKind::LinkFarm,
                 ..
-            } => {
+            } if self.content().is_none() => {
                 warn!(
                     "{} with target '{}' missing source, skipping...",
                     self.kind,
@@ -442,7 +1414,7 @@ impl FileWithMetadata {
             }
             Self {
                 source: Some(ref source),
-                kind: FileKind::Copy,
+                kind: FileKind::Copy | FileKind::Template | FileKind::Hardlink,
                 ..
             } if fs::symlink_metadata(source).is_ok_and(|x| !x.is_file()) => {
                 warn!(
@@ -453,21 +1425,241 @@ impl FileWithMetadata {
                 );
                 true
             }
+            Self {
+                source: Some(ref source),
+                kind: FileKind::RecursiveCopy | FileKind::LinkFarm,
+                ..
+            } if fs::metadata(source).is_ok_and(|x| !x.is_dir()) => {
+                warn!(
+                    "{} with target '{}' source '{}' is not a directory. Skipping...",
+                    self.kind,
+                    self.target.display(),
+                    source.display()
+                );
+                true
+            }
+            Self {
+                kind: FileKind::Template,
+                rendered: None,
+                ..
+            } => {
+                warn!(
+                    "template with target '{}' was not rendered, skipping...",
+                    self.target.display()
+                );
+                true
+            }
+            Self {
+                decrypt: Some(_),
+                rendered: None,
+                loaded: true,
+                ..
+            } => {
+                warn!(
+                    "{} with target '{}' was not decrypted, skipping...",
+                    self.kind,
+                    self.target.display()
+                );
+                true
+            }
 
             _ => false,
         }
     }
 
+    /// Whether this is a [`Modify`][FileKind::Modify] entry that changes
+    /// what a symlink target points to rather than the symlink itself, see
+    /// [`follow_symlinks`][Self::follow_symlinks].
+    fn follows_target(&self) -> bool {
+        self.kind == FileKind::Modify && self.follow_symlinks == Some(true)
+    }
+
+    /// Returns the path of what is actually changed: the target, or with
+    /// [`follows_target`][Self::follows_target], what it resolves to.
+    fn resolved_target(&self) -> Result<Cow<'_, Path>> {
+        if !self.follows_target() {
+            return Ok(Cow::Borrowed(&self.target));
+        }
+        fs::canonicalize(&self.target)
+            .map(Cow::Owned)
+            .wrap_err_with(|| format!("Failed to resolve '{}'", self.target.display()))
+    }
+
+    /// Opens the parent directory of the [resolved][Self::resolved_target]
+    /// target according to [`resolve`][Self::resolve], returning it and the
+    /// target's name in it.
+    fn parent(&self) -> Result<(sys::Dir, OsString)> {
+        sys::Dir::open_parent(&self.resolved_target()?, self.resolve).wrap_err_with(|| {
+            format!(
+                "Failed to open the parent directory of '{}'",
+                self.target.display()
+            )
+        })
+    }
+
+    /// With [`as_owner`][Self::as_owner], acts as [`uid`][Self::uid] and
+    /// [`gid`][Self::gid] until the returned guard is dropped, see
+    /// [`sys::impersonate`]. Call after [`set_metadata`][Self::set_metadata],
+    /// which resolves owner and group names.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is neither a uid nor a gid, or they can't
+    /// be assumed.
+    fn impersonate(&self) -> Result<Option<sys::Impersonation>> {
+        if self.as_owner != Some(true) {
+            return Ok(None);
+        }
+        if self.uid.is_none() && self.gid.is_none() {
+            return Err(eyre!(
+                "'{}' has as_owner set, but no owner to act as",
+                self.target.display()
+            ));
+        }
+        sys::impersonate(self.uid, self.gid)
+            .map(Some)
+            .wrap_err_with(|| format!("Failed to act as the owner of '{}'", self.target.display()))
+    }
+
+    /// Whether the target may carry the immutable flag [`lock`][Self::lock]
+    /// sets: a file or directory of an entry other than
+    /// [`Delete`][FileKind::Delete].
+    fn lockable(&self) -> bool {
+        self.immutable == Some(true)
+            && self.kind != FileKind::Delete
+            && self
+                .metadata
+                .as_ref()
+                .is_some_and(|x| x.is_file() || x.is_dir())
+    }
+
+    /// Returns `false` if the target should be [locked][Self::lock] but
+    /// isn't.
+    fn locked(&self) -> bool {
+        !self.lockable()
+            || self
+                .resolved_target()
+                .is_ok_and(|x| sys::immutable(&x).unwrap_or(false))
+    }
+
+    /// With [`immutable`][Self::immutable], sets the immutable flag on the
+    /// target.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the flag can't be set, e.g. on a filesystem
+    /// without it or without `CAP_LINUX_IMMUTABLE`.
+    pub(crate) fn lock(&mut self) -> Result<()> {
+        if self.immutable != Some(true) || self.kind == FileKind::Delete {
+            return Ok(());
+        }
+        self.set_metadata()?;
+        if !self.lockable() {
+            return Err(eyre!(
+                "Only files and directories can be immutable, not '{}'",
+                self.target.display()
+            ));
+        }
+        sys::set_immutable(&self.resolved_target()?, true)
+            .wrap_err_with(|| format!("Failed to make '{}' immutable", self.target.display()))?;
+        info!("Made '{}' immutable", self.target.display());
+        Ok(())
+    }
+
+    /// Clears the immutable flag [`lock`][Self::lock] set, so that the
+    /// target can be replaced or removed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the flag can't be cleared.
+    pub(crate) fn unlock(&self) -> Result<()> {
+        if !self.lockable() {
+            return Ok(());
+        }
+        sys::set_immutable(&self.resolved_target()?, false).wrap_err_with(|| {
+            format!(
+                "Failed to clear the immutable flag of '{}'",
+                self.target.display()
+            )
+        })
+    }
+
+    /// Sets the immutable flag [`unlock`][Self::unlock] cleared again, after
+    /// failing to replace the target. Failing to is only logged, so as not
+    /// to hide why replacing failed.
+    fn relock(&self) {
+        if let Err(err) = self
+            .resolved_target()
+            .and_then(|x| Ok(sys::set_immutable(&x, true)?))
+        {
+            warn!(
+                "Failed to make '{}' immutable again: {err}",
+                self.target.display()
+            );
+        }
+    }
+
+    /// With [`fsync`][Self::fsync], flushes the target, everything under it
+    /// for a [`RecursiveCopy`][FileKind::RecursiveCopy] or
+    /// [`LinkFarm`][FileKind::LinkFarm], and its parent directory to disk.
+    /// Symlinks themselves are only persisted through their directory.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if anything can't be opened or flushed.
+    pub fn sync(&self) -> Result<()> {
+        if !self.fsync {
+            return Ok(());
+        }
+        match self.kind {
+            FileKind::RecursiveCopy | FileKind::LinkFarm => sync_tree(&self.target)?,
+            FileKind::Directory => sys::Dir::open(&self.target, self.resolve)?.sync()?,
+            FileKind::Copy
+            | FileKind::Template
+            | FileKind::Hardlink
+            | FileKind::Touch
+            | FileKind::Modify => {
+                if !fs::symlink_metadata(&self.target)?.is_symlink() {
+                    sys::sync_file(&self.target)?;
+                }
+            }
+            // Opening a special file may block or have side effects
+            FileKind::Symlink
+            | FileKind::Fifo
+            | FileKind::Socket
+            | FileKind::CharDevice
+            | FileKind::BlockDevice
+            | FileKind::Delete => (),
+        }
+        self.sync_parent()
+    }
+
+    /// With [`fsync`][Self::fsync], flushes the parent directory of the
+    /// target, so that its creation, renaming, or removal survives a crash.
+    fn sync_parent(&self) -> Result<()> {
+        if !self.fsync {
+            return Ok(());
+        }
+        self.parent()?
+            .0
+            .sync()
+            .wrap_err_with(|| format!("Failed to sync '{}'", self.target.display()))
+    }
+
     /// Applies the configured [`permissions`][Self::permissions],
-    /// [`uid`][Self::uid], and [`gid`][Self::gid] to the target file.
+    /// [`uid`][Self::uid], [`gid`][Self::gid], and [`xattrs`][Self::xattrs]
+    /// to the target file, relative to its parent directory, which is only
+    /// opened once.
     ///
     /// # Errors
     ///
     /// Returns an error if:
     ///
     /// - the target does not exist
+    /// - its parent directory can't be opened
     /// - setting permissions fails
-    /// - `chown` or `lchown` fails
+    /// - `fchownat` fails
+    /// - setting an extended attribute fails
     pub fn chmod_chown(&mut self) -> Result<()> {
         self.set_metadata()?;
         let Some(metadata) = self.metadata.clone() else {
@@ -476,49 +1668,167 @@ impl FileWithMetadata {
                 self.target.display()
             ));
         };
+        let (dir, name) = self.parent()?;
 
-        if self.kind != FileKind::Symlink {
-            if let Some(x) = self.permissions {
-                let new_perms = fs::Permissions::from_mode(x);
+        if self.kind == FileKind::Modify && self.permissions.is_some() && metadata.is_symlink() {
+            return Err(eyre!(
+                "'{}' is a symlink, which has no permissions of its own, set follow_symlinks to change what it points to",
+                self.target.display()
+            ));
+        }
+        if self.kind != FileKind::Symlink
+            && let Some(x) = self.permissions
+            && !sys::mode_matches(&metadata, x)
+        {
+            info!(
+                "Setting permissions of: '{}' to: '{:o}'",
+                &self.target.display(),
+                x,
+            );
 
-                if metadata.mode() & 0o7_777 == new_perms.mode() {
-                    return Ok(());
-                }
+            //This doesn't work with symlinks
+            dir.set_mode(&name, x)?;
+            self.set_metadata()?;
+        }
+
+        if self.uid.is_some() || self.gid.is_some() {
+            let owner = sys::owner(&metadata);
+            if let Some((uid, gid)) = owner {
                 info!(
-                    "Setting permissions of: '{}' to: '{:o}'",
-                    &self.target.display(),
-                    new_perms.mode(),
+                    "Chowning '{}': 'uid:{} gid:{}' -> 'uid:{} gid::{}'",
+                    self.target.display(),
+                    uid,
+                    gid,
+                    self.uid.unwrap_or(uid),
+                    self.gid.unwrap_or(gid),
                 );
+            }
+            if owner.is_none_or(|(uid, gid)| {
+                self.uid.is_some_and(|x| x != uid) || self.gid.is_some_and(|x| x != gid)
+            }) {
+                dir.chown(&name, self.uid, self.gid)?;
+            }
+        }
+
+        self.set_mtime_at(&dir, &name)?;
+
+        // Last, as changing the owner clears `security.capability`
+        self.set_xattrs()
+    }
 
-                //This doesn't work with symlinks
-                fs::set_permissions(&self.target, new_perms)?;
+    /// Returns the modification time the target should have according to
+    /// [`mtime`][Self::mtime], if any.
+    fn expected_mtime(&self) -> Result<Option<SystemTime>> {
+        Ok(match self.mtime {
+            None => None,
+            Some(Mtime::Source) => {
+                let source = self.source.as_ref().ok_or_eyre("mtime requires a source")?;
+                Some(fs::metadata(source)?.modified()?)
             }
-            self.set_metadata()?;
+            Some(Mtime::Unix(x)) => Some(if x >= 0 {
+                UNIX_EPOCH + Duration::from_secs(x.unsigned_abs())
+            } else {
+                UNIX_EPOCH - Duration::from_secs(x.unsigned_abs())
+            }),
+        })
+    }
+
+    /// Sets the modification time of the target to [`mtime`][Self::mtime],
+    /// unless it already has it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the source's modification time cannot be read, or
+    /// the target's cannot be set.
+    pub fn set_mtime(&mut self) -> Result<()> {
+        let (dir, name) = self.parent()?;
+        self.set_mtime_at(&dir, &name)
+    }
+
+    /// Does the work of [`set_mtime`][Self::set_mtime] relative to `dir`, the
+    /// parent directory of the target, in which it is `name`.
+    fn set_mtime_at(&mut self, dir: &sys::Dir, name: &OsStr) -> Result<()> {
+        let Some(mtime) = self.expected_mtime()? else {
+            return Ok(());
+        };
+        if self
+            .metadata
+            .as_ref()
+            .is_some_and(|x| self.mtime_matches(x))
+        {
+            return Ok(());
         }
+        info!("Setting modification time of '{}'", self.target.display());
+        dir.set_mtime(name, mtime).wrap_err_with(|| {
+            format!(
+                "Failed to set modification time of '{}'",
+                self.target.display()
+            )
+        })?;
+        self.set_metadata()
+    }
 
-        if self.uid.is_some() || self.gid.is_some() {
-            if (self.uid.is_some_and(|x| x == metadata.uid()))
-                && (self.gid.is_some_and(|x| x == metadata.gid()))
-            {
-                return Ok(());
+    /// Returns `true` if `metadata` has the modification time expected by
+    /// [`mtime`][Self::mtime], to the second, as not every filesystem is
+    /// more precise. Errors count as a mismatch.
+    fn mtime_matches(&self, metadata: &Metadata) -> bool {
+        fn seconds(time: SystemTime) -> i128 {
+            match time.duration_since(UNIX_EPOCH) {
+                Ok(x) => i128::from(x.as_secs()),
+                Err(err) => -i128::from(err.duration().as_secs()),
+            }
+        }
+
+        match (self.expected_mtime(), metadata.modified()) {
+            (Ok(Some(expected)), Ok(actual)) => seconds(expected) == seconds(actual),
+            (Ok(None), _) => true,
+            _ => false,
+        }
+    }
+
+    /// Sets every attribute in [`xattrs`][Self::xattrs] on the target that
+    /// doesn't have the expected value yet.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a value cannot be decoded, see [`xattr_value`], or
+    /// reading or setting an attribute fails.
+    pub fn set_xattrs(&self) -> Result<()> {
+        let target = self.resolved_target()?;
+        for (name, value) in &self.xattrs {
+            let value = xattr_value(value)?;
+            if sys::xattr(&target, name)?.as_ref() == Some(&value) {
+                continue;
             }
             info!(
-                "Chowning '{}': 'uid:{} gid:{}' -> 'uid:{} gid::{}'",
-                self.target.display(),
-                metadata.uid(),
-                metadata.gid(),
-                self.uid.unwrap_or_else(|| metadata.uid()),
-                self.gid.unwrap_or_else(|| metadata.gid()),
+                "Setting extended attribute '{}' of '{}'",
+                name,
+                self.target.display()
             );
-            if metadata.is_symlink() {
-                lchown(&self.target, self.uid, self.gid)?;
-            } else {
-                chown(&self.target, self.uid, self.gid)?;
-            }
+            sys::set_xattr(&target, name, &value).wrap_err_with(|| {
+                format!(
+                    "Failed to set extended attribute '{}' of '{}'",
+                    name,
+                    self.target.display()
+                )
+            })?;
         }
         Ok(())
     }
 
+    /// Returns `true` if every attribute in [`xattrs`][Self::xattrs] is set on
+    /// the target with the expected value. Errors count as a mismatch.
+    fn xattrs_match(&self) -> bool {
+        let Ok(target) = self.resolved_target() else {
+            return false;
+        };
+        self.xattrs.iter().all(|(name, value)| {
+            xattr_value(value).is_ok_and(|value| {
+                sys::xattr(&target, name).is_ok_and(|x| x.as_ref() == Some(&value))
+            })
+        })
+    }
+
     /// Creates a symlink at [`target`][Self::target] pointing to
     /// [`source`][Self::source], then applies permissions and ownership.
     ///
@@ -534,19 +1844,16 @@ impl FileWithMetadata {
     ///
     /// Panics if `source` is `None`.
     pub fn symlink(&mut self) -> Result<()> {
-        _ = file_util::mkdir(
-            self.target
-                .parent()
-                .ok_or_eyre("Failed to get parent directory")?,
-        );
+        _ = self.mkdir_parents();
 
         let source = if self.follow_symlinks.unwrap_or(true) {
-            fs::canonicalize(self.source.as_ref().unwrap())?
+            self.memo.canonicalize(self.source.as_ref().unwrap())?
         } else {
             path::absolute(self.source.as_ref().unwrap())?
         };
 
-        symlink(&source, &self.target)?;
+        let (dir, name) = self.parent()?;
+        dir.symlink(&source, &name)?;
         info!(
             "Symlinked '{}' -> '{}'",
             source.display(),
@@ -558,189 +1865,2279 @@ impl FileWithMetadata {
         Ok(())
     }
 
-    /// Copies [`source`][Self::source] to [`target`][Self::target], then
-    /// applies permissions and ownership.
+    /// Copies [`source`][Self::source], or writes the inline
+    /// [`content`][Self::content], to [`target`][Self::target].
+    ///
+    /// The copy is written to an anonymous file next to the target, which
+    /// only gets a name once complete, see
+    /// [`write_copy_at`][Self::write_copy_at], which also sets its
+    /// permissions and owner. Its modification time and extended attributes
+    /// are set under a random name before it is renamed into place, so
+    /// readers never see a partially written file.
     ///
     /// # Errors
     ///
     /// Returns an error if:
     /// - parent directory cannot be created
     /// - source path cannot be canonicalized
-    /// - file copy fails
+    /// - file copy or write fails
+    /// - permission or ownership changes fail
+    /// - the final rename fails
     ///
     /// # Panics
     ///
-    /// Panics if `source` is `None`.
+    /// Panics if `source` is `None` and there is no inline content.
     pub fn copy(&mut self) -> Result<()> {
-        _ = file_util::mkdir(
+        _ = self.mkdir_parents();
+
+        let (dir, name) = self.parent()?;
+        let temp_path = random_sibling(&self.target);
+        let temp_name = temp_path
+            .file_name()
+            .ok_or_eyre("Failed to get file name")?
+            .to_owned();
+        let target = mem::replace(&mut self.target, temp_path);
+        let result = self
+            .write_copy_at(&dir, &temp_name)
+            .and_then(|()| self.chmod_chown())
+            .and_then(|()| {
+                dir.rename(&temp_name, &name)
+                    .wrap_err_with(|| format!("Failed to rename to '{}'", target.display()))
+            });
+        self.target = target;
+        if result.is_err() {
+            let _ = dir.remove_file(&temp_name);
+        }
+        result?;
+        self.set_metadata()
+    }
+
+    /// Writes the contents of [`copy`][Self::copy] to `name` in `dir`. They
+    /// are staged in an anonymous file with `O_TMPFILE`, which only gets
+    /// `name` once complete, so that neither readers nor a crash leave a
+    /// partially written file behind. Where the filesystem doesn't support
+    /// that, `name` is written directly. Decrypted contents are only ever
+    /// readable by their owner. The owner and permissions of the entry are
+    /// set through the open file, before it gets `name`.
+    fn write_copy_at(&self, dir: &sys::Dir, name: &OsStr) -> Result<()> {
+        let mode = if self.decrypt.is_some() { 0o600 } else { 0o666 };
+        match dir.create_anonymous(mode) {
+            Ok(file) => {
+                self.write_copy(&file)?;
+                self.chmod_chown_file(&file)?;
+                if self.fsync {
+                    file.sync_all()?;
+                }
+                dir.link_anonymous(&file, name)
+                    .wrap_err_with(|| format!("Failed to link '{}'", self.target.display()))
+            }
+            Err(err) if err.kind() == ErrorKind::Unsupported => {
+                let file = dir
+                    .create(name, mode)
+                    .wrap_err_with(|| format!("Failed to create '{}'", self.target.display()))?;
+                self.write_copy(&file)?;
+                self.chmod_chown_file(&file)?;
+                if self.fsync {
+                    file.sync_all()?;
+                }
+                Ok(())
+            }
+            Err(err) => Err(err).wrap_err_with(|| {
+                format!(
+                    "Failed to create an anonymous file for '{}'",
+                    self.target.display()
+                )
+            }),
+        }
+    }
+
+    /// Gives the open `file` the owner and then the permissions of the
+    /// entry, in that order as changing the owner clears setuid and setgid.
+    fn chmod_chown_file(&self, file: &fs::File) -> Result<()> {
+        if self.uid.is_some() || self.gid.is_some() {
+            sys::chown_file(file, self.uid, self.gid)
+                .wrap_err_with(|| format!("Failed to chown '{}'", self.target.display()))?;
+        }
+        if let Some(mode) = self.permissions {
+            sys::set_file_mode(file, mode).wrap_err_with(|| {
+                format!("Failed to set permissions of '{}'", self.target.display())
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Writes the contents of [`copy`][Self::copy] to `file`, which is
+    /// [`target`][Self::target] or will become it.
+    fn write_copy(&self, mut file: &fs::File) -> Result<()> {
+        if let Some(content) = self.content() {
+            file.write_all(content)
+                .wrap_err_with(|| format!("Failed to write '{}'", self.target.display()))?;
+            if self.decrypt.is_some() {
+                info!("Wrote decrypted content to '{}'", self.target.display());
+            } else {
+                info!("Wrote inline content to '{}'", self.target.display());
+            }
+            return Ok(());
+        }
+
+        let source = self.source.as_ref().unwrap();
+        let source = if self.follow_symlinks.unwrap_or(true) {
+            self.memo.canonicalize(source)?
+        } else if fs::symlink_metadata(source)?.is_symlink() {
+            return Err(eyre!(
+                "Source '{}' is a symlink, but follow_symlinks is false",
+                source.display()
+            ));
+        } else {
+            path::absolute(source)?
+        };
+        copy_file_into(&source, file, self.reflink.unwrap_or_default())?;
+        info!(
+            "Copied '{}' -> '{}'",
+            source.display(),
+            &self.target.display(),
+        );
+        Ok(())
+    }
+
+    /// Returns the inline content of a [`Copy`][FileKind::Copy] file, from
+    /// either [`text`][Self::text] or
+    /// [`content_base64`][Self::content_base64], or the
+    /// [`rendered`][Self::rendered] contents of a
+    /// [`Template`][FileKind::Template], if any.
+    #[must_use]
+    pub fn content(&self) -> Option<&[u8]> {
+        self.text
+            .as_deref()
+            .map(str::as_bytes)
+            .or(self.content_base64.as_deref())
+            .or(self.rendered.as_deref())
+    }
+
+    /// Whether this is a [`Copy`][FileKind::Copy] whose source doesn't exist,
+    /// and which [`if_missing_source`][Self::if_missing_source] says to
+    /// create empty instead.
+    fn creates_empty(&self) -> bool {
+        self.kind == FileKind::Copy
+            && self.if_missing_source == Some(IfMissingSource::CreateEmpty)
+            && self.source.as_ref().is_some_and(|x| {
+                fs::symlink_metadata(x).is_err_and(|e| e.kind() == ErrorKind::NotFound)
+            })
+    }
+
+    /// Creates an empty file at the target, see
+    /// [`creates_empty`][Self::creates_empty]. An existing file is kept, as
+    /// whatever uses it may have written to it since, and only has its
+    /// permissions and owner fixed if `clobber`.
+    fn create_empty(&mut self, clobber: bool) -> Result<(Action, Option<String>)> {
+        match self.metadata {
+            Some(ref metadata) if metadata.is_file() => {
+                if !clobber {
+                    return Ok((
+                        Action::Skipped,
+                        Some(String::from("source is missing, kept the existing file")),
+                    ));
+                }
+                self.chmod_chown()?;
+                Ok((
+                    Action::Modified,
+                    Some(String::from("source is missing, fixed the existing file")),
+                ))
+            }
+            Some(_) => Err(eyre!(
+                "Source of '{}' is missing, and something other than a file is in the way",
+                self.target.display()
+            )),
+            None => {
+                // Made by someone else in the meantime, so left alone
+                if !self.create_file()? {
+                    return Ok((
+                        Action::Skipped,
+                        Some(String::from("source is missing, kept the existing file")),
+                    ));
+                }
+                self.chmod_chown()?;
+                self.sync()?;
+                Ok((
+                    Action::Created,
+                    Some(String::from("source is missing, created empty")),
+                ))
+            }
+        }
+    }
+
+    /// Creates an empty file at the target unless a file is already there,
+    /// and gives it the permissions and owner of the entry.
+    fn touch(&mut self) -> Result<()> {
+        self.create_file()?;
+        self.chmod_chown()
+    }
+
+    /// Creates an empty file at the target, returning whether it did rather
+    /// than finding one already there.
+    fn create_file(&self) -> Result<bool> {
+        _ = self.mkdir_parents();
+        let (dir, name) = self.parent()?;
+        match dir.create(&name, 0o666) {
+            Ok(_) => {
+                info!("Created empty file '{}'", self.target.display());
+                Ok(true)
+            }
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => Ok(false),
+            Err(err) => {
+                Err(err).wrap_err_with(|| format!("Failed to create '{}'", self.target.display()))
+            }
+        }
+    }
+
+    /// With [`Options::show_diff`], logs how the contents of the target of a
+    /// [`Copy`][FileKind::Copy] or [`Template`][FileKind::Template] differ
+    /// from what is about to replace them.
+    pub(crate) fn show_diff(&self, options: &Options) {
+        if !options.show_diff
+            || !matches!(self.kind, FileKind::Copy | FileKind::Template)
+            || self.decrypt.is_some()
+        {
+            return;
+        }
+        let target = self.target.display();
+        match self.diff_contents() {
+            Ok(Some(diff)) if diff.is_empty() => {}
+            Ok(Some(diff)) => warn!("Replacing '{target}':\n{}", diff.trim_end()),
+            Ok(None) => warn!("Replacing '{target}', which is binary or too large to show"),
+            Err(err) => warn!("Failed to diff '{target}'\n{err:?}"),
+        }
+    }
+
+    /// Returns the diff [`show_diff`][Self::show_diff] logs, empty if the
+    /// target isn't a file or has the same contents, or `None` if either is
+    /// binary or larger than [`patch::MAX_SIZE`].
+    fn diff_contents(&self) -> Result<Option<String>> {
+        let Some(metadata) = fs::symlink_metadata(&self.target)
+            .ok()
+            .filter(Metadata::is_file)
+        else {
+            return Ok(Some(String::new()));
+        };
+        let (new, new_name) = match (self.content(), &self.source) {
+            (Some(content), _) => (Cow::Borrowed(content), String::from("new contents")),
+            (None, Some(source)) => {
+                if fs::metadata(source)?.len() > patch::MAX_SIZE {
+                    return Ok(None);
+                }
+                (Cow::Owned(fs::read(source)?), source.display().to_string())
+            }
+            (None, None) => return Ok(Some(String::new())),
+        };
+        if metadata.len() > patch::MAX_SIZE || new.len() as u64 > patch::MAX_SIZE {
+            return Ok(None);
+        }
+        let old = fs::read(&self.target)?;
+        Ok(patch::unified(
+            &old,
+            &new,
+            &self.target.to_string_lossy(),
+            &new_name,
+        ))
+    }
+
+    /// Whether this [`Touch`][FileKind::Touch], or
+    /// [empty copy][Self::creates_empty], has been written to since, which
+    /// makes its contents no longer smfh's to remove.
+    pub(crate) fn written_to(&self) -> bool {
+        (self.kind == FileKind::Touch || self.creates_empty())
+            && self.metadata.as_ref().is_some_and(|x| x.len() != 0)
+    }
+
+    /// Whether the target of this [`Delete`][FileKind::Delete] lacks its
+    /// [`target_hash`][Self::target_hash], i.e. was changed since it was
+    /// installed, so that it is backed up rather than deleted.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the target is not a file or can't be read.
+    pub(crate) fn edited(&self) -> Result<bool> {
+        let (FileKind::Delete, Some(expected), Some(metadata)) =
+            (self.kind, &self.target_hash, &self.metadata)
+        else {
+            return Ok(false);
+        };
+        if !metadata.is_file() {
+            return Err(eyre!(
+                "'{}' is not a file, so it can't have target_hash",
+                self.target.display()
+            ));
+        }
+        Ok(!expected.matches_file(&self.target)?)
+    }
+
+    /// Keeps the checksum of what the target of a [`Copy`][FileKind::Copy]
+    /// or [`Template`][FileKind::Template] has after `action`, if that is
+    /// what smfh wrote, in [`Options::installed`].
+    pub(crate) fn remember(&self, action: Action, reason: Option<&str>, options: &Options) {
+        let Some(ref installed) = options.installed else {
+            return;
+        };
+        let ours = match action {
+            Action::Created | Action::Clobbered | Action::BackedUp | Action::Modified => true,
+            Action::Skipped => reason == Some(ALREADY_CORRECT),
+            _ => false,
+        };
+        if !ours || !matches!(self.kind, FileKind::Copy | FileKind::Template) {
+            return;
+        }
+        match Checksum::of_file(Algorithm::Blake3, &self.target) {
+            Ok(checksum) => installed.insert(&self.target, checksum),
+            Err(err) => warn!("{err:?}"),
+        }
+    }
+
+    /// Size of the target of a [`Copy`][FileKind::Copy] or
+    /// [`Template`][FileKind::Template] entry once written, zero for other
+    /// kinds.
+    pub(crate) fn copied_bytes(&self) -> u64 {
+        if matches!(self.kind, FileKind::Copy | FileKind::Template) {
+            fs::symlink_metadata(&self.target).map_or(0, |x| x.len())
+        } else {
+            0
+        }
+    }
+
+    /// Hard links [`source`][Self::source] to [`target`][Self::target]. If
+    /// they are on different filesystems and
+    /// [`copy_fallback`][Self::copy_fallback] is set, copies instead. Since a
+    /// hard link shares the source's inode, permissions and ownership are
+    /// only applied to such copies.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if:
+    /// - parent directory cannot be created
+    /// - source path cannot be canonicalized
+    /// - hard link creation, or the fallback copy, fails
+    ///
+    /// # Panics
+    ///
+    /// Panics if `source` is `None`.
+    pub fn hardlink(&mut self) -> Result<()> {
+        _ = self.mkdir_parents();
+
+        let source = self.memo.canonicalize(self.source.as_ref().unwrap())?;
+
+        let (dir, name) = self.parent()?;
+        match dir.hard_link(&source, &name) {
+            Ok(()) => {
+                info!(
+                    "Hard linked '{}' -> '{}'",
+                    source.display(),
+                    &self.target.display(),
+                );
+                self.set_metadata()
+            }
+            Err(err)
+                if err.kind() == ErrorKind::CrossesDevices
+                    && self.copy_fallback.unwrap_or(false) =>
+            {
+                info!(
+                    "'{}' is on a different filesystem than '{}', copying instead",
+                    source.display(),
+                    &self.target.display(),
+                );
+                self.copy()
+            }
+            Err(err) => Err(err).wrap_err("While creating hard link"),
+        }
+    }
+
+    /// Copies the directory tree at [`source`][Self::source] to
+    /// [`target`][Self::target]. [`permissions`][Self::permissions] apply to
+    /// every regular file, and to every directory with execute bits added
+    /// wherever read bits are set. [`uid`][Self::uid] and [`gid`][Self::gid]
+    /// apply to every entry.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if:
+    /// - parent directory cannot be created
+    /// - source path cannot be canonicalized
+    /// - any directory, file, or symlink in the tree fails to be created
+    /// - permission or ownership changes fail
+    ///
+    /// # Panics
+    ///
+    /// Panics if `source` is `None`.
+    pub fn recursive_copy(&mut self) -> Result<()> {
+        _ = self.mkdir_parents();
+
+        let source = self.memo.canonicalize(self.source.as_ref().unwrap())?;
+
+        copy_tree(
+            &source,
+            &self.target,
+            self.reflink.unwrap_or_default(),
+            self.permissions,
+            self.uid,
+            self.gid,
+        )?;
+        info!(
+            "Recursively copied '{}' -> '{}'",
+            source.display(),
+            &self.target.display(),
+        );
+
+        self.set_metadata()
+    }
+
+    /// Symlinks every file under [`source`][Self::source] to the same relative
+    /// path under [`target`][Self::target], creating intermediate directories
+    /// as needed. Existing files in the way of a link are deleted if `clobber`
+    /// is set, otherwise backed up with [`Options::prefix`], unless
+    /// [`Options::confirm`] declines. [`uid`][Self::uid] and
+    /// [`gid`][Self::gid] apply to the links.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if:
+    /// - source path cannot be canonicalized or walked
+    /// - a directory in the target tree cannot be created
+    /// - an existing file cannot be removed or backed up
+    /// - symlink creation or ownership changes fail
+    ///
+    /// # Panics
+    ///
+    /// Panics if `source` is `None`.
+    pub fn link_farm(&mut self, clobber: bool, options: &Options) -> Result<()> {
+        let source = self.memo.canonicalize(self.source.as_ref().unwrap())?;
+        let journal_change = |path: &Path| {
+            options
+                .journal
+                .as_ref()
+                .map_or(Ok(()), |journal| journal.change(path))
+        };
+        self.mkdir(&self.target)?;
+
+        for relative in walk_files(&source)? {
+            let link = self.target.join(&relative);
+            let destination = source.join(&relative);
+            self.mkdir(link.parent().ok_or_eyre("Failed to get parent directory")?)?;
+
+            if let Ok(metadata) = fs::symlink_metadata(&link) {
+                if metadata.is_symlink() && read_link(&link)? == destination {
+                    continue;
+                }
+                let action = if clobber {
+                    Action::Clobbered
+                } else {
+                    Action::BackedUp
+                };
+                if let Some(ref confirm) = options.confirm
+                    && !confirm.ask(&Prompt {
+                        target: link.clone(),
+                        action,
+                        found: Prompt::describe(&link, &metadata),
+                        wanted: format!("a symlink to '{}'", destination.display()),
+                    })
+                {
+                    continue;
+                }
+                if clobber {
+                    journal_change(&link)?;
+                    delete_within(&link, &metadata, options)?;
+                } else {
+                    let backup = prefix_move(
+                        &link,
+                        &options.prefix,
+                        options.timestamped_backups,
+                        self.resolve,
+                    )?;
+                    if let Some(ref journal) = options.journal
+                        && let Some(ref backup) = backup
+                    {
+                        journal.rename(&link, backup)?;
+                    }
+                    journal_change(&link)?;
+                }
+            } else {
+                journal_change(&link)?;
+            }
+
+            sys::symlink(&destination, &link)?;
+            info!(
+                "Symlinked '{}' -> '{}'",
+                destination.display(),
+                link.display()
+            );
+            if self.uid.is_some() || self.gid.is_some() {
+                sys::chown(&link, &fs::symlink_metadata(&link)?, self.uid, self.gid)?;
+            }
+        }
+
+        self.set_metadata()
+    }
+
+    /// Removes every link created by [`link_farm`][Self::link_farm], then any
+    /// directories under [`target`][Self::target] left empty by that.
+    fn remove_link_farm(&self) -> Result<()> {
+        let source = self
+            .memo
+            .canonicalize(self.source.as_ref().ok_or_eyre("Missing source")?)?;
+        for relative in walk_files(&source)? {
+            let link = self.target.join(&relative);
+            fs::remove_file(&link)?;
+            info!("Deleted '{}'", link.display());
+
+            for dir in link.ancestors().skip(1) {
+                if !dir.starts_with(&self.target) || fs::remove_dir(dir).is_err() {
+                    break;
+                }
+                info!("Deleting directory '{}'", dir.display());
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the special file a [`Fifo`][FileKind::Fifo],
+    /// [`Socket`][FileKind::Socket], [`CharDevice`][FileKind::CharDevice],
+    /// or [`BlockDevice`][FileKind::BlockDevice] entry creates, or `None`
+    /// for other kinds and devices without [`major`][Self::major] and
+    /// [`minor`][Self::minor].
+    #[must_use]
+    pub const fn node(&self) -> Option<sys::Node> {
+        match (self.kind, self.major, self.minor) {
+            (FileKind::Fifo, ..) => Some(sys::Node::Fifo),
+            (FileKind::Socket, ..) => Some(sys::Node::Socket),
+            (FileKind::CharDevice, Some(major), Some(minor)) => {
+                Some(sys::Node::CharDevice(major, minor))
+            }
+            (FileKind::BlockDevice, Some(major), Some(minor)) => {
+                Some(sys::Node::BlockDevice(major, minor))
+            }
+            _ => None,
+        }
+    }
+
+    /// Creates the special file described by [`node`][Self::node] at
+    /// [`target`][Self::target], then applies permissions and ownership.
+    /// Without [`permissions`][Self::permissions], it gets `666` minus the
+    /// umask.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if:
+    /// - this isn't a special file, or a device without a major and minor
+    /// - creating it fails, e.g. a device node when not running as root
+    /// - permission or ownership changes fail
+    pub fn mknod(&mut self) -> Result<()> {
+        _ = self.mkdir_parents();
+        let node = self.node().ok_or_else(|| {
+            eyre!(
+                "'{}' is not a special file or lacks a major and minor",
+                self.target.display()
+            )
+        })?;
+        let (dir, name) = self.parent()?;
+        dir.mknod(&name, node, 0o666).wrap_err_with(|| {
+            format!("Failed to create {} '{}'", self.kind, self.target.display())
+        })?;
+        info!("Created {} '{}'", self.kind, self.target.display());
+        self.chmod_chown()
+    }
+
+    /// Creates the missing parent directories of [`target`][Self::target],
+    /// see [`mkdir`][Self::mkdir].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the target has no parent or creating one fails.
+    fn mkdir_parents(&self) -> Result<()> {
+        self.mkdir(
             self.target
                 .parent()
                 .ok_or_eyre("Failed to get parent directory")?,
-        );
+        )
+    }
+
+    /// Creates `path` like [`mkdir`], giving the directories it creates the
+    /// mode and ownership of [`parents`][Self::parents], if set.
+    fn mkdir(&self, path: &Path) -> Result<()> {
+        self.parents
+            .as_ref()
+            .map_or_else(|| mkdir(path), |parents| mkdir_as(path, parents))
+    }
+
+    /// Creates [`target`][Self::target] as a directory, then applies
+    /// permissions and ownership.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if:
+    /// - directory creation fails
+    /// - permission or ownership changes fail
+    pub fn directory(&mut self) -> Result<()> {
+        if let Some(parent) = self.target.parent() {
+            self.mkdir(parent)?;
+        }
+        mkdir(&self.target)?;
+        self.set_metadata()?;
+        self.chmod_chown()?;
+        Ok(())
+    }
+}
+
+/// Returns the kind of entry that creates `node`.
+const fn node_kind(node: sys::Node) -> FileKind {
+    match node {
+        sys::Node::Fifo => FileKind::Fifo,
+        sys::Node::Socket => FileKind::Socket,
+        sys::Node::CharDevice(..) => FileKind::CharDevice,
+        sys::Node::BlockDevice(..) => FileKind::BlockDevice,
+    }
+}
+
+/// Returns a random, currently unused path in the same directory as `path`.
+pub(crate) fn random_sibling(path: &Path) -> PathBuf {
+    let string = Alphanumeric.sample_string(&mut rand::rng(), 16);
+    let sibling = path.with_file_name(string);
+    if sibling.exists() {
+        random_sibling(path)
+    } else {
+        sibling
+    }
+}
+
+/// Writes `contents` to a temporary file next to `path` and renames it into
+/// place, so readers never observe a partially written file.
+///
+/// # Errors
+///
+/// Returns an error if writing or renaming the temporary file fails.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let temp_path = random_sibling(path);
+    fs::write(&temp_path, contents)
+        .and_then(|()| fs::rename(&temp_path, path))
+        .inspect_err(|_| {
+            let _ = fs::remove_file(&temp_path);
+        })
+        .wrap_err_with(|| format!("Failed to write '{}'", path.display()))
+}
+
+/// Renders the template at `source` by replacing `@name@` placeholders.
+///
+/// Every placeholder whose name is a key of `variables` is replaced by its
+/// value. Unknown placeholders and lone `@` signs are left as they are, and
+/// substituted values are not expanded again.
+///
+/// # Errors
+///
+/// Returns an error if the template cannot be read or is not valid UTF-8.
+pub fn render_template(source: &Path, variables: &BTreeMap<String, String>) -> Result<Vec<u8>> {
+    let template = fs::read_to_string(source)
+        .wrap_err_with(|| format!("Failed to read template '{}'", source.display()))?;
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template.as_str();
+    while let Some(start) = rest.find('@') {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        match rest
+            .find('@')
+            .and_then(|end| variables.get(&rest[..end]).map(|value| (end, value)))
+        {
+            Some((end, value)) => {
+                rendered.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => rendered.push('@'),
+        }
+    }
+    rendered.push_str(rest);
+    Ok(rendered.into_bytes())
+}
+
+/// Mode applied to directories of a [`RecursiveCopy`][FileKind::RecursiveCopy]
+/// whose files get `permissions`: every read bit also grants execute, so the
+/// tree stays traversable.
+const fn tree_dir_mode(permissions: u32) -> u32 {
+    permissions | ((permissions & 0o444) >> 2)
+}
+
+/// Copies the contents and permissions of `source` to a new file at
+/// `target`, see [`copy_file_into`].
+pub(crate) fn copy_file(source: &Path, target: &Path, reflink: Reflink) -> Result<()> {
+    let file = fs::File::create(target)
+        .wrap_err_with(|| format!("Failed to create '{}'", target.display()))?;
+    copy_file_into(source, &file, reflink).inspect_err(|_| {
+        let _ = fs::remove_file(target);
+    })
+}
+
+/// Copies the contents and permissions of `source` to the empty `target`,
+/// cloning them instead as `reflink` allows. Holes in sparse files are kept.
+fn copy_file_into(source: &Path, target: &fs::File, reflink: Reflink) -> Result<()> {
+    if reflink != Reflink::Never {
+        match sys::reflink(source, target) {
+            Ok(()) => return Ok(()),
+            Err(err) if reflink == Reflink::Always => {
+                return Err(err)
+                    .wrap_err_with(|| format!("Failed to reflink '{}'", source.display()));
+            }
+            Err(err) => info!(
+                "Failed to reflink '{}', copying instead: {err}",
+                source.display()
+            ),
+        }
+    }
+    if sys::is_sparse(&fs::metadata(source)?) {
+        match sys::copy_sparse(source, target) {
+            Err(err) if err.kind() == ErrorKind::Unsupported => {}
+            result => {
+                return result.wrap_err_with(|| format!("Failed to copy '{}'", source.display()));
+            }
+        }
+    }
+    sys::copy(source, target).wrap_err_with(|| format!("Failed to copy '{}'", source.display()))
+}
+
+pub(crate) fn copy_tree(
+    source: &Path,
+    target: &Path,
+    reflink: Reflink,
+    permissions: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+) -> Result<()> {
+    let metadata = fs::symlink_metadata(source)?;
+    let mode = if metadata.is_dir() {
+        fs::create_dir(target)
+            .wrap_err_with(|| format!("Failed to create directory '{}'", target.display()))?;
+        for entry in fs::read_dir(source)? {
+            let entry = entry?;
+            copy_tree(
+                &entry.path(),
+                &target.join(entry.file_name()),
+                reflink,
+                permissions,
+                uid,
+                gid,
+            )?;
+        }
+        permissions.map(tree_dir_mode)
+    } else if metadata.is_symlink() {
+        sys::symlink(&read_link(source)?, target)?;
+        None
+    } else {
+        copy_file(source, target, reflink)?;
+        permissions
+    };
+
+    if let Some(mode) = mode {
+        sys::set_mode(target, mode)?;
+    }
+    if uid.is_some() || gid.is_some() {
+        sys::chown(target, &fs::symlink_metadata(target)?, uid, gid)?;
+    }
+    Ok(())
+}
+
+/// Most symlinks followed from a target before giving up on it, as many as
+/// Linux follows when resolving a path.
+const MAX_SYMLINK_DEPTH: usize = 40;
+
+/// Where a chain of symlinks leads, see [`follow_chain`].
+enum Chain {
+    /// The canonical path of the file at its end.
+    Ends(PathBuf),
+    /// It comes back to a symlink it went through.
+    Loops,
+    /// It has more than [`MAX_SYMLINK_DEPTH`] symlinks.
+    TooDeep,
+}
+
+/// Follows the symlink at `path` one link at a time, so that a chain that
+/// loops or goes on too long is told apart from any other failure to
+/// resolve it.
+///
+/// # Errors
+///
+/// Returns an error if a link can't be read or the end of the chain doesn't
+/// exist.
+fn follow_chain(path: &Path) -> Result<Chain> {
+    let mut seen = BTreeSet::new();
+    let mut path = path.to_path_buf();
+    loop {
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if !metadata.is_symlink() => break,
+            Ok(_) => {}
+            // A loop through a directory in the path
+            Err(err) if sys::is_loop(&err) => return Ok(Chain::Loops),
+            Err(err) => return Err(err.into()),
+        }
+        if seen.len() == MAX_SYMLINK_DEPTH {
+            return Ok(Chain::TooDeep);
+        }
+        let destination = read_link(&path)?;
+        let next = path
+            .parent()
+            .map_or_else(|| destination.clone(), |x| x.join(&destination))
+            .components()
+            .collect();
+        seen.insert(mem::replace(&mut path, next));
+        if seen.contains(&path) {
+            return Ok(Chain::Loops);
+        }
+    }
+    Ok(match fs::canonicalize(&path) {
+        Ok(x) => Chain::Ends(x),
+        Err(err) if sys::is_loop(&err) => Chain::Loops,
+        Err(err) => return Err(err.into()),
+    })
+}
+
+fn tree_state(
+    source: &Path,
+    target: &Path,
+    permissions: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+) -> Result<FileState> {
+    let source_metadata = fs::symlink_metadata(source)?;
+    let target_metadata = match fs::symlink_metadata(target) {
+        Ok(x) => x,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(FileState::Missing),
+        Err(err) => return Err(err.into()),
+    };
+
+    if source_metadata.file_type() != target_metadata.file_type() {
+        return Ok(FileState::WrongKind);
+    }
+
+    if sys::owner(&target_metadata).is_some_and(|(target_uid, target_gid)| {
+        uid.is_some_and(|x| x != target_uid) || gid.is_some_and(|x| x != target_gid)
+    }) {
+        return Ok(FileState::WrongOwner);
+    }
+
+    if target_metadata.is_symlink() {
+        return Ok(if read_link(source)? == read_link(target)? {
+            FileState::Correct
+        } else {
+            FileState::Modified
+        });
+    }
+
+    let mode = if target_metadata.is_dir() {
+        permissions.map(tree_dir_mode)
+    } else {
+        permissions
+    };
+    if mode.is_some_and(|x| !sys::mode_matches(&target_metadata, x)) {
+        return Ok(FileState::WrongPermissions);
+    }
+
+    if target_metadata.is_dir() {
+        fn names(path: &Path) -> Result<Vec<OsString>> {
+            let mut names = fs::read_dir(path)?
+                .map(|entry| Ok(entry?.file_name()))
+                .collect::<Result<Vec<_>>>()?;
+            names.sort();
+            Ok(names)
+        }
+
+        let source_names = names(source)?;
+        if source_names != names(target)? {
+            return Ok(FileState::Modified);
+        }
+        for name in source_names {
+            let state = tree_state(
+                &source.join(&name),
+                &target.join(&name),
+                permissions,
+                uid,
+                gid,
+            )?;
+            if state != FileState::Correct {
+                return Ok(state);
+            }
+        }
+        return Ok(FileState::Correct);
+    }
+
+    if source_metadata.len() != target_metadata.len() {
+        return Ok(FileState::Modified);
+    }
+    match (hash_file(target), hash_file(source)) {
+        (Some(left), Some(right)) if left == right => Ok(FileState::Correct),
+        _ => Ok(FileState::Modified),
+    }
+}
+
+/// Returns the paths, relative to `root`, of every non-directory entry in the
+/// tree under `root`. Symlinks to directories are not descended into.
+///
+/// # Errors
+///
+/// Returns an error if any directory in the tree cannot be read.
+pub fn walk_files(root: &Path) -> Result<Vec<PathBuf>> {
+    fn walk(root: &Path, relative: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+        for entry in fs::read_dir(root.join(relative))? {
+            let entry = entry?;
+            let path = relative.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                walk(root, &path, files)?;
+            } else {
+                files.push(path);
+            }
+        }
+        Ok(())
+    }
+
+    let mut files = Vec::new();
+    walk(root, Path::new(""), &mut files)?;
+    files.sort();
+    Ok(files)
+}
+
+/// Flushes every file and directory under `root`, then `root` itself, to
+/// disk. Symlinks are persisted through their directories.
+fn sync_tree(root: &Path) -> Result<()> {
+    for entry in fs::read_dir(root)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            sync_tree(&entry.path())?;
+        } else if file_type.is_file() {
+            sys::sync_file(&entry.path())?;
+        }
+    }
+    sys::Dir::open(root, Resolve::Follow)?
+        .sync()
+        .wrap_err_with(|| format!("Failed to sync '{}'", root.display()))
+}
+
+fn link_farm_state(
+    source: &Path,
+    target: &Path,
+    uid: Option<u32>,
+    gid: Option<u32>,
+) -> Result<FileState> {
+    let source = fs::canonicalize(source)?;
+    for relative in walk_files(&source)? {
+        let link = target.join(&relative);
+        let metadata = match fs::symlink_metadata(&link) {
+            Ok(x) => x,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(FileState::Missing),
+            Err(err) => return Err(err.into()),
+        };
+        if !metadata.is_symlink() {
+            return Ok(FileState::WrongKind);
+        }
+        if sys::owner(&metadata).is_some_and(|(link_uid, link_gid)| {
+            uid.is_some_and(|x| x != link_uid) || gid.is_some_and(|x| x != link_gid)
+        }) {
+            return Ok(FileState::WrongOwner);
+        }
+        if read_link(&link)? != source.join(&relative) {
+            return Ok(FileState::Modified);
+        }
+    }
+    Ok(FileState::Correct)
+}
+
+/// Creates `path` as a directory, including any missing parent directories.
+/// No-op if the directory already exists.
+///
+/// # Errors
+///
+/// Returns an error if:
+/// - the path exists but is not a directory
+/// - directory creation fails
+pub fn mkdir(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Err(_) => {
+            fs::create_dir_all(path)?;
+            info!("Created directory '{}'", path.display());
+        }
+        Ok(x) => {
+            if !x.is_dir() {
+                return Err(eyre!("File in way of '{}'", path.display()));
+            }
+            info!("Directory '{}' already exists", path.display());
+        }
+    }
+    Ok(())
+}
+
+/// Like [`mkdir`], but gives every directory it creates the mode and
+/// ownership of `parents`. Directories that already exist are left alone.
+///
+/// # Errors
+///
+/// Returns an error if the path exists but is not a directory, or creating
+/// a directory or changing its mode or ownership fails.
+pub fn mkdir_as(path: &Path, parents: &manifest::Parents) -> Result<()> {
+    let missing: Vec<&Path> = path
+        .ancestors()
+        .take_while(|x| fs::symlink_metadata(x).is_err())
+        .collect();
+    if missing.is_empty() {
+        return mkdir(path);
+    }
+    for dir in missing.into_iter().rev() {
+        match fs::create_dir(dir) {
+            Ok(()) => {}
+            // Created concurrently, e.g. by another job
+            Err(err) if err.kind() == ErrorKind::AlreadyExists && dir.is_dir() => continue,
+            Err(err) => {
+                return Err(err)
+                    .wrap_err_with(|| format!("Failed to create directory '{}'", dir.display()));
+            }
+        }
+        if let Some(mode) = parents.mode {
+            sys::set_mode(dir, mode)?;
+        }
+        if parents.uid.is_some() || parents.gid.is_some() {
+            sys::chown(dir, &fs::symlink_metadata(dir)?, parents.uid, parents.gid)?;
+        }
+        info!("Created directory '{}'", dir.display());
+    }
+    Ok(())
+}
+
+/// Renames the file at `path` to a prefixed name in the same parent directory,
+/// backing it up, and returns that name.
+///
+/// No-op if the path does not exist. The parent directory is resolved
+/// according to `resolve`.
+///
+/// An existing backup with the same name is deleted, unless `timestamped`,
+/// in which case the name gets a suffix instead, see [`backup_path`].
+///
+/// # Errors
+///
+/// Returns an error if:
+/// - the path has no filename or parent component
+/// - an existing file at the destination cannot be deleted
+/// - the rename fails
+pub fn prefix_move(
+    path: &Path,
+    prefix: &str,
+    timestamped: bool,
+    resolve: Resolve,
+) -> Result<Option<PathBuf>> {
+    let Ok(_) = fs::symlink_metadata(path) else {
+        return Ok(None);
+    };
+
+    let new_path = backup_path(path, prefix, timestamped)?;
+
+    if let Ok(metadata) = fs::symlink_metadata(&new_path) {
+        delete(&new_path, &metadata, resolve)?;
+    }
+
+    let (dir, name) = sys::Dir::open_parent(path, resolve)?;
+    dir.rename(
+        &name,
+        new_path.file_name().ok_or_eyre("Failed to get file name")?,
+    )?;
+    info!("Renaming '{}' -> '{}'", path.display(), new_path.display());
+    Ok(Some(new_path))
+}
+
+/// Returns where [`prefix_move`] would back up `path`.
+///
+/// That is its [`prefixed_path`], or with `timestamped`, that with the
+/// current Unix time appended, e.g. `.backup-file.1760000000`, and a counter
+/// if a backup with that name already exists, e.g.
+/// `.backup-file.1760000000.1`.
+///
+/// # Errors
+///
+/// Returns an error if the path has no filename or parent component.
+pub fn backup_path(path: &Path, prefix: &str, timestamped: bool) -> Result<PathBuf> {
+    let prefixed = prefixed_path(path, prefix)?;
+    if !timestamped {
+        return Ok(prefixed);
+    }
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut stamped = prefixed.into_os_string();
+    stamped.push(format!(".{now}"));
+    let mut backup = PathBuf::from(&stamped);
+    let mut counter = 0;
+    while fs::symlink_metadata(&backup).is_ok() {
+        counter += 1;
+        let mut name = stamped.clone();
+        name.push(format!(".{counter}"));
+        backup = PathBuf::from(name);
+    }
+    Ok(backup)
+}
+
+/// Returns the existing backups [`prefix_move`] made of `path`, oldest
+/// first, whether timestamped or not. A backup without a timestamp counts as
+/// the oldest.
+///
+/// # Errors
+///
+/// Returns an error if the path has no filename or parent component, or its
+/// parent directory can't be read.
+pub fn backups_of(path: &Path, prefix: &str) -> Result<Vec<PathBuf>> {
+    let prefixed = prefixed_path(path, prefix)?;
+    let name = prefixed.file_name().ok_or_eyre("Failed to get file name")?;
+    let parent = prefixed
+        .parent()
+        .ok_or_eyre("Failed to get parent directory")?;
+    let entries = match fs::read_dir(parent) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut backups = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let Some(suffix) = file_name
+            .as_encoded_bytes()
+            .strip_prefix(name.as_encoded_bytes())
+        else {
+            continue;
+        };
+        if let Some(key) = backup_suffix(suffix) {
+            backups.push((key, entry.path()));
+        }
+    }
+    backups.sort();
+    Ok(backups.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Parses the suffix [`backup_path`] appends, returning the timestamp and
+/// counter, or zeroes for no suffix.
+///
+/// Only the exact form it writes counts, a timestamp of at least 10 digits
+/// and a counter, both without leading zeroes, so that e.g. the backup of
+/// `file.1` isn't taken for one of `file`.
+fn backup_suffix(suffix: &[u8]) -> Option<(u64, u64)> {
+    if suffix.is_empty() {
+        return Some((0, 0));
+    }
+    let number = |x: &[u8]| {
+        if x.first().is_none_or(|x| *x == b'0') || !x.iter().all(u8::is_ascii_digit) {
+            return None;
+        }
+        str::from_utf8(x).ok()?.parse().ok()
+    };
+    let mut parts = suffix.strip_prefix(b".")?.splitn(2, |x| *x == b'.');
+    let time = parts.next().filter(|x| x.len() >= 10).and_then(number)?;
+    let counter = match parts.next() {
+        Some(counter) => number(counter)?,
+        None => 0,
+    };
+    Some((time, counter))
+}
+
+/// Moves the newest backup [`prefix_move`] made of `path` back into place.
+/// Does nothing if there is no backup, or if `path` exists again. Returns
+/// whether a backup was restored.
+///
+/// The parent directory is resolved according to `resolve`.
+///
+/// # Errors
+///
+/// Returns an error if the backups cannot be listed or the rename fails.
+pub fn restore_prefixed(path: &Path, prefix: &str, resolve: Resolve) -> Result<bool> {
+    if fs::symlink_metadata(path).is_ok() {
+        return Ok(false);
+    }
+    let Some(backup) = backups_of(path, prefix)?.pop() else {
+        return Ok(false);
+    };
+
+    let (dir, name) = sys::Dir::open_parent(path, resolve)?;
+    dir.rename(
+        backup.file_name().ok_or_eyre("Failed to get file name")?,
+        &name,
+    )?;
+    info!("Restoring '{}' -> '{}'", backup.display(), path.display());
+    Ok(true)
+}
+
+/// Returns every entry under `root` whose file name starts with `prefix`,
+/// such as the backups left by [`prefix_move`]. Backups are not descended
+/// into, and neither are symlinks to directories.
+///
+/// # Errors
+///
+/// Returns an error if any directory in the tree cannot be read.
+pub fn find_backups(root: &Path, prefix: &str) -> Result<Vec<PathBuf>> {
+    let mut backups = Vec::new();
+    for entry in fs::read_dir(root)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_name().to_string_lossy().starts_with(prefix) {
+            backups.push(path);
+        } else if entry.file_type()?.is_dir() {
+            backups.extend(find_backups(&path, prefix)?);
+        }
+    }
+    backups.sort();
+    Ok(backups)
+}
+
+/// Deletes `backups`, except for the `keep` most recently modified ones and
+/// any modified less than `older_than` ago.
+///
+/// With `dry_run`, only logs what would be deleted. Returns the path and
+/// error of every failed deletion.
+#[must_use]
+pub fn prune_backups(
+    backups: &[PathBuf],
+    keep: usize,
+    older_than: Option<Duration>,
+    dry_run: bool,
+) -> Vec<(PathBuf, color_eyre::Report)> {
+    let mut failures = Vec::new();
+    let mut backups: Vec<(&PathBuf, Metadata)> = backups
+        .iter()
+        .filter_map(|path| match fs::symlink_metadata(path) {
+            Ok(metadata) => Some((path, metadata)),
+            Err(err) if err.kind() == ErrorKind::NotFound => None,
+            Err(err) => {
+                failures.push((path.clone(), err.into()));
+                None
+            }
+        })
+        .collect();
+    let modified = |metadata: &Metadata| metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+    backups.sort_by_key(|(_, metadata)| core::cmp::Reverse(modified(metadata)));
+
+    let now = SystemTime::now();
+    for (path, metadata) in backups.into_iter().skip(keep) {
+        let age = now.duration_since(modified(&metadata)).unwrap_or_default();
+        if older_than.is_some_and(|min| age < min) {
+            continue;
+        }
+        if dry_run {
+            info!("Would delete backup '{}'", path.display());
+        } else if let Err(err) = delete(path, &metadata, Resolve::Follow) {
+            failures.push((path.clone(), err));
+        }
+    }
+    failures
+}
+
+/// Decodes an extended attribute value the way `setfattr` does: a `0x` prefix
+/// introduces hex, a `0s` prefix base64, and anything else is taken as is.
+///
+/// # Errors
+///
+/// Returns an error if hex or base64 decoding fails.
+pub fn xattr_value(value: &str) -> Result<Vec<u8>> {
+    if let Some(hex) = value.strip_prefix("0x") {
+        if hex.len() % 2 != 0 {
+            return Err(eyre!("Odd number of hex digits in '{value}'"));
+        }
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| {
+                u8::from_str_radix(hex.get(i..i + 2).unwrap_or_default(), 16)
+                    .wrap_err_with(|| format!("Invalid hex in '{value}'"))
+            })
+            .collect()
+    } else if let Some(base64) = value.strip_prefix("0s") {
+        BASE64_STANDARD
+            .decode(base64)
+            .wrap_err_with(|| format!("Invalid base64 in '{value}'"))
+    } else {
+        Ok(value.as_bytes().to_vec())
+    }
+}
+
+/// Runs the shell command of a hook, with `SMFH_HOOK` set to `name` and, for
+/// hooks of a single file, `SMFH_TARGET` set to its target.
+///
+/// # Errors
+///
+/// Returns an error if the command cannot be started or exits unsuccessfully.
+pub fn run_hook(command: &str, target: Option<&Path>, name: &str) -> Result<()> {
+    info!("Running {name} hook '{command}'");
+    let mut shell = sys::shell(command);
+    shell.env("SMFH_HOOK", name);
+    if let Some(target) = target {
+        shell.env("SMFH_TARGET", target);
+    }
+    let status = shell
+        .status()
+        .wrap_err_with(|| format!("Failed to run {name} hook '{command}'"))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(eyre!("{name} hook '{command}' failed with {status}"))
+    }
+}
+
+/// Returns the default lock file for runs on the manifest at `manifest`, in
+/// `$XDG_RUNTIME_DIR` or else the temporary directory, named after a hash of
+/// the manifest's absolute path.
+#[must_use]
+pub fn lock_path(manifest: &Path) -> PathBuf {
+    let absolute = path::absolute(manifest).unwrap_or_else(|_| manifest.to_path_buf());
+    let hash = blake3::hash(absolute.as_os_str().as_encoded_bytes()).to_hex();
+    env::var_os("XDG_RUNTIME_DIR")
+        .map_or_else(env::temp_dir, PathBuf::from)
+        .join(format!("smfh-{}.lock", &hash[..16]))
+}
+
+/// Takes an exclusive advisory lock on the file at `path`, creating it if
+/// needed, and waits for other holders to release it first. The lock is held
+/// until the returned file is dropped.
+///
+/// # Errors
+///
+/// Returns an error if the lock file cannot be opened or locked.
+pub fn lock(path: &Path) -> Result<fs::File> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .wrap_err_with(|| format!("Failed to open lock file '{}'", path.display()))?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(fs::TryLockError::WouldBlock) => {
+            info!("Waiting for lock '{}'", path.display());
+            file.lock()?;
+        }
+        Err(fs::TryLockError::Error(err)) => {
+            return Err(err).wrap_err_with(|| format!("Failed to lock '{}'", path.display()));
+        }
+    }
+    Ok(file)
+}
+
+/// Returns the path [`prefix_move`] would back `path` up to.
+///
+/// # Errors
+///
+/// Returns an error if the path has no filename or parent component.
+pub fn prefixed_path(path: &Path, prefix: &str) -> Result<PathBuf> {
+    let mut appended_path = OsString::from(prefix);
+    appended_path.push(path.file_name().ok_or_eyre(format!(
+        "Failed to get file name of file '{}'",
+        path.display()
+    ))?);
+
+    Ok(path
+        .parent()
+        .ok_or_eyre(format!("Failed to get parent of file '{}'", path.display()))?
+        .join(PathBuf::from(appended_path)))
+}
+
+/// Blocks until `path` exists, checking every 100 milliseconds for up to
+/// `timeout`. For a mountpoint, `path` should be something that only exists
+/// once it is mounted.
+///
+/// # Errors
+///
+/// Returns an error if `path` still doesn't exist after `timeout`.
+pub fn wait_for(path: &Path, timeout: Duration) -> Result<()> {
+    if fs::symlink_metadata(path).is_ok() {
+        return Ok(());
+    }
+    info!("Waiting for '{}'", path.display());
+    let start = Instant::now();
+    while fs::symlink_metadata(path).is_err() {
+        if start.elapsed() >= timeout {
+            return Err(eyre!(
+                "'{}' didn't appear within {}s",
+                path.display(),
+                timeout.as_secs_f32()
+            ));
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    Ok(())
+}
+
+/// Returns the BLAKE3 hash of the file at `filepath` using memory-mapped I/O,
+/// or `None` if hashing fails.
+#[must_use]
+pub fn hash_file(filepath: &Path) -> Option<Hash> {
+    let mut hasher = blake3::Hasher::new();
+
+    if let Err(err) = hasher.update_mmap(filepath) {
+        warn!("Failed to hash file: '{}'\n{:?}", filepath.display(), err);
+        return None;
+    }
+    Some(hasher.finalize())
+}
+
+/// Like [`delete`], but with [`Options::one_file_system`] refuses to delete
+/// a directory that is, or has anything under it that is, on another
+/// filesystem than its parent. Nothing is deleted then.
+///
+/// # Errors
+///
+/// Returns an error if the tree crosses filesystems, cannot be walked, or
+/// removal fails.
+pub fn delete_within(filepath: &Path, metadata: &Metadata, options: &Options) -> Result<()> {
+    if options.one_file_system && metadata.is_dir() {
+        check_one_file_system(filepath, metadata)?;
+    }
+    delete(filepath, metadata, options.resolve())
+}
+
+/// Returns an error if the directory at `path` is a mountpoint or has one
+/// under it.
+fn check_one_file_system(path: &Path, metadata: &Metadata) -> Result<()> {
+    fn walk(dir: &Path, device: &Metadata) -> Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let metadata = fs::symlink_metadata(&path)?;
+            if !metadata.is_dir() {
+                continue;
+            }
+            if !sys::same_device(&metadata, device) {
+                return Err(eyre!(
+                    "'{}' is on another filesystem, refusing to delete it with --one-file-system",
+                    path.display()
+                ));
+            }
+            walk(&path, device)?;
+        }
+        Ok(())
+    }
+
+    if let Some(parent) = path.parent()
+        && !sys::same_device(metadata, &fs::symlink_metadata(parent)?)
+    {
+        return Err(eyre!(
+            "'{}' is a mountpoint, refusing to delete it with --one-file-system",
+            path.display()
+        ));
+    }
+    walk(path, metadata)
+}
+
+/// Removes the file or directory tree at `filepath`, whose parent directory
+/// is resolved according to `resolve`. The tree is walked relative to it,
+/// without following symlinks.
+///
+/// # Errors
+///
+/// Returns an error if the parent directory can't be opened or filesystem
+/// removal fails.
+pub fn delete(filepath: &Path, metadata: &Metadata, resolve: Resolve) -> Result<()> {
+    let (dir, name) = sys::Dir::open_parent(filepath, resolve)?;
+    if metadata.is_dir() {
+        dir.remove_dir_all(&name)?;
+    } else {
+        dir.remove_file(&name)?;
+    }
+    info!("Deleted '{}'", filepath.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        checksum::Algorithm,
+        confirm::Confirm,
+        manifest::FileKind,
+    };
+    use std::sync::{
+        Arc,
+        Mutex,
+    };
+
+    fn fwm(kind: FileKind, target: PathBuf, source: Option<PathBuf>) -> FileWithMetadata {
+        FileWithMetadata {
+            source,
+            target,
+            kind,
+            clobber: None,
+            permissions: None,
+            uid: None,
+            gid: None,
+            owner: None,
+            group: None,
+            deactivate: None,
+            follow_symlinks: None,
+            ignore_modification: None,
+            copy_fallback: None,
+            text: None,
+            content_base64: None,
+            restore_backup: None,
+            xattrs: BTreeMap::new(),
+            on_activate: None,
+            on_deactivate: None,
+            reflink: None,
+            mtime: None,
+            decrypt: None,
+            source_hash: None,
+            target_hash: None,
+            wait_for: None,
+            parents: None,
+            major: None,
+            minor: None,
+            immutable: None,
+            as_owner: None,
+            if_missing_source: None,
+            source_digest: None,
+            resolve: Resolve::Follow,
+            fsync: false,
+            memo: Memo::default(),
+            rendered: None,
+            loaded: false,
+            metadata: None,
+        }
+    }
+
+    #[test]
+    fn check_no_metadata_delete_returns_true() {
+        assert!(
+            fwm(FileKind::Delete, PathBuf::from("/x"), None)
+                .check()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn check_no_metadata_non_delete_returns_false() {
+        assert!(
+            !fwm(FileKind::Copy, PathBuf::from("/x"), None)
+                .check()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn check_metadata_present_delete_returns_false() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("f");
+        fs::write(&path, b"").unwrap();
+        let mut f = fwm(FileKind::Delete, path, None);
+        f.set_metadata().unwrap();
+        assert!(!f.check().unwrap());
+    }
+
+    #[test]
+    fn state_missing_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut f = fwm(FileKind::Directory, dir.path().join("d"), None);
+        f.set_metadata().unwrap();
+        assert_eq!(f.state().unwrap(), FileState::Missing);
+    }
+
+    #[test]
+    fn state_wrong_kind() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("f");
+        fs::write(&path, b"").unwrap();
+        let mut f = fwm(FileKind::Directory, path, None);
+        f.set_metadata().unwrap();
+        assert_eq!(f.state().unwrap(), FileState::WrongKind);
+    }
+
+    #[test]
+    fn state_copy_modified_and_correct() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let target = dir.path().join("target");
+        fs::write(&source, b"abc").unwrap();
+        fs::write(&target, b"abd").unwrap();
+        let mut f = fwm(FileKind::Copy, target.clone(), Some(source));
+        f.set_metadata().unwrap();
+        assert_eq!(f.state().unwrap(), FileState::Modified);
+
+        fs::write(&target, b"abc").unwrap();
+        f.set_metadata().unwrap();
+        assert_eq!(f.state().unwrap(), FileState::Correct);
+    }
+
+    #[test]
+    fn recursive_copy_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("nested")).unwrap();
+        fs::write(source.join("a"), b"a").unwrap();
+        fs::write(source.join("nested/b"), b"b").unwrap();
+
+        let target = dir.path().join("target");
+        let mut f = fwm(FileKind::RecursiveCopy, target.clone(), Some(source));
+        f.permissions = Some(0o640);
+        f.recursive_copy().unwrap();
+        assert_eq!(f.state().unwrap(), FileState::Correct);
+        assert_eq!(
+            sys::mode(&fs::metadata(target.join("nested")).unwrap()),
+            0o750
+        );
+
+        fs::write(target.join("nested/extra"), b"").unwrap();
+        assert_eq!(f.state().unwrap(), FileState::Modified);
+    }
+
+    #[test]
+    fn link_farm_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("nested")).unwrap();
+        fs::write(source.join("a"), b"a").unwrap();
+        fs::write(source.join("nested/b"), b"b").unwrap();
+
+        let target = dir.path().join("target");
+        fs::create_dir_all(&target).unwrap();
+        fs::write(target.join("sibling"), b"").unwrap();
+
+        let mut f = fwm(FileKind::LinkFarm, target.clone(), Some(source));
+        let options = Options {
+            prefix: String::from(".bak-"),
+            ..Options::default()
+        };
+        f.link_farm(false, &options).unwrap();
+        assert!(target.join("nested/b").is_symlink());
+        assert_eq!(f.state().unwrap(), FileState::Correct);
+
+        f.deactivate(&Options::default()).unwrap();
+        assert!(!target.join("nested").exists());
+        assert!(target.join("sibling").exists());
+    }
+
+    #[test]
+    fn hardlink_shares_inode() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        fs::write(&source, b"a").unwrap();
+        let target = dir.path().join("target");
+
+        let mut f = fwm(FileKind::Hardlink, target.clone(), Some(source));
+        f.hardlink().unwrap();
+        assert_eq!(f.state().unwrap(), FileState::Correct);
+
+        fs::remove_file(&target).unwrap();
+        fs::write(&target, b"a").unwrap();
+        f.set_metadata().unwrap();
+        assert_eq!(f.state().unwrap(), FileState::Modified);
+        f.copy_fallback = Some(true);
+        assert_eq!(f.state().unwrap(), FileState::Correct);
+
+        // Swapped for a link to the same inode, which leaves nothing behind
+        f.copy_fallback = None;
+        for _ in 0..3 {
+            f.set_metadata().unwrap();
+            assert!(f.atomic_activate().unwrap());
+        }
+        assert_eq!(f.state().unwrap(), FileState::Correct);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn copy_writes_decrypted_content_privately() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source.age");
+        let target = dir.path().join("target");
+        fs::write(&source, b"ciphertext").unwrap();
+        let mut f = fwm(FileKind::Copy, target.clone(), Some(source));
+        f.decrypt = Some(Decrypt::Age);
+
+        // Not decrypted until loaded, which a dry run doesn't do
+        f.load(&Options {
+            dry_run: true,
+            ..Options::default()
+        });
+        assert!(!f.loaded);
+        assert!(!f.check_source());
+        f.loaded = true;
+        assert!(f.check_source());
+
+        f.rendered = Some(b"plaintext".to_vec());
+        f.permissions = Some(0o600);
+        assert!(!f.check_source());
+        f.copy().unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"plaintext");
+        assert_eq!(sys::mode(&fs::metadata(&target).unwrap()), 0o600);
+        assert_eq!(f.state().unwrap(), FileState::Correct);
+    }
+
+    #[test]
+    fn hashes_guard_sources_and_define_unmodified() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let target = dir.path().join("target");
+        fs::write(&source, b"managed").unwrap();
+        let mut f = fwm(FileKind::Copy, target.clone(), Some(source));
+        f.source_hash = Some(Checksum::of(Algorithm::Sha256, b"other"));
+        assert!(f.activate(None, &Options::default()).is_err());
+        assert!(!target.exists());
+        assert!(f.state().is_err());
+
+        f.source_hash = Some(Checksum::of(Algorithm::Blake3, b"managed"));
+        f.target_hash = Some(Checksum::of(Algorithm::Blake3, b"managed"));
+        f.activate(None, &Options::default()).unwrap();
+        assert_eq!(f.state().unwrap(), FileState::Correct);
+
+        // A target may differ from its source, as long as it is as expected
+        f.target_hash = Some(Checksum::of(Algorithm::Blake3, b"edited"));
+        assert_eq!(f.state().unwrap(), FileState::Modified);
+        fs::write(&target, b"edited").unwrap();
+        assert_eq!(f.state().unwrap(), FileState::Correct);
+
+        // With the source gone, its checksum tells whether the target is ours
+        fs::write(&target, b"managed").unwrap();
+        f.target_hash = None;
+        fs::remove_file(f.source.as_ref().unwrap()).unwrap();
+        assert_eq!(f.state().unwrap(), FileState::Correct);
+        f.deactivate(&Options::default()).unwrap();
+        assert!(!target.exists());
+    }
+
+    #[test]
+    fn diff_contents_of_replaced_copies() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let target = dir.path().join("target");
+        fs::write(&source, b"new\n").unwrap();
+        let mut f = fwm(FileKind::Copy, target.clone(), Some(source.clone()));
+        assert_eq!(f.diff_contents().unwrap().unwrap(), "");
+
+        fs::write(&target, b"old\n").unwrap();
+        assert_eq!(
+            f.diff_contents().unwrap().unwrap(),
+            format!(
+                "--- {}\n+++ {}\n@@ -1 +1 @@\n-old\n+new\n",
+                target.display(),
+                source.display()
+            )
+        );
+        f.text = Some(String::from("\0"));
+        assert!(f.diff_contents().unwrap().is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn delete_removes_trees_without_following_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = dir.path().join("outside");
+        fs::create_dir(&outside).unwrap();
+        fs::write(outside.join("keep"), "x").unwrap();
+        let tree = dir.path().join("tree");
+        fs::create_dir_all(tree.join("sub/deeper")).unwrap();
+        fs::write(tree.join("sub/deeper/file"), "x").unwrap();
+        sys::symlink(&outside, &tree.join("sub/link")).unwrap();
+
+        delete(
+            &tree,
+            &fs::symlink_metadata(&tree).unwrap(),
+            Resolve::Follow,
+        )
+        .unwrap();
+        assert!(fs::symlink_metadata(&tree).is_err());
+        assert_eq!(fs::read(outside.join("keep")).unwrap(), b"x");
+    }
+
+    #[test]
+    fn delete_backs_up_edited_targets() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("target");
+        fs::write(&target, b"edited").unwrap();
+        let options = Options::default();
+        let mut f = fwm(FileKind::Delete, target.clone(), None);
+        f.target_hash = Some(Checksum::of(Algorithm::Blake3, b"installed"));
+        f.activate(None, &options).unwrap();
+        assert!(!target.exists());
+        assert_eq!(
+            fs::read(dir.path().join(".backup-target")).unwrap(),
+            b"edited"
+        );
+        assert_eq!(options.report.entries()[0].action, Action::BackedUp);
+
+        fs::write(&target, b"installed").unwrap();
+        f.activate(None, &options).unwrap();
+        assert!(!target.exists());
+        assert_eq!(options.report.entries()[1].action, Action::Deleted);
+    }
+
+    #[test]
+    fn copy_inline_text() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("target");
+        let mut f = fwm(FileKind::Copy, target.clone(), None);
+        f.text = Some(String::from("hello"));
+        assert!(!f.check_source());
+
+        f.copy().unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "hello");
+        assert_eq!(f.state().unwrap(), FileState::Correct);
+
+        f.text = Some(String::from("hullo"));
+        assert_eq!(f.state().unwrap(), FileState::Modified);
+    }
+
+    #[test]
+    fn copy_file_honors_reflink() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        fs::write(&source, "hello").unwrap();
+        sys::set_mode(&source, 0o640).unwrap();
+
+        for reflink in [Reflink::Auto, Reflink::Never] {
+            let target = dir.path().join(format!("{reflink:?}"));
+            copy_file(&source, &target, reflink).unwrap();
+            assert_eq!(fs::read_to_string(&target).unwrap(), "hello");
+            assert!(sys::mode_matches(&fs::metadata(&target).unwrap(), 0o640));
+        }
+
+        // Whether cloning works depends on the filesystem, but a failed clone
+        // must not leave a target behind
+        let target = dir.path().join("always");
+        if copy_file(&source, &target, Reflink::Always).is_ok() {
+            assert_eq!(fs::read_to_string(&target).unwrap(), "hello");
+        } else {
+            assert!(!target.exists());
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn copy_file_keeps_holes() {
+        use std::os::unix::fs::FileExt as _;
+
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let file = fs::File::create(&source).unwrap();
+        file.set_len(16 << 20).unwrap();
+        file.write_all_at(b"data", 8 << 20).unwrap();
+        drop(file);
+
+        let target = dir.path().join("target");
+        copy_file(&source, &target, Reflink::Never).unwrap();
+        assert_eq!(fs::read(&target).unwrap(), fs::read(&source).unwrap());
+        assert!(sys::is_sparse(&fs::metadata(&target).unwrap()));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn copy_file_falls_back_to_buffered_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("target");
+        // Reported as empty, so only a buffered copy gets the contents
+        copy_file(Path::new("/proc/self/cmdline"), &target, Reflink::Never).unwrap();
+        assert!(!fs::read(&target).unwrap().is_empty());
+    }
+
+    #[test]
+    fn copy_renames_into_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        fs::write(&source, "new").unwrap();
+        let target = dir.path().join("target");
+        fs::write(&target, "old").unwrap();
+        let other = dir.path().join("other");
+        fs::hard_link(&target, &other).unwrap();
+
+        let mut f = fwm(FileKind::Copy, target.clone(), Some(source));
+        f.copy().unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "new");
+        // Written to a new inode rather than through the old one
+        assert_eq!(fs::read_to_string(&other).unwrap(), "old");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn copy_refuses_symlink_source_without_follow_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let secret = dir.path().join("secret");
+        fs::write(&secret, "hunter2").unwrap();
+        let source = dir.path().join("source");
+        sys::symlink(&secret, &source).unwrap();
+
+        let target = dir.path().join("target");
+        let mut f = fwm(FileKind::Copy, target.clone(), Some(source));
+        f.follow_symlinks = Some(false);
+        assert!(f.copy().is_err());
+        assert!(!target.exists());
+
+        f.follow_symlinks = None;
+        f.copy().unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "hunter2");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn chmod_chown_does_not_follow_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let other = dir.path().join("other");
+        fs::write(&other, "").unwrap();
+        sys::set_mode(&other, 0o644).unwrap();
+        let target = dir.path().join("target");
+        sys::symlink(&other, &target).unwrap();
+
+        let mut f = fwm(FileKind::Modify, target, None);
+        f.permissions = Some(0o600);
+        assert!(f.chmod_chown().is_err());
+        assert_eq!(sys::mode(&fs::metadata(&other).unwrap()), 0o644);
+
+        f.target = other.clone();
+        f.chmod_chown().unwrap();
+        assert_eq!(sys::mode(&fs::metadata(&other).unwrap()), 0o600);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn modify_changes_symlinks_or_what_they_point_to() {
+        let dir = tempfile::tempdir().unwrap();
+        let other = dir.path().join("other");
+        fs::write(&other, "").unwrap();
+        sys::set_mode(&other, 0o644).unwrap();
+        let target = dir.path().join("target");
+        sys::symlink(&other, &target).unwrap();
+        let mtime = |metadata: Metadata| metadata.modified().unwrap();
+        let before = mtime(fs::metadata(&other).unwrap());
 
-        let source = fs::canonicalize(self.source.as_ref().unwrap())?;
+        let mut f = fwm(FileKind::Modify, target.clone(), None);
+        f.mtime = Some(Mtime::Unix(1_000_000_000));
+        f.activate(None, &Options::default()).unwrap();
+        let expected = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        assert_eq!(mtime(fs::symlink_metadata(&target).unwrap()), expected);
+        assert_eq!(mtime(fs::metadata(&other).unwrap()), before);
+        assert!(f.check().unwrap());
 
-        fs::copy(&source, &self.target)?;
-        info!(
-            "Copied '{}' -> '{}'",
-            source.display(),
-            &self.target.display(),
+        f.mtime = None;
+        f.permissions = Some(0o600);
+        f.follow_symlinks = Some(true);
+        f.activate(None, &Options::default()).unwrap();
+        assert_eq!(sys::mode(&fs::metadata(&other).unwrap()), 0o600);
+        assert!(fs::symlink_metadata(&target).unwrap().is_symlink());
+        assert!(f.check().unwrap());
+    }
+
+    #[test]
+    fn symlink_loops_are_backed_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        fs::write(&source, "").unwrap();
+        let target = dir.path().join("target");
+        let other = dir.path().join("other");
+        sys::symlink(Path::new("./other"), &target).unwrap();
+        sys::symlink(&target, &other).unwrap();
+
+        let mut f = fwm(FileKind::Symlink, target.clone(), Some(source.clone()));
+        f.set_metadata().unwrap();
+        assert_eq!(f.state().unwrap(), FileState::Modified);
+        f.activate(None, &Options::default()).unwrap();
+        assert_eq!(
+            fs::canonicalize(&target).unwrap(),
+            fs::canonicalize(&source).unwrap()
         );
 
-        self.set_metadata()?;
-        self.chmod_chown()?;
-        Ok(())
+        // Ending at the source, but too far down
+        let mut link = source;
+        for i in 0..=MAX_SYMLINK_DEPTH {
+            let next = dir.path().join(format!("link{i}"));
+            sys::symlink(&link, &next).unwrap();
+            link = next;
+        }
+        assert!(matches!(follow_chain(&link).unwrap(), Chain::TooDeep));
+        let chain = dir.path().join(format!("link{}", MAX_SYMLINK_DEPTH - 1));
+        assert!(matches!(follow_chain(&chain).unwrap(), Chain::Ends(_)));
     }
 
-    /// Creates [`target`][Self::target] as a directory, then applies
-    /// permissions and ownership.
-    ///
-    /// # Errors
-    ///
-    /// Returns an error if:
-    /// - directory creation fails
-    /// - permission or ownership changes fail
-    pub fn directory(&mut self) -> Result<()> {
-        mkdir(&self.target)?;
-        self.set_metadata()?;
-        self.chmod_chown()?;
-        Ok(())
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn mknod_creates_special_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut fifo = fwm(FileKind::Fifo, dir.path().join("fifo"), None);
+        fifo.permissions = Some(0o600);
+        fifo.activate(None, &Options::default()).unwrap();
+        let metadata = fs::symlink_metadata(&fifo.target).unwrap();
+        assert_eq!(sys::node(&metadata), Some(sys::Node::Fifo));
+        assert_eq!(sys::mode(&metadata), 0o600);
+        assert!(fifo.check().unwrap());
+
+        let mut socket = fwm(FileKind::Socket, dir.path().join("socket"), None);
+        socket.activate(None, &Options::default()).unwrap();
+        assert!(socket.check().unwrap());
+
+        // A fifo isn't a socket, and a device needs its numbers
+        socket.target = fifo.target.clone();
+        socket.set_metadata().unwrap();
+        assert!(!socket.check().unwrap());
+        let mut device = fwm(FileKind::CharDevice, dir.path().join("null"), None);
+        assert!(device.activate(None, &Options::default()).is_err());
+
+        fifo.deactivate(&Options::default()).unwrap();
+        assert!(fs::symlink_metadata(&fifo.target).is_err());
     }
-}
 
-/// Creates `path` as a directory, including any missing parent directories.
-/// No-op if the directory already exists.
-///
-/// # Errors
-///
-/// Returns an error if:
-/// - the path exists but is not a directory
-/// - directory creation fails
-pub fn mkdir(path: &Path) -> Result<()> {
-    match fs::symlink_metadata(path) {
-        Err(_) => {
-            fs::create_dir_all(path)?;
-            info!("Created directory '{}'", path.display());
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn immutable_targets_are_unlocked_to_change() {
+        let dir = tempfile::tempdir().unwrap();
+        // Needs CAP_LINUX_IMMUTABLE and a filesystem with the flag
+        let probe = dir.path().join("probe");
+        fs::write(&probe, "").unwrap();
+        if sys::set_immutable(&probe, true).is_err() {
+            return;
         }
-        Ok(x) => {
-            if !x.is_dir() {
-                return Err(eyre!("File in way of '{}'", path.display()));
-            }
-            info!("Directory '{}' already exists", path.display());
+        sys::set_immutable(&probe, false).unwrap();
+
+        let target = dir.path().join("target");
+        let mut f = fwm(FileKind::Copy, target.clone(), None);
+        f.text = Some(String::from("one"));
+        f.immutable = Some(true);
+        f.activate(None, &Options::default()).unwrap();
+        assert!(sys::immutable(&target).unwrap());
+        assert!(fs::write(&target, "two").is_err());
+
+        sys::set_immutable(&target, false).unwrap();
+        f.set_metadata().unwrap();
+        assert_eq!(f.state().unwrap(), FileState::WrongFlags);
+        f.activate(None, &Options::default()).unwrap();
+        assert!(f.check().unwrap());
+
+        // A stash directory that can't be created fails the swap
+        fs::write(dir.path().join("journal.d"), "").unwrap();
+        let options = Options {
+            journal: Some(crate::journal::Journal::open(&dir.path().join("journal")).unwrap()),
+            ..Options::default()
+        };
+        f.text = Some(String::from("two"));
+        assert!(f.activate(Some(true), &options).is_err());
+        assert!(sys::immutable(&target).unwrap());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "one");
+
+        f.activate(Some(true), &Options::default()).unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "two");
+        assert!(sys::immutable(&target).unwrap());
+
+        f.deactivate(&Options::default()).unwrap();
+        assert!(!target.exists());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn as_owner_creates_files_as_the_owner() {
+        if !sys::is_root() {
+            return;
         }
+        let dir = tempfile::tempdir().unwrap();
+        sys::set_mode(dir.path(), 0o755).unwrap();
+        let shared = dir.path().join("shared");
+        fs::create_dir(&shared).unwrap();
+        sys::set_mode(&shared, 0o777).unwrap();
+
+        let mut f = fwm(FileKind::Copy, shared.join("file"), None);
+        f.text = Some(String::from("nobody's"));
+        f.uid = Some(65534);
+        f.gid = Some(65534);
+        f.as_owner = Some(true);
+        f.activate(None, &Options::default()).unwrap();
+        let metadata = fs::metadata(&f.target).unwrap();
+        assert_eq!(sys::owner(&metadata), Some((65534, 65534)));
+
+        // Root may write here, but the owner may not
+        f.target = dir.path().join("file");
+        assert!(f.activate(None, &Options::default()).is_err());
+        assert!(!f.target.exists());
     }
-    Ok(())
-}
 
-/// Renames the file at `path` to a prefixed name in the same parent directory,
-/// backing it up. No-op if the path does not exist.
-///
-/// # Errors
-///
-/// Returns an error if:
-/// - the path has no filename or parent component
-/// - an existing file at the destination cannot be deleted
-/// - the rename fails
-pub fn prefix_move(path: &Path, prefix: &str) -> Result<()> {
-    let Ok(_) = fs::symlink_metadata(path) else {
-        return Ok(());
-    };
+    #[test]
+    fn touch_keeps_what_was_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("daemon.pid");
+        let mut f = fwm(FileKind::Touch, target.clone(), None);
+        f.permissions = Some(0o640);
+        f.activate(None, &Options::default()).unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"");
+        assert!(f.check().unwrap());
 
-    let mut appended_path = OsString::from(prefix);
-    appended_path.push(path.file_name().ok_or_eyre(format!(
-        "Failed to get file name of file '{}'",
-        path.display()
-    ))?);
+        fs::write(&target, "42").unwrap();
+        sys::set_mode(&target, 0o644).unwrap();
+        f.set_metadata().unwrap();
+        assert_eq!(f.state().unwrap(), FileState::WrongPermissions);
+        f.activate(None, &Options::default()).unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "42");
+        assert!(f.check().unwrap());
 
-    let new_path = path
-        .parent()
-        .ok_or_eyre(format!("Failed to get parent of file '{}'", path.display()))?
-        .join(PathBuf::from(appended_path));
+        f.deactivate(&Options::default()).unwrap();
+        assert!(target.exists());
+        fs::write(&target, "").unwrap();
+        f.deactivate(&Options::default()).unwrap();
+        assert!(!target.exists());
+    }
 
-    if let Ok(metadata) = fs::symlink_metadata(&new_path) {
-        delete(&new_path, &metadata)?;
+    #[test]
+    fn missing_source_creates_empty_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let target = dir.path().join("log");
+        let mut f = fwm(FileKind::Copy, target.clone(), Some(source.clone()));
+        f.if_missing_source = Some(IfMissingSource::CreateEmpty);
+        f.permissions = Some(0o600);
+        f.activate(None, &Options::default()).unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"");
+        assert!(sys::mode_matches(&fs::metadata(&target).unwrap(), 0o600));
+        assert!(f.check().unwrap());
+
+        // Written to meanwhile, so neither replaced nor removed, and only
+        // fixed with clobber
+        fs::write(&target, "line").unwrap();
+        sys::set_mode(&target, 0o644).unwrap();
+        f.activate(None, &Options::default()).unwrap();
+        assert!(sys::mode_matches(&fs::metadata(&target).unwrap(), 0o644));
+        f.activate(Some(true), &Options::default()).unwrap();
+        assert!(sys::mode_matches(&fs::metadata(&target).unwrap(), 0o600));
+        f.deactivate(&Options::default()).unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "line");
+
+        fs::write(&source, "real").unwrap();
+        f.activate(Some(true), &Options::default()).unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "real");
     }
 
-    fs::rename(path, &new_path)?;
-    info!("Renaming '{}' -> '{}'", path.display(), new_path.display());
-    Ok(())
-}
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn no_symlinks_refuses_symlinked_parents() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("real")).unwrap();
+        sys::symlink(&dir.path().join("real"), &dir.path().join("link")).unwrap();
 
-/// Returns the BLAKE3 hash of the file at `filepath` using memory-mapped I/O,
-/// or `None` if hashing fails.
-#[must_use]
-pub fn hash_file(filepath: &Path) -> Option<Hash> {
-    let mut hasher = blake3::Hasher::new();
+        let mut f = fwm(
+            FileKind::Symlink,
+            dir.path().join("link/target"),
+            Some(dir.path().to_path_buf()),
+        );
+        f.resolve = Resolve::NoSymlinks;
+        match f.symlink() {
+            // Kernels before 5.6
+            Err(err) if format!("{err:?}").contains("openat2") => return,
+            result => assert!(result.is_err()),
+        }
+        assert!(!dir.path().join("real/target").exists());
 
-    if let Err(err) = hasher.update_mmap(filepath) {
-        warn!("Failed to hash file: '{}'\n{:?}", filepath.display(), err);
-        return None;
+        f.target = dir.path().join("real/target");
+        f.symlink().unwrap();
+        assert!(dir.path().join("link/target").exists());
     }
-    Some(hasher.finalize())
-}
 
-/// Removes the file or directory tree at `filepath`.
-///
-/// # Errors
-///
-/// Returns an error if filesystem removal fails.
-pub fn delete(filepath: &Path, metadata: &Metadata) -> Result<()> {
-    if metadata.is_dir() {
-        fs::remove_dir_all(filepath)?;
-    } else {
-        fs::remove_file(filepath)?;
+    #[test]
+    fn copy_stages_anonymously() {
+        let dir = tempfile::tempdir().unwrap();
+        let parent = sys::Dir::open(dir.path(), Resolve::Follow).unwrap();
+        let mut file = match parent.create_anonymous(0o600) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::Unsupported => return,
+            Err(err) => panic!("{err:?}"),
+        };
+        file.write_all(b"partial").unwrap();
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+        parent.link_anonymous(&file, OsStr::new("done")).unwrap();
+        assert_eq!(fs::read(dir.path().join("done")).unwrap(), b"partial");
+
+        let target = dir.path().join("done");
+        let mut f = fwm(FileKind::Copy, target.clone(), None);
+        f.text = Some(String::from("hello"));
+        f.copy().unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"hello");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
     }
-    info!("Deleted '{}'", filepath.display());
-    Ok(())
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::manifest::FileKind;
+    #[test]
+    fn fsync_flushes_trees() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("sub/file"), b"data").unwrap();
 
-    fn fwm(kind: FileKind, target: PathBuf, source: Option<PathBuf>) -> FileWithMetadata {
-        FileWithMetadata {
-            source,
-            target,
-            kind,
-            clobber: None,
-            permissions: None,
-            uid: None,
-            gid: None,
-            deactivate: None,
-            follow_symlinks: None,
-            ignore_modification: None,
-            metadata: None,
-        }
+        let target = dir.path().join("target");
+        let mut f = fwm(FileKind::RecursiveCopy, target.clone(), Some(source));
+        f.fsync = true;
+        f.activate(None, &Options::default()).unwrap();
+        assert_eq!(fs::read(target.join("sub/file")).unwrap(), b"data");
+
+        let mut f = fwm(FileKind::Copy, dir.path().join("copy"), None);
+        f.fsync = true;
+        f.text = Some(String::from("hello"));
+        f.copy().unwrap();
+        f.sync().unwrap();
+        assert_eq!(fs::read(dir.path().join("copy")).unwrap(), b"hello");
     }
 
     #[test]
-    fn check_no_metadata_delete_returns_true() {
-        assert!(
-            fwm(FileKind::Delete, PathBuf::from("/x"), None)
-                .check()
-                .unwrap()
-        );
+    fn copy_sets_mtime() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        fs::write(&source, "hello").unwrap();
+        sys::set_mtime(&source, UNIX_EPOCH + Duration::from_secs(1_000_000_000)).unwrap();
+
+        let target = dir.path().join("target");
+        let mut f = fwm(FileKind::Copy, target.clone(), Some(source));
+        f.mtime = serde_json::from_str("\"source\"").unwrap();
+        f.copy().unwrap();
+        let modified = fs::metadata(&target).unwrap().modified().unwrap();
+        assert_eq!(modified, UNIX_EPOCH + Duration::from_secs(1_000_000_000));
+        assert_eq!(f.state().unwrap(), FileState::Correct);
+
+        f.mtime = serde_json::from_str("-86400").unwrap();
+        assert_eq!(f.state().unwrap(), FileState::WrongMtime);
+        f.chmod_chown().unwrap();
+        assert_eq!(f.state().unwrap(), FileState::Correct);
     }
 
+    #[cfg(target_os = "linux")]
     #[test]
-    fn check_no_metadata_non_delete_returns_false() {
-        assert!(
-            !fwm(FileKind::Copy, PathBuf::from("/x"), None)
-                .check()
-                .unwrap()
+    fn set_metadata_resolves_owner_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut f = fwm(FileKind::Directory, dir.path().to_path_buf(), None);
+        f.owner = Some(String::from("root"));
+        f.group = Some(String::from("root"));
+        f.set_metadata().unwrap();
+        assert_eq!((f.uid, f.gid), (Some(0), Some(0)));
+        assert!(f.owner.is_none() && f.group.is_none());
+
+        f.owner = Some(String::from("smfh-no-such-user"));
+        let err = f.set_metadata().unwrap_err();
+        assert_eq!(err.to_string(), "No such user 'smfh-no-such-user'");
+    }
+
+    #[test]
+    fn render_template_substitutes_known_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("template");
+        fs::write(&source, "@greeting@, @name@! mail@example.com @@").unwrap();
+        let variables = BTreeMap::from([
+            (String::from("greeting"), String::from("Hello")),
+            (String::from("name"), String::from("@greeting@")),
+        ]);
+        assert_eq!(
+            render_template(&source, &variables).unwrap(),
+            b"Hello, @greeting@! mail@example.com @@"
         );
     }
 
     #[test]
-    fn check_metadata_present_delete_returns_false() {
+    fn template_state() {
         let dir = tempfile::tempdir().unwrap();
-        let path = dir.path().join("f");
-        fs::write(&path, b"").unwrap();
-        let mut f = fwm(FileKind::Delete, path, None);
-        f.set_metadata().unwrap();
-        assert!(!f.check().unwrap());
+        let source = dir.path().join("template");
+        let target = dir.path().join("target");
+        fs::write(&source, "@x@").unwrap();
+        let mut f = fwm(FileKind::Template, target.clone(), Some(source.clone()));
+        assert!(f.check_source());
+
+        let variables = BTreeMap::from([(String::from("x"), String::from("1"))]);
+        f.rendered = Some(render_template(&source, &variables).unwrap());
+        assert!(!f.check_source());
+
+        f.copy().unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "1");
+        assert_eq!(f.state().unwrap(), FileState::Correct);
+
+        f.rendered = Some(b"2".to_vec());
+        assert_eq!(f.state().unwrap(), FileState::Modified);
     }
 
     #[test]
@@ -762,14 +4159,287 @@ mod tests {
         let dir = tempfile::tempdir().unwrap();
         let path = dir.path().join("file");
         fs::write(&path, b"").unwrap();
-        prefix_move(&path, ".bak-").unwrap();
+        prefix_move(&path, ".bak-", false, Resolve::Follow).unwrap();
         assert!(!path.exists());
         assert!(dir.path().join(".bak-file").exists());
     }
 
+    #[test]
+    #[cfg(unix)]
+    fn parents_apply_to_created_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let existing = dir.path().join("existing");
+        fs::create_dir(&existing).unwrap();
+        sys::set_mode(&existing, 0o755).unwrap();
+        let target = existing.join("a/b/file");
+        let mut f = fwm(FileKind::Copy, target.clone(), None);
+        f.text = Some(String::from("x"));
+        f.parents = Some(manifest::Parents {
+            mode: Some(0o700),
+            ..manifest::Parents::default()
+        });
+        f.activate(None, &Options::default()).unwrap();
+
+        let mode = |path: &Path| sys::mode(&fs::metadata(path).unwrap()) & 0o777;
+        assert_eq!(mode(&existing.join("a")), 0o700);
+        assert_eq!(mode(&existing.join("a/b")), 0o700);
+        assert_eq!(mode(&existing), 0o755);
+        assert_eq!(fs::read(&target).unwrap(), b"x");
+    }
+
+    #[test]
+    fn wait_for_times_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mount/home");
+        let mut f = fwm(FileKind::Directory, dir.path().join("target"), None);
+        f.wait_for = Some(path.clone());
+        let options = Options {
+            wait_timeout: Duration::from_millis(200),
+            ..Options::default()
+        };
+        assert!(f.activate(None, &options).is_err());
+        assert!(!f.target.exists());
+
+        let waiter = thread::spawn(move || wait_for(&path, Duration::from_secs(10)));
+        thread::sleep(Duration::from_millis(150));
+        fs::create_dir_all(dir.path().join("mount/home")).unwrap();
+        waiter.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn timestamped_backups_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        for contents in [b"first", b"other"] {
+            fs::write(&path, contents).unwrap();
+            prefix_move(&path, ".bak-", true, Resolve::Follow)
+                .unwrap()
+                .unwrap();
+        }
+        fs::write(dir.path().join(".bak-file"), b"plain").unwrap();
+        for unrelated in [".bak-file.old", ".bak-file.1", ".bak-file.1760000000.01"] {
+            fs::write(dir.path().join(unrelated), b"unrelated").unwrap();
+        }
+
+        let backups = backups_of(&path, ".bak-").unwrap();
+        assert_eq!(backups.len(), 3);
+        assert_eq!(fs::read(&backups[0]).unwrap(), b"plain");
+        assert_eq!(fs::read(&backups[1]).unwrap(), b"first");
+
+        assert!(restore_prefixed(&path, ".bak-", Resolve::Follow).unwrap());
+        assert_eq!(fs::read(&path).unwrap(), b"other");
+        assert_eq!(backups_of(&path, ".bak-").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn deactivate_restores_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let target = dir.path().join("target");
+        fs::write(&source, b"managed").unwrap();
+        fs::write(&target, b"original").unwrap();
+        prefix_move(&target, ".bak-", false, Resolve::Follow).unwrap();
+
+        let mut f = fwm(FileKind::Symlink, target.clone(), Some(source));
+        f.symlink().unwrap();
+        let options = Options {
+            prefix: String::from(".bak-"),
+            restore_backups: true,
+            ..Options::default()
+        };
+        f.deactivate(&options).unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"original");
+        assert!(!dir.path().join(".bak-target").exists());
+    }
+
+    #[test]
+    fn prune_backups_keeps_newest() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        for name in ["a", ".bak-b", "sub/.bak-c", "sub/d"] {
+            fs::write(dir.path().join(name), b"").unwrap();
+        }
+        let backups = find_backups(dir.path(), ".bak-").unwrap();
+        assert_eq!(
+            backups,
+            vec![dir.path().join(".bak-b"), dir.path().join("sub/.bak-c")]
+        );
+
+        assert!(prune_backups(&backups, 0, Some(Duration::from_hours(1)), false).is_empty());
+        assert!(backups.iter().all(|x| x.exists()));
+        assert!(prune_backups(&backups, 1, None, false).is_empty());
+        assert_eq!(backups.iter().filter(|x| x.exists()).count(), 1);
+    }
+
+    #[test]
+    fn activate_records_actions() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("target");
+        fs::write(&target, b"in the way").unwrap();
+        let mut f = fwm(FileKind::Copy, target, None);
+        f.text = Some(String::from("hello"));
+        let options = Options::default();
+        f.activate(None, &options).unwrap();
+        f.activate(None, &options).unwrap();
+
+        let actions: Vec<_> = options.report.entries().iter().map(|x| x.action).collect();
+        assert_eq!(actions, vec![Action::BackedUp, Action::Skipped]);
+    }
+
+    #[test]
+    fn clobber_journals_only_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("target");
+        fs::write(&target, b"in the way").unwrap();
+        let path = dir.path().join("journal");
+        let options = Options {
+            journal: Some(crate::journal::Journal::open(&path).unwrap()),
+            ..Options::default()
+        };
+        let mut f = fwm(FileKind::Copy, target, None);
+        f.text = Some(String::from("hello"));
+        f.activate(Some(true), &options).unwrap();
+        f.activate(Some(true), &options).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+    }
+
+    #[test]
+    fn lock_is_exclusive() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lock");
+        let held = lock(&path).unwrap();
+        let other = fs::File::open(&path).unwrap();
+        assert!(matches!(
+            other.try_lock(),
+            Err(fs::TryLockError::WouldBlock)
+        ));
+        drop(held);
+        other.try_lock().unwrap();
+    }
+
+    #[test]
+    fn xattr_value_decodes_setfattr_encodings() {
+        assert_eq!(xattr_value("bar").unwrap(), b"bar");
+        assert_eq!(xattr_value("0x0aff").unwrap(), [0x0a, 0xff]);
+        assert_eq!(xattr_value("0sYmFy").unwrap(), b"bar");
+        assert!(xattr_value("0xabc").is_err());
+        assert!(xattr_value("0xzz").is_err());
+    }
+
+    #[test]
+    fn xattrs_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("target");
+        let mut f = fwm(FileKind::Copy, target.clone(), None);
+        f.text = Some(String::new());
+        f.copy().unwrap();
+        f.xattrs
+            .insert(String::from("user.smfh"), String::from("test"));
+        // Not every filesystem supports user xattrs
+        if sys::set_xattr(&target, "user.probe", b"").is_err() {
+            return;
+        }
+        assert_eq!(f.state().unwrap(), FileState::WrongXattrs);
+        f.chmod_chown().unwrap();
+        assert_eq!(f.state().unwrap(), FileState::Correct);
+    }
+
+    #[test]
+    fn hooks_run_when_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("target");
+        let log = dir.path().join("log");
+        let mut f = fwm(FileKind::Directory, target.clone(), None);
+        f.on_activate = Some(format!(
+            "echo \"$SMFH_HOOK $SMFH_TARGET\" >> {}",
+            log.display()
+        ));
+        f.on_deactivate = f.on_activate.clone();
+        let options = Options::default();
+        f.activate(None, &options).unwrap();
+        f.activate(None, &options).unwrap();
+        f.deactivate(&options).unwrap();
+        assert_eq!(
+            fs::read_to_string(&log).unwrap(),
+            format!("on_activate {0}\non_deactivate {0}\n", target.display())
+        );
+
+        f.on_activate = Some(String::from("exit 3"));
+        assert!(f.activate(None, &options).is_err());
+    }
+
+    #[test]
+    fn activate_asks_before_backing_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let target = dir.path().join("target");
+        fs::write(&source, b"managed").unwrap();
+        fs::write(&target, b"mine").unwrap();
+
+        let prompts = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&prompts);
+        let options = Options {
+            confirm: Some(Confirm::new(move |prompt| {
+                seen.lock().unwrap().push(prompt.to_string());
+                false
+            })),
+            ..Options::default()
+        };
+        let mut f = fwm(FileKind::Symlink, target.clone(), Some(source.clone()));
+        f.activate(None, &options).unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"mine");
+        assert_eq!(
+            *prompts.lock().unwrap(),
+            vec![format!(
+                "'{}' is a file of 4 bytes, not a symlink to '{}'. Back it up?",
+                target.display(),
+                source.display()
+            )]
+        );
+        assert_eq!(options.report.entries()[0].action, Action::Skipped);
+    }
+
+    #[test]
+    fn force_overrides_clobber() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let target = dir.path().join("target");
+        fs::write(&source, b"managed").unwrap();
+        fs::write(&target, b"mine").unwrap();
+
+        let mut f = fwm(FileKind::Symlink, target.clone(), Some(source));
+        f.clobber = Some(false);
+        let options = Options {
+            force: true,
+            ..Options::default()
+        };
+        f.activate(Some(false), &options).unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"managed");
+        assert!(!dir.path().join(".backup-target").exists());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn check_one_file_system_stops_at_mountpoints() {
+        let proc = Path::new("/proc");
+        let metadata = fs::symlink_metadata(proc).unwrap();
+        assert!(check_one_file_system(proc, &metadata).is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("a/b")).unwrap();
+        let metadata = fs::symlink_metadata(dir.path()).unwrap();
+        check_one_file_system(dir.path(), &metadata).unwrap();
+    }
+
     #[test]
     fn prefix_move_nonexistent_is_ok() {
         let dir = tempfile::tempdir().unwrap();
-        prefix_move(&dir.path().join("nonexistent"), ".bak-").unwrap();
+        prefix_move(
+            &dir.path().join("nonexistent"),
+            ".bak-",
+            false,
+            Resolve::Follow,
+        )
+        .unwrap();
     }
 }
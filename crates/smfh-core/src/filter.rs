@@ -0,0 +1,137 @@
+//! Selection of the entries a run applies to, so that part of a manifest can
+//! be (de)activated without editing it.
+
+use crate::{
+    index::Files,
+    manifest::{
+        File,
+        rerooted,
+    },
+};
+use std::path::{
+    Path,
+    PathBuf,
+};
+
+/// Which entries of a manifest [`Options::filter`] lets through. The default
+/// lets everything through.
+///
+/// [`Options::filter`]: crate::options::Options::filter
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Filter {
+    /// If not empty, only entries whose target is one of these paths or
+    /// under one of them.
+    pub only: Vec<PathBuf>,
+    /// Entries whose target is one of these paths or under one of them.
+    pub skip: Vec<PathBuf>,
+    /// If not empty, only entries with at least one of these
+    /// [`tags`][File::tags].
+    pub tags: Vec<String>,
+    /// Entries with any of these [`tags`][File::tags].
+    pub skip_tags: Vec<String>,
+}
+
+impl Filter {
+    /// Whether `file` is selected.
+    #[must_use]
+    pub fn matches(&self, file: &File) -> bool {
+        let covers = |paths: &[PathBuf]| paths.iter().any(|x| file.target.starts_with(x));
+        (self.only.is_empty() || covers(&self.only))
+            && !covers(&self.skip)
+            && self.matches_tags(file)
+    }
+
+    /// Which of `files` are selected, as [`matches`][Self::matches] tells,
+    /// but finding the targets under [`only`][Self::only] and
+    /// [`skip`][Self::skip] by target rather than checking every entry
+    /// against every path.
+    #[must_use]
+    pub fn select(&self, files: &Files) -> Vec<bool> {
+        let mut selected = vec![self.only.is_empty(); files.len()];
+        if !self.only.is_empty() || !self.skip.is_empty() {
+            for (paths, value) in [(&self.only, true), (&self.skip, false)] {
+                for i in paths.iter().flat_map(|path| files.under(path)) {
+                    selected[i] = value;
+                }
+            }
+        }
+        for (selected, file) in selected.iter_mut().zip(files) {
+            *selected = *selected && self.matches_tags(file);
+        }
+        selected
+    }
+
+    fn matches_tags(&self, file: &File) -> bool {
+        let tagged = |tags: &[String]| tags.iter().any(|x| file.tags.contains(x));
+        (self.tags.is_empty() || tagged(&self.tags)) && !tagged(&self.skip_tags)
+    }
+
+    /// Whether every entry is selected.
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.only.is_empty()
+            && self.skip.is_empty()
+            && self.tags.is_empty()
+            && self.skip_tags.is_empty()
+    }
+
+    /// Moves every path under `root`, like
+    /// [`Manifest::reroot`][crate::manifest::Manifest::reroot] does with
+    /// targets.
+    pub fn reroot(&mut self, root: &Path) {
+        for path in self.only.iter_mut().chain(&mut self.skip) {
+            *path = rerooted(root, path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::FileKind;
+
+    #[test]
+    fn matches_targets_under_paths() {
+        let filter = Filter {
+            only: vec![PathBuf::from("/etc")],
+            skip: vec![PathBuf::from("/etc/ssh")],
+            ..Filter::default()
+        };
+        let matches = |target: &str| filter.matches(&File::new(FileKind::Directory, target.into()));
+        assert!(matches("/etc"));
+        assert!(matches("/etc/nginx/nginx.conf"));
+        assert!(!matches("/etcetera"));
+        assert!(!matches("/etc/ssh/sshd_config"));
+        assert!(Filter::default().matches(&File::new(FileKind::Directory, "/a".into())));
+
+        let files = Files::from(
+            ["/etc/ssh/sshd_config", "/etcetera", "/etc", "/etc/nginx"]
+                .into_iter()
+                .map(|x| File::new(FileKind::Directory, x.into()))
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(filter.select(&files), [false, false, true, true]);
+        assert_eq!(
+            filter.select(&files),
+            files.iter().map(|x| filter.matches(x)).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn matches_tags() {
+        let filter = Filter {
+            tags: vec![String::from("gui"), String::from("work")],
+            skip_tags: vec![String::from("laptop")],
+            ..Filter::default()
+        };
+        let matches = |tags: &[&str]| {
+            let mut file = File::new(FileKind::Directory, "/a".into());
+            file.tags = tags.iter().map(ToString::to_string).collect();
+            filter.matches(&file)
+        };
+        assert!(matches(&["work"]));
+        assert!(matches(&["gui", "desktop"]));
+        assert!(!matches(&[]));
+        assert!(!matches(&["gui", "laptop"]));
+    }
+}
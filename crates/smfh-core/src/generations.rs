@@ -0,0 +1,118 @@
+//! Archive of applied manifests, so that an earlier generation can be rolled
+//! back to without the caller keeping its manifest around.
+
+use crate::{
+    file_util::write_atomic,
+    manifest::Manifest,
+};
+use color_eyre::{
+    Result,
+    eyre::Context as _,
+};
+use std::{
+    fs,
+    io,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+/// A directory holding one JSON manifest per generation, named after its
+/// number, e.g. `1.json`, `2.json`. The highest number is the current one.
+#[derive(Debug, Clone)]
+pub struct Generations {
+    dir: PathBuf,
+}
+
+impl Generations {
+    #[must_use]
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    /// Returns the number and path of every generation, oldest first. A
+    /// missing directory has no generations.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the directory cannot be read.
+    pub fn list(&self) -> Result<Vec<(u64, PathBuf)>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(x) => x,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => {
+                return Err(err).wrap_err_with(|| {
+                    format!("Failed to read state directory '{}'", self.dir.display())
+                });
+            }
+        };
+        let mut generations = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|x| x == "json")
+                && let Some(number) = path
+                    .file_stem()
+                    .and_then(|x| x.to_str())
+                    .and_then(|x| x.parse().ok())
+            {
+                generations.push((number, path));
+            }
+        }
+        generations.sort_unstable();
+        Ok(generations)
+    }
+
+    /// Archives `manifest` as the new current generation and returns its
+    /// path. Includes and globs are already expanded in `manifest`, so the
+    /// archived copy stands on its own.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the directory cannot be read or created, or the
+    /// manifest cannot be serialized or written.
+    pub fn record(&self, manifest: &Manifest) -> Result<PathBuf> {
+        let next = self.list()?.last().map_or(1, |(number, _)| number + 1);
+        fs::create_dir_all(&self.dir).wrap_err_with(|| {
+            format!("Failed to create state directory '{}'", self.dir.display())
+        })?;
+        let path = self.dir.join(format!("{next}.json"));
+        write_atomic(&path, &serde_json::to_vec_pretty(manifest)?)?;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_appends_generations() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = dir.path().join("state");
+        let generations = Generations::new(&state);
+        assert!(generations.list().unwrap().is_empty());
+
+        let manifest_path = dir.path().join("manifest.json");
+        fs::write(
+            &manifest_path,
+            r#"{"files":[{"type":"delete","target":"/a"}],"version":3}"#,
+        )
+        .unwrap();
+        let manifest = Manifest::read(&manifest_path, false).unwrap();
+        generations.record(&manifest).unwrap();
+        let second = generations.record(&manifest).unwrap();
+        fs::write(state.join("notes.txt"), "").unwrap();
+
+        assert_eq!(
+            generations.list().unwrap(),
+            vec![(1, state.join("1.json")), (2, second.clone())]
+        );
+        assert_eq!(
+            Manifest::read(&second, false).unwrap().files,
+            manifest.files
+        );
+    }
+}
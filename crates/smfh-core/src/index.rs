@@ -0,0 +1,188 @@
+//! Entries of a manifest along with their positions by target, so that the
+//! entry for a target, or every entry under a directory, is found without
+//! going through all of them.
+
+use crate::manifest::File;
+use core::{
+    fmt,
+    ops::{
+        Bound,
+        Deref,
+    },
+};
+use serde::{
+    Deserialize,
+    Deserializer,
+    Serialize,
+    Serializer,
+};
+use std::{
+    collections::BTreeMap,
+    path::{
+        Path,
+        PathBuf,
+    },
+    slice,
+    vec,
+};
+
+/// The entries of a [`Manifest`][crate::manifest::Manifest] in the order
+/// they are applied, indexed by target.
+///
+/// Read like a slice, and changed through [`update`][Self::update] or
+/// [`push`][Self::push], which keep the index up to date. Paths are ordered
+/// by component, so the targets under a directory follow it.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Files {
+    files: Vec<File>,
+    targets: BTreeMap<PathBuf, Vec<usize>>,
+}
+
+impl Files {
+    /// Changes the entries with `f`, then indexes them again.
+    pub fn update<T>(&mut self, f: impl FnOnce(&mut Vec<File>) -> T) -> T {
+        let result = f(&mut self.files);
+        self.targets.clear();
+        for (i, file) in self.files.iter().enumerate() {
+            self.targets.entry(file.target.clone()).or_default().push(i);
+        }
+        result
+    }
+
+    /// Adds `file` after the other entries.
+    pub fn push(&mut self, file: File) {
+        self.targets
+            .entry(file.target.clone())
+            .or_default()
+            .push(self.files.len());
+        self.files.push(file);
+    }
+
+    /// Returns the position of the last entry with `target`, if any.
+    #[must_use]
+    pub fn get(&self, target: &Path) -> Option<usize> {
+        self.positions(target).last().copied()
+    }
+
+    /// Returns the positions of the entries with `target`, in order.
+    #[must_use]
+    pub fn positions(&self, target: &Path) -> &[usize] {
+        self.targets.get(target).map_or(&[], Vec::as_slice)
+    }
+
+    /// Whether an entry has `target`.
+    #[must_use]
+    pub fn contains(&self, target: &Path) -> bool {
+        self.targets.contains_key(target)
+    }
+
+    /// Returns the positions of the entries whose target is `path` or under
+    /// it, ordered by target.
+    pub fn under<'a>(&'a self, path: &'a Path) -> impl Iterator<Item = usize> + 'a {
+        self.targets
+            .range::<Path, _>((Bound::Included(path), Bound::Unbounded))
+            .take_while(move |(target, _)| target.starts_with(path))
+            .flat_map(|(_, positions)| positions.iter().copied())
+    }
+}
+
+impl From<Vec<File>> for Files {
+    fn from(files: Vec<File>) -> Self {
+        let mut indexed = Self::default();
+        indexed.update(|x| *x = files);
+        indexed
+    }
+}
+
+impl From<Files> for Vec<File> {
+    fn from(files: Files) -> Self {
+        files.files
+    }
+}
+
+impl Extend<File> for Files {
+    fn extend<I: IntoIterator<Item = File>>(&mut self, files: I) {
+        for file in files {
+            self.push(file);
+        }
+    }
+}
+
+impl Deref for Files {
+    type Target = [File];
+
+    fn deref(&self) -> &[File] {
+        &self.files
+    }
+}
+
+impl IntoIterator for Files {
+    type Item = File;
+    type IntoIter = vec::IntoIter<File>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.files.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Files {
+    type Item = &'a File;
+    type IntoIter = slice::Iter<'a, File>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.files.iter()
+    }
+}
+
+impl fmt::Debug for Files {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.files.fmt(f)
+    }
+}
+
+impl Serialize for Files {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.files.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Files {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::deserialize(deserializer).map(Self::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::FileKind;
+
+    #[test]
+    fn finds_targets_and_those_under_them() {
+        let mut files: Files = ["/etc/ssh", "/etcetera", "/etc", "/etc/nginx/nginx.conf"]
+            .into_iter()
+            .map(|x| File::new(FileKind::Directory, x.into()))
+            .collect::<Vec<_>>()
+            .into();
+        files.push(File::new(FileKind::Directory, "/etc".into()));
+        assert_eq!(files.get(Path::new("/etc")), Some(4));
+        assert!(!files.contains(Path::new("/etc/nginx")));
+        assert_eq!(
+            files.under(Path::new("/etc")).collect::<Vec<_>>(),
+            [2, 4, 3, 0]
+        );
+        assert_eq!(
+            files.under(Path::new("/etc/nginx")).collect::<Vec<_>>(),
+            [3]
+        );
+        assert_eq!(files.under(Path::new("/usr")).count(), 0);
+
+        // Kept up to date as entries change
+        files.update(|x| x.retain(|file| file.target != Path::new("/etc/ssh")));
+        assert_eq!(files.get(Path::new("/etc")), Some(3));
+        assert_eq!(
+            files.under(Path::new("/etc")).collect::<Vec<_>>(),
+            [1, 3, 2]
+        );
+    }
+}
@@ -0,0 +1,117 @@
+//! Checksums of what smfh last wrote to each copied target, kept across runs
+//! so that [`Manifest::diff`] can tell a target the user edited from one whose
+//! source merely changed since.
+//!
+//! [`Manifest::diff`]: crate::manifest::Manifest::diff
+
+use crate::{
+    checksum::Checksum,
+    file_util::write_atomic,
+};
+use color_eyre::{
+    Result,
+    eyre::WrapErr as _,
+};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io,
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::{
+        Arc,
+        Mutex,
+        MutexGuard,
+        PoisonError,
+    },
+};
+
+/// Name of the file in a state directory the checksums are kept in.
+pub const FILE_NAME: &str = "installed.json";
+
+/// The checksums kept in one state directory, by target. Clones share them.
+#[derive(Debug, Clone)]
+pub struct Installed {
+    path: PathBuf,
+    checksums: Arc<Mutex<BTreeMap<PathBuf, Checksum>>>,
+}
+
+impl Installed {
+    /// Reads the checksums kept in `dir`, none if there are none yet.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if they exist but can't be read or parsed.
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join(FILE_NAME);
+        let checksums = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .wrap_err_with(|| format!("Failed to parse '{}'", path.display()))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => {
+                return Err(err).wrap_err_with(|| format!("Failed to read '{}'", path.display()));
+            }
+        };
+        Ok(Self {
+            path,
+            checksums: Arc::new(Mutex::new(checksums)),
+        })
+    }
+
+    /// Writes the checksums back to the state directory they were read from.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the directory can't be created or the file can't
+    /// be written.
+    pub fn save(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).wrap_err_with(|| {
+                format!("Failed to create state directory '{}'", dir.display())
+            })?;
+        }
+        write_atomic(&self.path, &serde_json::to_vec_pretty(&*self.checksums())?)
+    }
+
+    /// Returns the checksum of what was last written to `target`.
+    #[must_use]
+    pub fn get(&self, target: &Path) -> Option<Checksum> {
+        self.checksums().get(target).cloned()
+    }
+
+    pub(crate) fn insert(&self, target: &Path, checksum: Checksum) {
+        self.checksums().insert(target.to_path_buf(), checksum);
+    }
+
+    pub(crate) fn remove(&self, target: &Path) -> Option<Checksum> {
+        self.checksums().remove(target)
+    }
+
+    fn checksums(&self) -> MutexGuard<'_, BTreeMap<PathBuf, Checksum>> {
+        self.checksums
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum::Algorithm;
+
+    #[test]
+    fn save_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = dir.path().join("state");
+        let installed = Installed::load(&state).unwrap();
+        let target = Path::new("/etc/a");
+        assert!(installed.get(target).is_none());
+
+        let checksum = Checksum::of(Algorithm::Blake3, b"a");
+        installed.insert(target, checksum.clone());
+        installed.save().unwrap();
+        assert_eq!(Installed::load(&state).unwrap().get(target), Some(checksum));
+    }
+}
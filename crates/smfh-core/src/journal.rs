@@ -0,0 +1,454 @@
+//! Append-only record of every change a run makes to the filesystem, with
+//! what was at each changed path before, so that [`undo`] can revert the
+//! changes without the manifest that caused them.
+//!
+//! A journal is a file of JSON lines, one [`Record`] each, written before the
+//! change it records, or right after for a move, so that a crash can't lose
+//! it. Files that are replaced
+//! or removed are kept in a directory next to it, named after it with `.d`
+//! appended, hard linked where possible. Contents of removed directories are
+//! not kept, nor are parent directories created along the way recorded.
+
+use crate::{
+    file_util::random_sibling,
+    sys::{
+        self,
+        Resolve,
+    },
+};
+use color_eyre::{
+    Result,
+    eyre::{
+        OptionExt as _,
+        WrapErr as _,
+        eyre,
+    },
+};
+use log::{
+    info,
+    warn,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::{
+    fs,
+    io::{
+        self,
+        BufRead as _,
+        Write as _,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::{
+        Arc,
+        Mutex,
+        PoisonError,
+    },
+};
+
+/// What was at a path before it was changed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Prior {
+    /// A file, whose contents were kept at `stash`.
+    File {
+        stash: PathBuf,
+        mode: u32,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    },
+    Symlink {
+        destination: PathBuf,
+    },
+    /// A directory, whose contents were not kept.
+    Directory {
+        mode: u32,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        #[serde(default)]
+        immutable: bool,
+    },
+    /// Anything else, such as a FIFO or a device node, which isn't restored.
+    Other,
+}
+
+/// One change, undone by [`undo`] as described for each.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "change", rename_all = "camelCase")]
+pub enum Record {
+    /// `path` was created where there was nothing. Removed again, unless it
+    /// is a directory that isn't empty.
+    Created { path: PathBuf },
+    /// `path` held `prior` and was changed or removed. Whatever is there now
+    /// is removed and `prior` put back, except for a directory that is still
+    /// one, which only gets its mode, owner, and immutable flag back.
+    Replaced { path: PathBuf, prior: Prior },
+    /// `from` was moved to `to`, e.g. as a backup. Moved back.
+    Renamed { from: PathBuf, to: PathBuf },
+}
+
+/// A journal being appended to. Clones append to the same one.
+#[derive(Debug, Clone)]
+pub struct Journal {
+    stash: PathBuf,
+    file: Arc<Mutex<fs::File>>,
+}
+
+/// Returns the directory the files of the journal at `path` are kept in.
+fn stash_dir(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".d");
+    PathBuf::from(name)
+}
+
+impl Journal {
+    /// Opens the journal at `path` for appending, creating it if needed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if it can't be opened.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .wrap_err_with(|| format!("Failed to open journal '{}'", path.display()))?;
+        Ok(Self {
+            stash: stash_dir(&std::path::absolute(path)?),
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    /// Records that `path` is about to be changed, replaced, or removed, or
+    /// created if there is nothing at it yet, keeping what is there now.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if what is at `path` can't be kept or the journal
+    /// can't be written.
+    pub fn change(&self, path: &Path) -> Result<()> {
+        let path = path.to_path_buf();
+        match self.stash(&path)? {
+            Some(prior) => {
+                let stash = match prior {
+                    Prior::File { ref stash, .. } => Some(stash.clone()),
+                    _ => None,
+                };
+                self.record(&Record::Replaced { path, prior })
+                    .inspect_err(|_| {
+                        if let Some(stash) = stash {
+                            _ = fs::remove_file(stash);
+                        }
+                    })
+            }
+            None => self.record(&Record::Created { path }),
+        }
+    }
+
+    /// Records that `from` was moved to `to`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the journal can't be written.
+    pub fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        self.record(&Record::Renamed {
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+        })
+    }
+
+    /// Appends `record`, making sure it is on disk before returning.
+    fn record(&self, record: &Record) -> Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        (&*file)
+            .write_all(&line)
+            .and_then(|()| file.sync_data())
+            .wrap_err("Failed to write to the journal")
+    }
+
+    /// Takes what is at `path`, if anything, so that it can be put back.
+    fn stash(&self, path: &Path) -> Result<Option<Prior>> {
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(x) => x,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let mode = sys::mode(&metadata) & 0o7777;
+        let (uid, gid) = sys::owner(&metadata).unzip();
+        let prior = if metadata.is_file() {
+            fs::create_dir_all(&self.stash)?;
+            let stash = random_sibling(&self.stash.join("stash"));
+            if fs::hard_link(path, &stash).is_err() {
+                fs::copy(path, &stash).wrap_err_with(|| {
+                    format!("Failed to keep '{}' in the journal", path.display())
+                })?;
+            }
+            Prior::File {
+                stash,
+                mode,
+                uid,
+                gid,
+            }
+        } else if metadata.is_symlink() {
+            Prior::Symlink {
+                destination: fs::read_link(path)?,
+            }
+        } else if metadata.is_dir() {
+            Prior::Directory {
+                mode,
+                uid,
+                gid,
+                immutable: sys::immutable(path).unwrap_or(false),
+            }
+        } else {
+            Prior::Other
+        };
+        Ok(Some(prior))
+    }
+}
+
+/// Undoes every change recorded in the journal at `path`, latest first.
+///
+/// The journal is deleted after, unless some changes couldn't be undone.
+/// With `dry_run`, only logs what would be done. Returns the paths that
+/// couldn't be restored and why.
+///
+/// # Errors
+///
+/// Returns an error if the journal can't be read or parsed.
+pub fn undo(path: &Path, dry_run: bool) -> Result<Vec<(PathBuf, color_eyre::Report)>> {
+    let file = fs::File::open(path)
+        .wrap_err_with(|| format!("Failed to open journal '{}'", path.display()))?;
+    let mut records = Vec::new();
+    for (number, line) in io::BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        // A crash may leave the last line incomplete
+        match serde_json::from_str::<Record>(&line) {
+            Ok(record) => records.push(record),
+            Err(err) if err.is_eof() => warn!("Ignoring incomplete line {}", number + 1),
+            Err(err) => {
+                return Err(err).wrap_err_with(|| {
+                    format!(
+                        "Failed to parse line {} of '{}'",
+                        number + 1,
+                        path.display()
+                    )
+                });
+            }
+        }
+    }
+
+    let mut failures = Vec::new();
+    for record in records.into_iter().rev() {
+        if dry_run {
+            info!("Would undo {record:?}");
+            continue;
+        }
+        let target = match record {
+            Record::Created { ref path }
+            | Record::Replaced { ref path, .. }
+            | Record::Renamed { from: ref path, .. } => path.clone(),
+        };
+        if let Err(err) = undo_record(record) {
+            warn!(
+                "Failed to undo the change to '{}'\n{err:?}",
+                target.display()
+            );
+            failures.push((target, err));
+        }
+    }
+    if !dry_run && failures.is_empty() {
+        fs::remove_file(path)?;
+        let stash = stash_dir(path);
+        if stash.exists() {
+            fs::remove_dir_all(stash)?;
+        }
+    }
+    Ok(failures)
+}
+
+fn undo_record(record: Record) -> Result<()> {
+    match record {
+        Record::Created { path } => {
+            remove(&path)?;
+            info!("Removed '{}'", path.display());
+        }
+        Record::Replaced {
+            path,
+            prior:
+                Prior::Directory {
+                    mode,
+                    uid,
+                    gid,
+                    immutable,
+                },
+        } if fs::symlink_metadata(&path).is_ok_and(|x| x.is_dir()) => {
+            if sys::immutable(&path).unwrap_or(false) {
+                sys::set_immutable(&path, false)?;
+            }
+            set_metadata(&path, mode, uid, gid)?;
+            if immutable {
+                sys::set_immutable(&path, true)?;
+            }
+            info!("Restored the mode and owner of '{}'", path.display());
+        }
+        Record::Replaced { path, prior } => {
+            remove(&path)?;
+            restore(&path, prior)?;
+            info!("Restored '{}'", path.display());
+        }
+        Record::Renamed { from, to } => {
+            // Recorded after the move, so it has happened
+            if fs::symlink_metadata(&to).is_err() {
+                return Err(eyre!("'{}' is gone", to.display()));
+            }
+            remove(&from)?;
+            let (dir, name) = sys::Dir::open_parent(&from, Resolve::Follow)?;
+            let to_name = to.file_name().ok_or_eyre("Failed to get file name")?;
+            if to.parent() == from.parent() {
+                dir.rename(to_name, &name)?;
+            } else {
+                fs::rename(&to, &from)?;
+            }
+            info!("Moved '{}' back to '{}'", to.display(), from.display());
+        }
+    }
+    Ok(())
+}
+
+/// Removes what is at `path`, if anything, refusing to remove a directory
+/// that isn't empty.
+fn remove(path: &Path) -> Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(x) => x,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    let (dir, name) = sys::Dir::open_parent(path, Resolve::Follow)?;
+    if metadata.is_dir() {
+        dir.remove_dir(&name).wrap_err_with(|| {
+            format!(
+                "'{}' is a directory that isn't empty, leaving it alone",
+                path.display()
+            )
+        })
+    } else {
+        dir.remove_file(&name).map_err(Into::into)
+    }
+}
+
+/// Puts `prior` back at `path`, where there is nothing.
+fn restore(path: &Path, prior: Prior) -> Result<()> {
+    let (mode, uid, gid) = match prior {
+        Prior::File {
+            stash,
+            mode,
+            uid,
+            gid,
+        } => {
+            fs::copy(&stash, path)
+                .wrap_err_with(|| format!("Failed to restore '{}'", stash.display()))?;
+            (mode, uid, gid)
+        }
+        Prior::Symlink { destination } => {
+            let (dir, name) = sys::Dir::open_parent(path, Resolve::Follow)?;
+            dir.symlink(&destination, &name)?;
+            return Ok(());
+        }
+        Prior::Directory {
+            mode,
+            uid,
+            gid,
+            immutable: _,
+        } => {
+            fs::create_dir(path)?;
+            warn!(
+                "Restored directory '{}', but not what was in it",
+                path.display()
+            );
+            (mode, uid, gid)
+        }
+        Prior::Other => {
+            return Err(eyre!(
+                "'{}' was neither a file, a symlink, nor a directory, so it can't be restored",
+                path.display()
+            ));
+        }
+    };
+    set_metadata(path, mode, uid, gid)
+}
+
+/// Gives what is at `path` back its `mode` and owner.
+fn set_metadata(path: &Path, mode: u32, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+    let (dir, name) = sys::Dir::open_parent(path, Resolve::Follow)?;
+    dir.set_mode(&name, mode)?;
+    if uid.is_some() || gid.is_some() {
+        dir.chown(&name, uid, gid)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn undo_restores_directories_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal");
+        let changed = dir.path().join("changed");
+        fs::create_dir(&changed).unwrap();
+        fs::write(changed.join("file"), b"kept").unwrap();
+        sys::set_mode(&changed, 0o755).unwrap();
+
+        let journal = Journal::open(&path).unwrap();
+        journal.change(&changed).unwrap();
+        sys::set_mode(&changed, 0o700).unwrap();
+
+        assert!(undo(&path, false).unwrap().is_empty());
+        assert_eq!(sys::mode(&fs::metadata(&changed).unwrap()) & 0o777, 0o755);
+        assert_eq!(fs::read(changed.join("file")).unwrap(), b"kept");
+    }
+
+    #[test]
+    fn undo_reverts_in_reverse() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal");
+        let replaced = dir.path().join("replaced");
+        let moved = dir.path().join("moved");
+        let backup = dir.path().join(".backup-moved");
+        let link = dir.path().join("link");
+        fs::write(&replaced, b"old").unwrap();
+        fs::write(&moved, b"moved").unwrap();
+        sys::symlink(Path::new("old"), &link).unwrap();
+
+        let journal = Journal::open(&path).unwrap();
+        journal.change(&replaced).unwrap();
+        fs::remove_file(&replaced).unwrap();
+        fs::write(&replaced, b"new").unwrap();
+        fs::rename(&moved, &backup).unwrap();
+        journal.rename(&moved, &backup).unwrap();
+        journal.change(&moved).unwrap();
+        fs::write(&moved, b"new").unwrap();
+        journal.change(&link).unwrap();
+        fs::remove_file(&link).unwrap();
+        // Left incomplete by a crash
+        (&*journal.file.lock().unwrap())
+            .write_all(b"{\"change\"")
+            .unwrap();
+
+        assert!(undo(&path, false).unwrap().is_empty());
+        assert_eq!(fs::read(&replaced).unwrap(), b"old");
+        assert_eq!(fs::read(&moved).unwrap(), b"moved");
+        assert!(!backup.exists());
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("old"));
+        assert!(!path.exists());
+        assert!(!stash_dir(&path).exists());
+    }
+}
@@ -1,4 +1,61 @@
+//! Core library of the Sleek Manifest File Handler.
+//!
+//! The `smfh` binary is a thin wrapper around this crate. Other tools can
+//! drive the same operations directly:
+//!
+//! ```no_run
+//! use smfh_core::{
+//!     Manifest,
+//!     Options,
+//! };
+//! use std::path::Path;
+//!
+//! let mut manifest = Manifest::read(Path::new("/etc/smfh/manifest.json"), false).unwrap();
+//! for (target, err) in manifest.activate(&Options::default()) {
+//!     eprintln!("{}: {err:?}", target.display());
+//! }
+//! ```
+
+pub mod cas;
+pub mod checksum;
+pub mod confirm;
+pub mod depends;
+pub mod fetch;
 pub mod file_util;
+pub mod filter;
+pub mod generations;
+pub mod index;
+pub mod installed;
+pub mod journal;
 pub mod manifest;
+pub mod memo;
+pub mod migrate;
+pub mod options;
+pub mod patch;
+pub mod plan;
+pub mod protect;
+pub mod report;
+pub mod schema;
+pub mod secret;
+pub mod sys;
+pub mod watch;
+
+pub use file_util::{
+    FileState,
+    FileWithMetadata,
+};
+pub use filter::Filter;
+pub use generations::Generations;
+pub use manifest::{
+    DiffError,
+    File,
+    FileKind,
+    Format,
+    Manifest,
+    ReadError,
+    VerifyError,
+};
+pub use options::Options;
+pub use report::Report;
 
-pub const VERSION: u64 = 3;
+pub const VERSION: u64 = 4;
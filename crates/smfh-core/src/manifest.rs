@@ -1,9 +1,39 @@
 use crate::{
     VERSION,
+    checksum::Checksum,
+    depends,
+    fetch,
     file_util::{
+        FileState,
         FileWithMetadata,
-        prefix_move,
+        backups_of,
+        copy_file,
+        copy_tree,
+        mkdir,
+        render_template,
+        run_hook,
+        wait_for,
+        walk_files,
     },
+    generations::Generations,
+    index::Files,
+    migrate,
+    options::Options,
+    plan::{
+        Change,
+        PlanEntry,
+        Step,
+    },
+    protect::{
+        self,
+        ProtectedError,
+    },
+    report::{
+        ALREADY_CORRECT,
+        Action,
+    },
+    secret::Decrypt,
+    sys,
 };
 use color_eyre::{
     Result,
@@ -19,12 +49,29 @@ use core::{
         self,
         Display,
     },
+    iter,
+    mem,
+    str::FromStr,
 };
 
 /// Error returned by [`Manifest::read`].
 #[derive(Debug)]
 pub enum ReadError {
-    VersionTooNew { manifest: u64 },
+    VersionTooNew {
+        manifest: u64,
+    },
+    /// The manifest at this path includes itself, directly or through other
+    /// manifests.
+    IncludeCycle(PathBuf),
+    /// Several entries of the manifest at `manifest` have this target, see
+    /// [`Duplicates`].
+    DuplicateTarget {
+        manifest: PathBuf,
+        target: PathBuf,
+    },
+    /// The entries with these targets depend on each other in a cycle, see
+    /// [`depends::cycle`].
+    DependencyCycle(Vec<PathBuf>),
     ExpandFailed(color_eyre::Report),
     Io(color_eyre::Report),
 }
@@ -36,6 +83,25 @@ impl Display for ReadError {
                 f,
                 "manifest version too new: program {VERSION}, manifest {manifest}"
             ),
+            Self::IncludeCycle(path) => {
+                write!(f, "manifest '{}' includes itself", path.display())
+            }
+            Self::DuplicateTarget { manifest, target } => write!(
+                f,
+                "manifest '{}' has several entries with target '{}'",
+                manifest.display(),
+                target.display()
+            ),
+            Self::DependencyCycle(targets) => {
+                write!(f, "entries depend on each other in a cycle: ")?;
+                for (i, target) in targets.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " -> ")?;
+                    }
+                    write!(f, "'{}'", target.display())?;
+                }
+                Ok(())
+            }
             Self::ExpandFailed(e) | Self::Io(e) => write!(f, "{e}"),
         }
     }
@@ -52,6 +118,8 @@ pub enum DiffError {
     /// target path and the formatted error. Returned instead of `Ok(())` so
     /// the manifest rename is skipped and the next run can retry.
     ActivationFailed(Vec<(PathBuf, String)>),
+    /// Entries would replace or remove protected paths, so nothing was done.
+    Protected(Vec<ProtectedError>),
     Other(color_eyre::Report),
 }
 
@@ -71,6 +139,13 @@ impl Display for DiffError {
                 }
                 Ok(())
             }
+            Self::Protected(errors) => {
+                write!(f, "{} file(s) would touch protected paths:", errors.len())?;
+                for err in errors {
+                    write!(f, "\n  {err}")?;
+                }
+                Ok(())
+            }
             Self::Other(e) => write!(f, "{e}"),
         }
     }
@@ -85,6 +160,25 @@ pub enum Violation {
     UnexpectedSource,
     UnexpectedFollowSymlinks,
     UnexpectedIgnoreModification,
+    UnexpectedCopyFallback,
+    UnexpectedXattrs,
+    UnexpectedContent,
+    UnexpectedReflink,
+    UnexpectedMtime,
+    ConflictingOwner,
+    UnexpectedDecrypt,
+    UnexpectedUrl,
+    MissingSourceHash,
+    UnexpectedSourceHash,
+    UnexpectedTargetHash,
+    UnexpectedImmutable,
+    UnsupportedImmutable,
+    MissingOwner,
+    UnexpectedIfMissingSource,
+    UnexpectedRecursive,
+    InvalidPattern,
+    UnexpectedReplaces,
+    UnknownDependency,
 }
 
 /// Error returned by [`Manifest::verify`].
@@ -102,6 +196,27 @@ impl Display for VerifyError {
             Violation::UnexpectedSource => "should not have a source",
             Violation::UnexpectedFollowSymlinks => "should not have follow_symlinks",
             Violation::UnexpectedIgnoreModification => "should not have ignore_modification",
+            Violation::UnexpectedCopyFallback => "should not have copy_fallback",
+            Violation::UnexpectedXattrs => "should not have xattrs or selinux_context",
+            Violation::UnexpectedContent => "should not have inline content",
+            Violation::UnexpectedReflink => "should not have reflink",
+            Violation::UnexpectedMtime => "should not have mtime",
+            Violation::ConflictingOwner => {
+                "should not have both a uid and an owner, or a gid and a group"
+            }
+            Violation::UnexpectedDecrypt => "should not have decrypt",
+            Violation::UnexpectedUrl => "should not have a URL source",
+            Violation::MissingSourceHash => "requires a source_hash for a URL source",
+            Violation::UnexpectedSourceHash => "should not have source_hash",
+            Violation::UnexpectedTargetHash => "should not have target_hash",
+            Violation::UnexpectedImmutable => "should not have immutable",
+            Violation::UnsupportedImmutable => "can't be immutable on this platform",
+            Violation::MissingOwner => "requires a uid, owner, gid, or group for as_owner",
+            Violation::UnexpectedIfMissingSource => "should not have if_missing_source",
+            Violation::UnexpectedRecursive => "should not have recursive",
+            Violation::InvalidPattern => "has an invalid glob pattern as its target",
+            Violation::UnexpectedReplaces => "should not have replaces",
+            Violation::UnknownDependency => "depends on a target no entry has",
         };
         write!(
             f,
@@ -114,29 +229,66 @@ impl Display for VerifyError {
 
 impl std::error::Error for VerifyError {}
 
+use base64::{
+    Engine as _,
+    prelude::BASE64_STANDARD,
+};
 use log::{
     error,
     info,
     warn,
 };
+use rayon::iter::{
+    IntoParallelIterator as _,
+    IntoParallelRefIterator as _,
+    ParallelIterator as _,
+};
+use ruzstd::decoding::StreamingDecoder;
+use schemars::{
+    JsonSchema,
+    Schema,
+    SchemaGenerator,
+    json_schema,
+};
 use serde::{
     Deserialize,
     Deserializer,
     Serialize,
-    de::Error as serdeErr,
+    Serializer,
+    de::{
+        DeserializeOwned,
+        Error as serdeErr,
+    },
 };
 use serde_json::Value;
-use shellexpand::path::full as shellexpand;
+use shellexpand::path::full_with_context as shellexpand;
 use std::{
+    borrow::Cow,
+    collections::{
+        BTreeMap,
+        HashSet,
+    },
+    env,
+    ffi::OsStr,
     fs::{
         self,
     },
-    io::BufReader,
+    io::{
+        BufRead,
+        BufReader,
+        Read as _,
+        Seek as _,
+    },
     path::{
         Component,
         Path,
         PathBuf,
     },
+    process::Stdio,
+    sync::atomic::{
+        self,
+        AtomicBool,
+    },
 };
 
 #[allow(clippy::ref_option, clippy::trivially_copy_pass_by_ref)]
@@ -148,15 +300,295 @@ fn is_true(t: &Option<bool>) -> bool {
     t.is_none_or(|x| x)
 }
 
+/// First bytes of a zstd frame, by which compressed manifests are told.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// The CBOR self-described tag, 55799, by which CBOR manifests are told. It
+/// can't start a text manifest, as it isn't UTF-8.
+const CBOR_MAGIC: [u8; 3] = [0xd9, 0xd9, 0xf7];
+
+/// Serialization format of a manifest file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    #[default]
+    Json,
+    Toml,
+    Yaml,
+    /// Binary [CBOR](https://cbor.io), for large manifests generated by
+    /// machines, faster to parse than the rest.
+    Cbor,
+}
+
+impl Format {
+    /// Guesses the format from the extension of `path`, defaulting to
+    /// [`Json`][Self::Json]. A `.zst` extension is looked through, so
+    /// `manifest.toml.zst` is TOML.
+    #[must_use]
+    pub fn from_path(path: &Path) -> Self {
+        let path = match path.extension() {
+            Some(x) if x == "zst" => Path::new(path.file_stem().unwrap_or_default()),
+            _ => path,
+        };
+        match path.extension().and_then(OsStr::to_str) {
+            Some("toml") => Self::Toml,
+            Some("yaml" | "yml") => Self::Yaml,
+            Some("cbor") => Self::Cbor,
+            _ => Self::Json,
+        }
+    }
+
+    /// Parses the contents of `file` in this format, without interpreting
+    /// them as a manifest. Contents compressed with zstd are decompressed
+    /// first, and contents starting with the CBOR self-described tag are
+    /// parsed as [`Cbor`][Self::Cbor], whatever the file is named.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading, decompressing, or parsing fails.
+    pub fn parse(self, file: &fs::File) -> Result<Value> {
+        let (format, reader) = self.open(file)?;
+        format.deserialize(reader)
+    }
+
+    /// Deserializes a manifest from `file` in this format, straight into its
+    /// entries rather than through a [`Value`] of the whole document. A
+    /// manifest of an older version that deserializes as it is needs no
+    /// [upgrade][migrate::upgrade], and is taken as one of [`VERSION`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading or deserializing fails, or the manifest
+    /// is newer than [`VERSION`].
+    fn read_in_place(self, file: &fs::File) -> Result<Manifest, ReadError> {
+        let (format, reader) = self.open(file).map_err(ReadError::Io)?;
+        let mut manifest: Manifest = format
+            .deserialize(reader)
+            .wrap_err("Failed to deserialize manifest")
+            .map_err(ReadError::Io)?;
+        if manifest.version > VERSION {
+            return Err(ReadError::VersionTooNew {
+                manifest: manifest.version,
+            });
+        }
+        manifest.version = VERSION;
+        Ok(manifest)
+    }
+
+    /// Opens the contents of `file` for reading, decompressing them if they
+    /// are compressed with zstd, along with the format to read them in: this
+    /// one, unless they start with the CBOR self-described tag.
+    fn open(self, file: &fs::File) -> Result<(Self, Box<dyn BufRead + '_>)> {
+        let mut reader = BufReader::new(file);
+        let mut reader: Box<dyn BufRead + '_> = if reader.fill_buf()?.starts_with(&ZSTD_MAGIC) {
+            let decoder = StreamingDecoder::new(reader)
+                .map_err(|e| eyre!(e).wrap_err("Failed to decompress manifest"))?;
+            Box::new(BufReader::new(decoder))
+        } else {
+            Box::new(reader)
+        };
+        if reader.fill_buf()?.starts_with(&CBOR_MAGIC) {
+            reader.consume(CBOR_MAGIC.len());
+            Ok((Self::Cbor, reader))
+        } else {
+            Ok((self, reader))
+        }
+    }
+
+    /// Deserializes a `T` from `reader` in this format.
+    fn deserialize<T: DeserializeOwned>(self, mut reader: Box<dyn BufRead + '_>) -> Result<T> {
+        match self {
+            Self::Json => Ok(serde_json::from_reader(reader)?),
+            Self::Toml => {
+                let mut content = String::new();
+                reader.read_to_string(&mut content)?;
+                Ok(toml::from_str(&content)?)
+            }
+            Self::Yaml => Ok(serde_yaml_ng::from_reader(reader)?),
+            Self::Cbor => Ok(ciborium::from_reader(reader)?),
+        }
+    }
+
+    /// Serializes `manifest` in this format, [`Cbor`][Self::Cbor] starting
+    /// with the self-described tag.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if serialization fails.
+    pub fn serialize(self, manifest: &Manifest) -> Result<Vec<u8>> {
+        match self {
+            Self::Json => Ok(serde_json::to_vec_pretty(manifest)?),
+            Self::Toml => Ok(toml::to_string_pretty(manifest)?.into_bytes()),
+            Self::Yaml => Ok(serde_yaml_ng::to_string(manifest)?.into_bytes()),
+            Self::Cbor => {
+                let mut bytes = CBOR_MAGIC.to_vec();
+                ciborium::into_writer(manifest, &mut bytes)?;
+                Ok(bytes)
+            }
+        }
+    }
+
+    /// Whether this format is text, rather than binary.
+    #[must_use]
+    pub const fn is_text(self) -> bool {
+        !matches!(self, Self::Cbor)
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "toml" => Ok(Self::Toml),
+            "yaml" => Ok(Self::Yaml),
+            "cbor" => Ok(Self::Cbor),
+            _ => Err(format!("unknown manifest format '{s}'")),
+        }
+    }
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match *self {
+            Self::Json => "json",
+            Self::Toml => "toml",
+            Self::Yaml => "yaml",
+            Self::Cbor => "cbor",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// What [`Manifest::read_as`] does when a manifest has several entries with
+/// the same target. Entries of different manifests, through includes, always
+/// override each other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Duplicates {
+    /// Fail with [`ReadError::DuplicateTarget`].
+    #[default]
+    Error,
+    /// Keep the last of the entries, warning about the others.
+    LastWins,
+}
+
+impl FromStr for Duplicates {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(Self::Error),
+            "last-wins" => Ok(Self::LastWins),
+            _ => Err(format!("unknown duplicates policy '{s}'")),
+        }
+    }
+}
+
+/// Whether [`Manifest::read_as`] allows relative paths and substitutes
+/// environment variables in paths, and which.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Impure {
+    /// Entries with relative paths are discarded and paths used as they are.
+    #[default]
+    No,
+    /// Any variable is substituted.
+    AnyVariable,
+    /// Only these variables are substituted, `~` counting as `HOME`, and any
+    /// other is an error.
+    Variables(Vec<String>),
+}
+
+impl Impure {
+    /// Whether the variable `name` may be substituted.
+    fn allows(&self, name: &str) -> bool {
+        match *self {
+            Self::No => false,
+            Self::AnyVariable => true,
+            Self::Variables(ref names) => names.iter().any(|x| x == name),
+        }
+    }
+
+    /// Substitutes the allowed variables in `path`, and `~` with the home
+    /// directory. `~` and `HOME` are those of `user` if set, even with
+    /// [`No`][Self::No], which leaves other variables alone.
+    fn expand(&self, path: &Path, user: Option<&sys::User>) -> Result<PathBuf> {
+        if path.starts_with("~")
+            && user.is_none()
+            && matches!(*self, Self::Variables(_))
+            && !self.allows("HOME")
+        {
+            return Err(eyre!(
+                "'{}' expands '~', but HOME is not allowed",
+                path.display()
+            ));
+        }
+        let home = || match user {
+            Some(user) => Some(user.home.clone()),
+            None if self.allows("HOME") => env::home_dir(),
+            None => None,
+        };
+        let variable = |name: &str| match user {
+            Some(user) if name == "HOME" => Ok(Some(user.home.clone().into_os_string())),
+            _ if *self == Self::No => Ok(None),
+            _ if !self.allows(name) => Err(String::from("not allowed")),
+            _ => env::var(name)
+                .map(|x| Some(x.into()))
+                .map_err(|err| err.to_string()),
+        };
+        Ok(shellexpand(path, home, variable)
+            .map_err(|err| eyre!("{err}"))?
+            .to_path_buf())
+    }
+}
+
 /// Deserialized representation of a smfh manifest file.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct Manifest {
-    pub files: Vec<File>,
+    /// Entries in the order they are applied, kept indexed by target.
+    #[serde(default)]
+    #[schemars(with = "Vec<File>")]
+    pub files: Files,
     #[serde(skip_serializing_if = "is_false")]
     pub clobber_by_default: Option<bool>,
+    /// Values inherited by this manifest's own files.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub defaults: Option<Defaults>,
+    /// Default [`File::reflink`] of this manifest's own files.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reflink: Option<Reflink>,
+    /// Umask the files are created with, unless [`Options::umask`] is set.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_octal",
+        serialize_with = "serialize_octal",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schemars(with = "Option<String>", regex(pattern = "^[0-7]{1,3}$"))]
+    pub umask: Option<u32>,
     pub version: u64,
+    /// Manifests whose files are merged into this one by
+    /// [`read`][Self::read]. Relative paths are resolved against the
+    /// directory of the including manifest. Empty once read.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub includes: Vec<PathBuf>,
+    /// Values substituted for `@name@` placeholders in
+    /// [`Template`][FileKind::Template] sources.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub variables: BTreeMap<String, String>,
+    /// Shell command run after [`activate`][Self::activate] and
+    /// [`diff`][Self::diff], see [`run_hook`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_activate: Option<String>,
+    /// Shell command run after [`deactivate`][Self::deactivate].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_deactivate: Option<String>,
     #[serde(skip)]
-    impure: bool,
+    impure: Impure,
+    #[serde(skip)]
+    user: Option<sys::User>,
+    /// Entries with a glob source, as they were before being expanded.
+    #[serde(skip)]
+    globs: Vec<File>,
 }
 
 fn deserialize_octal<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
@@ -169,8 +601,129 @@ fn deserialize_octal<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Optio
     Ok(Some(x))
 }
 
+#[allow(clippy::ref_option, clippy::trivially_copy_pass_by_ref)]
+fn serialize_octal<S: Serializer>(value: &Option<u32>, serializer: S) -> Result<S::Ok, S::Error> {
+    value.map(|x| format!("{x:o}")).serialize(serializer)
+}
+
+fn deserialize_base64<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<u8>>, D::Error> {
+    let Some(value) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    let x = BASE64_STANDARD.decode(value).map_err(serdeErr::custom)?;
+    Ok(Some(x))
+}
+
+#[allow(clippy::ref_option)]
+fn serialize_base64<S: Serializer>(
+    value: &Option<Vec<u8>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    value
+        .as_ref()
+        .map(|x| BASE64_STANDARD.encode(x))
+        .serialize(serializer)
+}
+
+/// Values inherited by the entries of a manifest that don't set them, see
+/// [`Manifest::apply_defaults`].
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Defaults {
+    /// Only inherited by entries whose permissions apply to files:
+    /// [`Copy`][FileKind::Copy], [`Template`][FileKind::Template],
+    /// [`Hardlink`][FileKind::Hardlink], and
+    /// [`RecursiveCopy`][FileKind::RecursiveCopy].
+    #[serde(
+        default,
+        deserialize_with = "deserialize_octal",
+        serialize_with = "serialize_octal",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schemars(with = "Option<String>", regex(pattern = "^[0-7]+$"))]
+    pub permissions: Option<u32>,
+    /// Inherited by every entry but [`Delete`][FileKind::Delete].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    /// Inherited by every entry but [`Delete`][FileKind::Delete].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+    /// Inherited like [`uid`][Self::uid], by entries without a uid.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    /// Inherited like [`gid`][Self::gid], by entries without a gid.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// Inherited by entries without [`File::parents`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parents: Option<Parents>,
+}
+
+/// What the missing parent directories of a target look like when
+/// activation creates them, see [`File::parents`]. Unset fields are left to
+/// the umask and the running user, as without it.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Parents {
+    #[serde(
+        default,
+        deserialize_with = "deserialize_octal",
+        serialize_with = "serialize_octal",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schemars(with = "Option<String>", regex(pattern = "^[0-7]+$"))]
+    pub mode: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+}
+
+/// Conditions of [`File::only_if`], all of which have to hold for the entry
+/// to be applied. Evaluated on every run, so that one manifest can skip
+/// hardware-specific files on machines they don't apply to.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default, PartialEq, Eq)]
+pub struct OnlyIf {
+    /// A path that has to exist.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path_exists: Option<PathBuf>,
+    /// A shell command that has to exit successfully.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command_succeeds: Option<String>,
+    /// `NAME` of an environment variable that has to be set, or
+    /// `NAME=VALUE` for one that has to be `VALUE`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub env: Option<String>,
+}
+
+impl OnlyIf {
+    /// Evaluates the conditions, running [`command_succeeds`] if the others
+    /// hold.
+    ///
+    /// [`command_succeeds`]: Self::command_succeeds
+    #[must_use]
+    pub fn holds(&self) -> bool {
+        self.path_exists
+            .as_ref()
+            .is_none_or(|path| fs::symlink_metadata(path).is_ok())
+            && self
+                .env
+                .as_deref()
+                .is_none_or(|env| match env.split_once('=') {
+                    Some((name, value)) => env::var_os(name).is_some_and(|x| x == value),
+                    None => env::var_os(env).is_some(),
+                })
+            && self.command_succeeds.as_deref().is_none_or(|command| {
+                sys::shell(command)
+                    .stdout(Stdio::null())
+                    .status()
+                    .is_ok_and(|x| x.success())
+            })
+    }
+}
+
 /// A single file entry in a [`Manifest`].
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
 pub struct File {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub source: Option<PathBuf>,
@@ -182,90 +735,900 @@ pub struct File {
     #[serde(
         default,
         deserialize_with = "deserialize_octal",
+        serialize_with = "serialize_octal",
         skip_serializing_if = "Option::is_none"
     )]
+    #[schemars(with = "Option<String>", regex(pattern = "^[0-7]+$"))]
     pub permissions: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub uid: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub gid: Option<u32>,
+    /// User name resolved to a [`uid`][Self::uid] when activating.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    /// Group name resolved to a [`gid`][Self::gid] when activating.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
     #[serde(skip_serializing_if = "is_true")]
     pub deactivate: Option<bool>,
-    #[serde(skip_serializing_if = "is_true")]
+    /// For symlinks, whether the target is compared to the source with both
+    /// resolved, the default, or by its literal destination. For copies,
+    /// `false` refuses a source that is itself a symlink. For modify entries,
+    /// `true` changes what a symlink target points to, and otherwise the
+    /// symlink itself: its owner, modification time, and extended
+    /// attributes, as it has no permissions.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub follow_symlinks: Option<bool>,
     #[serde(skip_serializing_if = "is_false")]
     pub ignore_modification: Option<bool>,
+    #[serde(skip_serializing_if = "is_false")]
+    pub copy_fallback: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_base64",
+        serialize_with = "serialize_base64",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schemars(with = "Option<String>")]
+    pub content_base64: Option<Vec<u8>>,
+    #[serde(skip_serializing_if = "is_false")]
+    pub restore_backup: Option<bool>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub selinux_context: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_activate: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_deactivate: Option<String>,
+    /// How the source is copied, defaulting to [`Manifest::reflink`] and
+    /// then [`Reflink::Auto`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reflink: Option<Reflink>,
+    /// Modification time set on the target, and checked by
+    /// [`state`][FileWithMetadata::state].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<Mtime>,
+    /// Entries are applied in ascending order, `0` when unset, and only
+    /// then by kind and depth. Removal runs in reverse.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub order: Option<i32>,
+    /// Labels selected by [`Filter::tags`][crate::filter::Filter::tags], so
+    /// that one manifest can serve several machine roles.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Conditions the entry is only applied under.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub only_if: Option<OnlyIf>,
+    /// If not empty, the entry only applies on machines with one of these
+    /// hostnames, either the full one or up to the first dot.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hosts: Vec<String>,
+    /// If not empty, the entry only applies when smfh runs as one of these
+    /// users.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub users: Vec<String>,
+    /// Tool the source of a [`Copy`][FileKind::Copy] is encrypted with. It
+    /// is decrypted by [`Manifest::prepare`] and written straight to the
+    /// target, `600` unless [`permissions`][Self::permissions] says
+    /// otherwise.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decrypt: Option<Decrypt>,
+    /// Checksum the source must have, checked before activation and by
+    /// [`state`][FileWithMetadata::state]. Required if the source is an
+    /// `http(s)://` URL, see [`fetch`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_hash: Option<Checksum>,
+    /// Checksum of what a [`Copy`][FileKind::Copy],
+    /// [`Template`][FileKind::Template], or [`Hardlink`][FileKind::Hardlink]
+    /// writes. A target is unmodified if it has this checksum, and
+    /// activation fails if it doesn't end up with it. A
+    /// [`Delete`][FileKind::Delete] only deletes a target with this
+    /// checksum, and backs up one without it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_hash: Option<Checksum>,
+    /// Path, such as something on a network mount, that has to exist before
+    /// the entry is applied or removed, waited for up to
+    /// [`Options::wait_timeout`]. Rerooted like the target.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wait_for: Option<PathBuf>,
+    /// Mode and ownership of the parent directories activation creates for
+    /// the target, which are otherwise `755` and owned by whoever runs smfh.
+    /// Directories that already exist are left alone.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parents: Option<Parents>,
+    /// Major number of a [`CharDevice`][FileKind::CharDevice] or
+    /// [`BlockDevice`][FileKind::BlockDevice].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub major: Option<u32>,
+    /// Minor number of a [`CharDevice`][FileKind::CharDevice] or
+    /// [`BlockDevice`][FileKind::BlockDevice].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub minor: Option<u32>,
+    /// Sets the immutable flag (`chattr +i`) on the target, so that nothing
+    /// else can change, rename, or remove it. smfh clears it before
+    /// replacing or removing the target itself. Linux only.
+    #[serde(skip_serializing_if = "is_false")]
+    pub immutable: Option<bool>,
+    /// Activates and deactivates the entry as its [`uid`][Self::uid] and
+    /// [`gid`][Self::gid] rather than as root, so that files are created by
+    /// that user, e.g. on NFS homes that squash root, and permission checks
+    /// apply to them. Linux only.
+    #[serde(skip_serializing_if = "is_false")]
+    pub as_owner: Option<bool>,
+    /// What a [`Copy`][FileKind::Copy] does while its source doesn't exist.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub if_missing_source: Option<IfMissingSource>,
+    /// Makes a [`Delete`][FileKind::Delete] match the file name of its
+    /// target at any depth under the target's directory, see
+    /// [`Manifest::select`].
+    #[serde(skip_serializing_if = "is_false")]
+    pub recursive: Option<bool>,
+    /// Target of an entry of the old manifest that this one supersedes, such
+    /// as a config file that moved. [`Manifest::diff`] moves the old target
+    /// here, see [`rename`][Manifest::rename], rather than leaving it behind.
+    /// Rerooted like the target.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replaces: Option<PathBuf>,
+    /// Targets of other entries that must be activated before this one, and
+    /// deactivated after it, whatever their [`order`][Self::order] or kind,
+    /// see [`depends`][crate::depends]. Rerooted like the target.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub depends_on: Vec<PathBuf>,
 }
 
-impl Ord for File {
-    fn cmp(&self, other: &Self) -> Ordering {
-        const fn value(file: &File) -> u8 {
-            match file.kind {
-                FileKind::Directory => 1,
-                FileKind::Copy => 2,
-                FileKind::Symlink => 3,
-                FileKind::Modify => 4,
-                FileKind::Delete => 5,
-            }
-        }
+/// Waits for each of [`Options::wait_for`], returning the failure of every
+/// one that doesn't appear in time.
+fn wait(options: &Options) -> Vec<(PathBuf, color_eyre::Report)> {
+    let _phase = options.report.phase("wait");
+    options
+        .wait_for
+        .iter()
+        .filter_map(|path| {
+            wait_for(path, options.wait_timeout)
+                .inspect_err(|err| error!("{err}"))
+                .err()
+                .map(|err| (path.clone(), err))
+        })
+        .collect()
+}
 
-        if other.kind == self.kind {
-            fn parents(path: &Path) -> usize {
-                path.ancestors().count()
-            }
-            parents(&self.target).cmp(&parents(&other.target))
-        } else {
-            value(self).cmp(&value(other))
+/// Sets the umask to [`Options::umask`] or else `umask`, returning the
+/// failure under the name `umask`.
+fn set_umask(umask: Option<u32>, options: &Options) -> Option<(PathBuf, color_eyre::Report)> {
+    let umask = options.umask.or(umask)?;
+    sys::set_umask(umask)
+        .wrap_err_with(|| format!("Failed to set the umask to {umask:03o}"))
+        .err()
+        .map(|err| (PathBuf::from("umask"), err))
+}
+
+/// Restricts the thread as set by [`Options::landlock`] and
+/// [`Options::seccomp`]. Returns the failure under the name of either.
+fn sandbox(manifests: &[&Manifest], options: &Options) -> Option<(PathBuf, color_eyre::Report)> {
+    if let Some(failure) = restrict_writes(manifests, options) {
+        return Some(failure);
+    }
+    if !options.seccomp {
+        return None;
+    }
+    sys::seccomp()
+        .wrap_err("Failed to filter system calls with seccomp")
+        .err()
+        .map(|err| (PathBuf::from("seccomp"), err))
+}
+
+/// With [`Options::landlock`], restricts writes to the directories the
+/// targets of `manifests` are in, or their closest existing ancestors, and
+/// those of the options. Returns the failure under the name `landlock`.
+fn restrict_writes(
+    manifests: &[&Manifest],
+    options: &Options,
+) -> Option<(PathBuf, color_eyre::Report)> {
+    let extra = options.landlock.as_ref()?;
+    let mut dirs: Vec<PathBuf> = manifests
+        .iter()
+        .flat_map(|manifest| &manifest.files)
+        .filter_map(|file| file.target.parent())
+        .chain(extra.iter().map(PathBuf::as_path))
+        .chain([options.cache.as_path()])
+        .filter_map(|path| {
+            // The parent of a relative target may be empty
+            let path = std::path::absolute(Path::new(".").join(path)).ok()?;
+            path.ancestors().find(|x| x.is_dir()).map(Path::to_path_buf)
+        })
+        .collect();
+    dirs.sort();
+    dirs.dedup();
+    info!("Restricting writes to {dirs:?}");
+    sys::landlock(&dirs)
+        .wrap_err("Failed to restrict writes with Landlock")
+        .err()
+        .map(|err| (PathBuf::from("landlock"), err))
+}
+
+/// Maps `items` with `f`, keeping their order, on a pool of
+/// [`Options::jobs`] threads, for checking many targets at once. On this
+/// thread if there is one job, or if the pool can't be started.
+fn map_parallel<T: Sync, U: Send>(
+    items: &[T],
+    options: &Options,
+    f: impl Fn(&T) -> U + Send + Sync,
+) -> Vec<U> {
+    if options.jobs != 1 {
+        match rayon::ThreadPoolBuilder::new()
+            .num_threads(options.jobs)
+            .build()
+        {
+            Ok(pool) => return pool.install(|| items.par_iter().map(f).collect()),
+            Err(err) => warn!("Failed to start thread pool, checking sequentially\n{err:?}"),
         }
     }
+    items.iter().map(f).collect()
 }
 
-impl PartialOrd for File {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+/// Runs a manifest-level hook unless it is unset or this is a dry run.
+/// Returns the failure of the hook under `name`.
+fn manifest_hook(
+    command: Option<&str>,
+    name: &str,
+    options: &Options,
+) -> Option<(PathBuf, color_eyre::Report)> {
+    let command = command?;
+    if options.dry_run {
+        info!("Would run {name} hook '{command}'");
+        return None;
     }
+    run_hook(command, None, name)
+        .inspect_err(|err| error!("Failed to run {name} hook\n{err:?}"))
+        .err()
+        .map(|err| (PathBuf::from(name), err))
 }
 
-/// The operation smfh performs for a given [`File`].
-#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
-#[serde(rename_all = "camelCase")]
-pub enum FileKind {
-    Directory,
-    Copy,
-    Symlink,
-    Modify,
-    Delete,
+/// Turns the errors of [`Manifest::check_protected`] into failures.
+fn refuse(errors: Vec<ProtectedError>) -> Vec<(PathBuf, color_eyre::Report)> {
+    errors
+        .into_iter()
+        .map(|err| {
+            error!("{err}");
+            (err.target.clone(), color_eyre::Report::new(err))
+        })
+        .collect()
 }
-impl fmt::Display for FileKind {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let name = match *self {
-            Self::Copy => "copy",
-            Self::Delete => "delete",
-            Self::Directory => "directory",
-            Self::Modify => "modify",
-            Self::Symlink => "symlink",
+
+/// Returns the target of `file` as a glob pattern if it is a
+/// [`Delete`][FileKind::Delete] to be expanded by [`Manifest::select`]:
+/// either [`recursive`][File::recursive], or with a pattern that isn't an
+/// existing path.
+fn deletion_pattern(file: &File) -> Option<&str> {
+    if file.kind != FileKind::Delete {
+        return None;
+    }
+    let pattern = file.target.to_str()?;
+    (file.recursive.unwrap_or(false)
+        || pattern.contains(['*', '?', '[']) && fs::symlink_metadata(&file.target).is_err())
+    .then_some(pattern)
+}
+
+/// Returns the paths matching `pattern`, or with `recursive` its file name
+/// under its directory at any depth, see [`Manifest::select`].
+fn glob_matches(pattern: &str, recursive: bool) -> Result<Vec<PathBuf>, glob::PatternError> {
+    fn walk(dir: &Path, depth: usize, pattern: &glob::Pattern, matches: &mut Vec<PathBuf>) {
+        let options = glob::MatchOptions {
+            require_literal_separator: true,
+            ..glob::MatchOptions::new()
         };
-        write!(f, "{name}")
+        for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+            let path = entry.path();
+            if pattern.matches_path_with(&path, options) {
+                matches.push(path);
+            } else if depth > 1 && entry.file_type().is_ok_and(|x| x.is_dir()) {
+                walk(&path, depth - 1, pattern, matches);
+            }
+        }
+    }
+
+    // Only what is under the components without a pattern has to be walked
+    fn literal(path: &Path) -> PathBuf {
+        path.components()
+            .take_while(|x| !x.as_os_str().to_string_lossy().contains(['*', '?', '[']))
+            .collect()
+    }
+
+    let path = Path::new(pattern);
+    let (pattern, base, depth) =
+        if recursive && let (Some(parent), Some(name)) = (path.parent(), path.file_name()) {
+            (parent.join("**").join(name), literal(parent), usize::MAX)
+        } else {
+            let base = literal(path);
+            let depth = path.components().count() - base.components().count();
+            (path.to_path_buf(), base, depth)
+        };
+    let pattern = glob::Pattern::new(&pattern.to_string_lossy())?;
+    let mut matches = Vec::new();
+    walk(&base, depth, &pattern, &mut matches);
+    matches.sort();
+    Ok(matches)
+}
+
+/// Returns `path` moved under `root`, see [`Manifest::reroot`].
+pub(crate) fn rerooted(root: &Path, path: &Path) -> PathBuf {
+    let mut relative = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => relative.push(name),
+            Component::ParentDir => {
+                relative.pop();
+            }
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+        }
     }
+    root.join(relative)
 }
 
-impl Manifest {
-    /// Reads and deserializes a manifest from `manifest_path`. In impure mode,
-    /// shell-expands all paths; otherwise discards any entry whose path is
-    /// not absolute.
-    ///
-    /// # Errors
+impl File {
+    /// Whether the entry applies to this machine, see [`hosts`],
+    /// [`users`], and [`only_if`].
     ///
-    /// Returns a [`ReadError`] if:
-    /// - [`ReadError::VersionTooNew`]: the manifest version exceeds [`VERSION`]
-    /// - [`ReadError::Io`]: the file cannot be opened or deserialized
-    /// - [`ReadError::ExpandFailed`]: shell expansion of a path fails (impure
-    ///   mode only)
-    pub fn read(manifest_path: &Path, impure: bool) -> Result<Self, ReadError> {
-        let file = fs::File::open(manifest_path)
-            .wrap_err("Failed to open manifest")
-            .map_err(ReadError::Io)?;
-        let root: Value = serde_json::from_reader(BufReader::new(&file))
+    /// [`hosts`]: Self::hosts
+    /// [`users`]: Self::users
+    /// [`only_if`]: Self::only_if
+    #[must_use]
+    pub fn applies(&self) -> bool {
+        (self.hosts.is_empty() || self.on_host())
+            && (self.users.is_empty() || self.as_user())
+            && self.only_if.as_ref().is_none_or(OnlyIf::holds)
+    }
+
+    fn on_host(&self) -> bool {
+        match sys::hostname() {
+            Ok(host) => {
+                let short = host.split('.').next().unwrap_or(&host);
+                self.hosts.iter().any(|x| *x == host || x == short)
+            }
+            Err(err) => {
+                warn!(
+                    "Failed to get the hostname, skipping '{}': {err}",
+                    self.target.display()
+                );
+                false
+            }
+        }
+    }
+
+    fn as_user(&self) -> bool {
+        match sys::user_name() {
+            Ok(Some(user)) => self.users.contains(&user),
+            Ok(None) => false,
+            Err(err) => {
+                warn!(
+                    "Failed to get the user name, skipping '{}': {err}",
+                    self.target.display()
+                );
+                false
+            }
+        }
+    }
+
+    /// Turns the entry into one of `kind` from `source`, unsetting what
+    /// described its old source or contents. See [`Manifest::adopt`].
+    fn adopt(&mut self, kind: FileKind, source: PathBuf) {
+        self.kind = kind;
+        self.source = Some(source);
+        self.text = None;
+        self.content_base64 = None;
+        self.decrypt = None;
+        self.source_hash = None;
+        self.target_hash = None;
+        self.copy_fallback = None;
+        if kind == FileKind::Symlink {
+            self.permissions = None;
+            self.reflink = None;
+            self.mtime = None;
+        }
+    }
+
+    /// Creates an entry of `kind` for `target`, with every other field unset.
+    #[must_use]
+    pub const fn new(kind: FileKind, target: PathBuf) -> Self {
+        Self {
+            source: None,
+            target,
+            kind,
+            clobber: None,
+            permissions: None,
+            uid: None,
+            gid: None,
+            owner: None,
+            group: None,
+            deactivate: None,
+            follow_symlinks: None,
+            ignore_modification: None,
+            copy_fallback: None,
+            text: None,
+            content_base64: None,
+            restore_backup: None,
+            xattrs: BTreeMap::new(),
+            selinux_context: None,
+            on_activate: None,
+            on_deactivate: None,
+            reflink: None,
+            mtime: None,
+            order: None,
+            tags: Vec::new(),
+            only_if: None,
+            hosts: Vec::new(),
+            users: Vec::new(),
+            decrypt: None,
+            source_hash: None,
+            target_hash: None,
+            wait_for: None,
+            parents: None,
+            major: None,
+            minor: None,
+            immutable: None,
+            as_owner: None,
+            if_missing_source: None,
+            recursive: None,
+            replaces: None,
+            depends_on: Vec::new(),
+        }
+    }
+}
+
+impl Ord for File {
+    fn cmp(&self, other: &Self) -> Ordering {
+        const fn value(file: &File) -> u8 {
+            match file.kind {
+                FileKind::Directory => 1,
+                FileKind::Copy => 2,
+                FileKind::Template => 3,
+                FileKind::Hardlink => 4,
+                FileKind::RecursiveCopy => 5,
+                FileKind::Symlink => 6,
+                FileKind::LinkFarm => 7,
+                FileKind::Fifo => 8,
+                FileKind::Socket => 9,
+                FileKind::CharDevice => 10,
+                FileKind::BlockDevice => 11,
+                FileKind::Touch => 12,
+                FileKind::Modify => 13,
+                FileKind::Delete => 14,
+            }
+        }
+
+        let order = |file: &Self| file.order.unwrap_or(0);
+        order(self).cmp(&order(other)).then_with(|| {
+            if other.kind == self.kind {
+                fn parents(path: &Path) -> usize {
+                    path.ancestors().count()
+                }
+                parents(&self.target).cmp(&parents(&other.target))
+            } else {
+                value(self).cmp(&value(other))
+            }
+        })
+    }
+}
+
+impl PartialOrd for File {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// The operation smfh performs for a given [`File`].
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum FileKind {
+    Directory,
+    Copy,
+    /// Copies the source with `@name@` placeholders replaced by
+    /// [`Manifest::variables`].
+    Template,
+    /// Hard links the source to the target.
+    Hardlink,
+    /// Copies a whole source directory tree.
+    RecursiveCopy,
+    Symlink,
+    /// Symlinks every file in a source directory tree individually,
+    /// creating intermediate directories as needed.
+    LinkFarm,
+    /// Creates a named pipe.
+    Fifo,
+    /// Creates a unix socket file, as a placeholder until something binds
+    /// it.
+    Socket,
+    /// Creates a character device node with [`File::major`] and
+    /// [`File::minor`].
+    CharDevice,
+    /// Creates a block device node with [`File::major`] and
+    /// [`File::minor`].
+    BlockDevice,
+    /// Creates an empty file without a source, keeping whatever is written
+    /// to it, and only removes it while still empty.
+    Touch,
+    Modify,
+    Delete,
+}
+/// Whether copies share data blocks with their source through a
+/// copy-on-write clone, as supported by e.g. btrfs and XFS.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum Reflink {
+    /// Clone where the filesystem supports it, and copy otherwise.
+    #[default]
+    Auto,
+    /// Clone, failing where the filesystem does not support it.
+    Always,
+    /// Always copy.
+    Never,
+}
+
+/// What a [`Copy`][FileKind::Copy] does while its source doesn't exist.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum IfMissingSource {
+    /// Leave the target alone, with a warning.
+    #[default]
+    Skip,
+    /// Create an empty file with the entry's permissions and owner, e.g. a
+    /// log or history file that has to exist before the real one does. It is
+    /// only removed on deactivation while still empty.
+    CreateEmpty,
+}
+
+/// Modification time of a target, written as `"source"` or as seconds since
+/// the Unix epoch.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mtime {
+    /// The modification time of the source.
+    Source,
+    /// Seconds since the Unix epoch.
+    Unix(i64),
+}
+
+impl Serialize for Mtime {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            Self::Source => serializer.serialize_str("source"),
+            Self::Unix(x) => serializer.serialize_i64(x),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Mtime {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Unix(i64),
+            Name(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Unix(x) => Ok(Self::Unix(x)),
+            Raw::Name(x) if x == "source" => Ok(Self::Source),
+            Raw::Name(x) => Err(serdeErr::custom(format!(
+                "expected \"source\" or seconds since the Unix epoch, got '{x}'"
+            ))),
+        }
+    }
+}
+
+impl JsonSchema for Mtime {
+    fn schema_name() -> Cow<'static, str> {
+        "Mtime".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "description": "\"source\" or seconds since the Unix epoch",
+            "oneOf": [{ "const": "source" }, { "type": "integer" }],
+        })
+    }
+}
+
+impl fmt::Display for FileKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Self::Copy => "copy",
+            Self::Delete => "delete",
+            Self::Directory => "directory",
+            Self::Hardlink => "hardlink",
+            Self::LinkFarm => "link_farm",
+            Self::Modify => "modify",
+            Self::RecursiveCopy => "recursive_copy",
+            Self::Symlink => "symlink",
+            Self::Template => "template",
+            Self::Fifo => "fifo",
+            Self::Socket => "socket",
+            Self::CharDevice => "char_device",
+            Self::BlockDevice => "block_device",
+            Self::Touch => "touch",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl Manifest {
+    /// Creates a manifest of the current [`VERSION`] from `files`, for
+    /// callers that build manifests in code rather than reading them.
+    #[must_use]
+    pub fn new(files: Vec<File>) -> Self {
+        Self {
+            files: files.into(),
+            clobber_by_default: None,
+            defaults: None,
+            reflink: None,
+            umask: None,
+            version: VERSION,
+            includes: Vec::new(),
+            variables: BTreeMap::new(),
+            on_activate: None,
+            on_deactivate: None,
+            impure: Impure::No,
+            user: None,
+            globs: Vec::new(),
+        }
+    }
+
+    /// Builds a manifest that recreates the tree under `dir` at `target`.
+    ///
+    /// Symlinks become [`FileKind::Symlink`] entries pointing where they
+    /// point now, and regular files become [`FileKind::Copy`] entries of
+    /// themselves with their current permissions and ownership. Anything
+    /// else is skipped with a warning.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the tree cannot be walked or an entry in it cannot
+    /// be inspected.
+    pub fn generate(dir: &Path, target: &Path) -> Result<Self> {
+        let dir = fs::canonicalize(dir)
+            .wrap_err_with(|| format!("Failed to resolve '{}'", dir.display()))?;
+        let mut files = Vec::new();
+        for relative in walk_files(&dir)? {
+            let path = dir.join(&relative);
+            let metadata = fs::symlink_metadata(&path)?;
+            if metadata.is_symlink() {
+                let mut file = File::new(FileKind::Symlink, target.join(&relative));
+                let parent = path.parent().unwrap_or(&dir);
+                file.source = Some(parent.join(fs::read_link(&path)?));
+                files.push(file);
+            } else if metadata.is_file() {
+                let mut file = File::new(FileKind::Copy, target.join(&relative));
+                file.permissions = Some(sys::mode(&metadata));
+                if let Some((uid, gid)) = sys::owner(&metadata) {
+                    file.uid = Some(uid);
+                    file.gid = Some(gid);
+                }
+                file.source = Some(path);
+                files.push(file);
+            } else {
+                warn!(
+                    "Skipping '{}', which is neither a file nor a symlink",
+                    path.display()
+                );
+            }
+        }
+        Ok(Self::new(files))
+    }
+
+    /// Copies what is at the targets of this manifest into `out_dir`, and
+    /// returns the manifest with its entries pointed at the copies, so that
+    /// a machine can be brought under management without losing what is
+    /// there.
+    ///
+    /// A regular file at the target of a [`Copy`][FileKind::Copy],
+    /// [`Template`][FileKind::Template], [`Hardlink`][FileKind::Hardlink],
+    /// or [`Symlink`][FileKind::Symlink] is copied to the same path under
+    /// `out_dir`, and the entry becomes a `Copy` of it. A symlink there makes
+    /// the entry a `Symlink` to where it points now. The tree at the target of
+    /// a [`RecursiveCopy`][FileKind::RecursiveCopy] is copied as a whole.
+    /// Every other entry, and those whose target is missing, are left as
+    /// they are.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a target can't be inspected or copied.
+    pub fn adopt(&self, out_dir: &Path) -> Result<Self> {
+        let mut adopted = self.clone();
+        let mut files: Vec<File> = mem::take(&mut adopted.files).into();
+        for file in &mut files {
+            let Ok(metadata) = fs::symlink_metadata(&file.target) else {
+                continue;
+            };
+            let captured = rerooted(out_dir, &file.target);
+            let contents = matches!(
+                file.kind,
+                FileKind::Copy | FileKind::Template | FileKind::Hardlink | FileKind::Symlink
+            );
+            if contents && metadata.is_symlink() {
+                let parent = file.target.parent().unwrap_or_else(|| Path::new("/"));
+                let source = parent.join(fs::read_link(&file.target)?);
+                file.adopt(FileKind::Symlink, source);
+            } else if contents && metadata.is_file() {
+                mkdir(
+                    captured
+                        .parent()
+                        .ok_or_eyre("Failed to get parent directory")?,
+                )?;
+                copy_file(&file.target, &captured, Reflink::Auto)?;
+                file.adopt(FileKind::Copy, captured);
+            } else if file.kind == FileKind::RecursiveCopy && metadata.is_dir() {
+                mkdir(
+                    captured
+                        .parent()
+                        .ok_or_eyre("Failed to get parent directory")?,
+                )?;
+                copy_tree(&file.target, &captured, Reflink::Auto, None, None, None)?;
+                file.source = Some(captured);
+            } else {
+                continue;
+            }
+            info!("Adopted '{}'", file.target.display());
+        }
+        adopted.files = files.into();
+        Ok(adopted)
+    }
+
+    /// Reads and deserializes a manifest from `manifest_path`, in the
+    /// [`Format`] matching its extension, [upgrading][migrate::upgrade] it
+    /// from an older version first if needed. If `impure`, shell-expands all
+    /// paths with [`Impure::AnyVariable`]; otherwise discards any entry
+    /// whose path is not absolute.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`ReadError`] if:
+    /// - [`ReadError::VersionTooNew`]: the manifest version exceeds [`VERSION`]
+    /// - [`ReadError::Io`]: the file cannot be opened or deserialized
+    /// - [`ReadError::ExpandFailed`]: shell expansion of a path fails (impure
+    ///   mode only)
+    /// - [`ReadError::IncludeCycle`]: a manifest includes itself, directly or
+    ///   through other manifests
+    /// - [`ReadError::DuplicateTarget`]: a manifest has several entries with
+    ///   the same target
+    /// - [`ReadError::DependencyCycle`]: entries [depend on][File::depends_on]
+    ///   each other in a cycle
+    pub fn read(manifest_path: &Path, impure: bool) -> Result<Self, ReadError> {
+        Self::read_as(
+            manifest_path,
+            Format::from_path(manifest_path),
+            if impure {
+                Impure::AnyVariable
+            } else {
+                Impure::No
+            },
+            Duplicates::Error,
+            None,
+        )
+    }
+
+    /// Like [`read`][Self::read], but parses the manifest as `format`
+    /// regardless of its file extension, expands paths as `impure` says,
+    /// handles duplicate targets as `duplicates` says, and reads it for
+    /// `user`, see [`Impure`] and [`default_owner`][Self::default_owner].
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`ReadError`] under the same conditions as
+    /// [`read`][Self::read], except for duplicate targets with
+    /// [`Duplicates::LastWins`].
+    pub fn read_as(
+        manifest_path: &Path,
+        format: Format,
+        impure: Impure,
+        duplicates: Duplicates,
+        user: Option<&sys::User>,
+    ) -> Result<Self, ReadError> {
+        let manifest = Self::read_included(
+            manifest_path,
+            format,
+            impure,
+            duplicates,
+            user,
+            &mut Vec::new(),
+        )?;
+        if let Some(cycle) = depends::cycle(&manifest.files) {
+            return Err(ReadError::DependencyCycle(cycle));
+        }
+        Ok(manifest)
+    }
+
+    /// Reads the manifest at `manifest_path`, where `stack` holds the
+    /// canonical paths of the manifests currently including it.
+    fn read_included(
+        manifest_path: &Path,
+        format: Format,
+        impure: Impure,
+        duplicates: Duplicates,
+        user: Option<&sys::User>,
+        stack: &mut Vec<PathBuf>,
+    ) -> Result<Self, ReadError> {
+        let mut file = fs::File::open(manifest_path)
+            .wrap_err("Failed to open manifest")
+            .map_err(ReadError::Io)?;
+        // One that can only be read once, e.g. from a pipe, is read as a
+        // whole document, in case it needs upgrading
+        let mut manifest = if file.stream_position().is_err() {
+            Self::from_value(format.parse(&file))?
+        } else if let Ok(manifest) = format.read_in_place(&file) {
+            manifest
+        } else {
+            // Read again as a whole document, which upgrades a manifest of an
+            // older version and tells one that is newer from one that is
+            // broken
+            file.rewind()
+                .wrap_err("Failed to reread manifest")
+                .map_err(ReadError::Io)?;
+            Self::from_value(format.parse(&file))?
+        };
+
+        info!("Deserialized manifest: '{}'", manifest_path.display());
+
+        if impure != Impure::No || user.is_some() {
+            let mut files: Vec<File> = mem::take(&mut manifest.files).into();
+            for file in &mut files {
+                if let Some(ref src) = file.source
+                    && !fetch::is_url(src)
+                {
+                    file.source = Some(impure.expand(src, user).map_err(ReadError::ExpandFailed)?);
+                }
+                file.target = impure
+                    .expand(&file.target, user)
+                    .map_err(ReadError::ExpandFailed)?;
+                if let Some(ref path) = file.replaces {
+                    file.replaces =
+                        Some(impure.expand(path, user).map_err(ReadError::ExpandFailed)?);
+                }
+                for path in &mut file.depends_on {
+                    *path = impure.expand(path, user).map_err(ReadError::ExpandFailed)?;
+                }
+            }
+            manifest.files = files.into();
+        }
+        if !cfg!(debug_assertions) && impure == Impure::No {
+            manifest.files.update(|files| {
+                files.retain(|file| {
+                    let absolute = file.target.is_absolute()
+                        && !file.target.components().any(|x| x == Component::ParentDir)
+                        && file
+                            .source
+                            .as_ref()
+                            .is_none_or(|x| x.is_absolute() || fetch::is_url(x));
+                    if !absolute {
+                        warn!(
+                            "{} with target '{}' is not absolute, ignoring.",
+                            file.kind,
+                            file.target.display()
+                        );
+                    }
+                    absolute
+                });
+            });
+        }
+
+        manifest.impure = impure;
+        manifest.apply_defaults();
+        if let Some(user) = user {
+            manifest.default_owner(user);
+        }
+        manifest.user = user.cloned();
+        manifest.expand_globs()?;
+        manifest.check_duplicates(manifest_path, duplicates)?;
+        manifest.merge_includes(manifest_path, duplicates, stack)?;
+        Ok(manifest)
+    }
+
+    /// Deserializes a manifest from `root`, as parsed by [`Format::parse`],
+    /// after upgrading it to the current [`VERSION`].
+    fn from_value(root: Result<Value>) -> Result<Self, ReadError> {
+        let root = root
             .wrap_err("Failed to deserialize manifest")
             .map_err(ReadError::Io)?;
         let version = root
@@ -283,340 +1646,2854 @@ impl Manifest {
             });
         }
 
-        let mut manifest: Self = serde_json::from_value(root)
-            .wrap_err("Failed to deserialize manifest")
-            .map_err(ReadError::Io)?;
+        let root = migrate::upgrade(root, manifest_version).map_err(ReadError::Io)?;
 
-        info!("Deserialized manifest: '{}'", manifest_path.display());
+        serde_json::from_value(root)
+            .wrap_err("Failed to deserialize manifest")
+            .map_err(ReadError::Io)
+    }
 
-        if !cfg!(debug_assertions) && !impure {
-            manifest.files.retain(|file| {
-                let absolute = file.target.is_absolute()
-                    && !file.target.components().any(|x| x == Component::ParentDir)
-                    && file.source.as_ref().is_none_or(|x| x.is_absolute());
-                if !absolute {
-                    warn!(
-                        "{} with target '{}' is not absolute, ignoring.",
-                        file.kind,
-                        file.target.display()
-                    );
+    /// Fills in the unset fields of every file from
+    /// [`defaults`][Self::defaults]. Done by [`read`][Self::read] before
+    /// includes are merged, so that a manifest's defaults only apply to its
+    /// own files.
+    pub fn apply_defaults(&mut self) {
+        let Some(ref defaults) = self.defaults else {
+            return;
+        };
+        self.files.update(|files| {
+            for file in files {
+                // Secrets stay private unless they say otherwise
+                if matches!(
+                    file.kind,
+                    FileKind::Copy
+                        | FileKind::Template
+                        | FileKind::Hardlink
+                        | FileKind::RecursiveCopy
+                ) && file.decrypt.is_none()
+                {
+                    file.permissions = file.permissions.or(defaults.permissions);
                 }
-                absolute
-            });
-        } else if impure {
-            fn expand(path_buf: &PathBuf) -> Result<PathBuf> {
-                Ok(shellexpand(path_buf)
-                    .map_err(|err| eyre!("{err:?}"))?
-                    .to_path_buf())
-            }
-            for file in &mut manifest.files {
-                if let Some(ref src) = file.source.clone() {
-                    file.source = Some(expand(src).map_err(ReadError::ExpandFailed)?);
+                if file.kind != FileKind::Delete {
+                    if file.owner.is_none() {
+                        file.uid = file.uid.or(defaults.uid);
+                    }
+                    if file.uid.is_none() {
+                        file.owner = file.owner.take().or_else(|| defaults.owner.clone());
+                    }
+                    if file.group.is_none() {
+                        file.gid = file.gid.or(defaults.gid);
+                    }
+                    if file.gid.is_none() {
+                        file.group = file.group.take().or_else(|| defaults.group.clone());
+                    }
                 }
-                file.target = expand(&file.target.clone()).map_err(ReadError::ExpandFailed)?;
+                file.parents = file.parents.or(defaults.parents);
             }
-        }
-
-        manifest.impure = impure;
-        Ok(manifest)
+        });
     }
 
-    /// Verifies that every file entry complies with the manifest spec.
-    ///
-    /// # Errors
-    ///
-    /// Returns a [`VerifyError`] if:
-    ///
-    /// - [`VerifyError::MissingSource`]: a `Copy` or `Symlink` file has no
-    ///   `source`
-    /// - [`VerifyError::UnexpectedSource`]: a `Delete`, `Directory`, or
-    ///   `Modify` file has a `source`
-    /// - [`VerifyError::UnexpectedFollowSymlinks`]: a non-`Symlink` file has
-    ///   `follow_symlinks` set
-    /// - [`VerifyError::UnexpectedIgnoreModification`]: a non-`Copy` file has
-    ///   `ignore_modification` set
-    #[must_use]
-    pub fn verify(&self) -> Vec<VerifyError> {
-        let mut errors = Vec::new();
-        for file in &self.files {
-            match file.kind {
-                FileKind::Copy | FileKind::Symlink if file.source.is_none() => {
-                    errors.push(VerifyError {
-                        target: file.target.clone(),
-                        kind: file.kind,
-                        violation: Violation::MissingSource,
-                    });
-                }
-                FileKind::Delete | FileKind::Directory | FileKind::Modify
-                    if file.source.is_some() =>
+    /// Makes `user` own every file with neither a uid nor an owner, and its
+    /// primary group the group of those with neither a gid nor a group.
+    /// [`Delete`][FileKind::Delete] and [`Modify`][FileKind::Modify] entries
+    /// are left alone, as they don't create their target. Done by
+    /// [`read_as`][Self::read_as] after
+    /// [`apply_defaults`][Self::apply_defaults].
+    pub fn default_owner(&mut self, user: &sys::User) {
+        self.files.update(|files| {
+            for file in files {
+                if matches!(file.kind, FileKind::Delete | FileKind::Modify)
+                    || file.uid.is_some()
+                    || file.owner.is_some()
                 {
-                    errors.push(VerifyError {
-                        target: file.target.clone(),
-                        kind: file.kind,
-                        violation: Violation::UnexpectedSource,
-                    });
+                    continue;
+                }
+                file.uid = Some(user.uid);
+                if file.gid.is_none() && file.group.is_none() {
+                    file.gid = Some(user.gid);
                 }
-                _ => {}
             }
+        });
+    }
 
-            if file.follow_symlinks.is_some() && file.kind != FileKind::Symlink {
-                errors.push(VerifyError {
-                    target: file.target.clone(),
-                    kind: file.kind,
-                    violation: Violation::UnexpectedFollowSymlinks,
-                });
+    /// Moves every target under `root`, leaving sources alone, so the manifest
+    /// can be activated into a mounted image or chroot. Targets are
+    /// normalized lexically first, so `..` cannot climb out of `root`.
+    pub fn reroot(&mut self, root: &Path) {
+        self.files.update(|files| {
+            for file in files {
+                file.target = rerooted(root, &file.target);
+                if let Some(ref path) = file.wait_for {
+                    file.wait_for = Some(rerooted(root, path));
+                }
+                if let Some(ref path) = file.replaces {
+                    file.replaces = Some(rerooted(root, path));
+                }
+                for path in &mut file.depends_on {
+                    *path = rerooted(root, path);
+                }
             }
+        });
+    }
 
-            if file.ignore_modification.is_some()
-                && !matches!(file.kind, FileKind::Copy | FileKind::Symlink)
-            {
-                errors.push(VerifyError {
-                    target: file.target.clone(),
-                    kind: file.kind,
-                    violation: Violation::UnexpectedIgnoreModification,
+    /// Handles the entries of this manifest's own files that share a target,
+    /// before includes are merged.
+    fn check_duplicates(
+        &mut self,
+        manifest_path: &Path,
+        duplicates: Duplicates,
+    ) -> Result<(), ReadError> {
+        let last: Vec<bool> = self
+            .files
+            .iter()
+            .enumerate()
+            .map(|(i, file)| self.files.get(&file.target) == Some(i))
+            .collect();
+        if duplicates == Duplicates::Error
+            && let Some(i) = last.iter().rposition(|last| !last)
+        {
+            return Err(ReadError::DuplicateTarget {
+                manifest: manifest_path.to_path_buf(),
+                target: self.files[i].target.clone(),
+            });
+        }
+        if last.contains(&false) {
+            let mut last = last.into_iter();
+            self.files.update(|files| {
+                files.retain(|file| {
+                    last.next() == Some(true) || {
+                        warn!(
+                            "{} with target '{}' is overridden by a later entry",
+                            file.kind,
+                            file.target.display()
+                        );
+                        false
+                    }
                 });
-            }
+            });
         }
-        errors
+        Ok(())
     }
 
-    /// Activates every file in the manifest, applying them to the filesystem in
-    /// dependency order. Returns per-file failures; the caller decides whether
-    /// any failure is fatal.
-    pub fn activate(&mut self, prefix: &str) -> Vec<(PathBuf, color_eyre::Report)> {
-        self.files.sort();
-        let mut failures = Vec::new();
-        for mut file in self.files.iter().map(FileWithMetadata::from) {
-            if let Err(err) = file.activate(self.clobber_by_default, prefix) {
-                error!(
-                    "Failed to activate file: '{}'\n{:?}",
-                    file.target.display(),
-                    err
+    /// Replaces every file whose source is a glob pattern, rather than an
+    /// existing path, with one file per match. The target of such a file is
+    /// treated as a directory, and each match is placed in it under its own
+    /// file name.
+    fn expand_globs(&mut self) -> Result<(), ReadError> {
+        let mut files = Vec::with_capacity(self.files.len());
+        for file in mem::take(&mut self.files) {
+            let Some(pattern) = file
+                .source
+                .as_ref()
+                .filter(|x| !x.exists() && !fetch::is_url(x))
+                .and_then(|x| x.to_str())
+                .filter(|x| x.contains(['*', '?', '[']))
+            else {
+                files.push(file);
+                continue;
+            };
+
+            let matches = glob::glob(pattern)
+                .wrap_err_with(|| format!("Invalid glob pattern '{pattern}'"))
+                .map_err(ReadError::Io)?
+                .collect::<Result<Vec<_>, _>>()
+                .wrap_err_with(|| format!("Failed to expand glob pattern '{pattern}'"))
+                .map_err(ReadError::Io)?;
+            if matches.is_empty() {
+                warn!(
+                    "{} with target '{}' source '{pattern}' matches nothing, ignoring.",
+                    file.kind,
+                    file.target.display()
                 );
-                failures.push((file.target.clone(), err));
             }
-        }
-        failures
-    }
+
+            for source in matches {
+                let Some(name) = source.file_name() else {
+                    continue;
+                };
+                files.push(File {
+                    target: file.target.join(name),
+                    source: Some(source.clone()),
+                    ..file.clone()
+                });
+            }
+            self.globs.push(file);
+        }
+        self.files = files.into();
+        Ok(())
+    }
+
+    /// Adds the entries a glob source of this manifest expanded to in
+    /// `previous`, the same manifest as it was applied, that it no longer
+    /// expands to. Their sources are gone, but their targets still have to be
+    /// removed when this manifest is replaced.
+    pub fn keep_expanded(&mut self, previous: &Self) {
+        for glob in &self.globs {
+            let Some(pattern) = glob
+                .source
+                .as_ref()
+                .and_then(|x| glob::Pattern::new(&x.to_string_lossy()).ok())
+            else {
+                continue;
+            };
+            for file in &previous.files {
+                if file.kind == glob.kind
+                    && file.target.parent() == Some(&glob.target)
+                    && file
+                        .source
+                        .as_ref()
+                        .is_some_and(|x| pattern.matches_path(x))
+                    && !self.files.contains(&file.target)
+                {
+                    info!(
+                        "{} with target '{}' no longer matches '{pattern}', keeping it from the last generation",
+                        file.kind,
+                        file.target.display()
+                    );
+                    self.files.push(file.clone());
+                }
+            }
+        }
+    }
+
+    /// Reads every manifest in [`includes`][Self::includes] and merges their
+    /// files into this one. Files of later manifests replace files of earlier
+    /// ones with the same target, and the including manifest comes last. An
+    /// included manifest's `clobber_by_default` and `reflink` apply to its
+    /// own files only. Variables are merged the same way.
+    fn merge_includes(
+        &mut self,
+        manifest_path: &Path,
+        duplicates: Duplicates,
+        stack: &mut Vec<PathBuf>,
+    ) -> Result<(), ReadError> {
+        fn override_with(files: &mut Files, new: Files, inherit: impl Fn(File) -> File) {
+            files.update(|files| {
+                files.retain(|file| {
+                    let overridden = new.contains(&file.target);
+                    if overridden {
+                        warn!(
+                            "{} with target '{}' is overridden by a later manifest",
+                            file.kind,
+                            file.target.display()
+                        );
+                    }
+                    !overridden
+                });
+                files.extend(new.into_iter().map(inherit));
+            });
+        }
+
+        if self.includes.is_empty() {
+            return Ok(());
+        }
+
+        stack.push(
+            fs::canonicalize(manifest_path)
+                .wrap_err("Failed to canonicalize manifest path")
+                .map_err(ReadError::Io)?,
+        );
+
+        let parent = manifest_path.parent().unwrap_or_else(|| Path::new(""));
+        let mut files = Files::default();
+        let mut variables = BTreeMap::new();
+        for include in mem::take(&mut self.includes) {
+            let path = parent.join(include);
+            let canonical = fs::canonicalize(&path)
+                .wrap_err_with(|| format!("Failed to open included manifest '{}'", path.display()))
+                .map_err(ReadError::Io)?;
+            if stack.contains(&canonical) {
+                return Err(ReadError::IncludeCycle(path));
+            }
+
+            let child = Self::read_included(
+                &path,
+                Format::from_path(&path),
+                self.impure.clone(),
+                duplicates,
+                self.user.as_ref(),
+                stack,
+            )?;
+            variables.extend(child.variables);
+            self.globs.extend(child.globs);
+            let clobber_by_default = child.clobber_by_default;
+            let reflink = child.reflink;
+            override_with(&mut files, child.files, |mut file| {
+                file.clobber = file.clobber.or(clobber_by_default);
+                file.reflink = file.reflink.or(reflink);
+                file
+            });
+        }
+        stack.pop();
+
+        override_with(&mut files, mem::take(&mut self.files), |file| file);
+        self.files = files;
+        variables.append(&mut self.variables);
+        self.variables = variables;
+        Ok(())
+    }
+
+    /// Expands the [`Delete`][FileKind::Delete] entries with a glob pattern
+    /// as their target, then drops the entries outside [`Options::filter`]
+    /// and those whose [`only_if`][File::only_if] conditions don't hold, so
+    /// that a run leaves them alone.
+    ///
+    /// Unlike glob sources, these are expanded here rather than by
+    /// [`read`][Self::read], so that they match what is under
+    /// [`Options::root`] when the manifest is applied. With
+    /// [`recursive`][File::recursive], the file name of the target matches
+    /// at any depth under its directory. Symlinks are never followed and
+    /// nothing under a match is matched again, since deleting it deletes
+    /// everything under it, and neither are targets of other entries.
+    fn select(&mut self, options: &Options) {
+        self.expand_deletions();
+        let mut selected = options.filter.select(&self.files).into_iter();
+        self.files.update(|files| {
+            files.retain(|file| {
+                if selected.next() != Some(true) {
+                    return false;
+                }
+                let applies = file.applies();
+                if !applies {
+                    info!(
+                        "Skipping {} with target '{}', its conditions don't hold",
+                        file.kind,
+                        file.target.display()
+                    );
+                }
+                applies
+            });
+        });
+    }
+
+    /// See [`select`][Self::select].
+    fn expand_deletions(&mut self) {
+        if !self
+            .files
+            .iter()
+            .any(|file| deletion_pattern(file).is_some())
+        {
+            return;
+        }
+        let mut seen: HashSet<PathBuf> = self
+            .files
+            .iter()
+            .filter(|file| deletion_pattern(file).is_none())
+            .map(|file| file.target.clone())
+            .collect();
+        let mut files = Vec::with_capacity(self.files.len());
+        for file in mem::take(&mut self.files) {
+            let Some(pattern) = deletion_pattern(&file) else {
+                files.push(file);
+                continue;
+            };
+            let matches = match glob_matches(pattern, file.recursive.unwrap_or(false)) {
+                Ok(matches) => matches,
+                Err(e) => {
+                    warn!("Ignoring delete with invalid target pattern '{pattern}': {e}");
+                    continue;
+                }
+            };
+            for target in matches {
+                if seen.insert(target.clone()) {
+                    files.push(File {
+                        target,
+                        recursive: None,
+                        ..file.clone()
+                    });
+                }
+            }
+        }
+        self.files = files.into();
+    }
+
+    /// Checks every entry [`Options::filter`] selects against
+    /// [`Options::protected`], see [`protect::check`]. [`activate`] and
+    /// [`deactivate`], as chosen by `removal`, do nothing if this finds
+    /// anything, and neither does [`diff`].
+    ///
+    /// [`activate`]: Self::activate
+    /// [`deactivate`]: Self::deactivate
+    /// [`diff`]: Self::diff
+    #[must_use]
+    pub fn check_protected(&self, options: &Options, removal: bool) -> Vec<ProtectedError> {
+        self.files
+            .iter()
+            .filter(|file| options.filter.matches(file))
+            .filter_map(|file| protect::check(file, &options.protected, removal).err())
+            .collect()
+    }
+
+    /// Verifies that every file entry complies with the manifest spec.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`VerifyError`] if:
+    ///
+    /// - [`VerifyError::MissingSource`]: a `Copy`, `Template`, `Hardlink`,
+    ///   `RecursiveCopy`, `Symlink`, or `LinkFarm` file has no `source`, and
+    ///   for `Copy` no inline content either
+    /// - [`VerifyError::UnexpectedSource`]: a `Delete`, `Directory`, `Touch`,
+    ///   or `Modify` file has a `source`
+    /// - [`VerifyError::UnexpectedFollowSymlinks`]: a file other than a
+    ///   `Symlink` or `Copy` has `follow_symlinks` set
+    /// - [`VerifyError::UnexpectedIgnoreModification`]: a non-`Copy` file has
+    ///   `ignore_modification` set
+    /// - [`VerifyError::UnexpectedContent`]: a non-`Copy` file has `text` or
+    ///   `content_base64` set, or a `Copy` file has more than one of `source`,
+    ///   `text`, and `content_base64`
+    /// - [`VerifyError::UnexpectedCopyFallback`]: a non-`Hardlink` file has
+    ///   `copy_fallback` set
+    /// - [`VerifyError::UnexpectedXattrs`]: a `Hardlink`, `RecursiveCopy`,
+    ///   `LinkFarm`, or `Delete` file has `xattrs` or `selinux_context` set
+    /// - [`VerifyError::UnexpectedReflink`]: a file other than a `Copy`,
+    ///   `Hardlink`, or `RecursiveCopy` has `reflink` set
+    /// - [`VerifyError::UnexpectedMtime`]: a file other than a `Copy` or
+    ///   `Template` has `mtime` set, or one without a source has it set to
+    ///   `"source"`
+    /// - [`VerifyError::ConflictingOwner`]: a file has both `uid` and `owner`,
+    ///   or both `gid` and `group`, set
+    /// - [`VerifyError::UnexpectedDecrypt`]: a file other than a `Copy` with a
+    ///   source has `decrypt` set
+    /// - [`VerifyError::UnexpectedUrl`]: a file other than a `Copy` or
+    ///   `Template` has a URL source
+    /// - [`VerifyError::MissingSourceHash`]: a file has a URL source but no
+    ///   `source_hash`
+    /// - [`VerifyError::UnexpectedSourceHash`]: a file other than a `Copy`,
+    ///   `Template`, `Hardlink`, or `Symlink` with a source has `source_hash`
+    ///   set
+    /// - [`VerifyError::UnexpectedTargetHash`]: a file other than a `Copy`,
+    ///   `Template`, `Hardlink`, or `Delete` has `target_hash` set
+    /// - [`VerifyError::UnexpectedImmutable`]: a file other than a `Copy`,
+    ///   `Template`, `Directory`, or `Modify` has `immutable` set
+    /// - [`VerifyError::UnsupportedImmutable`]: a file has `immutable` set on a
+    ///   platform without the flag
+    /// - [`VerifyError::MissingOwner`]: a file has `as_owner` set, but no
+    ///   `uid`, `owner`, `gid`, or `group`
+    /// - [`VerifyError::UnexpectedIfMissingSource`]: a non-`Copy` file has
+    ///   `if_missing_source` set
+    /// - [`VerifyError::UnexpectedRecursive`]: a non-`Delete` file has
+    ///   `recursive` set
+    /// - [`VerifyError::InvalidPattern`]: the target of a `Delete` file to be
+    ///   expanded isn't a valid glob pattern
+    /// - [`VerifyError::UnexpectedReplaces`]: a file other than a `Symlink`,
+    ///   `Copy`, `Template`, or `Hardlink` has `replaces` set
+    /// - [`VerifyError::UnknownDependency`]: a file [depends
+    ///   on][File::depends_on] a target that no file has
+    #[must_use]
+    #[allow(clippy::too_many_lines)]
+    pub fn verify(&self) -> Vec<VerifyError> {
+        let mut errors = Vec::new();
+        for file in &self.files {
+            let mut push = |violation| {
+                errors.push(VerifyError {
+                    target: file.target.clone(),
+                    kind: file.kind,
+                    violation,
+                });
+            };
+            let contents =
+                usize::from(file.text.is_some()) + usize::from(file.content_base64.is_some());
+            match file.kind {
+                FileKind::Copy if usize::from(file.source.is_some()) + contents > 1 => {
+                    push(Violation::UnexpectedContent);
+                }
+                FileKind::Copy if contents == 1 => {}
+                FileKind::Copy
+                | FileKind::Template
+                | FileKind::Hardlink
+                | FileKind::RecursiveCopy
+                | FileKind::Symlink
+                | FileKind::LinkFarm
+                    if file.source.is_none() =>
+                {
+                    push(Violation::MissingSource);
+                }
+                FileKind::Delete | FileKind::Directory | FileKind::Touch | FileKind::Modify
+                    if file.source.is_some() =>
+                {
+                    push(Violation::UnexpectedSource);
+                }
+                _ => {}
+            }
+
+            if file.follow_symlinks.is_some()
+                && !matches!(file.kind, FileKind::Symlink | FileKind::Copy)
+            {
+                push(Violation::UnexpectedFollowSymlinks);
+            }
+
+            if file.ignore_modification.is_some()
+                && !matches!(
+                    file.kind,
+                    FileKind::Copy
+                        | FileKind::Template
+                        | FileKind::Hardlink
+                        | FileKind::RecursiveCopy
+                        | FileKind::Symlink
+                )
+            {
+                push(Violation::UnexpectedIgnoreModification);
+            }
+
+            if contents > 0 && file.kind != FileKind::Copy {
+                push(Violation::UnexpectedContent);
+            }
+
+            if file.copy_fallback.is_some() && file.kind != FileKind::Hardlink {
+                push(Violation::UnexpectedCopyFallback);
+            }
+
+            if (!file.xattrs.is_empty() || file.selinux_context.is_some())
+                && matches!(
+                    file.kind,
+                    FileKind::Hardlink
+                        | FileKind::RecursiveCopy
+                        | FileKind::LinkFarm
+                        | FileKind::Delete
+                )
+            {
+                push(Violation::UnexpectedXattrs);
+            }
+
+            if file.reflink.is_some()
+                && !matches!(
+                    file.kind,
+                    FileKind::Copy | FileKind::Hardlink | FileKind::RecursiveCopy
+                )
+            {
+                push(Violation::UnexpectedReflink);
+            }
+
+            if let Some(mtime) = file.mtime
+                && (!matches!(file.kind, FileKind::Copy | FileKind::Template)
+                    || mtime == Mtime::Source && file.source.is_none())
+            {
+                push(Violation::UnexpectedMtime);
+            }
+            if file.uid.is_some() && file.owner.is_some()
+                || file.gid.is_some() && file.group.is_some()
+            {
+                push(Violation::ConflictingOwner);
+            }
+            if file.decrypt.is_some() && (file.kind != FileKind::Copy || file.source.is_none()) {
+                push(Violation::UnexpectedDecrypt);
+            }
+            if file.source.as_deref().is_some_and(fetch::is_url) {
+                if !matches!(file.kind, FileKind::Copy | FileKind::Template) {
+                    push(Violation::UnexpectedUrl);
+                }
+                if file.source_hash.is_none() {
+                    push(Violation::MissingSourceHash);
+                }
+            }
+            if file.source_hash.is_some()
+                && (file.source.is_none()
+                    || !matches!(
+                        file.kind,
+                        FileKind::Copy
+                            | FileKind::Template
+                            | FileKind::Hardlink
+                            | FileKind::Symlink
+                    ))
+            {
+                push(Violation::UnexpectedSourceHash);
+            }
+            if file.target_hash.is_some()
+                && !matches!(
+                    file.kind,
+                    FileKind::Copy | FileKind::Template | FileKind::Hardlink | FileKind::Delete
+                )
+            {
+                push(Violation::UnexpectedTargetHash);
+            }
+            if file.immutable.is_some_and(|x| x)
+                && !matches!(
+                    file.kind,
+                    FileKind::Copy | FileKind::Template | FileKind::Directory | FileKind::Modify
+                )
+            {
+                push(Violation::UnexpectedImmutable);
+            } else if file.immutable.is_some_and(|x| x) && !sys::IMMUTABLE_SUPPORTED {
+                push(Violation::UnsupportedImmutable);
+            }
+            if file.as_owner.is_some_and(|x| x)
+                && file.uid.is_none()
+                && file.owner.is_none()
+                && file.gid.is_none()
+                && file.group.is_none()
+            {
+                push(Violation::MissingOwner);
+            }
+            if file.if_missing_source.is_some() && file.kind != FileKind::Copy {
+                push(Violation::UnexpectedIfMissingSource);
+            }
+            if file.recursive.is_some() && file.kind != FileKind::Delete {
+                push(Violation::UnexpectedRecursive);
+            }
+            if let Some(pattern) = deletion_pattern(file)
+                && glob::Pattern::new(pattern).is_err()
+            {
+                push(Violation::InvalidPattern);
+            }
+            if file.replaces.is_some() && !replaceable(file.kind) {
+                push(Violation::UnexpectedReplaces);
+            }
+            if !file
+                .depends_on
+                .iter()
+                .all(|target| self.files.contains(target))
+            {
+                push(Violation::UnknownDependency);
+            }
+        }
+        errors
+    }
+
+    /// Pairs `file` with its metadata, rendering it first if it is a
+    /// [`Template`][FileKind::Template]. A template that fails to render is
+    /// left without content, so activation skips it. Fetching and decrypting
+    /// the source are left to [`FileWithMetadata::load`], so that only what
+    /// changes the target does them.
+    #[must_use]
+    pub fn prepare(&self, file: &File, options: &Options) -> FileWithMetadata {
+        let mut file = FileWithMetadata::from(file);
+        file.reflink = file.reflink.or(self.reflink);
+        file.resolve = options.resolve();
+        file.fsync = options.fsync;
+        file.memo = options.memo.clone();
+        if file.decrypt.is_some() {
+            file.permissions = file.permissions.or(Some(0o600));
+        }
+        if file.kind == FileKind::Template {
+            // A URL source is rendered from the cache, if it is there yet
+            let source = match (&file.source, &file.source_hash) {
+                (Some(source), Some(checksum)) if fetch::is_url(source) => {
+                    Some(fetch::cached_path(&options.cache, checksum)).filter(|x| x.is_file())
+                }
+                (source, _) => source.clone(),
+            };
+            if let Some(source) = source {
+                self.render(&mut file, &source);
+            }
+        }
+        file
+    }
+
+    /// [Loads][FileWithMetadata::load] `file`, prepared by
+    /// [`prepare`][Self::prepare], for it to be changed, then renders it if
+    /// it is a [`Template`][FileKind::Template] whose source that fetched.
+    fn load(&self, file: &mut FileWithMetadata, options: &Options) {
+        let remote = file.source.as_deref().is_some_and(fetch::is_url);
+        file.load(options);
+        if remote
+            && file.kind == FileKind::Template
+            && let Some(source) = file.source.clone()
+            && !fetch::is_url(&source)
+        {
+            self.render(file, &source);
+        }
+    }
+
+    /// Renders the [`Template`][FileKind::Template] `file` from `source`,
+    /// leaving it without content if that fails.
+    fn render(&self, file: &mut FileWithMetadata, source: &Path) {
+        file.rendered = render_template(source, &self.variables)
+            .inspect_err(|err| {
+                warn!(
+                    "Failed to render template '{}'\n{:?}",
+                    source.display(),
+                    err
+                );
+            })
+            .ok();
+    }
+
+    /// Like [`prepare`][Self::prepare], for an entry of an old manifest
+    /// about to be replaced: a [`Copy`][FileKind::Copy] or
+    /// [`Template`][FileKind::Template] without a
+    /// [`target_hash`][File::target_hash] counts as unmodified if it has what
+    /// was last written to it, as kept in [`Options::installed`], even if its
+    /// source has changed or is gone since.
+    fn prepare_installed(&self, file: &File, options: &Options) -> FileWithMetadata {
+        let mut prepared = self.prepare(file, options);
+        if matches!(file.kind, FileKind::Copy | FileKind::Template)
+            && let Some(ref installed) = options.installed
+        {
+            prepared.target_hash = prepared.target_hash.or_else(|| installed.get(&file.target));
+        }
+        prepared
+    }
+
+    /// Returns the existing backups that [`activate`][Self::activate] or
+    /// [`diff`][Self::diff] may have made of the targets in this manifest
+    /// with `prefix`, including those of the links in a
+    /// [`LinkFarm`][FileKind::LinkFarm].
+    #[must_use]
+    pub fn backups(&self, prefix: &str) -> Vec<PathBuf> {
+        let mut targets = Vec::new();
+        for file in &self.files {
+            targets.push(file.target.clone());
+            if file.kind == FileKind::LinkFarm
+                && let Some(ref source) = file.source
+            {
+                targets.extend(
+                    walk_files(source)
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|relative| file.target.join(relative)),
+                );
+            }
+        }
+        targets
+            .iter()
+            .filter_map(|target| backups_of(target, prefix).ok())
+            .flatten()
+            .collect()
+    }
+
+    /// Returns what is inside the targets of [`Directory`][FileKind::Directory]
+    /// entries without being accounted for by any entry, such as leftovers of
+    /// removed entries. Backups with `prefix` are accounted for, and so is
+    /// everything under a [`RecursiveCopy`][FileKind::RecursiveCopy] or
+    /// [`LinkFarm`][FileKind::LinkFarm]. A directory with nothing accounted
+    /// for in it is returned rather than its contents. Directories that can't
+    /// be read are skipped with a warning.
+    #[must_use]
+    pub fn orphans(&self, prefix: &str) -> Vec<PathBuf> {
+        fn walk(
+            dir: &Path,
+            targets: &HashSet<&Path>,
+            ancestors: &HashSet<&Path>,
+            prefix: &str,
+            orphans: &mut Vec<PathBuf>,
+        ) {
+            let entries = match fs::read_dir(dir) {
+                Ok(x) => x,
+                Err(err) => {
+                    warn!("Failed to read '{}': {err}", dir.display());
+                    return;
+                }
+            };
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if targets.contains(path.as_path())
+                    || entry.file_name().to_string_lossy().starts_with(prefix)
+                {
+                    continue;
+                }
+                if ancestors.contains(path.as_path()) && entry.file_type().is_ok_and(|x| x.is_dir())
+                {
+                    walk(&path, targets, ancestors, prefix, orphans);
+                } else {
+                    orphans.push(path);
+                }
+            }
+        }
+
+        let targets: HashSet<&Path> = self.files.iter().map(|x| x.target.as_path()).collect();
+        let ancestors: HashSet<&Path> =
+            targets.iter().flat_map(|x| x.ancestors().skip(1)).collect();
+        let mut orphans = Vec::new();
+        for file in &self.files {
+            if file.kind == FileKind::Directory && file.target.is_dir() {
+                walk(&file.target, &targets, &ancestors, prefix, &mut orphans);
+            }
+        }
+        orphans.sort();
+        orphans
+    }
+
+    /// Compares every file in the manifest against the filesystem without
+    /// modifying anything, on [`Options::jobs`] threads. Returns the
+    /// [`FileState`] of each target, or the error encountered while
+    /// inspecting it.
+    #[must_use]
+    pub fn states(&self, options: &Options) -> Vec<(PathBuf, Result<FileState>)> {
+        map_parallel(&self.files, options, |file| {
+            let mut file = self.prepare(file, options);
+            let state = file.set_metadata().and_then(|()| file.state());
+            (file.target, state)
+        })
+    }
+
+    /// Activates every file in the manifest, applying them to the filesystem in
+    /// dependency order, then runs [`on_activate`][Self::on_activate]. With
+    /// more than one of [`Options::jobs`], see
+    /// [`activate_parallel`][Self::activate_parallel]. Returns per-file
+    /// failures, and that of the hook under its name; the caller decides
+    /// whether any failure is fatal. Nothing is done if one of
+    /// [`Options::wait_for`] doesn't appear.
+    pub fn activate(&mut self, options: &Options) -> Vec<(PathBuf, color_eyre::Report)> {
+        let mut waited = wait(options);
+        waited.extend(set_umask(self.umask, options));
+        if !waited.is_empty() {
+            return waited;
+        }
+        self.select(options);
+        if let Some(failure) = sandbox(&[self], options) {
+            return vec![failure];
+        }
+        self.activate_selected(options)
+    }
+
+    /// Does the work of [`activate`][Self::activate] once the entries are
+    /// [selected][Self::select].
+    fn activate_selected(&mut self, options: &Options) -> Vec<(PathBuf, color_eyre::Report)> {
+        let protected = self.check_protected(options, false);
+        if !protected.is_empty() {
+            return refuse(protected);
+        }
+        let phase = options.report.phase("activate");
+        let mut failures = self.activate_files(options);
+        drop(phase);
+        if options.fail_fast && !failures.is_empty() {
+            return failures;
+        }
+        let _phase = options.report.phase("hooks");
+        failures.extend(manifest_hook(
+            self.on_activate.as_deref(),
+            "on_activate",
+            options,
+        ));
+        failures
+    }
+
+    fn activate_files(&mut self, options: &Options) -> Vec<(PathBuf, color_eyre::Report)> {
+        depends::sort(&mut self.files);
+        if options.jobs != 1 {
+            return self.activate_parallel(options);
+        }
+        self.activate_all(&self.files, &AtomicBool::new(false), options)
+    }
+
+    /// Activates `files` one after the other. With [`Options::fail_fast`],
+    /// stops once `failed` is set, which the first failure does, possibly on
+    /// another thread.
+    fn activate_all<'a>(
+        &self,
+        files: impl IntoIterator<Item = &'a File>,
+        failed: &AtomicBool,
+        options: &Options,
+    ) -> Vec<(PathBuf, color_eyre::Report)> {
+        let mut failures = Vec::new();
+        for file in files {
+            if options.fail_fast && failed.load(atomic::Ordering::Relaxed) {
+                break;
+            }
+            if let Some(failure) = self.activate_file(file, options) {
+                failed.store(true, atomic::Ordering::Relaxed);
+                failures.push(failure);
+            }
+        }
+        failures
+    }
+
+    /// Activates `file`, returning its target and the error if it fails.
+    fn activate_file(
+        &self,
+        file: &File,
+        options: &Options,
+    ) -> Option<(PathBuf, color_eyre::Report)> {
+        let mut file = self.prepare(file, options);
+        self.load(&mut file, options);
+        file.activate(self.clobber_by_default, options)
+            .inspect_err(|err| {
+                error!(
+                    "Failed to activate file: '{}'\n{:?}",
+                    file.target.display(),
+                    err
+                );
+            })
+            .err()
+            .map(|err| (file.target, err))
+    }
+
+    /// Activates the sorted files on a pool of [`Options::jobs`] threads, one
+    /// [batch][depends::batches] at a time, see
+    /// [`activate_batch`][Self::activate_batch].
+    fn activate_parallel(&self, options: &Options) -> Vec<(PathBuf, color_eyre::Report)> {
+        let pool = match rayon::ThreadPoolBuilder::new()
+            .num_threads(options.jobs)
+            .build()
+        {
+            Ok(pool) => pool,
+            Err(err) => {
+                warn!("Failed to start thread pool, activating sequentially\n{err:?}");
+                return self.activate_all(&self.files, &AtomicBool::new(false), options);
+            }
+        };
+
+        let failed = AtomicBool::new(false);
+        depends::batches(&self.files)
+            .into_iter()
+            .flat_map(|batch| self.activate_batch(batch, &pool, &failed, options))
+            .collect()
+    }
+
+    /// Directories are created first and `Modify` and `Delete` entries are
+    /// applied last, one at a time. Everything in between is grouped by the
+    /// parent of its outermost managed ancestor, so that nested targets stay
+    /// in order, and the groups are activated concurrently on `pool`.
+    fn activate_batch(
+        &self,
+        batch: &[File],
+        pool: &rayon::ThreadPool,
+        failed: &AtomicBool,
+        options: &Options,
+    ) -> Vec<(PathBuf, color_eyre::Report)> {
+        let (directories, rest): (Vec<&File>, Vec<&File>) = batch
+            .iter()
+            .partition(|file| file.kind == FileKind::Directory);
+        let (last, rest): (Vec<&File>, Vec<&File>) = rest
+            .into_iter()
+            .partition(|file| matches!(file.kind, FileKind::Modify | FileKind::Delete));
+
+        let managed: HashSet<&Path> = rest.iter().map(|file| file.target.as_path()).collect();
+        let mut groups: BTreeMap<&Path, Vec<&File>> = BTreeMap::new();
+        for file in rest {
+            let outermost = file
+                .target
+                .ancestors()
+                .filter(|ancestor| managed.contains(ancestor))
+                .last()
+                .unwrap_or(&file.target);
+            groups
+                .entry(outermost.parent().unwrap_or(outermost))
+                .or_default()
+                .push(file);
+        }
+
+        let mut failures = self.activate_all(directories, failed, options);
+        failures.extend(pool.install(|| {
+            groups
+                .into_par_iter()
+                .flat_map_iter(|(_, files)| self.activate_all(files, failed, options))
+                .collect::<Vec<_>>()
+        }));
+        failures.extend(self.activate_all(last, failed, options));
+        failures
+    }
 
     /// Removes every file in the manifest from the filesystem in reverse
-    /// dependency order. Returns per-file failures; the caller decides whether
-    /// any failure is fatal.
-    pub fn deactivate(&mut self) -> Vec<(PathBuf, color_eyre::Report)> {
-        self.files.sort();
+    /// dependency order, then runs [`on_deactivate`][Self::on_deactivate].
+    /// Returns per-file failures, and that of the hook under its name; the
+    /// caller decides whether any failure is fatal. Nothing is done if one of
+    /// [`Options::wait_for`] doesn't appear.
+    pub fn deactivate(&mut self, options: &Options) -> Vec<(PathBuf, color_eyre::Report)> {
+        let mut waited = wait(options);
+        waited.extend(set_umask(self.umask, options));
+        if !waited.is_empty() {
+            return waited;
+        }
+        self.select(options);
+        if let Some(failure) = sandbox(&[self], options) {
+            return vec![failure];
+        }
+        let protected = self.check_protected(options, true);
+        if !protected.is_empty() {
+            return refuse(protected);
+        }
+        let phase = options.report.phase("deactivate");
+        let mut failures = self.deactivate_files(options);
+        drop(phase);
+        if options.fail_fast && !failures.is_empty() {
+            return failures;
+        }
+        let _phase = options.report.phase("hooks");
+        failures.extend(manifest_hook(
+            self.on_deactivate.as_deref(),
+            "on_deactivate",
+            options,
+        ));
+        failures
+    }
+
+    fn deactivate_files(&mut self, options: &Options) -> Vec<(PathBuf, color_eyre::Report)> {
+        depends::sort(&mut self.files);
         let mut failures = Vec::new();
-        for mut file in self.files.iter().map(FileWithMetadata::from).rev() {
-            if let Err(err) = file.deactivate() {
+        for mut file in self
+            .files
+            .iter()
+            .map(|file| self.prepare(file, options))
+            .rev()
+        {
+            self.load(&mut file, options);
+            if let Err(err) = file.deactivate(options) {
                 error!(
                     "Failed to deactivate file: '{}'\n{:?}",
                     file.target.display(),
                     err
                 );
                 failures.push((file.target.clone(), err));
+                if options.fail_fast {
+                    break;
+                }
             }
         }
         failures
     }
 
-    /// Brings the filesystem from the state described by the manifest at
-    /// `old_path` to the state described by `self`. Files removed from the
-    /// new manifest are deactivated; files added or updated are
-    /// (re-)activated. If `fallback` is `true` and no old manifest exists,
-    /// falls back to a full activation.
-    ///
-    /// # Errors
-    ///
-    /// Returns a [`DiffError`] if:
-    /// - [`DiffError::OldManifestMissing`]: the old manifest does not exist and
-    ///   `fallback` is `false`
-    /// - [`DiffError::OldManifestRead`]: the old manifest exists but cannot be
-    ///   read
-    /// - [`DiffError::Other`]: probing the old manifest path fails
-    #[allow(clippy::too_many_lines)]
-    pub fn diff(mut self, old_path: &Path, prefix: &str, fallback: bool) -> Result<(), DiffError> {
-        let mut old_manifest = match old_path.try_exists() {
-            Ok(true) => Self::read(old_path, self.impure).map_err(DiffError::OldManifestRead)?,
-            Ok(false) if fallback => {
-                let failures = self.activate(prefix);
-                return if failures.is_empty() {
-                    Ok(())
-                } else {
-                    Err(DiffError::ActivationFailed(
-                        failures
-                            .into_iter()
-                            .map(|(p, e)| (p, format!("{e:?}")))
-                            .collect(),
-                    ))
-                };
-            }
-            Ok(false) => return Err(DiffError::OldManifestMissing),
-            Err(err) => return Err(DiffError::Other(color_eyre::Report::from(err))),
-        };
+    /// Computes what [`diff`][Self::diff] would do to every target without
+    /// modifying anything. Entries are sorted by target.
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as [`diff`][Self::diff] when reading the old
+    /// manifest.
+    pub fn plan(
+        &self,
+        old_path: &Path,
+        options: &Options,
+        fallback: bool,
+    ) -> Result<Vec<PlanEntry>, DiffError> {
+        let old = match self.read_old(old_path, options)? {
+            Some(old) => old,
+            None if fallback => Self::new(Vec::new()),
+            None => return Err(DiffError::OldManifestMissing),
+        };
+        Ok(self.plan_against(old, options))
+    }
+
+    /// Computes what [`activate`][Self::activate] would do to every target
+    /// without modifying anything, as a [`plan`][Self::plan] from nothing.
+    #[must_use]
+    pub fn plan_activation(&self, options: &Options) -> Vec<PlanEntry> {
+        self.plan_against(Self::new(Vec::new()), options)
+    }
+
+    /// Computes what [`deactivate`][Self::deactivate] would do to every
+    /// target without modifying anything, as a [`plan`][Self::plan] to
+    /// nothing.
+    #[must_use]
+    pub fn plan_deactivation(&self, options: &Options) -> Vec<PlanEntry> {
+        Self::new(Vec::new()).plan_against(self.clone(), options)
+    }
+
+    fn plan_against(&self, mut old: Self, options: &Options) -> Vec<PlanEntry> {
+        let mut new = self.clone();
+        new.select(options);
+        old.select(options);
+        let (updated_files, same_files) = new.take_matching(&mut old);
+        let (renamed_files, unrenamed): (Vec<_>, Vec<_>) = new
+            .take_renamed(&mut old)
+            .into_iter()
+            .partition(|(old_file, new_file)| self.can_rename(&old, old_file, new_file, options));
+        for (old_file, new_file) in unrenamed {
+            old.files.push(old_file);
+            new.files.push(new_file);
+        }
+
+        let entry = |file: &File, old_file: Option<&File>, change, (step, reason)| PlanEntry {
+            target: file.target.clone(),
+            kind: file.kind,
+            source: file.source.clone(),
+            old_source: old_file.and_then(|x| x.source.clone()),
+            change,
+            step,
+            reason,
+        };
+        let mut plan: Vec<PlanEntry> = same_files
+            .iter()
+            .map(|file| {
+                entry(
+                    file,
+                    Some(file),
+                    Change::Unchanged,
+                    self.predict(file, options),
+                )
+            })
+            .chain(updated_files.iter().map(|(old_file, new_file)| {
+                entry(
+                    new_file,
+                    Some(old_file),
+                    Change::Updated,
+                    self.predict_update(&old, old_file, new_file, options),
+                )
+            }))
+            .chain(renamed_files.iter().map(|(old_file, new_file)| {
+                entry(
+                    new_file,
+                    Some(old_file),
+                    Change::Renamed,
+                    (Step::Rename, "only the target changed"),
+                )
+            }))
+            .chain(old.files.iter().map(|file| PlanEntry {
+                source: None,
+                ..entry(
+                    file,
+                    Some(file),
+                    Change::Removed,
+                    old.predict_removal(file, options),
+                )
+            }))
+            .chain(
+                new.files
+                    .iter()
+                    .map(|file| entry(file, None, Change::New, self.predict(file, options))),
+            )
+            .collect();
+        plan.sort_by(|left, right| left.target.cmp(&right.target));
+        plan
+    }
+
+    /// Predicts what [`activate`][Self::activate] would do to `file`, and
+    /// why.
+    fn predict(&self, file: &File, options: &Options) -> (Step, &'static str) {
+        let mut file = self.prepare(file, options);
+        if file.check_source() {
+            return (Step::Skip, "source is missing or invalid");
+        }
+        if file.set_metadata().is_err() {
+            return (Step::Skip, "target can't be inspected");
+        }
+        let clobber = options.clobber(file.clobber, self.clobber_by_default);
+
+        match file.metadata {
+            None if file.kind == FileKind::Delete => (Step::Nothing, "target doesn't exist"),
+            None if file.kind == FileKind::Modify => (Step::Skip, "target doesn't exist"),
+            None => (Step::Create, "target doesn't exist"),
+            Some(_) if file.kind == FileKind::Delete => match file.edited() {
+                Ok(true) => (Step::BackUp, "target was modified since activation"),
+                Ok(false) => (Step::Delete, "target exists"),
+                Err(_) => (Step::Skip, "target can't be inspected"),
+            },
+            Some(_) if file.check().unwrap_or(false) => (Step::Nothing, ALREADY_CORRECT),
+            Some(ref metadata)
+                if clobber
+                    && !metadata.is_dir()
+                    && matches!(
+                        file.kind,
+                        FileKind::Symlink
+                            | FileKind::Copy
+                            | FileKind::Template
+                            | FileKind::Hardlink
+                    ) =>
+            {
+                (Step::Swap, "target differs and clobber is set")
+            }
+            Some(_) if file.in_the_way() => {
+                if clobber {
+                    (Step::Clobber, "target is in the way and clobber is set")
+                } else {
+                    (Step::BackUp, "target is in the way")
+                }
+            }
+            Some(_) => (Step::Fix, "target's metadata differs"),
+        }
+    }
+
+    /// Predicts what [`diff`][Self::diff] would do when `old_file` of `old` is
+    /// updated to `new_file`, and why.
+    fn predict_update(
+        &self,
+        old: &Self,
+        old_file: &File,
+        new_file: &File,
+        options: &Options,
+    ) -> (Step, &'static str) {
+        if !replaceable(old_file.kind) || !replaceable(new_file.kind) {
+            return self.predict(new_file, options);
+        }
+        if !options.clobber(old_file.clobber, old.clobber_by_default) {
+            let mut file = old.prepare_installed(old_file, options);
+            if file.set_metadata().is_ok()
+                && file.metadata.is_some()
+                && !file.check().unwrap_or(false)
+            {
+                return (Step::BackUp, "target was modified since activation");
+            }
+        }
+
+        let mut file = self.prepare(new_file, options);
+        match file.set_metadata().map(|()| file.metadata.as_ref()) {
+            Ok(None) => (Step::Create, "target doesn't exist"),
+            Ok(Some(metadata)) if !metadata.is_dir() => (Step::Swap, "entry changed"),
+            _ => self.predict(new_file, options),
+        }
+    }
+
+    /// Predicts what [`deactivate`][Self::deactivate] would do to `file`, and
+    /// why.
+    fn predict_removal(&self, file: &File, options: &Options) -> (Step, &'static str) {
+        if !file.deactivate.unwrap_or(true) {
+            return (Step::Keep, "deactivate is unset");
+        }
+        let mut file = self.prepare(file, options);
+        if file.set_metadata().is_err() {
+            return (Step::Keep, "target can't be inspected");
+        }
+        match file.metadata {
+            None => (Step::Nothing, "target doesn't exist"),
+            Some(_) if matches!(file.kind, FileKind::Delete | FileKind::Modify) => {
+                (Step::Nothing, "entry leaves nothing behind")
+            }
+            Some(_) if file.written_to() || !file.check().unwrap_or(false) => {
+                (Step::Keep, "target was modified since activation")
+            }
+            Some(_) => (Step::Delete, "entry was removed"),
+        }
+    }
+
+    /// Reads the old manifest of [`diff`][Self::diff] and [`plan`][Self::plan]
+    /// from `old_path`, moving it under [`Options::root`]. Returns `None` if
+    /// it does not exist.
+    fn read_old(&self, old_path: &Path, options: &Options) -> Result<Option<Self>, DiffError> {
+        match old_path.try_exists() {
+            Ok(true) => {
+                // The old manifest was applied as it is, duplicates or not
+                let mut old = Self::read_as(
+                    old_path,
+                    Format::from_path(old_path),
+                    self.impure.clone(),
+                    Duplicates::LastWins,
+                    self.user.as_ref(),
+                )
+                .map_err(DiffError::OldManifestRead)?;
+                if let Some(previous) = options.generations.as_ref().and_then(previous_generation) {
+                    old.keep_expanded(&previous);
+                }
+                if let Some(ref root) = options.root {
+                    old.reroot(root);
+                }
+                Ok(Some(old))
+            }
+            Ok(false) => Ok(None),
+            Err(err) => Err(DiffError::Other(color_eyre::Report::from(err))),
+        }
+    }
+
+    /// Returns the installed target of `old`, an entry of this manifest that
+    /// `new` replaces, if it has to be backed up first: it doesn't clobber,
+    /// and was modified since it was activated.
+    fn modified_target(
+        &self,
+        old: &File,
+        new: &File,
+        options: &Options,
+    ) -> Option<FileWithMetadata> {
+        if !replaceable(old.kind)
+            || !replaceable(new.kind)
+            || options.clobber(old.clobber, self.clobber_by_default)
+        {
+            return None;
+        }
+        let mut file = self.prepare_installed(old, options);
+        self.load(&mut file, options);
+
+        // Don't care if this errors
+        // metadata will just be none
+        if let Err(err) = file.set_metadata() {
+            warn!(
+                "Failed to get metadata for file '{}'\n{:?}",
+                file.target.display(),
+                err
+            );
+        }
+
+        let modified = file.metadata.is_some()
+            && !file
+                .check()
+                .inspect_err(|err| {
+                    warn!(
+                        "Failed to check file: '{}', assuming file is incorrect\n{:?}",
+                        file.target.display(),
+                        err
+                    );
+                })
+                .unwrap_or(false);
+        modified.then_some(file)
+    }
+
+    /// Asks [`Options::confirm`] whether the target of `old`, modified since
+    /// it was activated, may be backed up to make way for `new`.
+    fn confirm_backup(&self, new: &File, old: &FileWithMetadata, options: &Options) -> bool {
+        if options.confirm.is_none() {
+            return true;
+        }
+        let mut wanted = self.prepare(new, options);
+        wanted.metadata.clone_from(&old.metadata);
+        wanted.confirm(Action::BackedUp, options)
+    }
+
+    /// Moves the files that also appear in `old` out of both manifests.
+    /// Returns the `(old, new)` pairs of files whose target stayed the same
+    /// but which changed otherwise, and the files that did not change at all.
+    /// See [`updatable`] for which changes count as updates; other changed
+    /// files count as removed and added.
+    fn take_matching(&mut self, old: &mut Self) -> (Vec<(File, File)>, Vec<File>) {
+        let same_files = self
+            .take_pairs(old, None, by_target, |old, new| old == new)
+            .into_iter()
+            .map(|(_, new)| new)
+            .collect();
+        let updated_files = self.take_pairs(old, None, by_target, |old, new| {
+            updatable(old.kind, new.kind)
+        });
+        (updated_files, same_files)
+    }
+
+    /// Moves the files of `old` that a file of `self` of the same kind
+    /// [`replaces`][File::replaces], or whose kind and source reappear in
+    /// `self` under another target, out of both manifests, returning the
+    /// `(old, new)` pairs. Only symlinks, copies, templates, and hard links
+    /// are paired.
+    fn take_renamed(&mut self, old: &mut Self) -> Vec<(File, File)> {
+        // Functions rather than closures, for the key to borrow from the file
+        fn replaces(file: &File) -> Option<&Path> {
+            file.replaces.as_deref()
+        }
+        fn source(file: &File) -> Option<&Path> {
+            file.source.as_deref().filter(|_| replaceable(file.kind))
+        }
+        let mut pairs = self.take_pairs(old, Some(replaces), by_target, |old, new| {
+            old.kind == new.kind && replaceable(old.kind)
+        });
+        pairs.extend(self.take_pairs(old, Some(source), source, |old, new| old.kind == new.kind));
+        pairs
+    }
+
+    /// Moves the files of `old` that `pair` accepts together with a file of
+    /// `self` with the same key, `new_key` of the latter, or its target if
+    /// `None`, and `old_key` of the former, out of both manifests, returning
+    /// the `(old, new)` pairs. Files without a key are never paired. The
+    /// files of `self` are looked up by key, so that a manifest of thousands
+    /// of entries is not compared pair by pair, and are moved rather than
+    /// cloned.
+    fn take_pairs(
+        &mut self,
+        old: &mut Self,
+        new_key: Option<fn(&File) -> Option<&Path>>,
+        old_key: impl for<'a> Fn(&'a File) -> Option<&'a Path>,
+        pair: impl Fn(&File, &File) -> bool,
+    ) -> Vec<(File, File)> {
+        // Targets are already indexed
+        let by_key = new_key.map(|new_key| {
+            let mut index: BTreeMap<&Path, Vec<usize>> = BTreeMap::new();
+            for (i, file) in self.files.iter().enumerate() {
+                if let Some(key) = new_key(file) {
+                    index.entry(key).or_default().push(i);
+                }
+            }
+            index
+        });
+        let candidates = |key: &Path| match by_key {
+            Some(ref index) => index.get(key).map_or(&[][..], Vec::as_slice),
+            None => self.files.positions(key),
+        };
+
+        // Which files of each are paired, and the index of the new file
+        // paired with each old one, in order
+        let mut new_paired = vec![false; self.files.len()];
+        let mut old_paired = vec![false; old.files.len()];
+        let mut matches = Vec::new();
+        for (i, file) in old.files.iter().enumerate() {
+            let Some(key) = old_key(file) else {
+                continue;
+            };
+            if let Some(&j) = candidates(key)
+                .iter()
+                .find(|&&j| !new_paired[j] && pair(file, &self.files[j]))
+            {
+                new_paired[j] = true;
+                old_paired[i] = true;
+                matches.push(j);
+            }
+        }
+        drop(by_key);
+        if matches.is_empty() {
+            return Vec::new();
+        }
+
+        // Both are moved out in place rather than into new lists, and the new
+        // files, which come out in their own order, are first put in that of
+        // the old ones they are paired with
+        let mut order: Vec<usize> = (0..matches.len()).collect();
+        order.sort_unstable_by_key(|&k| matches[k]);
+        let mut new_files: Vec<Option<File>> =
+            iter::repeat_with(|| None).take(matches.len()).collect();
+        let mut j = 0;
+        self.files.update(|files| {
+            let paired_new = files.extract_if(.., |_| {
+                j += 1;
+                new_paired[j - 1]
+            });
+            for (file, k) in paired_new.zip(order) {
+                new_files[k] = Some(file);
+            }
+            files.shrink_to_fit();
+        });
+
+        let mut i = 0;
+        old.files.update(|files| {
+            let pairs = files
+                .extract_if(.., |_| {
+                    i += 1;
+                    old_paired[i - 1]
+                })
+                .zip(new_files.iter_mut())
+                .filter_map(|(old, new)| Some((old, new.take()?)))
+                .collect();
+            files.shrink_to_fit();
+            pairs
+        })
+    }
+
+    /// Whether [`diff`][Self::diff] can [rename][Self::rename] the target of
+    /// `old_file` of `old` to that of `new_file`. The old target must exist,
+    /// still be what `old_file` made it rather than something edited or put
+    /// there by hand, and may be deactivated without restoring a backup, and
+    /// the new target must not exist.
+    fn can_rename(&self, old: &Self, old_file: &File, new_file: &File, options: &Options) -> bool {
+        if !old_file.deactivate.unwrap_or(true)
+            || options.restore_backups
+            || old_file.restore_backup.unwrap_or(false)
+        {
+            return false;
+        }
+        let mut from = old.prepare(old_file, options);
+        let mut to = self.prepare(new_file, options);
+        from.set_metadata().is_ok()
+            && from.metadata.as_ref().is_some_and(|x| !x.is_dir())
+            && from.check().unwrap_or(false)
+            && !to.check_source()
+            && to.set_metadata().is_ok()
+            && to.metadata.is_none()
+    }
+
+    /// Moves the target of `old_file` of `old` to that of `new_file`, and fixes
+    /// its permissions and the like. If it still doesn't match `new_file`,
+    /// e.g. for a new source, `false` is returned, for it to be activated
+    /// after over what [`can_rename`][Self::can_rename] made sure is only
+    /// what `old_file` made.
+    fn rename(
+        &self,
+        old: &Self,
+        old_file: &File,
+        new_file: &File,
+        options: &Options,
+    ) -> Result<bool> {
+        let mut old_file = old.prepare(old_file, options);
+        old_file.set_metadata()?;
+        old_file.unlock()?;
+        let from = old_file.target;
+        let mut file = self.prepare(new_file, options);
+        info!(
+            "Renaming '{}' -> '{}'",
+            from.display(),
+            file.target.display()
+        );
+        mkdir(
+            file.target
+                .parent()
+                .ok_or_eyre("Failed to get parent directory")?,
+        )?;
+        fs::rename(&from, &file.target)
+            .wrap_err_with(|| format!("Failed to rename to '{}'", file.target.display()))?;
+        self.load(&mut file, options);
+        if let Some(ref journal) = options.journal {
+            journal.rename(&from, &file.target)?;
+        }
+        file.chmod_chown()?;
+        file.lock()?;
+        file.sync()?;
+        if file.fsync
+            && let Some(parent) = from.parent().filter(|x| Some(*x) != file.target.parent())
+        {
+            sys::Dir::open(parent, file.resolve)?.sync()?;
+        }
+        options.report.record(
+            &file.target,
+            Action::Renamed,
+            Some(format!("from '{}'", from.display())),
+        );
+        if let Some(ref installed) = options.installed
+            && let Some(checksum) = installed.remove(&from)
+        {
+            installed.insert(&file.target, checksum);
+        }
+
+        file.set_metadata()?;
+        Ok(file.check().unwrap_or(false))
+    }
+
+    /// Brings the filesystem from the state described by the manifest at
+    /// `old_path` to the state described by `self`. Files removed from the
+    /// new manifest are deactivated; files added or updated are
+    /// (re-)activated. If `fallback` is `true` and no old manifest exists,
+    /// falls back to a full activation. With [`Options::dry_run`], only logs
+    /// what would be done. Nothing is done if one of [`Options::wait_for`]
+    /// doesn't appear.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`DiffError`] if:
+    /// - [`DiffError::OldManifestMissing`]: the old manifest does not exist and
+    ///   `fallback` is `false`
+    /// - [`DiffError::OldManifestRead`]: the old manifest exists but cannot be
+    ///   read
+    /// - [`DiffError::Other`]: probing the old manifest path fails
+    pub fn diff(
+        mut self,
+        old_path: &Path,
+        options: &Options,
+        fallback: bool,
+    ) -> Result<(), DiffError> {
+        let mut waited = wait(options);
+        waited.extend(set_umask(self.umask, options));
+        if !waited.is_empty() {
+            return Err(DiffError::ActivationFailed(
+                waited
+                    .into_iter()
+                    .map(|(p, e)| (p, format!("{e:?}")))
+                    .collect(),
+            ));
+        }
+        let Some(old_manifest) = self.read_old(old_path, options)? else {
+            if !fallback {
+                return Err(DiffError::OldManifestMissing);
+            }
+            let failures = self.activate(options);
+            return if failures.is_empty() {
+                Ok(())
+            } else {
+                Err(DiffError::ActivationFailed(
+                    failures
+                        .into_iter()
+                        .map(|(p, e)| (p, format!("{e:?}")))
+                        .collect(),
+                ))
+            };
+        };
+        self.diff_with(old_manifest, options)
+    }
+
+    /// Does the work of [`diff`][Self::diff] against `old_manifest`, which
+    /// has already been read, e.g. by a caller that keeps it in memory.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`DiffError`] if entries fail or would touch protected
+    /// paths.
+    #[allow(clippy::too_many_lines)]
+    pub fn diff_with(mut self, mut old_manifest: Self, options: &Options) -> Result<(), DiffError> {
+        // Entries that aren't selected are left alone, even if they changed
+        self.select(options);
+        old_manifest.select(options);
+        if let Some((_, err)) = sandbox(&[&self, &old_manifest], options) {
+            return Err(DiffError::Other(err));
+        }
+        let (updated_files, same_files) = self.take_matching(&mut old_manifest);
+        let renamed_files = self.take_renamed(&mut old_manifest);
+
+        let mut protected = old_manifest.check_protected(options, true);
+        protected.extend(
+            renamed_files
+                .iter()
+                .filter_map(|(old, _)| protect::check(old, &options.protected, true).err()),
+        );
+        protected.extend(
+            self.files
+                .iter()
+                .chain(updated_files.iter().map(|(_, new)| new))
+                .chain(renamed_files.iter().map(|(_, new)| new))
+                .filter_map(|file| protect::check(file, &options.protected, false).err()),
+        );
+        if !protected.is_empty() {
+            return Err(DiffError::Protected(protected));
+        }
+
+        // Before deactivating, so that directories the old targets were in
+        // can be removed
+        let mut failures: Vec<(PathBuf, String)> = vec![];
+        let phase = options.report.phase("rename");
+        for (old, mut new) in renamed_files {
+            if !self.can_rename(&old_manifest, &old, &new, options) {
+                old_manifest.files.push(old);
+                self.files.push(new);
+                continue;
+            }
+            if options.dry_run {
+                info!(
+                    "Would rename '{}' -> '{}'",
+                    old.target.display(),
+                    new.target.display()
+                );
+                continue;
+            }
+            match self.rename(&old_manifest, &old, &new, options) {
+                Ok(false) => {
+                    // Stale rather than edited, so nothing is lost
+                    new.clobber = Some(true);
+                    self.files.push(new);
+                }
+                Ok(true) => {
+                    if let Some(ref command) = new.on_activate
+                        && let Err(err) = run_hook(command, Some(&new.target), "on_activate")
+                    {
+                        error!("{err:?}");
+                        failures.push((new.target.clone(), format!("{err:?}")));
+                    }
+                }
+                Err(err) => {
+                    warn!(
+                        "Failed to rename to '{}', deactivating and activating instead\n{:?}",
+                        new.target.display(),
+                        err
+                    );
+                    old_manifest.files.push(old);
+                    self.files.push(new);
+                }
+            }
+        }
+
+        drop(phase);
+
+        // Remove files in old manifest
+        // which aren't in new manifest
+        if options.fail_fast && !failures.is_empty() {
+            return Err(DiffError::ActivationFailed(failures));
+        }
+        let phase = options.report.phase("deactivate");
+        failures.extend(
+            old_manifest
+                .deactivate_files(options)
+                .into_iter()
+                .map(|(p, e)| (p, format!("{e:?}"))),
+        );
+        drop(phase);
+
+        let phase = options.report.phase("update");
+        // Checking the old targets is what takes long, so it is done for
+        // all of them at once
+        let modified = map_parallel(&updated_files, options, |(old, new)| {
+            old_manifest.modified_target(old, new, options)
+        });
+        for ((old, new), modified) in updated_files.into_iter().zip(modified) {
+            if options.fail_fast && !failures.is_empty() {
+                return Err(DiffError::ActivationFailed(failures));
+            }
+            // Nothing of the old entry needs to be undone, so the new one is
+            // just activated over it
+            if !replaceable(old.kind) || !replaceable(new.kind) {
+                self.files.push(new);
+                continue;
+            }
+
+            if let Some(file) = modified {
+                if options.show_diff {
+                    self.prepare(&new, options).show_diff(options);
+                }
+                if options.dry_run {
+                    info!("Would back up '{}'", file.target.display());
+                } else if !self.confirm_backup(&new, &file, options) {
+                    options.report.record(
+                        &file.target,
+                        Action::Skipped,
+                        Some(String::from("declined")),
+                    );
+                    continue;
+                } else if let Err(err) = file.back_up(options) {
+                    warn!(
+                        "Failed to backup file '{}'\n{:?}",
+                        file.target.display(),
+                        err
+                    );
+                } else {
+                    options.report.record(
+                        &file.target,
+                        Action::BackedUp,
+                        Some(String::from(
+                            "modified since the old manifest was activated",
+                        )),
+                    );
+                }
+                // if file existed but was wrong,
+                // atomic action cannot be taken
+                // so there's no point of forcing clobber
+
+                // except this double checks
+                self.files.push(new);
+                continue;
+            }
+
+            let mut atomic = self.prepare(&new, options);
+
+            if let Err(err) = atomic.set_metadata() {
+                warn!(
+                    "Failed to get metadata for file '{}'\n{:?}",
+                    atomic.target.display(),
+                    err
+                );
+                continue;
+            }
+
+            if atomic.metadata.is_none() {
+                self.files.push(new);
+                continue;
+            }
+
+            atomic.show_diff(options);
+            if options.dry_run {
+                info!("Would atomically replace '{}'", new.target.display());
+                continue;
+            }
+            self.load(&mut atomic, options);
+
+            let res = atomic
+                .swappable()
+                .and_then(|swappable| {
+                    if swappable {
+                        atomic.unlock()?;
+                        atomic.journal_change(options)?;
+                    }
+                    atomic.atomic_activate()
+                })
+                .and_then(|replaced| {
+                    if replaced {
+                        atomic.lock()?;
+                    }
+                    Ok(replaced)
+                })
+                .inspect_err(|err| {
+                    error!(
+                        "Failed to (atomic) activate file: '{}'\n{:?}",
+                        new.target.display(),
+                        err
+                    );
+                });
+            if res.unwrap_or(false) {
+                options.report.add_copied(atomic.copied_bytes());
+                atomic.remember(Action::Clobbered, None, options);
+                options.report.record(
+                    &new.target,
+                    Action::Clobbered,
+                    Some(String::from("replaced atomically")),
+                );
+                if let Some(ref command) = new.on_activate
+                    && let Err(err) = run_hook(command, Some(&new.target), "on_activate")
+                {
+                    error!("{err:?}");
+                    failures.push((new.target.clone(), format!("{err:?}")));
+                }
+            } else {
+                self.files.push(new);
+            }
+        }
+
+        drop(phase);
+
+        // These files could technically just be
+        // Verified
+        self.files.extend(same_files);
+        if options.fail_fast && !failures.is_empty() {
+            return Err(DiffError::ActivationFailed(failures));
+        }
+        // Activate new files
+        failures.extend(
+            self.activate_selected(options)
+                .into_iter()
+                .map(|(p, e)| (p, format!("{e:?}"))),
+        );
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(DiffError::ActivationFailed(failures))
+        }
+    }
+}
+
+/// Reads the latest of `generations`, if there is one, warning if it can't be
+/// read.
+fn previous_generation(generations: &Generations) -> Option<Manifest> {
+    let (_, path) = generations.list().map_err(|e| warn!("{e:?}")).ok()?.pop()?;
+    Manifest::read(&path, false)
+        .map_err(|e| warn!("Failed to read generation '{}': {e:?}", path.display()))
+        .ok()
+}
+
+/// Key of [`Manifest::take_pairs`] pairing files by target, a function
+/// rather than a closure for it to borrow from the file.
+#[allow(clippy::unnecessary_wraps)]
+fn by_target(file: &File) -> Option<&Path> {
+    Some(&file.target)
+}
+
+/// Whether [`Manifest::diff`] can swap a target of `kind` for its new
+/// version atomically.
+const fn replaceable(kind: FileKind) -> bool {
+    matches!(
+        kind,
+        FileKind::Symlink | FileKind::Copy | FileKind::Template | FileKind::Hardlink
+    )
+}
+
+/// Whether [`Manifest::diff`] can update an entry of `old` kind to one of
+/// `new` kind on the same target, rather than deactivating the old entry
+/// first. Directories are only updated by directories, since removing
+/// one is what would make way for anything else. An old modify or delete
+/// entry left nothing to undo, and a new one works on whatever is there.
+const fn updatable(old: FileKind, new: FileKind) -> bool {
+    match (old, new) {
+        (FileKind::Modify | FileKind::Delete, _)
+        | (_, FileKind::Modify | FileKind::Delete)
+        | (FileKind::Directory, FileKind::Directory) => true,
+        (old, new) => replaceable(old) && replaceable(new),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cas::ContentCache,
+        filter::Filter,
+        installed::Installed,
+    };
+    use std::{
+        io::{
+            self,
+            Write as _,
+        },
+        path::PathBuf,
+    };
+
+    fn file(kind: FileKind, target: &str) -> File {
+        File::new(kind, PathBuf::from(target))
+    }
+
+    fn write_manifest(content: &str) -> tempfile::NamedTempFile {
+        let mut f = tempfile::NamedTempFile::new().unwrap();
+        write!(f, "{content}").unwrap();
+        f
+    }
+
+    #[test]
+    fn read_rejects_future_version() {
+        let f = write_manifest(r#"{"files":[],"version":9999}"#);
+        assert!(matches!(
+            Manifest::read(f.path(), false),
+            Err(ReadError::VersionTooNew { manifest: 9999 })
+        ));
+        // Told even when the rest no longer deserializes
+        let f = write_manifest(r#"{"files":{"renamed":[]},"version":9999}"#);
+        assert!(matches!(
+            Manifest::read(f.path(), false),
+            Err(ReadError::VersionTooNew { manifest: 9999 })
+        ));
+    }
+
+    #[test]
+    fn read_valid_empty_manifest() {
+        let f = write_manifest(r#"{"files":[],"version":3}"#);
+        let m = Manifest::read(f.path(), false).unwrap();
+        assert!(m.files.is_empty());
+        assert_eq!(m.version, VERSION);
+    }
+
+    #[test]
+    fn read_upgrades_older_manifests() {
+        let old = r#"{"files":[{"type":"recursiveCopy","source":"/s","target":"/t"},{"type":"directory","target":"/d"}],"version":3}"#;
+        let f = write_manifest(old);
+        let m = Manifest::read(f.path(), false).unwrap();
+        assert_eq!(m.version, VERSION);
+        assert_eq!(
+            m.files.iter().map(|x| x.kind).collect::<Vec<_>>(),
+            [FileKind::RecursiveCopy, FileKind::Directory]
+        );
+
+        // Also when it can only be read once
+        #[cfg(unix)]
+        {
+            let dir = tempfile::tempdir().unwrap();
+            let pipe = dir.path().join("manifest.json");
+            sys::Dir::open(dir.path(), sys::Resolve::Follow)
+                .unwrap()
+                .mknod(OsStr::new("manifest.json"), sys::Node::Fifo, 0o600)
+                .unwrap();
+            let writer = {
+                let pipe = pipe.clone();
+                std::thread::spawn(move || fs::write(pipe, old).unwrap())
+            };
+            let piped = Manifest::read(&pipe, false).unwrap();
+            writer.join().unwrap();
+            assert_eq!(piped.files, m.files);
+        }
+    }
+
+    #[test]
+    fn read_toml_manifest() {
+        let mut f = tempfile::Builder::new().suffix(".toml").tempfile().unwrap();
+        write!(
+            f,
+            "version = 3\n[[files]]\ntype = \"directory\"\ntarget = \"/tmp/x\"\npermissions = \"700\"\n"
+        )
+        .unwrap();
+        let m = Manifest::read(f.path(), false).unwrap();
+        assert_eq!(m.files[0].kind, FileKind::Directory);
+        assert_eq!(m.files[0].permissions, Some(0o700));
+    }
+
+    #[test]
+    fn read_yaml_manifest() {
+        let f = write_manifest(
+            "version: 3\nfiles:\n  - type: symlink\n    source: /tmp/y\n    target: /tmp/x\n",
+        );
+        let m =
+            Manifest::read_as(f.path(), Format::Yaml, Impure::No, Duplicates::Error, None).unwrap();
+        assert_eq!(m.files[0].kind, FileKind::Symlink);
+        assert_eq!(m.files[0].source, Some(PathBuf::from("/tmp/y")));
+    }
+
+    #[test]
+    fn impure_substitutes_allowed_variables_only() {
+        let path = env::var("PATH").unwrap();
+        let allowed = Impure::Variables(vec![String::from("PATH")]);
+        assert_eq!(
+            allowed.expand(Path::new("$PATH/x"), None).unwrap(),
+            Path::new(&path).join("x")
+        );
+        assert!(allowed.expand(Path::new("$HOME/x"), None).is_err());
+        assert!(allowed.expand(Path::new("~/x"), None).is_err());
+        assert!(
+            Impure::AnyVariable
+                .expand(Path::new("$HOME/x"), None)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn read_for_user() {
+        let f = write_manifest(
+            r#"{"version": 3, "files": [
+                {"type": "symlink", "source": "$HOME/a", "target": "~/b"},
+                {"type": "directory", "target": "$HOME/$C", "uid": 0},
+                {"type": "delete", "target": "/tmp/d"},
+                {"type": "modify", "target": "/tmp/m", "permissions": "600"}
+            ]}"#,
+        );
+        let user = sys::User {
+            name: String::from("u"),
+            uid: 1000,
+            gid: 100,
+            home: PathBuf::from("/home/u"),
+        };
+        let m = Manifest::read_as(
+            f.path(),
+            Format::Json,
+            Impure::No,
+            Duplicates::Error,
+            Some(&user),
+        )
+        .unwrap();
+        assert_eq!(m.files[0].source, Some(PathBuf::from("/home/u/a")));
+        assert_eq!(m.files[0].target, Path::new("/home/u/b"));
+        assert_eq!((m.files[0].uid, m.files[0].gid), (Some(1000), Some(100)));
+        assert_eq!(m.files[1].target, Path::new("/home/u/$C"));
+        assert_eq!((m.files[1].uid, m.files[1].gid), (Some(0), None));
+        assert_eq!((m.files[2].uid, m.files[2].gid), (None, None));
+        assert_eq!((m.files[3].uid, m.files[3].gid), (None, None));
+    }
+
+    #[test]
+    fn generate_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = dir.path().join("tree");
+        fs::create_dir_all(tree.join("sub")).unwrap();
+        fs::write(tree.join("sub/file"), "hello").unwrap();
+        sys::set_mode(&tree.join("sub/file"), 0o640).unwrap();
+        sys::symlink(Path::new("sub/file"), &tree.join("link")).unwrap();
+
+        let mut manifest = Manifest::generate(&tree, Path::new("/home/user")).unwrap();
+        let tree = fs::canonicalize(&tree).unwrap();
+        let [link, copy] = &manifest.files[..] else {
+            panic!("expected two entries, got {:?}", manifest.files);
+        };
+        assert_eq!(link.kind, FileKind::Symlink);
+        assert_eq!(link.target, Path::new("/home/user/link"));
+        assert_eq!(link.source, Some(tree.join("sub/file")));
+        assert_eq!(copy.kind, FileKind::Copy);
+        assert_eq!(copy.target, Path::new("/home/user/sub/file"));
+        assert_eq!(copy.source, Some(tree.join("sub/file")));
+        assert_eq!(copy.permissions, Some(0o640));
+        // Differs from unset for some kinds, so must survive the round trip
+        manifest
+            .files
+            .update(|files| files[0].follow_symlinks = Some(true));
+
+        for format in [Format::Json, Format::Toml, Format::Yaml, Format::Cbor] {
+            let path = dir.path().join(format!("manifest.{format}"));
+            fs::write(&path, format.serialize(&manifest).unwrap()).unwrap();
+            assert_eq!(Manifest::read(&path, false).unwrap().files, manifest.files);
+        }
+        // Told by its magic bytes rather than its extension
+        let path = dir.path().join("manifest");
+        fs::write(&path, Format::Cbor.serialize(&manifest).unwrap()).unwrap();
+        assert_eq!(Manifest::read(&path, false).unwrap().files, manifest.files);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn read_reports_broken_manifests_from_pipes() {
+        let dir = tempfile::tempdir().unwrap();
+        let pipe = dir.path().join("manifest.json");
+        sys::Dir::open(dir.path(), sys::Resolve::Follow)
+            .unwrap()
+            .mknod(OsStr::new("manifest.json"), sys::Node::Fifo, 0o600)
+            .unwrap();
+        let writer = {
+            let pipe = pipe.clone();
+            std::thread::spawn(move || fs::write(pipe, r#"{"version": 3, "files": 1}"#).unwrap())
+        };
+        let err = Manifest::read(&pipe, false).unwrap_err();
+        writer.join().unwrap();
+        assert!(
+            format!("{err:?}").contains("Failed to deserialize"),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn read_decompresses_zstd() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = "version = 3\n[[files]]\ntype = \"directory\"\ntarget = \"/d\"\n";
+        let path = dir.path().join("manifest.toml.zst");
+        fs::write(
+            &path,
+            ruzstd::encoding::compress_to_vec(
+                toml.as_bytes(),
+                ruzstd::encoding::CompressionLevel::Fastest,
+            ),
+        )
+        .unwrap();
+        assert_eq!(Format::from_path(&path), Format::Toml);
+        let manifest = Manifest::read(&path, false).unwrap();
+        assert_eq!(manifest.files[0].target, Path::new("/d"));
+    }
+
+    #[test]
+    fn read_applies_defaults_to_own_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("child.json"),
+            r#"{"files":[{"type":"directory","target":"/c"}],"version":3}"#,
+        )
+        .unwrap();
+        let path = dir.path().join("parent.json");
+        fs::write(
+            &path,
+            r#"{
+                "defaults": {"permissions": "600", "uid": 1000, "gid": 100},
+                "files": [
+                    {"type": "copy", "source": "/s", "target": "/a", "gid": 0},
+                    {"type": "directory", "target": "/b"},
+                    {"type": "delete", "target": "/d"}
+                ],
+                "includes": ["child.json"],
+                "version": 3
+            }"#,
+        )
+        .unwrap();
+
+        let m = Manifest::read(&path, false).unwrap();
+        let get = |target: &str| {
+            m.files
+                .iter()
+                .find(|x| x.target == Path::new(target))
+                .unwrap()
+        };
+        let owner = |file: &File| (file.permissions, file.uid, file.gid);
+        assert_eq!(owner(get("/a")), (Some(0o600), Some(1000), Some(0)));
+        assert_eq!(owner(get("/b")), (None, Some(1000), Some(100)));
+        assert_eq!(owner(get("/c")), (None, None, None));
+        assert_eq!(owner(get("/d")), (None, None, None));
+    }
+
+    #[test]
+    fn read_merges_includes() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("child.json"),
+            r#"{"files":[{"type":"directory","target":"/a"},{"type":"directory","target":"/b"}],"clobber_by_default":true,"version":3}"#,
+        )
+        .unwrap();
+        let parent = dir.path().join("parent.json");
+        fs::write(
+            &parent,
+            r#"{"files":[{"type":"delete","target":"/b"}],"includes":["child.json"],"version":3}"#,
+        )
+        .unwrap();
+
+        let m = Manifest::read(&parent, false).unwrap();
+        assert!(m.includes.is_empty());
+        assert_eq!(m.files.len(), 2);
+        assert_eq!(m.files[0].target, PathBuf::from("/a"));
+        assert_eq!(m.files[0].clobber, Some(true));
+        assert_eq!(m.files[1].kind, FileKind::Delete);
+    }
+
+    #[test]
+    fn read_rejects_duplicate_targets() {
+        let f = write_manifest(
+            r#"{"files":[
+                {"type":"directory","target":"/a"},
+                {"type":"delete","target":"/b"},
+                {"type":"symlink","source":"/c","target":"/a"}
+            ],"version":3}"#,
+        );
+        assert!(matches!(
+            Manifest::read(f.path(), false),
+            Err(ReadError::DuplicateTarget { ref target, .. }) if target == Path::new("/a")
+        ));
+
+        let m = Manifest::read_as(
+            f.path(),
+            Format::Json,
+            Impure::No,
+            Duplicates::LastWins,
+            None,
+        )
+        .unwrap();
+        let kinds: Vec<FileKind> = m.files.iter().map(|x| x.kind).collect();
+        assert_eq!(kinds, vec![FileKind::Delete, FileKind::Symlink]);
+    }
+
+    #[test]
+    fn activate_honors_dependencies() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+        fs::write(path("source"), "generated").unwrap();
+        // Copied from what the entry after it generates
+        let mut copy = file(FileKind::Copy, path("copy").to_str().unwrap());
+        copy.source = Some(path("generated"));
+        copy.depends_on = vec![path("generated")];
+        let mut generated = file(FileKind::Copy, path("generated").to_str().unwrap());
+        generated.source = Some(path("source"));
+        let mut m = manifest_with(vec![copy, generated]);
+        assert!(m.verify().is_empty());
+        assert!(m.activate(&Options::default()).is_empty());
+        assert_eq!(fs::read_to_string(path("copy")).unwrap(), "generated");
+
+        m.files.update(|files| {
+            let generated = files.iter_mut().find(|x| x.target == path("generated"));
+            generated.unwrap().depends_on = vec![path("copy"), path("missing")];
+        });
+        assert_eq!(m.verify()[0].violation, Violation::UnknownDependency);
+        let manifest = path("manifest.json");
+        fs::write(&manifest, serde_json::to_string(&m).unwrap()).unwrap();
+        assert!(matches!(
+            Manifest::read(&manifest, false),
+            Err(ReadError::DependencyCycle(ref targets)) if targets.len() == 3
+        ));
+    }
+
+    #[test]
+    fn activate_renders_template() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("template");
+        let target = dir.path().join("target");
+        fs::write(&source, "user=@user@").unwrap();
+        let mut f = file(FileKind::Template, target.to_str().unwrap());
+        f.source = Some(source);
+        let mut m = manifest_with(vec![f]);
+        m.variables
+            .insert(String::from("user"), String::from("alice"));
+
+        assert!(m.activate(&Options::default()).is_empty());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "user=alice");
+        assert!(matches!(
+            m.states(&Options::default())[0].1,
+            Ok(FileState::Correct)
+        ));
+    }
+
+    #[test]
+    fn activate_parallel_keeps_nested_targets_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        fs::write(&source, b"x").unwrap();
+        let mut files = Vec::new();
+        for name in ["a", "b", "c", "d"] {
+            let mut directory = file(FileKind::Directory, dir.path().join(name).to_str().unwrap());
+            directory.permissions = Some(0o700);
+            files.push(directory);
+            for inner in ["1", "2", "3"] {
+                let mut copy = file(
+                    FileKind::Copy,
+                    dir.path().join(name).join(inner).to_str().unwrap(),
+                );
+                copy.source = Some(source.clone());
+                files.push(copy);
+            }
+        }
+        let mut symlink = file(
+            FileKind::Symlink,
+            dir.path().join("a/1/link").to_str().unwrap(),
+        );
+        symlink.source = Some(source);
+        files.push(symlink);
+        let mut m = manifest_with(files);
+
+        let options = Options {
+            jobs: 4,
+            ..Options::default()
+        };
+        let failures = m.activate(&options);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].0, dir.path().join("a/1/link"));
+        let states = m.states(&options);
+        assert!(
+            states
+                .iter()
+                .map(|(target, _)| target)
+                .eq(m.files.iter().map(|x| &x.target))
+        );
+        assert!(
+            states
+                .iter()
+                .filter(|(target, _)| target != &dir.path().join("a/1/link"))
+                .all(|(_, state)| matches!(state, Ok(FileState::Correct)))
+        );
+    }
+
+    #[test]
+    fn fail_fast_stops_at_first_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        fs::write(&source, b"x").unwrap();
+        let files: Vec<File> = ["a", "b", "c"]
+            .into_iter()
+            .map(|name| {
+                let mut copy = file(FileKind::Copy, dir.path().join(name).to_str().unwrap());
+                copy.source = Some(source.clone());
+                copy
+            })
+            .collect();
+        let mut m = manifest_with(files);
+        m.files.update(|files| {
+            files[0].source_hash = Some(Checksum::of(crate::checksum::Algorithm::Sha256, b"y"));
+        });
+
+        let failures = m.clone().activate(&Options {
+            fail_fast: true,
+            ..Options::default()
+        });
+        assert_eq!(failures.len(), 1);
+        assert!(!dir.path().join("b").exists());
+
+        assert_eq!(m.activate(&Options::default()).len(), 1);
+        assert!(dir.path().join("b").exists() && dir.path().join("c").exists());
+    }
+
+    #[test]
+    fn order_overrides_kind() {
+        let mut modify = file(FileKind::Modify, "/a");
+        modify.order = Some(-1);
+        let mut m = manifest_with(vec![
+            file(FileKind::Copy, "/a/b"),
+            file(FileKind::Directory, "/c"),
+            modify,
+        ]);
+        m.files.update(|files| files.sort());
+        let kinds: Vec<FileKind> = m.files.iter().map(|x| x.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![FileKind::Modify, FileKind::Directory, FileKind::Copy]
+        );
+    }
+
+    #[test]
+    fn activate_leaves_filtered_entries_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+        let mut m = manifest_with(vec![
+            file(FileKind::Directory, path("a").to_str().unwrap()),
+            file(FileKind::Directory, path("a/b").to_str().unwrap()),
+            file(FileKind::Directory, path("c").to_str().unwrap()),
+        ]);
+        let options = Options {
+            filter: Filter {
+                only: vec![path("a")],
+                skip: vec![path("a/b")],
+                ..Filter::default()
+            },
+            ..Options::default()
+        };
+        assert!(m.activate(&options).is_empty());
+        assert!(path("a").is_dir());
+        assert!(!path("a/b").exists());
+        assert!(!path("c").exists());
+    }
+
+    #[test]
+    fn activate_refuses_protected_paths_before_anything() {
+        let dir = tempfile::tempdir().unwrap();
+        let protected = dir.path().join("protected");
+        fs::write(&protected, b"x").unwrap();
+        let created = dir.path().join("created");
+        let mut m = manifest_with(vec![
+            file(FileKind::Directory, created.to_str().unwrap()),
+            file(FileKind::Delete, protected.to_str().unwrap()),
+        ]);
+        let options = Options {
+            protected: vec![protected.clone()],
+            ..Options::default()
+        };
+
+        let failures = m.activate(&options);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].0, protected);
+        assert!(protected.exists());
+        assert!(!created.exists());
+    }
+
+    #[test]
+    fn only_if_skips_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+        let mut unmet = file(FileKind::Directory, path("unmet").to_str().unwrap());
+        unmet.only_if = Some(OnlyIf {
+            path_exists: Some(path("missing")),
+            ..OnlyIf::default()
+        });
+        let mut met = file(FileKind::Directory, path("met").to_str().unwrap());
+        met.only_if = Some(OnlyIf {
+            path_exists: Some(dir.path().to_path_buf()),
+            command_succeeds: Some(String::from("true")),
+            env: Some(String::from("PATH")),
+        });
+
+        let mut m = manifest_with(vec![unmet, met]);
+        assert!(m.activate(&Options::default()).is_empty());
+        assert!(!path("unmet").exists());
+        assert!(path("met").is_dir());
+    }
+
+    #[test]
+    fn applies_to_hosts_and_users() {
+        let host = sys::hostname().unwrap();
+        let user = sys::user_name().unwrap().unwrap();
+        let applies = |hosts: &[&str], users: &[&str]| {
+            let mut f = file(FileKind::Directory, "/a");
+            f.hosts = hosts.iter().map(ToString::to_string).collect();
+            f.users = users.iter().map(ToString::to_string).collect();
+            f.applies()
+        };
+        assert!(applies(&[], &[]));
+        assert!(applies(&["other", &host], &[&user]));
+        assert!(!applies(&["other"], &[]));
+        assert!(!applies(&[], &["other"]));
+    }
+
+    #[test]
+    fn reroot_confines_targets() {
+        let mut m = manifest_with(vec![
+            file(FileKind::Directory, "/etc/foo"),
+            file(FileKind::Directory, "/../../etc/./bar"),
+        ]);
+        m.files
+            .update(|files| files[0].source = Some(PathBuf::from("/nix/store/x")));
+        m.reroot(Path::new("/mnt"));
+        assert_eq!(m.files[0].target, PathBuf::from("/mnt/etc/foo"));
+        assert_eq!(m.files[0].source, Some(PathBuf::from("/nix/store/x")));
+        assert_eq!(m.files[1].target, PathBuf::from("/mnt/etc/bar"));
+    }
+
+    #[test]
+    fn selinux_context_is_an_xattr() {
+        let mut f = file(FileKind::Directory, "/a");
+        f.selinux_context = Some(String::from("system_u:object_r:etc_t:s0"));
+        assert_eq!(
+            FileWithMetadata::from(&f).xattrs.get("security.selinux"),
+            Some(&String::from("system_u:object_r:etc_t:s0\0"))
+        );
+    }
+
+    #[test]
+    fn plan_classifies_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        fs::write(&source, b"x").unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_owned();
 
-        let mut updated_files: Vec<(File, File)> = vec![];
-        let mut same_files: Vec<File> = vec![];
+        let mut kept = file(FileKind::Symlink, &path("kept"));
+        kept.source = Some(source.clone());
+        let mut updated = file(FileKind::Copy, &path("updated"));
+        updated.source = Some(source);
+        let removed = file(FileKind::Directory, &path("removed"));
+        let mut old = manifest_with(vec![kept.clone(), updated.clone(), removed]);
+        assert!(old.activate(&Options::default()).is_empty());
+        let old_path = dir.path().join("old.json");
+        fs::write(&old_path, serde_json::to_string(&old).unwrap()).unwrap();
 
-        old_manifest.files.retain(|file| {
-            if let Some(index) = self.files.iter().position(|inner| inner == file) {
-                same_files.push(self.files.swap_remove(index));
-                false
-            } else if let Some(index) = self.files.iter().position(|inner| {
-                matches!(inner.clone(), File {
-                    kind: FileKind::Symlink | FileKind::Copy,
-                   target,
-                    ..
-                } if (target == file.target))
-            }) {
-                updated_files.push((file.clone(), self.files.swap_remove(index)));
-                false
-            } else {
-                true
-            }
-        });
+        updated.permissions = Some(0o600);
+        let new = manifest_with(vec![kept, updated, file(FileKind::Directory, &path("new"))]);
+        let plan = new.plan(&old_path, &Options::default(), false).unwrap();
+        assert_eq!(plan[3].old_source, plan[3].source);
+        assert!(plan[3].source.is_some());
+        assert_eq!(
+            plan.into_iter()
+                .map(|x| (x.change, x.step))
+                .collect::<Vec<_>>(),
+            vec![
+                (Change::Unchanged, Step::Nothing),
+                (Change::New, Step::Create),
+                (Change::Removed, Step::Delete),
+                (Change::Updated, Step::Swap),
+            ]
+        );
+        assert!(dir.path().join("removed").exists());
+    }
 
-        // Remove files in old manifest
-        // which aren't in new manifest
-        let mut failures: Vec<(PathBuf, String)> = old_manifest
-            .deactivate()
+    #[test]
+    fn plan_activation_and_deactivation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_owned();
+        let mut manifest = manifest_with(vec![file(FileKind::Directory, &path("dir"))]);
+        let steps = |plan: Vec<PlanEntry>| plan.into_iter().map(|x| x.step).collect::<Vec<_>>();
+        let options = Options::default();
+
+        assert_eq!(
+            steps(manifest.plan_activation(&options)),
+            vec![Step::Create]
+        );
+        assert!(manifest.activate(&options).is_empty());
+        assert_eq!(
+            steps(manifest.plan_activation(&options)),
+            vec![Step::Nothing]
+        );
+        assert_eq!(
+            steps(manifest.plan_deactivation(&options)),
+            vec![Step::Delete]
+        );
+        assert!(dir.path().join("dir").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn diff_updates_every_kind() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        fs::write(&source, b"x").unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_owned();
+        fs::write(path("modified"), b"mine").unwrap();
+
+        let mut directory = file(FileKind::Directory, &path("directory"));
+        directory.permissions = Some(0o755);
+        let mut modified = file(FileKind::Modify, &path("modified"));
+        modified.permissions = Some(0o644);
+        let mut deleted = file(FileKind::Copy, &path("deleted"));
+        deleted.source = Some(source);
+        let mut old = manifest_with(vec![directory.clone(), modified.clone(), deleted.clone()]);
+        assert!(old.activate(&Options::default()).is_empty());
+        fs::write(dir.path().join("directory/kept"), b"").unwrap();
+        let old_path = dir.path().join("old.json");
+        fs::write(&old_path, serde_json::to_string(&old).unwrap()).unwrap();
+
+        directory.permissions = Some(0o700);
+        modified.permissions = Some(0o600);
+        let new = manifest_with(vec![
+            directory,
+            modified,
+            file(FileKind::Delete, &deleted.target.to_string_lossy()),
+        ]);
+        let plan: Vec<_> = new
+            .plan(&old_path, &Options::default(), false)
+            .unwrap()
             .into_iter()
-            .map(|(p, e)| (p, format!("{e:?}")))
+            .map(|x| (x.change, x.step))
             .collect();
+        assert_eq!(
+            plan,
+            vec![
+                (Change::Updated, Step::Delete),
+                (Change::Updated, Step::Fix),
+                (Change::Updated, Step::Fix),
+            ]
+        );
 
-        for (old, new) in updated_files {
-            if !old
-                .clobber
-                .unwrap_or_else(|| old_manifest.clobber_by_default.unwrap_or(false))
-            {
-                let mut file = FileWithMetadata::from(&old);
+        new.diff(&old_path, &Options::default(), false).unwrap();
+        let mode = |name: &str| sys::mode(&fs::metadata(dir.path().join(name)).unwrap()) & 0o777;
+        assert_eq!(mode("directory"), 0o700);
+        assert!(dir.path().join("directory/kept").exists());
+        assert_eq!(mode("modified"), 0o600);
+        assert_eq!(fs::read(dir.path().join("modified")).unwrap(), b"mine");
+        assert!(!dir.path().join("deleted").exists());
+    }
 
-                // Don't care if this errors
-                // metadata will just be none
-                if let Err(err) = file.set_metadata() {
-                    warn!(
-                        "Failed to get metadata for file '{}'\n{:?}",
-                        file.target.display(),
-                        err
-                    );
+    #[test]
+    fn landlock_limits_writes_to_target_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+        fs::create_dir(path("allowed")).unwrap();
+        fs::create_dir(path("other")).unwrap();
+        let options = Options {
+            landlock: Some(Vec::new()),
+            cache: path("allowed"),
+            ..Options::default()
+        };
+        let mut manifest = manifest_with(vec![file(
+            FileKind::Directory,
+            path("allowed/new/dir").to_str().unwrap(),
+        )]);
+        // Restrictions stay with the thread
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let failures = manifest.activate(&options);
+                if let Some((_, err)) = failures.first()
+                    && err
+                        .root_cause()
+                        .downcast_ref::<io::Error>()
+                        .is_some_and(|x| x.kind() == io::ErrorKind::Unsupported)
+                {
+                    return;
                 }
+                assert!(failures.is_empty());
+                assert!(path("allowed/new/dir").is_dir());
+                assert!(fs::write(path("other/file"), b"").is_err());
+            });
+        });
+    }
 
-                if file.metadata.is_some()
-                    && !file
-                        .check()
-                        .inspect_err(|err| {
-                            warn!(
-                                "Failed to check file: '{}', assuming file is incorrect\n{:?}",
-                                file.target.display(),
-                                err
-                            );
-                        })
-                        .unwrap_or(false)
+    #[test]
+    #[cfg(unix)]
+    fn seccomp_allows_only_file_syscalls() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+        fs::write(path("source"), b"source").unwrap();
+        let options = Options {
+            seccomp: true,
+            jobs: 4,
+            ..Options::default()
+        };
+        let mut copy = file(FileKind::Copy, path("target").to_str().unwrap());
+        copy.source = Some(path("source"));
+        copy.permissions = Some(0o600);
+        let mut manifest = manifest_with(vec![copy]);
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let failures = manifest.clone().activate(&options);
+                if let Some((_, err)) = failures.first()
+                    && err
+                        .root_cause()
+                        .downcast_ref::<io::Error>()
+                        .is_some_and(|x| x.kind() == io::ErrorKind::Unsupported)
                 {
-                    if let Err(err) = prefix_move(&file.target, prefix) {
-                        warn!(
-                            "Failed to backup file '{}'\n{:?}",
-                            file.target.display(),
-                            err
-                        );
-                    }
-                    // if file existed but was wrong,
-                    // atomic action cannot be taken
-                    // so there's no point of forcing clobber
-
-                    // except this double checks
-                    self.files.push(new.clone());
-                    continue;
+                    return;
                 }
-            }
-
-            let mut atomic = FileWithMetadata::from(&new.clone());
+                assert!(failures.is_empty());
+                assert_eq!(fs::read(path("target")).unwrap(), b"source");
+                // Filtering again does nothing
+                assert!(manifest.activate(&options).is_empty());
+                let denied = std::net::UdpSocket::bind("127.0.0.1:0").unwrap_err();
+                assert_eq!(denied.kind(), io::ErrorKind::PermissionDenied);
+                assert!(std::os::unix::net::UnixDatagram::unbound().is_ok());
+                assert!(std::process::Command::new("true").status().is_err());
+                // Only some `prctl` options and `ioctl` requests
+                // SAFETY: neither changes anything, and both only read an
+                // integer argument
+                unsafe {
+                    assert_eq!(libc::prctl(libc::PR_GET_DUMPABLE), -1);
+                    assert_eq!(
+                        io::Error::last_os_error().kind(),
+                        io::ErrorKind::PermissionDenied
+                    );
+                    let source = fs::File::open(path("source")).unwrap();
+                    let mut pending: libc::c_int = 0;
+                    assert_eq!(
+                        libc::ioctl(
+                            std::os::fd::AsRawFd::as_raw_fd(&source),
+                            libc::FIONREAD,
+                            &raw mut pending
+                        ),
+                        -1
+                    );
+                    assert_eq!(
+                        io::Error::last_os_error().kind(),
+                        io::ErrorKind::PermissionDenied
+                    );
+                }
+                // Threads can still be started
+                assert!(std::thread::spawn(|| ()).join().is_ok());
+            });
+        });
+    }
 
-            if let Err(err) = atomic.set_metadata() {
-                warn!(
-                    "Failed to get metadata for file '{}'\n{:?}",
-                    atomic.target.display(),
-                    err
-                );
-                continue;
-            }
+    #[test]
+    fn diff_backs_up_only_edited_copies() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+        fs::write(path("old"), b"old").unwrap();
+        fs::write(path("new"), b"new").unwrap();
+        let options = Options {
+            installed: Some(Installed::load(&path("state")).unwrap()),
+            ..Options::default()
+        };
 
-            if atomic.metadata.is_none() {
-                self.files.push(new);
-                continue;
-            }
+        let mut old = file(FileKind::Copy, path("target").to_str().unwrap());
+        old.source = Some(path("old"));
+        let mut new = old.clone();
+        new.source = Some(path("new"));
+        assert!(
+            manifest_with(vec![old.clone()])
+                .activate(&options)
+                .is_empty()
+        );
+        // Gone, e.g. garbage collected, so the target can't be compared to it
+        fs::remove_file(path("old")).unwrap();
+        manifest_with(vec![new.clone()])
+            .diff_with(manifest_with(vec![old.clone()]), &options)
+            .unwrap();
+        assert_eq!(fs::read(path("target")).unwrap(), b"new");
+        assert!(!path(".backup-target").exists());
 
-            let res = atomic.atomic_activate().inspect_err(|err| {
-                error!(
-                    "Failed to (atomic) activate file: '{}'\n{:?}",
-                    new.target.display(),
-                    err
-                );
-            });
-            if !res.unwrap_or(false) {
-                self.files.push(new);
-            }
-        }
+        fs::write(path("old"), b"old").unwrap();
+        fs::write(path("target"), b"edited").unwrap();
+        manifest_with(vec![old])
+            .diff_with(manifest_with(vec![new]), &options)
+            .unwrap();
+        assert_eq!(fs::read(path(".backup-target")).unwrap(), b"edited");
+        assert_eq!(fs::read(path("target")).unwrap(), b"old");
+    }
 
-        // These files could technically just be
-        // Verified
-        self.files.append(&mut same_files);
-        // Activate new files
-        failures.extend(
-            self.activate(prefix)
-                .into_iter()
-                .map(|(p, e)| (p, format!("{e:?}"))),
+    #[test]
+    fn take_matching_pairs_by_target() {
+        let mut changed = file(FileKind::Directory, "/b");
+        changed.permissions = Some(0o700);
+        let mut old = manifest_with(
+            (0..1000)
+                .map(|i| file(FileKind::Directory, &format!("/a/{i}")))
+                .chain([file(FileKind::Directory, "/b"), file(FileKind::Copy, "/c")])
+                .collect(),
         );
-        if failures.is_empty() {
-            Ok(())
-        } else {
-            Err(DiffError::ActivationFailed(failures))
-        }
+        let mut new = manifest_with(
+            (0..1000)
+                .rev()
+                .map(|i| file(FileKind::Directory, &format!("/a/{i}")))
+                .chain([changed.clone(), file(FileKind::Directory, "/c")])
+                .collect(),
+        );
+
+        let (updated, same) = new.take_matching(&mut old);
+        assert_eq!(same.len(), 1000);
+        assert_eq!(updated, vec![(file(FileKind::Directory, "/b"), changed)]);
+        assert_eq!(*old.files, [file(FileKind::Copy, "/c")]);
+        assert_eq!(*new.files, [file(FileKind::Directory, "/c")]);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::{
-        io::Write as _,
-        path::PathBuf,
-    };
+    #[cfg(unix)]
+    #[test]
+    fn diff_renames_moved_targets() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        fs::write(&source, b"x").unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_owned();
 
-    fn file(kind: FileKind, target: &str) -> File {
-        File {
-            source: None,
-            target: PathBuf::from(target),
-            kind,
-            clobber: None,
-            permissions: None,
-            uid: None,
-            gid: None,
-            deactivate: None,
-            follow_symlinks: None,
-            ignore_modification: None,
-        }
+        let mut moved = file(FileKind::Copy, &path("old"));
+        moved.source = Some(source);
+        let mut old = manifest_with(vec![moved.clone()]);
+        assert!(old.activate(&Options::default()).is_empty());
+        let old_path = dir.path().join("old.json");
+        fs::write(&old_path, serde_json::to_string(&old).unwrap()).unwrap();
+
+        moved.target = PathBuf::from(path("sub/new"));
+        moved.permissions = Some(0o600);
+        let new = manifest_with(vec![moved.clone()]);
+        let plan = new.plan(&old_path, &Options::default(), false).unwrap();
+        assert_eq!(
+            (plan[0].change, plan[0].step),
+            (Change::Renamed, Step::Rename)
+        );
+
+        let options = Options::default();
+        let current = serde_json::to_string(&new).unwrap();
+        new.diff(&old_path, &options, false).unwrap();
+        assert!(!dir.path().join("old").exists());
+        let renamed = dir.path().join("sub/new");
+        assert_eq!(fs::read(&renamed).unwrap(), b"x");
+        assert_eq!(sys::mode(&fs::metadata(&renamed).unwrap()) & 0o777, 0o600);
+        assert_eq!(options.report.entries()[0].action, Action::Renamed);
+
+        // Edited by hand, so it is left alone, as deactivating it would
+        fs::write(&renamed, b"local").unwrap();
+        fs::write(&old_path, current).unwrap();
+        moved.target = PathBuf::from(path("other"));
+        let new = manifest_with(vec![moved]);
+        let plan = new.plan(&old_path, &Options::default(), false).unwrap();
+        assert!(plan.iter().all(|x| x.change != Change::Renamed));
+        assert!(matches!(
+            new.diff(&old_path, &Options::default(), false),
+            Err(DiffError::ActivationFailed(_))
+        ));
+        assert_eq!(fs::read(&renamed).unwrap(), b"local");
+        assert_eq!(fs::read(dir.path().join("other")).unwrap(), b"x");
     }
 
-    fn write_manifest(content: &str) -> tempfile::NamedTempFile {
-        let mut f = tempfile::NamedTempFile::new().unwrap();
-        write!(f, "{content}").unwrap();
-        f
+    #[test]
+    fn diff_moves_replaced_targets() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+        fs::write(path("source"), b"x").unwrap();
+        fs::write(path("new_source"), b"y").unwrap();
+
+        let mut config = file(FileKind::Symlink, path("app.conf").to_str().unwrap());
+        config.source = Some(path("source"));
+        let mut old = manifest_with(vec![config.clone()]);
+        assert!(old.activate(&Options::default()).is_empty());
+        let old_path = path("old.json");
+        fs::write(&old_path, serde_json::to_string(&old).unwrap()).unwrap();
+
+        // Moved, with a new source, so only `replaces` tells it apart from a
+        // removed entry and a new one
+        config.target = path("app/config");
+        config.source = Some(path("new_source"));
+        config.replaces = Some(path("app.conf"));
+        let new = manifest_with(vec![config]);
+        let plan = new.plan(&old_path, &Options::default(), false).unwrap();
+        assert_eq!(plan.len(), 1);
+        assert_eq!(
+            (plan[0].change, plan[0].step),
+            (Change::Renamed, Step::Rename)
+        );
+
+        let check = new.clone();
+        new.diff(&old_path, &Options::default(), false).unwrap();
+        assert!(!path("app.conf").exists());
+        assert_eq!(fs::read(path("app/config")).unwrap(), b"y");
+        let (_, state) = &check.states(&Options::default())[0];
+        assert_eq!(*state.as_ref().unwrap(), FileState::Correct);
+
+        let mut directory = file(FileKind::Directory, "/d");
+        directory.replaces = Some(PathBuf::from("/c"));
+        assert_eq!(
+            manifest_with(vec![directory]).verify()[0].violation,
+            Violation::UnexpectedReplaces
+        );
     }
 
     #[test]
-    fn read_rejects_future_version() {
-        let f = write_manifest(r#"{"files":[],"version":9999}"#);
+    fn read_rejects_include_cycle() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("self.json");
+        fs::write(&path, r#"{"includes":["self.json"],"version":3}"#).unwrap();
         assert!(matches!(
-            Manifest::read(f.path(), false),
-            Err(ReadError::VersionTooNew { manifest: 9999 })
+            Manifest::read(&path, false),
+            Err(ReadError::IncludeCycle(_))
         ));
     }
 
     #[test]
-    fn read_valid_empty_manifest() {
-        let f = write_manifest(r#"{"files":[],"version":3}"#);
+    fn read_expands_glob_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["a.desktop", "b.desktop", "c.txt"] {
+            fs::write(dir.path().join(name), b"").unwrap();
+        }
+        let f = write_manifest(&format!(
+            r#"{{"files":[{{"type":"symlink","source":"{}/*.desktop","target":"/apps"}}],"version":3}}"#,
+            dir.path().display()
+        ));
         let m = Manifest::read(f.path(), false).unwrap();
-        assert!(m.files.is_empty());
-        assert_eq!(m.version, 3);
+        let targets: Vec<_> = m.files.iter().map(|x| x.target.clone()).collect();
+        assert_eq!(
+            targets,
+            vec![
+                PathBuf::from("/apps/a.desktop"),
+                PathBuf::from("/apps/b.desktop")
+            ]
+        );
+        assert_eq!(m.files[0].source, Some(dir.path().join("a.desktop")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn diff_removes_entries_a_glob_no_longer_expands_to() {
+        let dir = tempfile::tempdir().unwrap();
+        let sources = dir.path().join("sources");
+        fs::create_dir(&sources).unwrap();
+        for name in ["a.desktop", "b.desktop"] {
+            fs::write(sources.join(name), b"").unwrap();
+        }
+        let apps = dir.path().join("apps");
+        fs::create_dir(&apps).unwrap();
+        let old_path = dir.path().join("old.json");
+        fs::write(
+            &old_path,
+            format!(
+                r#"{{"files":[{{"type":"symlink","source":"{}/*.desktop","target":"{}"}}],"version":3}}"#,
+                sources.display(),
+                apps.display()
+            ),
+        )
+        .unwrap();
+        let mut old = Manifest::read(&old_path, false).unwrap();
+        assert!(old.activate(&Options::default()).is_empty());
+        let generations = Generations::new(&dir.path().join("state"));
+        generations.record(&old).unwrap();
+        fs::remove_file(sources.join("b.desktop")).unwrap();
+
+        manifest_with(Vec::new())
+            .diff(&old_path, &Options::default(), false)
+            .unwrap();
+        assert!(fs::symlink_metadata(apps.join("b.desktop")).is_ok());
+
+        let options = Options {
+            generations: Some(generations),
+            ..Options::default()
+        };
+        manifest_with(Vec::new())
+            .diff(&old_path, &options, false)
+            .unwrap();
+        assert!(fs::symlink_metadata(apps.join("a.desktop")).is_err());
+        assert!(fs::symlink_metadata(apps.join("b.desktop")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn delete_expands_glob_targets() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        for name in ["a.pyc", "keep.pyc", "c.txt", "sub/b.pyc"] {
+            fs::write(dir.path().join(name), b"").unwrap();
+        }
+        std::os::unix::fs::symlink(dir.path().join("sub"), dir.path().join("link")).unwrap();
+
+        let pattern = dir.path().join("*.pyc");
+        let mut keep = file(
+            FileKind::Copy,
+            dir.path().join("keep.pyc").to_str().unwrap(),
+        );
+        keep.source = Some(PathBuf::from("/src"));
+        let delete = file(FileKind::Delete, pattern.to_str().unwrap());
+        let mut m = manifest_with(vec![keep.clone(), delete.clone()]);
+        m.select(&Options::default());
+        let targets: Vec<_> = m.files.iter().map(|x| x.target.clone()).collect();
+        assert_eq!(targets, vec![keep.target.clone(), dir.path().join("a.pyc")]);
+
+        let recursive = File {
+            recursive: Some(true),
+            ..delete
+        };
+        let mut m = manifest_with(vec![keep, recursive.clone()]);
+        m.select(&Options::default());
+        let targets: Vec<_> = m.files[1..].iter().map(|x| x.target.clone()).collect();
+        assert_eq!(
+            targets,
+            vec![dir.path().join("a.pyc"), dir.path().join("sub/b.pyc")]
+        );
+        assert!(m.files[1].recursive.is_none());
+
+        assert!(m.activate(&Options::default()).is_empty());
+        assert!(!dir.path().join("sub/b.pyc").exists());
+        assert!(dir.path().join("keep.pyc").exists());
+
+        let mut copy = recursive;
+        copy.kind = FileKind::Copy;
+        copy.source = Some(PathBuf::from("/src"));
+        assert_eq!(
+            manifest_with(vec![copy]).verify()[0].violation,
+            Violation::UnexpectedRecursive
+        );
     }
 
     #[test]
@@ -628,6 +4505,14 @@ mod tests {
         assert_eq!(m.files[0].permissions, Some(0o755));
     }
 
+    #[test]
+    fn read_parses_octal_umask() {
+        let f = write_manifest(r#"{"files":[],"umask":"077","version":3}"#);
+        let m = Manifest::read(f.path(), false).unwrap();
+        assert_eq!(m.umask, Some(0o77));
+        assert_eq!(serde_json::to_value(&m).unwrap()["umask"], "77");
+    }
+
     #[test]
     fn read_null_permissions_is_none() {
         let f = write_manifest(
@@ -637,6 +4522,20 @@ mod tests {
         assert_eq!(m.files[0].permissions, None);
     }
 
+    #[test]
+    fn read_decodes_base64_content() {
+        let f = write_manifest(
+            r#"{"files":[{"type":"copy","target":"/tmp/x","content_base64":"AAEC"}],"version":3}"#,
+        );
+        let m = Manifest::read(f.path(), false).unwrap();
+        assert_eq!(m.files[0].content_base64, Some(vec![0, 1, 2]));
+        assert!(
+            serde_json::to_string(&m)
+                .unwrap()
+                .contains(r#""content_base64":"AAEC""#)
+        );
+    }
+
     #[test]
     fn file_ordering_by_kind() {
         let dir = file(FileKind::Directory, "/a");
@@ -659,12 +4558,7 @@ mod tests {
     }
 
     fn manifest_with(files: Vec<File>) -> Manifest {
-        Manifest {
-            files,
-            clobber_by_default: None,
-            version: 3,
-            impure: false,
-        }
+        Manifest::new(files)
     }
 
     #[test]
@@ -709,8 +4603,8 @@ mod tests {
     }
 
     #[test]
-    fn verify_rejects_unexpected_follow_symlinks_for_copy() {
-        let mut f = file(FileKind::Copy, "/a");
+    fn verify_rejects_unexpected_follow_symlinks_for_hardlink() {
+        let mut f = file(FileKind::Hardlink, "/a");
         f.source = Some(PathBuf::from("/b"));
         f.follow_symlinks = Some(true);
         let errors = manifest_with(vec![f]).verify();
@@ -718,7 +4612,7 @@ mod tests {
             errors,
             vec![VerifyError {
                 target: PathBuf::from("/a"),
-                kind: FileKind::Copy,
+                kind: FileKind::Hardlink,
                 violation: Violation::UnexpectedFollowSymlinks,
             }]
         );
@@ -739,6 +4633,173 @@ mod tests {
         );
     }
 
+    #[test]
+    fn verify_rejects_uid_with_owner() {
+        let mut f = file(FileKind::Directory, "/a");
+        f.uid = Some(0);
+        f.owner = Some(String::from("root"));
+        let errors = manifest_with(vec![f]).verify();
+        assert_eq!(
+            errors,
+            vec![VerifyError {
+                target: PathBuf::from("/a"),
+                kind: FileKind::Directory,
+                violation: Violation::ConflictingOwner,
+            }]
+        );
+    }
+
+    #[test]
+    fn adopt_captures_existing_targets() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let path = |name: &str| dir.path().join(name);
+        fs::write(path("config"), b"existing").unwrap();
+        sys::symlink(Path::new("/elsewhere"), &path("link")).unwrap();
+
+        let mut inline = file(FileKind::Copy, path("config").to_str().unwrap());
+        inline.text = Some(String::from("managed"));
+        let link = file(FileKind::Template, path("link").to_str().unwrap());
+        let mut missing = file(FileKind::Symlink, path("missing").to_str().unwrap());
+        missing.source = Some(PathBuf::from("/source"));
+        let m = manifest_with(vec![inline, link, missing.clone()]);
+
+        let adopted = m.adopt(&out).unwrap();
+        let captured = rerooted(&out, &path("config"));
+        assert_eq!(fs::read(&captured).unwrap(), b"existing");
+        assert_eq!(adopted.files[0].kind, FileKind::Copy);
+        assert_eq!(adopted.files[0].source, Some(captured));
+        assert_eq!(adopted.files[0].text, None);
+        assert_eq!(adopted.files[1].kind, FileKind::Symlink);
+        assert_eq!(adopted.files[1].source, Some(PathBuf::from("/elsewhere")));
+        assert_eq!(adopted.files[2], missing);
+        assert!(adopted.verify().is_empty());
+    }
+
+    #[test]
+    fn orphans_lists_unaccounted_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+        for name in ["tree/x", "stale/y", "kept/nested/z", "kept/nested/old"] {
+            fs::create_dir_all(path(name).parent().unwrap()).unwrap();
+            fs::write(path(name), b"").unwrap();
+        }
+        fs::write(path(".backup-gone"), b"").unwrap();
+        fs::write(path("gone"), b"").unwrap();
+
+        let tree = file(FileKind::RecursiveCopy, path("tree").to_str().unwrap());
+        let nested = file(FileKind::Copy, path("kept/nested/z").to_str().unwrap());
+        let m = manifest_with(vec![
+            file(FileKind::Directory, dir.path().to_str().unwrap()),
+            tree,
+            nested,
+        ]);
+        assert_eq!(
+            m.orphans(".backup-"),
+            vec![path("gone"), path("kept/nested/old"), path("stale")]
+        );
+    }
+
+    #[test]
+    fn inspecting_remote_copies_does_not_fetch_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("a");
+        let mut copy = file(FileKind::Copy, target.to_str().unwrap());
+        copy.source = Some(PathBuf::from("https://example.invalid/a"));
+        copy.source_hash = Some(Checksum::of(crate::checksum::Algorithm::Sha256, b"x"));
+        let m = manifest_with(vec![copy]);
+        let options = Options {
+            cache: dir.path().join("cache"),
+            ..Options::default()
+        };
+        let steps = |m: &Manifest| {
+            m.plan_activation(&options)
+                .into_iter()
+                .map(|x| x.step)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(steps(&m), vec![Step::Create]);
+        fs::write(&target, b"x").unwrap();
+        assert_eq!(steps(&m), vec![Step::Nothing]);
+        assert!(matches!(m.states(&options)[0].1, Ok(FileState::Correct)));
+        assert!(!dir.path().join("cache").exists());
+    }
+
+    #[test]
+    fn load_copies_through_content_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        fs::write(&source, b"x").unwrap();
+        let mut copy = file(FileKind::Copy, dir.path().join("a").to_str().unwrap());
+        copy.source = Some(source);
+        let m = manifest_with(vec![copy]);
+        let options = Options {
+            cache: dir.path().join("cache"),
+            content_cache: Some(ContentCache::default()),
+            ..Options::default()
+        };
+
+        // Only once loaded, for what changes the target
+        let mut prepared = m.prepare(&m.files[0], &options);
+        assert_eq!(prepared.source, m.files[0].source);
+        prepared.load(&options);
+        assert!(
+            prepared
+                .source
+                .as_ref()
+                .unwrap()
+                .starts_with(&options.cache)
+        );
+        assert_eq!(prepared.source_digest, Some(blake3::hash(b"x")));
+        prepared.activate(None, &options).unwrap();
+        assert_eq!(prepared.state().unwrap(), FileState::Correct);
+    }
+
+    #[test]
+    fn load_leaves_undecryptable_secrets_without_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source.age");
+        fs::write(&source, b"not age").unwrap();
+        let mut f = file(FileKind::Copy, "/a");
+        f.source = Some(source);
+        f.decrypt = Some(Decrypt::Age);
+        let m = manifest_with(vec![f]);
+        assert!(m.verify().is_empty());
+
+        let mut prepared = m.prepare(&m.files[0], &Options::default());
+        assert_eq!(prepared.permissions, Some(0o600));
+        assert!(!prepared.check_source());
+        prepared.load(&Options::default());
+        assert_eq!(prepared.rendered, None);
+        assert!(prepared.check_source());
+
+        let mut inline = file(FileKind::Copy, "/b");
+        inline.text = Some(String::new());
+        inline.decrypt = Some(Decrypt::Sops);
+        assert_eq!(
+            manifest_with(vec![inline]).verify()[0].violation,
+            Violation::UnexpectedDecrypt
+        );
+    }
+
+    #[test]
+    fn verify_inline_content_copy() {
+        let mut inline = file(FileKind::Copy, "/a");
+        inline.text = Some(String::new());
+        let mut both = inline.clone();
+        both.target = PathBuf::from("/b");
+        both.source = Some(PathBuf::from("/c"));
+        assert_eq!(
+            manifest_with(vec![inline, both]).verify(),
+            vec![VerifyError {
+                target: PathBuf::from("/b"),
+                kind: FileKind::Copy,
+                violation: Violation::UnexpectedContent,
+            }]
+        );
+    }
+
     #[test]
     fn verify_accepts_valid_manifest() {
         let mut copy = file(FileKind::Copy, "/a");
@@ -754,21 +4815,21 @@ mod tests {
 
     #[test]
     fn verify_reports_all_errors() {
-        let mut copy = file(FileKind::Copy, "/a");
-        copy.follow_symlinks = Some(true);
+        let mut hardlink = file(FileKind::Hardlink, "/a");
+        hardlink.follow_symlinks = Some(true);
         let symlink = file(FileKind::Symlink, "/b");
         let mut delete = file(FileKind::Delete, "/c");
         delete.source = Some(PathBuf::from("/d"));
-        let errors = manifest_with(vec![copy, symlink, delete]).verify();
+        let errors = manifest_with(vec![hardlink, symlink, delete]).verify();
         assert_eq!(errors.len(), 4);
         assert!(errors.contains(&VerifyError {
             target: PathBuf::from("/a"),
-            kind: FileKind::Copy,
+            kind: FileKind::Hardlink,
             violation: Violation::MissingSource,
         }));
         assert!(errors.contains(&VerifyError {
             target: PathBuf::from("/a"),
-            kind: FileKind::Copy,
+            kind: FileKind::Hardlink,
             violation: Violation::UnexpectedFollowSymlinks,
         }));
         assert!(errors.contains(&VerifyError {
@@ -0,0 +1,121 @@
+//! Hashes and canonical paths of sources, remembered for the rest of a run so
+//! that a source shared by many entries is only read and resolved once.
+
+use crate::{
+    file_util::hash_file,
+    sys,
+};
+use blake3::Hash;
+use std::{
+    collections::HashMap,
+    fs::{
+        self,
+        Metadata,
+    },
+    io,
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::{
+        Arc,
+        Mutex,
+        PoisonError,
+    },
+};
+
+/// What was remembered about each path, along with the metadata of the file
+/// it resolved to then. Clones share the same values.
+#[derive(Debug, Clone, Default)]
+pub struct Memo {
+    hashes: Arc<Mutex<HashMap<PathBuf, (Metadata, Hash)>>>,
+    canonical: Arc<Mutex<HashMap<PathBuf, (Metadata, PathBuf)>>>,
+}
+
+/// Whether `then` and `now` describe the same file with the same contents,
+/// as far as its size and modification time tell.
+fn unchanged(then: &Metadata, now: &Metadata) -> bool {
+    sys::same_file(then, now)
+        && then.len() == now.len()
+        && then.modified().ok() == now.modified().ok()
+}
+
+impl Memo {
+    /// Returns the BLAKE3 hash of the file at `path`, or `None` if hashing
+    /// fails, only reading it again if it changed since.
+    #[must_use]
+    pub fn hash(&self, path: &Path) -> Option<Hash> {
+        let Ok(metadata) = fs::metadata(path) else {
+            return hash_file(path);
+        };
+        if let Some((then, hash)) = self
+            .hashes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(path)
+            && unchanged(then, &metadata)
+        {
+            return Some(*hash);
+        }
+
+        let hash = hash_file(path)?;
+        self.hashes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(path.to_path_buf(), (metadata, hash));
+        Some(hash)
+    }
+
+    /// Returns the canonical form of `path` like [`fs::canonicalize`], only
+    /// resolving it again if it now leads to another file.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `path` can't be resolved.
+    pub fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        let metadata = fs::metadata(path)?;
+        if let Some((then, canonical)) = self
+            .canonical
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(path)
+            && sys::same_file(then, &metadata)
+        {
+            return Ok(canonical.clone());
+        }
+
+        let canonical = fs::canonicalize(path)?;
+        self.canonical
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(path.to_path_buf(), (metadata, canonical.clone()));
+        Ok(canonical)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_is_remembered_until_the_file_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("source");
+        fs::write(&path, b"old").unwrap();
+        let mtime = fs::metadata(&path).unwrap().modified().unwrap();
+        let memo = Memo::default();
+        assert_eq!(memo.hash(&path), Some(blake3::hash(b"old")));
+
+        // Looks the same, so isn't read again
+        fs::write(&path, b"new").unwrap();
+        sys::set_mtime(&path, mtime).unwrap();
+        assert_eq!(memo.hash(&path), Some(blake3::hash(b"old")));
+
+        fs::write(&path, b"newer").unwrap();
+        assert_eq!(memo.hash(&path), Some(blake3::hash(b"newer")));
+        assert_eq!(
+            memo.canonicalize(&dir.path().join("./source")).unwrap(),
+            fs::canonicalize(&path).unwrap()
+        );
+    }
+}
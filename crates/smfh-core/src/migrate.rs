@@ -0,0 +1,114 @@
+//! Upgrades of manifests written for an older [`VERSION`], applied to the
+//! parsed document before it is deserialized, so that the rest of smfh only
+//! deals with the current format.
+//!
+//! Bumping [`VERSION`] means appending the step from the previous version to
+//! [`MIGRATIONS`]. A step only rewrites what the new version rejects, as
+//! [`Manifest::read`][crate::manifest::Manifest::read] takes an older
+//! manifest that deserializes as it is without upgrading it.
+
+use crate::VERSION;
+use color_eyre::{
+    Result,
+    eyre::{
+        WrapErr as _,
+        eyre,
+    },
+};
+use log::info;
+use serde_json::Value;
+
+/// Turns a manifest of one version into one of the next.
+type Migration = fn(Value) -> Result<Value>;
+
+/// The step from version `n` to `n + 1` is at index `n - 1`.
+const MIGRATIONS: [Migration; 3] = [
+    // Versions 2 and 3 only added fields
+    Ok,
+    Ok,
+    snake_case_kinds,
+];
+
+const _: () = assert!(MIGRATIONS.len() as u64 == VERSION - 1);
+
+/// Upgrades `manifest` from `version` to [`VERSION`], one version at a time,
+/// and sets its `version` accordingly. Manifests claiming a version below 1
+/// are read like version 1.
+///
+/// # Errors
+///
+/// Returns an error if `manifest` isn't an object, `version` is newer than
+/// [`VERSION`], or a step fails.
+pub fn upgrade(mut manifest: Value, version: u64) -> Result<Value> {
+    if !manifest.is_object() {
+        return Err(eyre!("manifest is not an object"));
+    }
+    if version > VERSION {
+        return Err(eyre!(
+            "manifest version {version} is newer than the supported {VERSION}"
+        ));
+    }
+    let version = version.max(1);
+    let skip = usize::try_from(version - 1)?;
+    for (from, migrate) in (version..).zip(&MIGRATIONS[skip..]) {
+        manifest = migrate(manifest)
+            .wrap_err_with(|| format!("Failed to upgrade manifest from version {from}"))?;
+        info!("Upgraded manifest from version {from} to {}", from + 1);
+    }
+    manifest["version"] = VERSION.into();
+    Ok(manifest)
+}
+
+/// Version 4 names kinds in `snake_case` like every field, rather than in
+/// `camelCase`, e.g. `linkFarm` becomes `link_farm`.
+#[allow(clippy::unnecessary_wraps)]
+fn snake_case_kinds(mut manifest: Value) -> Result<Value> {
+    let Some(Value::Array(files)) = manifest.get_mut("files") else {
+        return Ok(manifest);
+    };
+    for kind in files.iter_mut().filter_map(|x| x.get_mut("type")) {
+        if let Value::String(name) = kind {
+            let mut snake = String::with_capacity(name.len() + 1);
+            for c in name.chars() {
+                if c.is_ascii_uppercase() {
+                    snake.push('_');
+                }
+                snake.push(c.to_ascii_lowercase());
+            }
+            *name = snake;
+        }
+    }
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn upgrade_walks_every_version() {
+        let manifest = json!({
+            "files": [
+                { "type": "symlink", "source": "/a", "target": "/b" },
+                { "type": "linkFarm", "source": "/c", "target": "/d" },
+                { "type": "charDevice", "target": "/e", "major": 1, "minor": 3 },
+            ],
+            "version": 1,
+        });
+        let upgraded = upgrade(manifest, 1).unwrap();
+        assert_eq!(upgraded["version"], VERSION);
+        let kinds: Vec<_> = upgraded["files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|x| x["type"].as_str().unwrap())
+            .collect();
+        assert_eq!(kinds, ["symlink", "link_farm", "char_device"]);
+
+        // Already upgraded manifests are left alone
+        assert_eq!(upgrade(upgraded.clone(), VERSION).unwrap(), upgraded);
+        assert!(upgrade(json!({ "files": [], "version": VERSION + 1 }), VERSION + 1).is_err());
+        assert!(upgrade(json!([]), 1).is_err());
+    }
+}
@@ -0,0 +1,221 @@
+use crate::{
+    cas::ContentCache,
+    confirm::Confirm,
+    fetch,
+    filter::Filter,
+    generations::Generations,
+    installed::Installed,
+    journal::Journal,
+    memo::Memo,
+    protect,
+    report::Report,
+    sys::Resolve,
+};
+use std::{
+    path::PathBuf,
+    time::Duration,
+};
+
+/// Run-wide settings for [`Manifest::activate`], [`Manifest::deactivate`],
+/// and [`Manifest::diff`].
+///
+/// [`Manifest::activate`]: crate::manifest::Manifest::activate
+/// [`Manifest::deactivate`]: crate::manifest::Manifest::deactivate
+/// [`Manifest::diff`]: crate::manifest::Manifest::diff
+#[derive(Debug, Clone)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct Options {
+    /// Prepended to the file name of a target when it is moved out of the
+    /// way instead of being clobbered.
+    pub prefix: String,
+    /// Log what would be done without writing to the filesystem.
+    pub dry_run: bool,
+    /// Move backups made with [`prefix`][Self::prefix] back into place after
+    /// deactivating their target.
+    pub restore_backups: bool,
+    /// Give backups a timestamp suffix rather than replacing an earlier
+    /// backup of the same target, see [`backup_path`].
+    ///
+    /// [`backup_path`]: crate::file_util::backup_path
+    pub timestamped_backups: bool,
+    /// Where the action taken on each target is recorded. Nothing is
+    /// recorded with [`dry_run`][Self::dry_run].
+    pub report: Report,
+    /// Number of threads [`Manifest::activate`] and [`Manifest::states`] may
+    /// use, `0` meaning one per CPU.
+    ///
+    /// [`Manifest::activate`]: crate::manifest::Manifest::activate
+    /// [`Manifest::states`]: crate::manifest::Manifest::states
+    pub jobs: usize,
+    /// Root the targets of the old manifest read by [`Manifest::diff`] are
+    /// moved under, see [`Manifest::reroot`]. Manifests passed in have to be
+    /// rerooted by the caller.
+    ///
+    /// [`Manifest::reroot`]: crate::manifest::Manifest::reroot
+    pub root: Option<PathBuf>,
+    /// Entries outside of it are left alone by [`Manifest::activate`],
+    /// [`Manifest::deactivate`], [`Manifest::diff`], and [`Manifest::plan`],
+    /// in both the old and the new manifest. Like targets, its paths have to
+    /// be rerooted by the caller.
+    ///
+    /// [`Manifest::activate`]: crate::manifest::Manifest::activate
+    /// [`Manifest::deactivate`]: crate::manifest::Manifest::deactivate
+    /// [`Manifest::diff`]: crate::manifest::Manifest::diff
+    /// [`Manifest::plan`]: crate::manifest::Manifest::plan
+    pub filter: Filter,
+    /// Asked before an existing target that doesn't match the manifest is
+    /// clobbered, backed up, or deleted. A declined entry is skipped. Never
+    /// asked with [`dry_run`][Self::dry_run].
+    pub confirm: Option<Confirm>,
+    /// Clobber every target, regardless of [`File::clobber`] and
+    /// [`Manifest::clobber_by_default`].
+    ///
+    /// [`File::clobber`]: crate::manifest::File::clobber
+    /// [`Manifest::clobber_by_default`]: crate::manifest::Manifest::clobber_by_default
+    pub force: bool,
+    /// Paths no entry may replace or remove, see
+    /// [`Manifest::check_protected`]. Like targets, they have to be rerooted
+    /// by the caller. [`protect::defaults`] by default.
+    ///
+    /// [`Manifest::check_protected`]: crate::manifest::Manifest::check_protected
+    pub protected: Vec<PathBuf>,
+    /// Refuse to delete a directory, for a [`Delete`] entry or to clobber
+    /// it, that is or has under it a mountpoint, see
+    /// [`delete_within`][crate::file_util::delete_within].
+    ///
+    /// [`Delete`]: crate::manifest::FileKind::Delete
+    pub one_file_system: bool,
+    /// Stop at the first entry that fails, skipping the rest and the
+    /// manifest's hook, rather than carrying on with the others.
+    pub fail_fast: bool,
+    /// Paths, such as something on a network mount, that have to exist
+    /// before anything is done, see [`wait_for`]. Like targets, they have to
+    /// be rerooted by the caller.
+    ///
+    /// [`wait_for`]: crate::file_util::wait_for
+    pub wait_for: Vec<PathBuf>,
+    /// How long to wait for each of [`wait_for`][Self::wait_for] and the
+    /// [`File::wait_for`] of entries, 30 seconds by default.
+    ///
+    /// [`File::wait_for`]: crate::manifest::File::wait_for
+    pub wait_timeout: Duration,
+    /// Keys sources with [`File::decrypt`] are decrypted with, see
+    /// [`secret::decrypt`].
+    ///
+    /// [`File::decrypt`]: crate::manifest::File::decrypt
+    /// [`secret::decrypt`]: crate::secret::decrypt
+    pub identities: Vec<PathBuf>,
+    /// Where URL sources are downloaded to, see [`fetch::fetch`].
+    /// [`fetch::default_cache`] by default.
+    pub cache: PathBuf,
+    /// Copy sources through the [`ContentCache`] in [`cache`][Self::cache],
+    /// so that identical contents are read once and cloned where the
+    /// filesystem allows.
+    pub content_cache: Option<ContentCache>,
+    /// Refuse to change targets with a symlink anywhere in the path to their
+    /// parent directory, see [`Resolve::NoSymlinks`].
+    pub no_symlinks: bool,
+    /// Flush written files and the directories they were created, renamed,
+    /// or removed in to disk, so that changes survive a power cut right
+    /// after activation.
+    pub fsync: bool,
+    /// Umask of the process while it changes anything, overriding
+    /// [`Manifest::umask`]. The inherited one is kept if neither is set.
+    ///
+    /// [`Manifest::umask`]: crate::manifest::Manifest::umask
+    pub umask: Option<u32>,
+    /// Where the checksum of what is written to every
+    /// [`Copy`][crate::manifest::FileKind::Copy] or
+    /// [`Template`][crate::manifest::FileKind::Template] target is kept, so
+    /// that [`Manifest::diff`] only backs up the ones edited since.
+    ///
+    /// [`Manifest::diff`]: crate::manifest::Manifest::diff
+    pub installed: Option<Installed>,
+    /// Applied manifests, the latest of which [`Manifest::diff`] takes the
+    /// entries from that a glob source of the old manifest no longer expands
+    /// to, so that they are still removed.
+    ///
+    /// [`Manifest::diff`]: crate::manifest::Manifest::diff
+    pub generations: Option<Generations>,
+    /// Log how the contents of a [`Copy`] or [`Template`] target differ from
+    /// what replaces them, as a unified diff, before it is clobbered or
+    /// backed up. Decrypted contents are never shown.
+    ///
+    /// [`Copy`]: crate::manifest::FileKind::Copy
+    /// [`Template`]: crate::manifest::FileKind::Template
+    pub show_diff: bool,
+    /// Where every change made to the filesystem is recorded, with what was
+    /// there before, so that it can be undone with [`journal::undo`].
+    ///
+    /// [`journal::undo`]: crate::journal::undo
+    pub journal: Option<Journal>,
+    /// Before changing anything, restrict the thread, and those it starts,
+    /// to only writing under the directories the targets are in,
+    /// [`cache`][Self::cache], and these, see [`sys::landlock`]. Unrestricted
+    /// if `None`.
+    ///
+    /// [`sys::landlock`]: crate::sys::landlock
+    pub landlock: Option<Vec<PathBuf>>,
+    /// Before changing anything, restrict the thread, and those it starts,
+    /// to the system calls needed for files, see [`sys::seccomp`]. Hooks,
+    /// remote sources, and secrets then fail.
+    ///
+    /// [`sys::seccomp`]: crate::sys::seccomp
+    pub seccomp: bool,
+    /// Hashes and canonical paths of sources seen so far, shared by clones.
+    pub memo: Memo,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            prefix: String::from(".backup-"),
+            dry_run: false,
+            restore_backups: false,
+            timestamped_backups: false,
+            report: Report::default(),
+            jobs: 1,
+            root: None,
+            filter: Filter::default(),
+            confirm: None,
+            force: false,
+            protected: protect::defaults(),
+            one_file_system: false,
+            fail_fast: false,
+            wait_for: Vec::new(),
+            wait_timeout: Duration::from_secs(30),
+            identities: Vec::new(),
+            cache: fetch::default_cache(),
+            content_cache: None,
+            no_symlinks: false,
+            fsync: false,
+            umask: None,
+            installed: None,
+            generations: None,
+            show_diff: false,
+            journal: None,
+            landlock: None,
+            seccomp: false,
+            memo: Memo::default(),
+        }
+    }
+}
+
+impl Options {
+    /// Whether an entry with `clobber`, in a manifest with
+    /// `clobber_by_default`, clobbers rather than backs up.
+    #[must_use]
+    pub fn clobber(&self, clobber: Option<bool>, clobber_by_default: Option<bool>) -> bool {
+        self.force || clobber.unwrap_or_else(|| clobber_by_default.unwrap_or(false))
+    }
+
+    /// How the parent directories of targets are resolved.
+    #[must_use]
+    pub const fn resolve(&self) -> Resolve {
+        if self.no_symlinks {
+            Resolve::NoSymlinks
+        } else {
+            Resolve::Follow
+        }
+    }
+}
@@ -0,0 +1,49 @@
+//! Unified diffs between the contents of a target and what replaces them,
+//! logged with [`Options::show_diff`][crate::options::Options::show_diff].
+
+use similar::TextDiff;
+
+/// Contents larger than this, in bytes, aren't diffed.
+pub const MAX_SIZE: u64 = 1024 * 1024;
+
+/// How many bytes from the start are looked at to tell binary contents.
+const BINARY_PROBE: usize = 8000;
+
+/// Whether `bytes` look like binary rather than text, by having a NUL byte
+/// near the start, as `git` checks.
+fn is_binary(bytes: &[u8]) -> bool {
+    bytes[..bytes.len().min(BINARY_PROBE)].contains(&0)
+}
+
+/// Returns the unified diff from `old` to `new`, labelled with `old_name`
+/// and `new_name`, `None` if either is binary or not UTF-8, and an empty
+/// string if they are the same.
+#[must_use]
+pub fn unified(old: &[u8], new: &[u8], old_name: &str, new_name: &str) -> Option<String> {
+    if is_binary(old) || is_binary(new) {
+        return None;
+    }
+    let (Ok(old), Ok(new)) = (str::from_utf8(old), str::from_utf8(new)) else {
+        return None;
+    };
+    Some(
+        TextDiff::from_lines(old, new)
+            .unified_diff()
+            .header(old_name, new_name)
+            .to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unified_diffs_text_only() {
+        let diff = unified(b"a\nb\n", b"a\nc\n", "old", "new").unwrap();
+        assert_eq!(diff, "--- old\n+++ new\n@@ -1,2 +1,2 @@\n a\n-b\n+c\n");
+        assert_eq!(unified(b"a\n", b"a\n", "old", "new").unwrap(), "");
+        assert!(unified(b"a\0", b"a\n", "old", "new").is_none());
+        assert!(unified(b"a\n", &[0xff], "old", "new").is_none());
+    }
+}
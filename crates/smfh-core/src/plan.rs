@@ -0,0 +1,142 @@
+//! Read-only preview of what [`Manifest::diff`] would do, as returned by
+//! [`Manifest::plan`].
+//!
+//! [`Manifest::diff`]: crate::manifest::Manifest::diff
+//! [`Manifest::plan`]: crate::manifest::Manifest::plan
+
+use crate::manifest::FileKind;
+use core::fmt::{
+    self,
+    Display,
+};
+use serde::Serialize;
+use std::path::PathBuf;
+
+/// How an entry differs between the old and the new manifest.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    /// The entry is the same in both manifests.
+    Unchanged,
+    /// The entry's target is in both manifests, but something else changed.
+    Updated,
+    /// The entry is only in the old manifest.
+    Removed,
+    /// The entry is only in the new manifest.
+    New,
+    /// The entry's kind and source are in both manifests, but its target
+    /// moved.
+    Renamed,
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match *self {
+            Self::Unchanged => "unchanged",
+            Self::Updated => "updated",
+            Self::Removed => "removed",
+            Self::New => "new",
+            Self::Renamed => "renamed",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// What would be done to a target.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Step {
+    /// The target is already as it should be.
+    Nothing,
+    /// The entry would be skipped because its source is missing or invalid.
+    Skip,
+    /// The target does not exist and would be created.
+    Create,
+    /// The target would be replaced through a temporary file and a rename.
+    Swap,
+    /// The target would be moved aside with the backup prefix, then created.
+    BackUp,
+    /// The target would be deleted, then created.
+    Clobber,
+    /// Permissions, ownership, or the like of the target would be fixed.
+    Fix,
+    /// The target would be deleted.
+    Delete,
+    /// The old target would be moved to the new one, then fixed in place.
+    Rename,
+    /// The target would be left alone, either because the entry has
+    /// `deactivate` unset or because it was modified since activation.
+    Keep,
+}
+
+impl Step {
+    /// Whether the target would be changed.
+    #[must_use]
+    pub const fn changes(self) -> bool {
+        !matches!(self, Self::Nothing | Self::Skip | Self::Keep)
+    }
+}
+
+impl Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match *self {
+            Self::Nothing => "nothing",
+            Self::Skip => "skip",
+            Self::Create => "create",
+            Self::Swap => "atomic swap",
+            Self::BackUp => "back up and create",
+            Self::Clobber => "clobber and create",
+            Self::Fix => "fix in place",
+            Self::Delete => "delete",
+            Self::Rename => "rename",
+            Self::Keep => "keep",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// A single entry of a plan.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct PlanEntry {
+    pub target: PathBuf,
+    pub kind: FileKind,
+    /// Source of the entry in the new manifest, if it is in it.
+    pub source: Option<PathBuf>,
+    /// Source of the entry in the old manifest, if it is in it.
+    pub old_source: Option<PathBuf>,
+    pub change: Change,
+    pub step: Step,
+    /// Why [`step`][Self::step] would be taken, e.g. "target doesn't exist".
+    pub reason: &'static str,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serializes_snake_case_tags() {
+        let entry = PlanEntry {
+            target: PathBuf::from("/a"),
+            kind: FileKind::Copy,
+            source: Some(PathBuf::from("/new")),
+            old_source: Some(PathBuf::from("/old")),
+            change: Change::Updated,
+            step: Step::BackUp,
+            reason: "target was modified since activation",
+        };
+        assert_eq!(
+            serde_json::to_value(&entry).unwrap(),
+            serde_json::json!({
+                "target": "/a",
+                "kind": "copy",
+                "source": "/new",
+                "oldSource": "/old",
+                "change": "updated",
+                "step": "back_up",
+                "reason": "target was modified since activation",
+            })
+        );
+    }
+}
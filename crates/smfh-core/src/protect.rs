@@ -0,0 +1,152 @@
+//! Paths smfh refuses to clobber, back up, or delete, so that a malformed
+//! manifest can't destroy critical files.
+
+use crate::manifest::{
+    File,
+    FileKind,
+    rerooted,
+};
+use core::fmt::{
+    self,
+    Display,
+};
+use std::{
+    env,
+    fs,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+/// Protected by default, along with `$HOME`.
+pub const DEFAULT: &[&str] = &[
+    "/",
+    "/bin",
+    "/boot",
+    "/dev",
+    "/etc",
+    "/etc/group",
+    "/etc/gshadow",
+    "/etc/passwd",
+    "/etc/shadow",
+    "/etc/sudoers",
+    "/home",
+    "/lib",
+    "/nix",
+    "/nix/store",
+    "/proc",
+    "/root",
+    "/run",
+    "/sbin",
+    "/sys",
+    "/usr",
+    "/var",
+];
+
+/// Returns [`DEFAULT`] and `$HOME`, if set.
+#[must_use]
+pub fn defaults() -> Vec<PathBuf> {
+    DEFAULT
+        .iter()
+        .map(PathBuf::from)
+        .chain(env::var_os("HOME").map(PathBuf::from))
+        .collect()
+}
+
+/// Moves every path of `protected` under `root`, like
+/// [`Manifest::reroot`][crate::manifest::Manifest::reroot] does with targets.
+pub fn reroot(protected: &mut [PathBuf], root: &Path) {
+    for path in protected {
+        *path = rerooted(root, path);
+    }
+}
+
+/// An entry that would clobber, back up, or delete a protected path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtectedError {
+    pub target: PathBuf,
+    pub kind: FileKind,
+    /// Whether the entry is being deactivated rather than activated.
+    pub removal: bool,
+}
+
+impl Display for ProtectedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verb = if self.removal {
+            "deactivating"
+        } else {
+            "activating"
+        };
+        write!(
+            f,
+            "refusing {verb} {} '{}', which is a protected path",
+            self.kind,
+            self.target.display()
+        )
+    }
+}
+
+impl std::error::Error for ProtectedError {}
+
+/// Checks whether (de)activating `file` could replace or remove one of
+/// `protected`.
+///
+/// Activation may still create a protected path that doesn't exist, or
+/// modify one in place. Only the exact paths are protected, not what is
+/// under them.
+///
+/// # Errors
+///
+/// Returns a [`ProtectedError`] if it could.
+pub fn check(file: &File, protected: &[PathBuf], removal: bool) -> Result<(), ProtectedError> {
+    let target = if file.target.is_absolute() {
+        rerooted(Path::new("/"), &file.target)
+    } else {
+        file.target.clone()
+    };
+    if !protected.contains(&target) {
+        return Ok(());
+    }
+    let harmful = match file.kind {
+        FileKind::Modify => false,
+        FileKind::Delete => !removal,
+        _ if removal => true,
+        FileKind::Directory | FileKind::LinkFarm => {
+            fs::symlink_metadata(&target).is_ok_and(|x| !x.is_dir())
+        }
+        FileKind::Touch => fs::symlink_metadata(&target).is_ok_and(|x| !x.is_file()),
+        _ => fs::symlink_metadata(&target).is_ok(),
+    };
+    if harmful {
+        return Err(ProtectedError {
+            target: file.target.clone(),
+            kind: file.kind,
+            removal,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_refuses_replacing_protected_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let protected = vec![dir.path().to_path_buf(), dir.path().join("missing")];
+        let target = dir.path().join("x/..");
+        let check = |kind, target: &Path, removal| {
+            check(&File::new(kind, target.to_path_buf()), &protected, removal).is_ok()
+        };
+
+        assert!(!check(FileKind::Delete, &target, false));
+        assert!(!check(FileKind::Symlink, &target, false));
+        assert!(check(FileKind::Directory, &target, false));
+        assert!(!check(FileKind::Directory, &target, true));
+        assert!(check(FileKind::Modify, &target, true));
+        assert!(check(FileKind::Copy, &dir.path().join("missing"), false));
+        assert!(check(FileKind::Delete, &dir.path().join("other"), false));
+    }
+}
@@ -0,0 +1,273 @@
+//! Machine-readable record of what activation, deactivation, and diffing did
+//! to each target, for wrappers that should not have to parse log lines.
+
+use color_eyre::Result;
+use serde::Serialize;
+use std::{
+    fs,
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::{
+        Arc,
+        Mutex,
+        PoisonError,
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+/// Reason of [`Action::Skipped`] entries whose target needed no change, which
+/// [`Stats`] counts apart from other skipped ones.
+pub const ALREADY_CORRECT: &str = "already correct";
+
+/// What was done to a target, as recorded in a [`Report`].
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    /// The target did not exist, or was not in the way, and was created.
+    Created,
+    /// An existing target was deleted or atomically replaced.
+    Clobbered,
+    /// An existing target was moved aside with the backup prefix.
+    BackedUp,
+    /// Permissions or ownership of the target were changed.
+    Modified,
+    /// The target was deleted.
+    Deleted,
+    /// The target was moved here from the old target of its entry.
+    Renamed,
+    /// A backup was moved back into place after deleting the target.
+    Restored,
+    /// Nothing was done to the target.
+    Skipped,
+    /// An error occurred while handling the target.
+    Failed,
+}
+
+/// A single [`Action`] taken on a target.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub target: PathBuf,
+    pub action: Action,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Wall time spent in one phase of a run, such as reading the manifest or
+/// activating its files.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PhaseTime {
+    pub name: &'static str,
+    pub seconds: f64,
+}
+
+/// Counters over a [`Report`], for spotting e.g. every copy being rewritten
+/// on each run.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Stats {
+    pub created: usize,
+    /// Skipped because they already were as the manifest says.
+    pub correct: usize,
+    pub clobbered: usize,
+    pub backed_up: usize,
+    pub modified: usize,
+    pub deleted: usize,
+    pub renamed: usize,
+    pub restored: usize,
+    /// Skipped for any other reason, e.g. a missing source.
+    pub skipped: usize,
+    pub failed: usize,
+    /// Size of the files written by `copy` and `template` entries.
+    pub bytes_copied: u64,
+    /// In the order they first started.
+    pub phases: Vec<PhaseTime>,
+}
+
+/// Collects an [`Entry`] for everything activation, deactivation, and diffing
+/// do to targets, along with what [`Stats`] needs. Clones share the same
+/// entries.
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    entries: Arc<Mutex<Vec<Entry>>>,
+    bytes_copied: Arc<AtomicU64>,
+    phases: Arc<Mutex<Vec<(&'static str, Duration)>>>,
+}
+
+/// Adds the time since its creation to its phase of a [`Report`] when
+/// dropped.
+#[must_use]
+pub struct Phase<'a> {
+    report: &'a Report,
+    name: &'static str,
+    start: Instant,
+}
+
+impl Drop for Phase<'_> {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        let mut phases = self
+            .report
+            .phases
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some((_, total)) = phases.iter_mut().find(|(name, _)| *name == self.name) {
+            *total += elapsed;
+        } else {
+            phases.push((self.name, elapsed));
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Serialized<'a> {
+    files: &'a [Entry],
+    stats: Stats,
+}
+
+impl Report {
+    /// Appends an entry for `target`.
+    pub fn record(&self, target: &Path, action: Action, reason: Option<String>) {
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(Entry {
+                target: target.to_path_buf(),
+                action,
+                reason,
+            });
+    }
+
+    /// Counts `bytes` towards [`Stats::bytes_copied`].
+    pub fn add_copied(&self, bytes: u64) {
+        self.bytes_copied.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Starts timing the phase `name`, until the returned guard is dropped.
+    /// Phases timed repeatedly add up.
+    pub fn phase(&self, name: &'static str) -> Phase<'_> {
+        Phase {
+            report: self,
+            name,
+            start: Instant::now(),
+        }
+    }
+
+    /// Returns the entries recorded so far, in order.
+    #[must_use]
+    pub fn entries(&self) -> Vec<Entry> {
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Counts the entries recorded so far by action, along with the bytes
+    /// copied and the time spent in each phase.
+    #[must_use]
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats {
+            bytes_copied: self.bytes_copied.load(Ordering::Relaxed),
+            phases: self
+                .phases
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .iter()
+                .map(|&(name, time)| PhaseTime {
+                    name,
+                    seconds: time.as_secs_f64(),
+                })
+                .collect(),
+            ..Stats::default()
+        };
+        for entry in self
+            .entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+        {
+            let count = match entry.action {
+                Action::Created => &mut stats.created,
+                Action::Clobbered => &mut stats.clobbered,
+                Action::BackedUp => &mut stats.backed_up,
+                Action::Modified => &mut stats.modified,
+                Action::Deleted => &mut stats.deleted,
+                Action::Renamed => &mut stats.renamed,
+                Action::Restored => &mut stats.restored,
+                Action::Skipped if entry.reason.as_deref() == Some(ALREADY_CORRECT) => {
+                    &mut stats.correct
+                }
+                Action::Skipped => &mut stats.skipped,
+                Action::Failed => &mut stats.failed,
+            };
+            *count += 1;
+        }
+        stats
+    }
+
+    /// Writes the entries to `path` as a JSON object with a `files` array,
+    /// and their [`Stats`] as `stats`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if serialization or writing fails.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let entries = self.entries();
+        let json = serde_json::to_string_pretty(&Serialized {
+            files: &entries,
+            stats: self.stats(),
+        })?;
+        fs::write(path, json)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clones_share_entries() {
+        let report = Report::default();
+        let shared = report.clone();
+        report.record(Path::new("/a"), Action::Created, None);
+        shared.record(
+            Path::new("/b"),
+            Action::Skipped,
+            Some(String::from(ALREADY_CORRECT)),
+        );
+        report.add_copied(3);
+        drop(shared.phase("activate"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("report.json");
+        report.write(&path).unwrap();
+        let mut value: serde_json::Value =
+            serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(
+            value["files"],
+            serde_json::json!([
+                {"target": "/a", "action": "created"},
+                {"target": "/b", "action": "skipped", "reason": "already correct"},
+            ])
+        );
+        let stats = value["stats"].as_object_mut().unwrap();
+        assert_eq!(stats.remove("phases").unwrap()[0]["name"], "activate");
+        assert_eq!(
+            serde_json::Value::from(stats.clone()),
+            serde_json::json!({
+                "created": 1, "correct": 1, "clobbered": 0, "backedUp": 0,
+                "modified": 0, "deleted": 0, "renamed": 0, "restored": 0,
+                "skipped": 0, "failed": 0, "bytesCopied": 3,
+            })
+        );
+    }
+}
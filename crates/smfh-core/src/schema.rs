@@ -0,0 +1,286 @@
+//! JSON Schema of the manifest format, and validation of a manifest against
+//! it without touching the files it manages.
+
+use crate::{
+    VERSION,
+    fetch,
+    manifest::{
+        File,
+        Manifest,
+    },
+    migrate,
+};
+use core::fmt::{
+    self,
+    Display,
+};
+use schemars::{
+    Schema,
+    schema_for,
+};
+use serde_json::{
+    Map,
+    Value,
+};
+use std::{
+    collections::HashMap,
+    path::{
+        Component,
+        Path,
+    },
+};
+
+/// Returns the JSON Schema of the manifest format.
+#[must_use]
+pub fn schema() -> Schema {
+    schema_for!(Manifest)
+}
+
+/// A problem found by [`validate`], at a location such as
+/// `files[2].permissions`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    pub location: String,
+    pub message: String,
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+/// Checks a parsed manifest for structural errors and unknown fields.
+///
+/// Also reports invalid permissions and the entries [`Manifest::verify`]
+/// rejects. Unless `impure`, paths must be absolute, as they must be to be
+/// activated.
+///
+/// A manifest of an older version is checked after it is
+/// [upgraded][migrate::upgrade], as [`Manifest::read`] would. Unlike it, this
+/// does not follow includes or expand globs, so nothing but `value` is looked
+/// at.
+#[must_use]
+pub fn validate(value: &Value, impure: bool) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+    let upgraded;
+    let value = match value.get("version").and_then(Value::as_u64) {
+        Some(version) if version < VERSION => match migrate::upgrade(value.clone(), version) {
+            Ok(value) => {
+                upgraded = value;
+                &upgraded
+            }
+            Err(err) => {
+                push(&mut errors, "version", &format!("{err:#}"));
+                value
+            }
+        },
+        _ => value,
+    };
+    let Some(object) = value.as_object() else {
+        push(&mut errors, "manifest", "expected an object");
+        return errors;
+    };
+    unknown_fields(object, &schema(), "", &mut errors);
+
+    // Entries are checked one by one below, so that every error is reported
+    let mut rest = object.clone();
+    rest.remove("files");
+    match serde_json::from_value::<Manifest>(Value::Object(rest)) {
+        Ok(manifest) if manifest.version > VERSION => push(
+            &mut errors,
+            "version",
+            &format!("{} is newer than the supported {VERSION}", manifest.version),
+        ),
+        Ok(_) => {}
+        Err(err) => push(&mut errors, "manifest", &err.to_string()),
+    }
+
+    match object.get("files") {
+        None => {}
+        Some(Value::Array(files)) => {
+            let mut targets = HashMap::new();
+            for (i, file) in files.iter().enumerate() {
+                let location = format!("files[{i}]");
+                validate_file(file, &location, impure, &mut errors);
+                if let Some(target) = file.get("target").and_then(Value::as_str) {
+                    let first = *targets.entry(target).or_insert(i);
+                    if first != i {
+                        push(
+                            &mut errors,
+                            &field(&location, "target"),
+                            &format!("same as files[{first}].target"),
+                        );
+                    }
+                }
+            }
+        }
+        Some(_) => push(&mut errors, "files", "expected an array"),
+    }
+    errors
+}
+
+fn validate_file(value: &Value, location: &str, impure: bool, errors: &mut Vec<ValidationError>) {
+    let Some(object) = value.as_object() else {
+        push(errors, location, "expected an object");
+        return;
+    };
+    unknown_fields(object, &schema_for!(File), location, errors);
+
+    let mut object = object.clone();
+    if let Some(permissions) = object.get("permissions")
+        && permissions
+            .as_str()
+            .is_none_or(|x| u32::from_str_radix(x, 8).is_err())
+    {
+        push(
+            errors,
+            &field(location, "permissions"),
+            &format!("{permissions} is not an octal mode string such as \"644\""),
+        );
+        object.remove("permissions");
+    }
+    let file: File = match serde_json::from_value(Value::Object(object)) {
+        Ok(x) => x,
+        Err(err) => {
+            push(errors, location, &err.to_string());
+            return;
+        }
+    };
+
+    if !impure {
+        if !is_absolute(&file.target) {
+            push(
+                errors,
+                &field(location, "target"),
+                "must be absolute, without '..'",
+            );
+        }
+        if file
+            .source
+            .as_deref()
+            .is_some_and(|x| !x.is_absolute() && !fetch::is_url(x))
+        {
+            push(errors, &field(location, "source"), "must be absolute");
+        }
+    }
+    for err in Manifest::new(vec![file]).verify() {
+        push(errors, location, &err.to_string());
+    }
+}
+
+fn is_absolute(path: &Path) -> bool {
+    path.is_absolute() && !path.components().any(|x| x == Component::ParentDir)
+}
+
+fn unknown_fields(
+    object: &Map<String, Value>,
+    schema: &Schema,
+    location: &str,
+    errors: &mut Vec<ValidationError>,
+) {
+    let Some(properties) = schema.get("properties").and_then(Value::as_object) else {
+        return;
+    };
+    for key in object.keys() {
+        if !properties.contains_key(key) {
+            push(errors, &field(location, key), "unknown field");
+        }
+    }
+}
+
+fn field(location: &str, name: &str) -> String {
+    if location.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{location}.{name}")
+    }
+}
+
+fn push(errors: &mut Vec<ValidationError>, location: &str, message: &str) {
+    errors.push(ValidationError {
+        location: location.to_owned(),
+        message: message.to_owned(),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_reports_every_error() {
+        let value = serde_json::json!({
+            "version": 3,
+            "clober_by_default": true,
+            "files": [
+                {"type": "symlink", "source": "/a", "target": "/b"},
+                {"type": "copy", "target": "relative", "permissions": "999", "sorce": "/a", "text": "x"},
+                {"type": "hardlink", "target": "/c"},
+                {"type": "copy", "source": "https://example.com/a", "target": "/e"},
+                {"type": "nonsense", "target": "/d"},
+            ],
+        });
+        let errors: Vec<String> = validate(&value, false)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(errors.len(), 7, "{errors:#?}");
+        assert_eq!(errors[0], "clober_by_default: unknown field");
+        assert_eq!(errors[1], "files[1].sorce: unknown field");
+        assert!(errors[2].starts_with("files[1].permissions: \"999\" is not an octal"));
+        assert_eq!(errors[3], "files[1].target: must be absolute, without '..'");
+        assert_eq!(
+            errors[4],
+            "files[2]: file '/c' of type 'hardlink' requires a source"
+        );
+        assert_eq!(
+            errors[5],
+            "files[3]: file '/e' of type 'copy' requires a source_hash for a URL source"
+        );
+        assert!(errors[6].starts_with("files[4]: unknown variant `nonsense`"));
+
+        assert!(
+            validate(&value["files"][0], false)
+                .iter()
+                .any(|x| x.message.contains("missing field `version`"))
+        );
+    }
+
+    #[test]
+    fn validate_reports_duplicate_targets() {
+        let value = serde_json::json!({
+            "version": 3,
+            "files": [
+                {"type": "directory", "target": "/a"},
+                {"type": "delete", "target": "/b"},
+                {"type": "delete", "target": "/a"},
+            ],
+        });
+        assert_eq!(
+            validate(&value, false),
+            vec![ValidationError {
+                location: String::from("files[2].target"),
+                message: String::from("same as files[0].target"),
+            }]
+        );
+    }
+
+    #[test]
+    fn validate_upgrades_older_manifests() {
+        let mut value = serde_json::json!({
+            "version": 3,
+            "files": [{"type": "linkFarm", "source": "/s", "target": "/t"}],
+        });
+        assert_eq!(validate(&value, false), Vec::new());
+        value["version"] = VERSION.into();
+        assert_eq!(validate(&value, false).len(), 1);
+    }
+
+    #[test]
+    fn schema_describes_permissions() {
+        let schema = serde_json::to_value(schema()).unwrap();
+        let permissions = &schema["$defs"]["File"]["properties"]["permissions"];
+        assert_eq!(permissions["pattern"], "^[0-7]+$");
+    }
+}
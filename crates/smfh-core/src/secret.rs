@@ -0,0 +1,122 @@
+//! Decryption of encrypted [`Copy`][crate::manifest::FileKind::Copy]
+//! sources, so that secrets never have to be in the store in plain text.
+
+use color_eyre::{
+    Result,
+    eyre::{
+        WrapErr as _,
+        eyre,
+    },
+};
+use core::fmt::{
+    self,
+    Display,
+};
+use schemars::JsonSchema;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::{
+    env,
+    path::{
+        Path,
+        PathBuf,
+    },
+    process::{
+        Command,
+        Stdio,
+    },
+};
+
+/// Tool a source is encrypted with, run to decrypt it into memory.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum Decrypt {
+    /// `age`, with [`Options::identities`] or else those of
+    /// [`default_identities`].
+    ///
+    /// [`Options::identities`]: crate::options::Options::identities
+    Age,
+    /// `sops`, with the first of [`Options::identities`] as its age key
+    /// file if any, and its own key lookup otherwise.
+    ///
+    /// [`Options::identities`]: crate::options::Options::identities
+    Sops,
+}
+
+impl Display for Decrypt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match *self {
+            Self::Age => "age",
+            Self::Sops => "sops",
+        })
+    }
+}
+
+/// Returns the existing ones of `$SOPS_AGE_KEY_FILE`,
+/// `$XDG_CONFIG_HOME/sops/age/keys.txt`, `~/.ssh/id_ed25519`, and
+/// `/etc/ssh/ssh_host_ed25519_key`.
+#[must_use]
+pub fn default_identities() -> Vec<PathBuf> {
+    let home = env::var_os("HOME").map(PathBuf::from);
+    let config = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| home.as_ref().map(|x| x.join(".config")));
+    [
+        env::var_os("SOPS_AGE_KEY_FILE").map(PathBuf::from),
+        config.map(|x| x.join("sops/age/keys.txt")),
+        home.map(|x| x.join(".ssh/id_ed25519")),
+        Some(PathBuf::from("/etc/ssh/ssh_host_ed25519_key")),
+    ]
+    .into_iter()
+    .flatten()
+    .filter(|x| x.is_file())
+    .collect()
+}
+
+/// Decrypts `source` with `tool`, returning the plain text without writing
+/// it anywhere.
+///
+/// # Errors
+///
+/// Returns an error if `tool` can't be run or fails, with what it printed on
+/// stderr.
+pub fn decrypt(source: &Path, tool: Decrypt, identities: &[PathBuf]) -> Result<Vec<u8>> {
+    let mut command = Command::new(tool.to_string());
+    match tool {
+        Decrypt::Age => {
+            let defaults;
+            let identities = if identities.is_empty() {
+                defaults = default_identities();
+                &defaults
+            } else {
+                identities
+            };
+            command.arg("--decrypt");
+            for identity in identities {
+                command.arg("--identity").arg(identity);
+            }
+        }
+        Decrypt::Sops => {
+            if let Some(identity) = identities.first() {
+                command.env("SOPS_AGE_KEY_FILE", identity);
+            }
+            command.arg("--decrypt");
+        }
+    }
+    let output = command
+        .arg(source)
+        .stdin(Stdio::null())
+        .output()
+        .wrap_err_with(|| format!("Failed to run {tool}"))?;
+    if !output.status.success() {
+        return Err(eyre!(
+            "{tool} failed to decrypt '{}' with {}: {}",
+            source.display(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(output.stdout)
+}
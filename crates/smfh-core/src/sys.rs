@@ -0,0 +1,55 @@
+//! Platform-specific filesystem primitives. Everything outside this module
+//! should go through these helpers instead of reaching for
+//! `std::os::{unix,windows}` directly.
+
+#[cfg(unix)]
+mod unix;
+#[cfg(unix)]
+pub use unix::*;
+
+#[cfg(all(windows, feature = "windows"))]
+mod windows;
+#[cfg(all(windows, feature = "windows"))]
+pub use windows::*;
+
+#[cfg(all(windows, not(feature = "windows")))]
+compile_error!("Windows support is experimental, enable the `windows` feature to build it");
+
+use std::path::PathBuf;
+
+/// Whether files and directories can be made immutable with
+/// `set_immutable`, which only Linux supports.
+pub const IMMUTABLE_SUPPORTED: bool = cfg!(target_os = "linux");
+
+/// A special file, as created by [`Dir::mknod`] and described by [`node`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Node {
+    Fifo,
+    Socket,
+    /// Character device with a major and minor number.
+    CharDevice(u32, u32),
+    /// Block device with a major and minor number.
+    BlockDevice(u32, u32),
+}
+
+/// An entry of the user database, as looked up by [`user`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct User {
+    pub name: String,
+    pub uid: u32,
+    /// The primary group.
+    pub gid: u32,
+    pub home: PathBuf,
+}
+
+/// How [`Dir::open`] resolves the path to a directory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Resolve {
+    /// Follow symlinks like any other path lookup.
+    #[default]
+    Follow,
+    /// Fail if any component of the path is a symlink, so that nobody who
+    /// can write to a directory along the way can redirect it. Only
+    /// supported on Linux 5.6 and later, with `openat2`.
+    NoSymlinks,
+}
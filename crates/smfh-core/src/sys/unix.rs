@@ -0,0 +1,1934 @@
+use super::{
+    Node,
+    Resolve,
+    User,
+};
+use std::{
+    env,
+    ffi::{
+        CStr,
+        CString,
+        OsStr,
+        OsString,
+    },
+    fs::{
+        self,
+        Metadata,
+    },
+    io,
+    mem::{
+        self,
+        MaybeUninit,
+    },
+    os::{
+        fd::{
+            AsRawFd as _,
+            FromRawFd as _,
+            OwnedFd,
+        },
+        unix::{
+            ffi::OsStrExt as _,
+            fs::{
+                FileTypeExt as _,
+                MetadataExt as _,
+                OpenOptionsExt as _,
+                PermissionsExt as _,
+                chown as std_chown,
+                fchown,
+                lchown,
+                symlink as std_symlink,
+            },
+            process::CommandExt as _,
+        },
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    process::Command,
+    ptr,
+    time::{
+        Duration,
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+
+/// Creates a symlink at `target` pointing to `source`.
+///
+/// # Errors
+///
+/// Returns an error if symlink creation fails.
+pub fn symlink(source: &Path, target: &Path) -> io::Result<()> {
+    std_symlink(source, target)
+}
+
+/// An open directory whose entries are changed with the `*at` system calls.
+///
+/// The path to it is only resolved once, so none of its components can be
+/// swapped for a symlink between checking an entry and changing it. Entries
+/// themselves are never followed if they are symlinks.
+#[derive(Debug)]
+pub struct Dir(OwnedFd);
+
+impl Dir {
+    /// Opens the directory at `path`, resolved according to `resolve`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `path` is not a directory or can't be opened, or
+    /// with [`Resolve::NoSymlinks`] if it has a symlink in it or `openat2` is
+    /// not supported.
+    pub fn open(path: &Path, resolve: Resolve) -> io::Result<Self> {
+        match resolve {
+            Resolve::Follow => fs::File::options()
+                .read(true)
+                .custom_flags(libc::O_DIRECTORY)
+                .open(path)
+                .map(|x| Self(x.into())),
+            Resolve::NoSymlinks => open_no_symlinks(path).map_err(|err| {
+                if err.raw_os_error() == Some(libc::ELOOP) {
+                    io::Error::new(
+                        err.kind(),
+                        format!("'{}' has a symlink in it", path.display()),
+                    )
+                } else {
+                    err
+                }
+            }),
+        }
+    }
+
+    /// Opens the parent directory of `path` like [`open`][Self::open],
+    /// returning it and the file name of `path` within it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `path` has no file name, or its parent can't be
+    /// opened.
+    pub fn open_parent(path: &Path, resolve: Resolve) -> io::Result<(Self, OsString)> {
+        let name = path.file_name().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("'{}' has no file name", path.display()),
+            )
+        })?;
+        let parent = path
+            .parent()
+            .filter(|x| !x.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        Ok((Self::open(parent, resolve)?, name.to_owned()))
+    }
+
+    /// Sets the permission bits of `name` to `mode`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `name` is a symlink or setting permissions fails.
+    pub fn set_mode(&self, name: &OsStr, mode: u32) -> io::Result<()> {
+        let name = c_name(name)?;
+        let mode = libc::mode_t::try_from(mode).map_err(|_| io::ErrorKind::InvalidInput)?;
+        // SAFETY: `name` is terminated and the descriptor stays open for the
+        // duration of the call
+        let chmod = |flags| unsafe { libc::fchmodat(self.fd(), name.as_ptr(), mode, flags) };
+        match cvt(chmod(libc::AT_SYMLINK_NOFOLLOW)) {
+            // Older C libraries can't avoid following anything, which only
+            // matters for symlinks
+            Err(err)
+                if err.raw_os_error() == Some(libc::EOPNOTSUPP) && !self.is_symlink(&name)? =>
+            {
+                cvt(chmod(0))
+            }
+            result => result,
+        }
+    }
+
+    /// Changes the ownership of `name`, leaving the uid or gid alone if
+    /// `None`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `fchownat` fails.
+    pub fn chown(&self, name: &OsStr, uid: Option<u32>, gid: Option<u32>) -> io::Result<()> {
+        let name = c_name(name)?;
+        // SAFETY: `name` is terminated and the descriptor stays open for the
+        // duration of the call. `-1` leaves an id alone.
+        cvt(unsafe {
+            libc::fchownat(
+                self.fd(),
+                name.as_ptr(),
+                uid.unwrap_or(libc::uid_t::MAX),
+                gid.unwrap_or(libc::gid_t::MAX),
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        })
+    }
+
+    /// Sets the modification time of `name`, leaving its access time alone.
+    /// A symlink gets its own time, not that of what it points to.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `mtime` is out of range or setting the time
+    /// fails.
+    pub fn set_mtime(&self, name: &OsStr, mtime: SystemTime) -> io::Result<()> {
+        fn out_of_range<E>(_: E) -> io::Error {
+            io::ErrorKind::InvalidInput.into()
+        }
+
+        let name = c_name(name)?;
+        let (secs, nanos) = match mtime.duration_since(UNIX_EPOCH) {
+            Ok(x) => (i128::from(x.as_secs()), x.subsec_nanos()),
+            Err(err) => {
+                let x = err.duration();
+                match x.subsec_nanos() {
+                    0 => (-i128::from(x.as_secs()), 0),
+                    nanos => (-i128::from(x.as_secs()) - 1, 1_000_000_000 - nanos),
+                }
+            }
+        };
+        let times = [
+            libc::timespec {
+                tv_sec: 0,
+                tv_nsec: libc::UTIME_OMIT,
+            },
+            libc::timespec {
+                tv_sec: secs.try_into().map_err(out_of_range)?,
+                // Below a billion, so it fits any `c_long`
+                tv_nsec: libc::c_long::from(nanos.cast_signed()),
+            },
+        ];
+        // SAFETY: `name` is terminated, `times` has two entries, and the
+        // descriptor stays open for the duration of the call
+        cvt(unsafe {
+            libc::utimensat(
+                self.fd(),
+                name.as_ptr(),
+                times.as_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        })
+    }
+
+    /// Creates a symlink `name` pointing to `source`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if symlink creation fails.
+    pub fn symlink(&self, source: &Path, name: &OsStr) -> io::Result<()> {
+        let source = c_name(source.as_os_str())?;
+        let name = c_name(name)?;
+        // SAFETY: both strings are terminated and the descriptor stays open
+        // for the duration of the call
+        cvt(unsafe { libc::symlinkat(source.as_ptr(), self.fd(), name.as_ptr()) })
+    }
+
+    /// Creates `name` as a hard link of `source`, which is not followed if
+    /// it is a symlink.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if linking fails, e.g. across filesystems.
+    pub fn hard_link(&self, source: &Path, name: &OsStr) -> io::Result<()> {
+        let source = c_name(source.as_os_str())?;
+        let name = c_name(name)?;
+        // SAFETY: both strings are terminated and the descriptor stays open
+        // for the duration of the call
+        cvt(unsafe { libc::linkat(libc::AT_FDCWD, source.as_ptr(), self.fd(), name.as_ptr(), 0) })
+    }
+
+    /// Renames `from` to `to`, replacing `to` if it exists.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if renaming fails.
+    pub fn rename(&self, from: &OsStr, to: &OsStr) -> io::Result<()> {
+        let from = c_name(from)?;
+        let to = c_name(to)?;
+        // SAFETY: both strings are terminated and the descriptor stays open
+        // for the duration of the call
+        cvt(unsafe { libc::renameat(self.fd(), from.as_ptr(), self.fd(), to.as_ptr()) })
+    }
+
+    /// Creates the special file `name` with `mode`, minus the umask. Device
+    /// nodes can usually only be created by root.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `name` exists or can't be created.
+    pub fn mknod(&self, name: &OsStr, node: Node, mode: u32) -> io::Result<()> {
+        let name = c_name(name)?;
+        let (kind, device) = match node {
+            Node::Fifo => (libc::S_IFIFO, 0),
+            Node::Socket => (libc::S_IFSOCK, 0),
+            Node::CharDevice(major, minor) => (libc::S_IFCHR, makedev(major, minor)),
+            Node::BlockDevice(major, minor) => (libc::S_IFBLK, makedev(major, minor)),
+        };
+        let mode = libc::mode_t::try_from(mode).map_err(|_| io::ErrorKind::InvalidInput)?;
+        // SAFETY: `name` is terminated and the descriptor stays open for the
+        // duration of the call
+        cvt(unsafe { libc::mknodat(self.fd(), name.as_ptr(), kind | mode, device) })
+    }
+
+    /// Creates a new file `name` with `mode`, minus the umask, for writing.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `name` exists or can't be created.
+    pub fn create(&self, name: &OsStr, mode: u32) -> io::Result<fs::File> {
+        let name = c_name(name)?;
+        let flags =
+            libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+        // SAFETY: `name` is terminated and the descriptor stays open for the
+        // duration of the call
+        let fd = unsafe { libc::openat(self.fd(), name.as_ptr(), flags, mode) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` was just opened and nothing else owns it
+        Ok(fs::File::from(unsafe { OwnedFd::from_raw_fd(fd) }))
+    }
+
+    /// Creates a file in this directory that has no name, with `mode` minus
+    /// the umask, for writing. It disappears when closed unless given a name
+    /// with [`link_anonymous`][Self::link_anonymous].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::Unsupported`] if the filesystem or kernel
+    /// doesn't support `O_TMPFILE`, or another error if creating fails.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn create_anonymous(&self, mode: u32) -> io::Result<fs::File> {
+        // SAFETY: the path is terminated and the descriptor stays open for the
+        // duration of the call
+        let fd = unsafe {
+            libc::openat(
+                self.fd(),
+                c".".as_ptr(),
+                libc::O_TMPFILE | libc::O_WRONLY | libc::O_CLOEXEC,
+                mode,
+            )
+        };
+        if fd == -1 {
+            let err = io::Error::last_os_error();
+            // Kernels before 3.11 only see `O_DIRECTORY` in `O_TMPFILE`
+            return Err(match err.raw_os_error() {
+                Some(libc::EOPNOTSUPP | libc::EISDIR) => io::ErrorKind::Unsupported.into(),
+                _ => err,
+            });
+        }
+        // SAFETY: `fd` was just opened and nothing else owns it
+        Ok(fs::File::from(unsafe { OwnedFd::from_raw_fd(fd) }))
+    }
+
+    /// Anonymous files are only supported on Linux.
+    ///
+    /// # Errors
+    ///
+    /// Always returns [`io::ErrorKind::Unsupported`].
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub fn create_anonymous(&self, _mode: u32) -> io::Result<fs::File> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Gives `file`, created with [`create_anonymous`][Self::create_anonymous],
+    /// the name `name` in this directory.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `name` exists, or linking fails both with
+    /// `AT_EMPTY_PATH`, which may require privileges, and through `/proc`.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn link_anonymous(&self, file: &fs::File, name: &OsStr) -> io::Result<()> {
+        let name = c_name(name)?;
+        // SAFETY: both strings are terminated and both descriptors stay open
+        // for the duration of the call
+        let link = |fd, path: &CStr, flags| unsafe {
+            libc::linkat(fd, path.as_ptr(), self.fd(), name.as_ptr(), flags)
+        };
+        if cvt(link(file.as_raw_fd(), c"", libc::AT_EMPTY_PATH)).is_ok() {
+            return Ok(());
+        }
+        let proc = c_name(OsStr::new(&format!("/proc/self/fd/{}", file.as_raw_fd())))?;
+        cvt(link(libc::AT_FDCWD, &proc, libc::AT_SYMLINK_FOLLOW))
+    }
+
+    /// Anonymous files are only supported on Linux.
+    ///
+    /// # Errors
+    ///
+    /// Always returns [`io::ErrorKind::Unsupported`].
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub fn link_anonymous(&self, _file: &fs::File, _name: &OsStr) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Removes `name`, which must not be a directory.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if removing fails.
+    pub fn remove_file(&self, name: &OsStr) -> io::Result<()> {
+        self.unlink(name, 0)
+    }
+
+    /// Removes the empty directory `name`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `name` is not an empty directory or removing
+    /// fails.
+    pub fn remove_dir(&self, name: &OsStr) -> io::Result<()> {
+        self.unlink(name, libc::AT_REMOVEDIR)
+    }
+
+    /// Removes the directory `name` and everything in it. Symlinks in the
+    /// tree are removed rather than followed, and each directory is opened
+    /// relative to its parent, so none can be swapped for a symlink on the
+    /// way down.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `name` is not a directory, or anything in it
+    /// can't be removed.
+    pub fn remove_dir_all(&self, name: &OsStr) -> io::Result<()> {
+        let dir = self.open_dir(name)?;
+        for entry in dir.entries()? {
+            if dir.mode(&c_name(&entry)?)? & libc::S_IFMT == libc::S_IFDIR {
+                dir.remove_dir_all(&entry)?;
+            } else {
+                dir.remove_file(&entry)?;
+            }
+        }
+        self.remove_dir(name)
+    }
+
+    /// Flushes the directory's entries to disk, so that names created,
+    /// renamed, or removed in it survive a crash.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `fsync` fails.
+    pub fn sync(&self) -> io::Result<()> {
+        // SAFETY: the descriptor stays open for the duration of the call
+        cvt(unsafe { libc::fsync(self.fd()) })
+    }
+
+    fn unlink(&self, name: &OsStr, flags: libc::c_int) -> io::Result<()> {
+        let name = c_name(name)?;
+        // SAFETY: `name` is terminated and the descriptor stays open for the
+        // duration of the call
+        cvt(unsafe { libc::unlinkat(self.fd(), name.as_ptr(), flags) })
+    }
+
+    fn is_symlink(&self, name: &CStr) -> io::Result<bool> {
+        Ok(self.mode(name)? & libc::S_IFMT == libc::S_IFLNK)
+    }
+
+    /// Returns the mode of `name`, including its file type, without
+    /// following it.
+    fn mode(&self, name: &CStr) -> io::Result<libc::mode_t> {
+        let mut stat = MaybeUninit::<libc::stat>::uninit();
+        // SAFETY: `name` is terminated, `stat` is valid for writes, and the
+        // descriptor stays open for the duration of the call
+        cvt(unsafe {
+            libc::fstatat(
+                self.fd(),
+                name.as_ptr(),
+                stat.as_mut_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        })?;
+        // SAFETY: `fstatat` succeeded, so `stat` was filled in
+        Ok(unsafe { stat.assume_init() }.st_mode)
+    }
+
+    /// Opens the directory `name`, failing if it is a symlink.
+    fn open_dir(&self, name: &OsStr) -> io::Result<Self> {
+        let name = c_name(name)?;
+        // SAFETY: `name` is terminated and the descriptor stays open for the
+        // duration of the call
+        let fd = unsafe {
+            libc::openat(
+                self.fd(),
+                name.as_ptr(),
+                libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            )
+        };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` was just opened and nothing else owns it
+        Ok(Self(unsafe { OwnedFd::from_raw_fd(fd) }))
+    }
+
+    /// Returns the names of the entries in the directory, without `.` and
+    /// `..`.
+    fn entries(&self) -> io::Result<Vec<OsString>> {
+        // The stream takes ownership of the descriptor it reads, so it gets
+        // a copy
+        let fd = self.0.try_clone()?;
+        // SAFETY: the stream owns `fd` from here on, and closes it
+        let stream = unsafe { libc::fdopendir(fd.as_raw_fd()) };
+        if stream.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        mem::forget(fd);
+
+        let mut entries = Vec::new();
+        // A failure to read ends the list early, which leaves the directory
+        // not empty and so fails removing it
+        loop {
+            // SAFETY: `stream` is open until `closedir` below
+            let entry = unsafe { libc::readdir(stream) };
+            if entry.is_null() {
+                break;
+            }
+            // SAFETY: `readdir` returned an entry, whose name is terminated
+            // and valid until the next call
+            let name = unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) };
+            if name != c"." && name != c".." {
+                entries.push(OsStr::from_bytes(name.to_bytes()).to_owned());
+            }
+        }
+        // SAFETY: `stream` is open and not used afterwards
+        unsafe { libc::closedir(stream) };
+        Ok(entries)
+    }
+
+    fn fd(&self) -> libc::c_int {
+        self.0.as_raw_fd()
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn open_no_symlinks(path: &Path) -> io::Result<Dir> {
+    let path = c_name(path.as_os_str())?;
+    // SAFETY: all zeros is a valid `open_how`, which can't be built otherwise
+    let mut how: libc::open_how = unsafe { std::mem::zeroed() };
+    how.flags = (libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC) as u64;
+    how.resolve = libc::RESOLVE_NO_SYMLINKS;
+    // SAFETY: `path` is terminated and `how` is valid for reads of its size
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            &raw const how,
+            size_of::<libc::open_how>(),
+        )
+    };
+    let fd = libc::c_int::try_from(fd).map_err(|_| io::ErrorKind::InvalidData)?;
+    if fd == -1 {
+        let err = io::Error::last_os_error();
+        return Err(if err.raw_os_error() == Some(libc::ENOSYS) {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                "refusing symlinks requires openat2, which this kernel lacks",
+            )
+        } else {
+            err
+        });
+    }
+    // SAFETY: `fd` was just opened and nothing else owns it
+    Ok(Dir(unsafe { OwnedFd::from_raw_fd(fd) }))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn open_no_symlinks(_path: &Path) -> io::Result<Dir> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "refusing symlinks requires openat2, which is only available on Linux",
+    ))
+}
+
+fn c_name(name: &OsStr) -> io::Result<CString> {
+    Ok(CString::new(name.as_bytes())?)
+}
+
+fn cvt(result: libc::c_int) -> io::Result<()> {
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns the permission bits of `metadata`, including
+/// setuid/setgid/sticky.
+#[must_use]
+pub fn mode(metadata: &Metadata) -> u32 {
+    metadata.mode() & 0o7_777
+}
+
+/// Returns `true` if the permission bits of `metadata` are exactly `mode`.
+#[must_use]
+pub fn mode_matches(metadata: &Metadata, mode: u32) -> bool {
+    self::mode(metadata) == mode
+}
+
+/// Flushes the contents and metadata of the file at `path` to disk.
+///
+/// # Errors
+///
+/// Returns an error if opening `path` or `fsync` fails.
+pub fn sync_file(path: &Path) -> io::Result<()> {
+    fs::File::open(path)?.sync_all()
+}
+
+/// Sets the permission bits of `path` to `mode`.
+///
+/// # Errors
+///
+/// Returns an error if setting permissions fails.
+pub fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+/// Sets the permission bits of the open `file` to `mode`.
+///
+/// # Errors
+///
+/// Returns an error if `fchmod` fails.
+pub fn set_file_mode(file: &fs::File, mode: u32) -> io::Result<()> {
+    file.set_permissions(fs::Permissions::from_mode(mode))
+}
+
+/// Changes the ownership of the open `file`.
+///
+/// # Errors
+///
+/// Returns an error if `fchown` fails.
+pub fn chown_file(file: &fs::File, uid: Option<u32>, gid: Option<u32>) -> io::Result<()> {
+    fchown(file, uid, gid)
+}
+
+/// Returns the `(uid, gid)` owning `metadata`.
+#[allow(clippy::unnecessary_wraps)]
+#[must_use]
+pub fn owner(metadata: &Metadata) -> Option<(u32, u32)> {
+    Some((metadata.uid(), metadata.gid()))
+}
+
+/// Returns `true` if both metadata describe the same inode, i.e. the files
+/// are hard links of each other.
+#[must_use]
+pub fn same_file(left: &Metadata, right: &Metadata) -> bool {
+    left.dev() == right.dev() && left.ino() == right.ino()
+}
+
+/// Returns `true` if `err` is from too many symlinks in a path, i.e. a
+/// symlink loop.
+#[must_use]
+pub fn is_loop(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::ELOOP)
+}
+
+/// Returns the device number of `major` and `minor`. Their types differ
+/// between platforms, e.g. signed on Apple ones.
+#[allow(
+    clippy::cast_possible_wrap,
+    clippy::cast_sign_loss,
+    clippy::cast_possible_truncation
+)]
+const fn makedev(major: u32, minor: u32) -> libc::dev_t {
+    libc::makedev(major as _, minor as _)
+}
+
+/// Returns the major and minor number of the device number `rdev`.
+#[allow(
+    clippy::cast_possible_wrap,
+    clippy::cast_sign_loss,
+    clippy::cast_possible_truncation
+)]
+const fn device(rdev: u64) -> (u32, u32) {
+    let rdev = rdev as _;
+    (libc::major(rdev) as _, libc::minor(rdev) as _)
+}
+
+/// Returns the kind of special file `metadata` describes, if it is one.
+#[must_use]
+pub fn node(metadata: &Metadata) -> Option<Node> {
+    let file_type = metadata.file_type();
+    let device = || device(metadata.rdev());
+    if file_type.is_fifo() {
+        Some(Node::Fifo)
+    } else if file_type.is_socket() {
+        Some(Node::Socket)
+    } else if file_type.is_char_device() {
+        let (major, minor) = device();
+        Some(Node::CharDevice(major, minor))
+    } else if file_type.is_block_device() {
+        let (major, minor) = device();
+        Some(Node::BlockDevice(major, minor))
+    } else {
+        None
+    }
+}
+
+/// Sets the umask of the process to `mask`.
+///
+/// # Errors
+///
+/// Returns an error if `mask` is larger than `777`.
+pub fn set_umask(mask: u32) -> io::Result<()> {
+    if mask > 0o777 {
+        return Err(io::ErrorKind::InvalidInput.into());
+    }
+    let mask = libc::mode_t::try_from(mask).map_err(|_| io::ErrorKind::InvalidInput)?;
+    // SAFETY: `umask` always succeeds
+    unsafe { libc::umask(mask) };
+    Ok(())
+}
+
+/// Returns `true` if both metadata describe files on the same filesystem.
+#[must_use]
+pub fn same_device(left: &Metadata, right: &Metadata) -> bool {
+    left.dev() == right.dev()
+}
+
+/// Returns the value of the extended attribute `name` of `path`, or `None`
+/// if it is not set. Symlinks themselves are inspected.
+///
+/// # Errors
+///
+/// Returns an error if reading the attribute fails.
+pub fn xattr(path: &Path, name: &str) -> io::Result<Option<Vec<u8>>> {
+    xattr::get(path, name)
+}
+
+/// Sets the extended attribute `name` of `path` to `value`. Symlinks
+/// themselves are changed.
+///
+/// # Errors
+///
+/// Returns an error if setting the attribute fails.
+pub fn set_xattr(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+    xattr::set(path, name, value)
+}
+
+/// `FS_IMMUTABLE_FL` from `linux/fs.h`.
+#[cfg(target_os = "linux")]
+const IMMUTABLE_FL: libc::c_int = 0x10;
+
+/// Returns `true` if `path` has the immutable flag, as set by `chattr +i`.
+/// Symlinks are not followed.
+///
+/// # Errors
+///
+/// Returns an error if `path` can't be opened, or its filesystem has no
+/// such flag.
+#[cfg(target_os = "linux")]
+pub fn immutable(path: &Path) -> io::Result<bool> {
+    Ok(flags(&open_flags(path)?)? & IMMUTABLE_FL != 0)
+}
+
+/// Sets or clears the immutable flag of `path`, which keeps everyone,
+/// root included, from changing, renaming, or removing it. Symlinks are not
+/// followed.
+///
+/// # Errors
+///
+/// Returns an error if `path` can't be opened, its filesystem has no such
+/// flag, or the process lacks `CAP_LINUX_IMMUTABLE`.
+#[cfg(target_os = "linux")]
+pub fn set_immutable(path: &Path, immutable: bool) -> io::Result<()> {
+    let file = open_flags(path)?;
+    let old = flags(&file)?;
+    let new = if immutable {
+        old | IMMUTABLE_FL
+    } else {
+        old & !IMMUTABLE_FL
+    };
+    if new == old {
+        return Ok(());
+    }
+    // SAFETY: the descriptor stays open and `new` outlives the call
+    cvt(unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_SETFLAGS, &raw const new) })
+}
+
+/// Opens `path` for reading its flags, without blocking on fifos.
+#[cfg(target_os = "linux")]
+fn open_flags(path: &Path) -> io::Result<fs::File> {
+    fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+        .open(path)
+}
+
+#[cfg(target_os = "linux")]
+fn flags(file: &fs::File) -> io::Result<libc::c_int> {
+    let mut flags: libc::c_int = 0;
+    // SAFETY: the descriptor stays open and `flags` outlives the call
+    cvt(unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &raw mut flags) })?;
+    Ok(flags)
+}
+
+/// The immutable flag is only supported on Linux.
+///
+/// # Errors
+///
+/// Always returns [`io::ErrorKind::Unsupported`].
+#[cfg(not(target_os = "linux"))]
+pub fn immutable(_path: &Path) -> io::Result<bool> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// The immutable flag is only supported on Linux.
+///
+/// # Errors
+///
+/// Always returns [`io::ErrorKind::Unsupported`].
+#[cfg(not(target_os = "linux"))]
+pub fn set_immutable(_path: &Path, _immutable: bool) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Accesses of Landlock ABI 1 that change the filesystem: writing files,
+/// removing files and directories, and making every kind of file.
+#[cfg(target_os = "linux")]
+const LANDLOCK_WRITES: u64 = 1 << 1 | 0x1ff << 4;
+/// Linking or renaming across directories, from ABI 2.
+#[cfg(target_os = "linux")]
+const LANDLOCK_REFER: u64 = 1 << 13;
+/// Truncating files, from ABI 3.
+#[cfg(target_os = "linux")]
+const LANDLOCK_TRUNCATE: u64 = 1 << 14;
+
+/// `struct landlock_ruleset_attr` of ABI 1.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+/// `struct landlock_path_beneath_attr`.
+#[cfg(target_os = "linux")]
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// Restricts the calling thread with Landlock to only write under `dirs`.
+///
+/// Applies to every thread and process it starts from then on, and covers
+/// creating, writing, renaming, and removing files. Reading, and changing
+/// metadata, stay unrestricted. Restrictions can't be lifted, and applying more
+/// only narrows them.
+///
+/// # Errors
+///
+/// Returns [`io::ErrorKind::Unsupported`] if the kernel lacks Landlock, and
+/// an error if one of `dirs` can't be opened or the restriction fails.
+#[cfg(target_os = "linux")]
+pub fn landlock(dirs: &[PathBuf]) -> io::Result<()> {
+    let landlock = |result: libc::c_long| -> io::Result<libc::c_long> {
+        if result == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(result)
+        }
+    };
+    // SAFETY: asking for the ABI version takes no attributes
+    let abi = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            ptr::null::<RulesetAttr>(),
+            0,
+            1,
+        )
+    };
+    if abi < 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Landlock is not available on this kernel",
+        ));
+    }
+    let mut handled = LANDLOCK_WRITES;
+    if abi >= 2 {
+        handled |= LANDLOCK_REFER;
+    }
+    if abi >= 3 {
+        handled |= LANDLOCK_TRUNCATE;
+    }
+
+    let attr = RulesetAttr {
+        handled_access_fs: handled,
+    };
+    // SAFETY: `attr` is valid for reads of its size
+    let fd = landlock(unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &raw const attr,
+            size_of::<RulesetAttr>(),
+            0,
+        )
+    })?;
+    let fd = libc::c_int::try_from(fd).map_err(|_| io::ErrorKind::InvalidData)?;
+    // SAFETY: `fd` was just opened and nothing else owns it
+    let ruleset = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    for dir in dirs {
+        let dir = fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_DIRECTORY)
+            .open(dir)?;
+        let rule = PathBeneathAttr {
+            allowed_access: handled,
+            parent_fd: dir.as_raw_fd(),
+        };
+        // SAFETY: both descriptors stay open and `rule` outlives the call
+        landlock(unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                1,
+                &raw const rule,
+                0,
+            )
+        })?;
+    }
+
+    let restrict = || {
+        // SAFETY: the descriptor stays open for the duration of the call
+        landlock(unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) })
+    };
+    match restrict() {
+        // Without CAP_SYS_ADMIN, gaining privileges through exec has to be
+        // ruled out first
+        Err(err) if err.raw_os_error() == Some(libc::EPERM) => {
+            // SAFETY: `PR_SET_NO_NEW_PRIVS` takes no pointers
+            cvt(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) })?;
+            restrict()
+        }
+        result => result,
+    }
+    .map(drop)
+}
+
+/// Landlock is only available on Linux.
+///
+/// # Errors
+///
+/// Always returns [`io::ErrorKind::Unsupported`].
+#[cfg(not(target_os = "linux"))]
+pub fn landlock(_dirs: &[PathBuf]) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// `AUDIT_ARCH_*` of the architecture, which a filter has to check so that
+/// system calls can't be made under the numbers of another one.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// Not in `libc` for every architecture, but the same number on all of them.
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+const SYS_FCHMODAT2: libc::c_long = 452;
+
+/// System calls allowed by [`seccomp`]: those for files and their metadata,
+/// for memory and threads, for local sockets, and for watching and
+/// sandboxing, which can only narrow what is allowed. `socket`, `ioctl`,
+/// `prctl`, and `clone` are allowed separately, and only with some
+/// arguments.
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+const ALLOWED_SYSCALLS: &[libc::c_long] = &[
+    // Files
+    libc::SYS_read,
+    libc::SYS_readv,
+    libc::SYS_pread64,
+    libc::SYS_write,
+    libc::SYS_writev,
+    libc::SYS_pwrite64,
+    libc::SYS_lseek,
+    libc::SYS_openat,
+    libc::SYS_openat2,
+    libc::SYS_close,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_statfs,
+    libc::SYS_fstatfs,
+    libc::SYS_faccessat,
+    libc::SYS_faccessat2,
+    libc::SYS_getdents64,
+    libc::SYS_getcwd,
+    libc::SYS_readlinkat,
+    libc::SYS_mkdirat,
+    libc::SYS_mknodat,
+    libc::SYS_symlinkat,
+    libc::SYS_linkat,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    libc::SYS_unlinkat,
+    libc::SYS_ftruncate,
+    libc::SYS_fallocate,
+    libc::SYS_copy_file_range,
+    libc::SYS_sendfile,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_flock,
+    libc::SYS_fcntl,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    // Metadata
+    libc::SYS_umask,
+    libc::SYS_fchmod,
+    libc::SYS_fchmodat,
+    SYS_FCHMODAT2,
+    libc::SYS_fchown,
+    libc::SYS_fchownat,
+    libc::SYS_utimensat,
+    libc::SYS_getxattr,
+    libc::SYS_lgetxattr,
+    libc::SYS_fgetxattr,
+    libc::SYS_listxattr,
+    libc::SYS_llistxattr,
+    libc::SYS_flistxattr,
+    libc::SYS_setxattr,
+    libc::SYS_lsetxattr,
+    libc::SYS_fsetxattr,
+    libc::SYS_removexattr,
+    libc::SYS_lremovexattr,
+    libc::SYS_fremovexattr,
+    libc::SYS_setfsuid,
+    libc::SYS_setfsgid,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    // Memory and threads
+    libc::SYS_brk,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_futex,
+    libc::SYS_set_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sigaltstack,
+    libc::SYS_getpid,
+    libc::SYS_gettid,
+    libc::SYS_tgkill,
+    libc::SYS_prlimit64,
+    libc::SYS_getrandom,
+    libc::SYS_uname,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_ppoll,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    // Local sockets, for notifying systemd and looking up users
+    libc::SYS_connect,
+    libc::SYS_sendto,
+    libc::SYS_sendmsg,
+    libc::SYS_recvfrom,
+    libc::SYS_recvmsg,
+    // Watching and sandboxing
+    libc::SYS_inotify_init1,
+    libc::SYS_inotify_add_watch,
+    libc::SYS_inotify_rm_watch,
+    libc::SYS_seccomp,
+    libc::SYS_landlock_create_ruleset,
+    libc::SYS_landlock_add_rule,
+    libc::SYS_landlock_restrict_self,
+    // Older variants of the above
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_open,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_stat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_lstat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_access,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_readlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_mkdir,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_rmdir,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_unlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_rename,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_symlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_link,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_chmod,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_chown,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_lchown,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_poll,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_arch_prctl,
+];
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+thread_local! {
+    /// Whether [`seccomp`] already filtered this thread.
+    static FILTERED: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+/// Restricts the calling thread with a seccomp filter to the system calls smfh
+/// needs for files.
+///
+/// Applies to every thread and process it starts from then on. Others, such
+/// as running programs or opening network sockets, fail with
+/// [`io::ErrorKind::PermissionDenied`]. Does nothing if the thread was
+/// already filtered.
+///
+/// # Errors
+///
+/// Returns [`io::ErrorKind::Unsupported`] if the kernel lacks seccomp
+/// filters, or an error if the filter can't be installed.
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+#[allow(clippy::too_many_lines)]
+pub fn seccomp() -> io::Result<()> {
+    // BPF codes are 16 bits, but `libc` has them as `u32`
+    #[allow(clippy::cast_possible_truncation)]
+    const fn insn(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        }
+    }
+    const fn load(offset: u32) -> libc::sock_filter {
+        insn(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, offset, 0, 0)
+    }
+    const fn ret(action: u32) -> libc::sock_filter {
+        insn(libc::BPF_RET | libc::BPF_K, action, 0, 0)
+    }
+    fn word<T: TryInto<u32>>(x: T) -> io::Result<u32> {
+        x.try_into().map_err(|_| io::ErrorKind::InvalidData.into())
+    }
+    // Allows `syscall` only if the low half of the argument at `offset`,
+    // masked with `mask`, is one of `values`
+    fn allow_if(
+        syscall: libc::c_long,
+        offset: u32,
+        mask: Option<u32>,
+        values: &[u32],
+    ) -> io::Result<Vec<libc::sock_filter>> {
+        let after = u8::try_from(values.len() + 3 + usize::from(mask.is_some()))
+            .map_err(|_| io::ErrorKind::InvalidData)?;
+        let mut block = vec![load(0), insn(JEQ, word(syscall)?, 0, after), load(offset)];
+        if let Some(mask) = mask {
+            block.push(insn(
+                libc::BPF_ALU | libc::BPF_AND | libc::BPF_K,
+                mask,
+                0,
+                0,
+            ));
+        }
+        for (i, &value) in values.iter().enumerate() {
+            // Past the rest and the denial, to the allowing return
+            let to_allow =
+                u8::try_from(values.len() - i).map_err(|_| io::ErrorKind::InvalidData)?;
+            block.push(insn(JEQ, value, to_allow, 0));
+        }
+        block.push(ret(libc::SECCOMP_RET_ERRNO | libc::EPERM.cast_unsigned()));
+        block.push(ret(libc::SECCOMP_RET_ALLOW));
+        Ok(block)
+    }
+    const JEQ: u32 = libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K;
+    const NAMESPACES: libc::c_int = libc::CLONE_NEWNS
+        | libc::CLONE_NEWCGROUP
+        | libc::CLONE_NEWUTS
+        | libc::CLONE_NEWIPC
+        | libc::CLONE_NEWUSER
+        | libc::CLONE_NEWPID
+        | libc::CLONE_NEWNET;
+    if FILTERED.get() {
+        return Ok(());
+    }
+
+    let mut filter = vec![
+        // `struct seccomp_data` starts with the number, then the architecture
+        load(4),
+        // Skip the next instruction if the architecture matches
+        insn(JEQ, AUDIT_ARCH, 1, 0),
+        ret(libc::SECCOMP_RET_KILL_PROCESS),
+        load(0),
+        // Its flags can't be checked, so the C library falls back to `clone`
+        insn(JEQ, word(libc::SYS_clone3)?, 0, 1),
+        ret(libc::SECCOMP_RET_ERRNO | libc::ENOSYS.cast_unsigned()),
+    ];
+    for &syscall in ALLOWED_SYSCALLS {
+        // Fall through to allow on a match, otherwise skip past that
+        filter.push(insn(JEQ, word(syscall)?, 0, 1));
+        filter.push(ret(libc::SECCOMP_RET_ALLOW));
+    }
+    // Arguments start at 16, each 8 bytes with the low half first
+    // Only `AF_UNIX` sockets
+    filter.extend(allow_if(
+        libc::SYS_socket,
+        16,
+        None,
+        &[word(libc::AF_UNIX)?],
+    )?);
+    // Only threads, not processes, and no new namespaces
+    filter.extend(allow_if(
+        libc::SYS_clone,
+        16,
+        Some(word(libc::CLONE_THREAD | NAMESPACES)?),
+        &[word(libc::CLONE_THREAD)?],
+    )?);
+    // Only naming threads and what sandboxing needs, so that a filter can't
+    // be swapped out through `PR_SET_SECCOMP`
+    filter.extend(allow_if(
+        libc::SYS_prctl,
+        16,
+        None,
+        &[
+            word(libc::PR_SET_NAME)?,
+            word(libc::PR_GET_NAME)?,
+            word(libc::PR_SET_NO_NEW_PRIVS)?,
+            word(libc::PR_GET_NO_NEW_PRIVS)?,
+        ],
+    )?);
+    // Only cloning, the immutable flag, and asking about terminals, rather
+    // than e.g. pushing input to one with `TIOCSTI`
+    filter.extend(allow_if(
+        libc::SYS_ioctl,
+        24,
+        None,
+        &[
+            word(libc::FICLONE)?,
+            word(libc::FS_IOC_GETFLAGS)?,
+            word(libc::FS_IOC_SETFLAGS)?,
+            word(libc::TCGETS)?,
+            word(libc::TIOCGWINSZ)?,
+        ],
+    )?);
+    filter.push(ret(libc::SECCOMP_RET_ERRNO | libc::EPERM.cast_unsigned()));
+    let program = libc::sock_fprog {
+        len: u16::try_from(filter.len()).map_err(|_| io::ErrorKind::InvalidData)?,
+        filter: filter.as_mut_ptr(),
+    };
+
+    // Without CAP_SYS_ADMIN, gaining privileges through exec has to be ruled
+    // out first
+    // SAFETY: `PR_SET_NO_NEW_PRIVS` takes no pointers
+    cvt(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) })?;
+    // SAFETY: `program` and the filter it points to outlive the call
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            0,
+            &raw const program,
+        )
+    };
+    if result == -1 {
+        let err = io::Error::last_os_error();
+        // Built without seccomp, or without its filters
+        if matches!(err.raw_os_error(), Some(libc::ENOSYS | libc::EINVAL)) {
+            return Err(io::ErrorKind::Unsupported.into());
+        }
+        return Err(err);
+    }
+    FILTERED.set(true);
+    Ok(())
+}
+
+/// seccomp filters are only installed on Linux, for x86-64 and AArch64.
+///
+/// # Errors
+///
+/// Always returns [`io::ErrorKind::Unsupported`].
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+pub fn seccomp() -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Makes the empty `target` a copy-on-write clone of `source`, sharing its
+/// data blocks, with the permissions of `source`.
+///
+/// # Errors
+///
+/// Returns an error if opening `source` fails, or the filesystem cannot clone
+/// between them, e.g. because it does not support reflinks or they are on
+/// different filesystems. `target` is left empty in the latter case.
+pub fn reflink(source: &Path, target: &fs::File) -> io::Result<()> {
+    let source = fs::File::open(source)?;
+    let permissions = source.metadata()?.permissions();
+    clone(&source, target)?;
+    target.set_permissions(permissions)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn clone(source: &fs::File, target: &fs::File) -> io::Result<()> {
+    // SAFETY: both descriptors stay open for the duration of the call
+    if unsafe { libc::ioctl(target.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) } == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn clone(_source: &fs::File, _target: &fs::File) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Copies the contents and permissions of `source` to the empty `target`.
+///
+/// On Linux the kernel copies the data with `copy_file_range`, without
+/// moving it through userspace and possibly sharing blocks on filesystems
+/// that allow it. Where that is not possible, e.g. between filesystems on
+/// older kernels, the data is copied through a large buffer instead.
+///
+/// # Errors
+///
+/// Returns an error if opening, reading, or writing either file fails.
+pub fn copy(source: &Path, target: &fs::File) -> io::Result<()> {
+    let mut source = fs::File::open(source)?;
+    let metadata = source.metadata()?;
+    if !copy_range(&source, target, metadata.len())? {
+        copy_buffered(&mut source, target)?;
+    }
+    target.set_permissions(metadata.permissions())
+}
+
+/// Copies `len` bytes from `source` to `target` with `copy_file_range`.
+/// Returns `false`, having copied nothing, if it is unavailable for them.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn copy_range(source: &fs::File, target: &fs::File, len: u64) -> io::Result<bool> {
+    const CHUNK: usize = 1 << 30;
+
+    // Some filesystems, e.g. procfs, wrongly report files as empty
+    if len == 0 {
+        return Ok(false);
+    }
+    let mut copied = 0;
+    while copied < len {
+        let chunk = usize::try_from(len - copied).map_or(CHUNK, |x| x.min(CHUNK));
+        // SAFETY: both descriptors stay open for the duration of the call,
+        // and null offsets make it use and advance the file positions
+        let result = unsafe {
+            libc::copy_file_range(
+                source.as_raw_fd(),
+                std::ptr::null_mut(),
+                target.as_raw_fd(),
+                std::ptr::null_mut(),
+                chunk,
+                0,
+            )
+        };
+        match u64::try_from(result) {
+            Ok(0) if copied == 0 => return Ok(false),
+            // The file shrank while being copied
+            Ok(0) => break,
+            Ok(x) => copied += x,
+            Err(_) => {
+                let err = io::Error::last_os_error();
+                let unsupported = matches!(
+                    err.raw_os_error(),
+                    Some(
+                        libc::ENOSYS | libc::EXDEV | libc::EOPNOTSUPP | libc::EINVAL | libc::EPERM
+                    )
+                );
+                return if copied == 0 && unsupported {
+                    Ok(false)
+                } else {
+                    Err(err)
+                };
+            }
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const fn copy_range(_source: &fs::File, _target: &fs::File, _len: u64) -> io::Result<bool> {
+    Ok(false)
+}
+
+fn copy_buffered(source: &mut fs::File, mut target: &fs::File) -> io::Result<()> {
+    use std::io::{
+        Read as _,
+        Write as _,
+    };
+
+    let mut buffer = vec![0; 1 << 20];
+    loop {
+        let read = match source.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(x) => x,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        target.write_all(&buffer[..read])?;
+    }
+}
+
+/// Returns `true` if `metadata` describes a file with holes, i.e. with fewer
+/// blocks allocated than its size needs.
+#[must_use]
+pub fn is_sparse(metadata: &Metadata) -> bool {
+    metadata.blocks().saturating_mul(512) < metadata.len()
+}
+
+/// Copies the contents and permissions of `source` to the empty `target`,
+/// leaving holes where `source` has them instead of writing zeros.
+///
+/// # Errors
+///
+/// Returns an error if reading, writing, or seeking fails, or
+/// [`io::ErrorKind::Unsupported`] if holes cannot be found on this platform.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn copy_sparse(source: &Path, file: &fs::File) -> io::Result<()> {
+    use std::os::unix::fs::FileExt as _;
+
+    let source = fs::File::open(source)?;
+    let metadata = source.metadata()?;
+    let mut buffer = vec![0; 128 * 1024];
+    let mut offset = 0;
+    while offset < metadata.len() {
+        let Some(start) = seek(&source, offset, libc::SEEK_DATA)? else {
+            break;
+        };
+        let end = seek(&source, start, libc::SEEK_HOLE)?.unwrap_or(metadata.len());
+        let mut position = start;
+        while position < end {
+            let length =
+                usize::try_from(end - position).map_or(buffer.len(), |x| x.min(buffer.len()));
+            let read = source.read_at(&mut buffer[..length], position)?;
+            if read == 0 {
+                break;
+            }
+            file.write_all_at(&buffer[..read], position)?;
+            position += read as u64;
+        }
+        offset = end;
+    }
+    // Trailing holes are not written
+    file.set_len(metadata.len())?;
+    file.set_permissions(metadata.permissions())
+}
+
+/// Holes cannot be found on this platform.
+///
+/// # Errors
+///
+/// Always returns [`io::ErrorKind::Unsupported`].
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn copy_sparse(_source: &Path, _target: &fs::File) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Returns the offset of the next data or hole at or after `offset`, or
+/// `None` if there is none.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn seek(file: &fs::File, offset: u64, whence: libc::c_int) -> io::Result<Option<u64>> {
+    let offset = libc::off_t::try_from(offset).map_err(|_| io::ErrorKind::InvalidInput)?;
+    // SAFETY: the descriptor stays open for the duration of the call
+    let result = unsafe { libc::lseek(file.as_raw_fd(), offset, whence) };
+    if let Ok(x) = u64::try_from(result) {
+        return Ok(Some(x));
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() == Some(libc::ENXIO) {
+        Ok(None)
+    } else {
+        Err(err)
+    }
+}
+
+/// Sets the modification time of `path`, leaving its access time alone.
+///
+/// # Errors
+///
+/// Returns an error if opening `path` or setting the time fails.
+pub fn set_mtime(path: &Path, mtime: SystemTime) -> io::Result<()> {
+    // Explicit times only require ownership, not write access
+    fs::File::open(path)?.set_modified(mtime)
+}
+
+/// Returns a command that runs `command` with `sh`.
+#[must_use]
+pub fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+/// Makes `command` run as `user`, with their primary group and no
+/// supplementary ones, and with `HOME`, `USER`, and `LOGNAME` set for them
+/// and `XDG_*` variables left out.
+///
+/// # Errors
+///
+/// Never fails on Unix.
+#[allow(clippy::unnecessary_wraps)]
+pub fn run_as(command: &mut Command, user: &User) -> io::Result<()> {
+    for (name, _) in env::vars_os() {
+        if name.as_bytes().starts_with(b"XDG_") {
+            command.env_remove(name);
+        }
+    }
+    command
+        .uid(user.uid)
+        .gid(user.gid)
+        .env("HOME", &user.home)
+        .env("USER", &user.name)
+        .env("LOGNAME", &user.name);
+    Ok(())
+}
+
+/// Returns the name of this machine.
+///
+/// # Errors
+///
+/// Returns an error if `gethostname` fails.
+pub fn hostname() -> io::Result<String> {
+    let mut buf = vec![0u8; 256];
+    // SAFETY: `buf.len()` is the size of `buf`.
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Truncated names aren't guaranteed to be terminated
+    let len = buf.iter().position(|&x| x == 0).unwrap_or(buf.len());
+    buf.truncate(len);
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+/// Returns whether the effective user is root.
+#[must_use]
+pub fn is_root() -> bool {
+    // SAFETY: `geteuid` always succeeds.
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Looks up the name of the effective user in the NSS database, `None` if it
+/// has no entry.
+///
+/// # Errors
+///
+/// Returns an error if the lookup fails.
+pub fn user_name() -> io::Result<Option<String>> {
+    // SAFETY: `geteuid` always succeeds.
+    let uid = unsafe { libc::geteuid() };
+    let mut buf = vec![0; 1024];
+    loop {
+        let mut passwd = MaybeUninit::<libc::passwd>::uninit();
+        let mut result = ptr::null_mut();
+        // SAFETY: every pointer is valid for the duration of the call, and
+        // `buf.len()` is the size of `buf`.
+        let err = unsafe {
+            libc::getpwuid_r(
+                uid,
+                passwd.as_mut_ptr(),
+                buf.as_mut_ptr(),
+                buf.len(),
+                &raw mut result,
+            )
+        };
+        match err {
+            0 if result.is_null() => return Ok(None),
+            0 => {
+                // SAFETY: `result` is not null, so `passwd` was filled in, and
+                // `pw_name` points to a terminated string in `buf`.
+                let name = unsafe { CStr::from_ptr(passwd.assume_init().pw_name) };
+                return Ok(Some(name.to_string_lossy().into_owned()));
+            }
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            err => return Err(io::Error::from_raw_os_error(err)),
+        }
+    }
+}
+
+/// Filesystem ids of a thread before [`impersonate`], restored when dropped.
+#[derive(Debug)]
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub struct Impersonation {
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+/// Makes the calling thread create files and pass permission checks as `uid`
+/// and `gid`, where set, until the returned guard is dropped.
+///
+/// Only the filesystem ids of this thread change, so the rest of the process
+/// keeps running as before.
+///
+/// # Errors
+///
+/// Returns an error if the ids can't be switched, e.g. when not running as
+/// root.
+#[cfg(target_os = "linux")]
+pub fn impersonate(uid: Option<u32>, gid: Option<u32>) -> io::Result<Impersonation> {
+    let mut guard = Impersonation {
+        uid: None,
+        gid: None,
+    };
+    if let Some(gid) = gid {
+        guard.gid = Some(set_fsid(libc::setfsgid, gid)?);
+    }
+    if let Some(uid) = uid {
+        guard.uid = Some(set_fsid(libc::setfsuid, uid)?);
+    }
+    Ok(guard)
+}
+
+/// Impersonation is only supported on Linux.
+///
+/// # Errors
+///
+/// Always returns [`io::ErrorKind::Unsupported`].
+#[cfg(not(target_os = "linux"))]
+pub fn impersonate(_uid: Option<u32>, _gid: Option<u32>) -> io::Result<Impersonation> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Switches a filesystem id with `set`, which never fails but leaves the id
+/// alone if it may not be changed. Returns the previous id.
+#[cfg(target_os = "linux")]
+fn set_fsid(set: unsafe extern "C" fn(u32) -> libc::c_int, id: u32) -> io::Result<u32> {
+    // SAFETY: `setfsuid` and `setfsgid` only return the previous id, and
+    // leave it unchanged when passed the invalid id `u32::MAX`
+    let (old, new) = unsafe { (set(id), set(u32::MAX)) };
+    if new.cast_unsigned() == id {
+        return Ok(old.cast_unsigned());
+    }
+    // SAFETY: as above
+    unsafe { set(old.cast_unsigned()) };
+    Err(io::ErrorKind::PermissionDenied.into())
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Impersonation {
+    fn drop(&mut self) {
+        // SAFETY: as in `set_fsid`, in the reverse order of `impersonate`
+        unsafe {
+            if let Some(uid) = self.uid {
+                libc::setfsuid(uid);
+            }
+            if let Some(gid) = self.gid {
+                libc::setfsgid(gid);
+            }
+        }
+    }
+}
+
+/// Looks up the uid of the user `name` in the NSS database, `None` if there is
+/// no such user.
+///
+/// # Errors
+///
+/// Returns an error if `name` contains a NUL byte or the lookup fails.
+pub fn user_id(name: &str) -> io::Result<Option<u32>> {
+    Ok(user(name)?.map(|x| x.uid))
+}
+
+/// Looks up the user `name` in the NSS database, `None` if there is no such
+/// user.
+///
+/// # Errors
+///
+/// Returns an error if `name` contains a NUL byte or the lookup fails.
+pub fn user(name: &str) -> io::Result<Option<User>> {
+    let c_name = CString::new(name)?;
+    let mut buf = vec![0; 1024];
+    loop {
+        let mut passwd = MaybeUninit::<libc::passwd>::uninit();
+        let mut result = ptr::null_mut();
+        // SAFETY: every pointer is valid for the duration of the call, and
+        // `buf.len()` is the size of `buf`.
+        let err = unsafe {
+            libc::getpwnam_r(
+                c_name.as_ptr(),
+                passwd.as_mut_ptr(),
+                buf.as_mut_ptr(),
+                buf.len(),
+                &raw mut result,
+            )
+        };
+        match err {
+            0 if result.is_null() => return Ok(None),
+            0 => {
+                // SAFETY: `result` is not null, so `passwd` was filled in.
+                let passwd = unsafe { passwd.assume_init() };
+                // SAFETY: `pw_dir` points to a terminated string in `buf`.
+                let home = unsafe { CStr::from_ptr(passwd.pw_dir) };
+                return Ok(Some(User {
+                    name: name.to_owned(),
+                    uid: passwd.pw_uid,
+                    gid: passwd.pw_gid,
+                    home: PathBuf::from(OsStr::from_bytes(home.to_bytes())),
+                }));
+            }
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            err => return Err(io::Error::from_raw_os_error(err)),
+        }
+    }
+}
+
+/// Looks up the gid of the group `name` in the NSS database, `None` if there
+/// is no such group.
+///
+/// # Errors
+///
+/// Returns an error if `name` contains a NUL byte or the lookup fails.
+pub fn group_id(name: &str) -> io::Result<Option<u32>> {
+    let name = CString::new(name)?;
+    let mut buf = vec![0; 1024];
+    loop {
+        let mut group = MaybeUninit::<libc::group>::uninit();
+        let mut result = ptr::null_mut();
+        // SAFETY: every pointer is valid for the duration of the call, and
+        // `buf.len()` is the size of `buf`.
+        let err = unsafe {
+            libc::getgrnam_r(
+                name.as_ptr(),
+                group.as_mut_ptr(),
+                buf.as_mut_ptr(),
+                buf.len(),
+                &raw mut result,
+            )
+        };
+        match err {
+            0 if result.is_null() => return Ok(None),
+            // SAFETY: `result` is not null, so `group` was filled in.
+            0 => return Ok(Some(unsafe { group.assume_init() }.gr_gid)),
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            err => return Err(io::Error::from_raw_os_error(err)),
+        }
+    }
+}
+
+/// Changes the ownership of `path`. Symlinks themselves are changed rather
+/// than their destination.
+///
+/// # Errors
+///
+/// Returns an error if `chown` or `lchown` fails.
+pub fn chown(
+    path: &Path,
+    metadata: &Metadata,
+    uid: Option<u32>,
+    gid: Option<u32>,
+) -> io::Result<()> {
+    if metadata.is_symlink() {
+        lchown(path, uid, gid)
+    } else {
+        std_chown(path, uid, gid)
+    }
+}
+
+/// Reports changes to the entries of directories through inotify.
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+pub struct Watcher {
+    fd: OwnedFd,
+    dirs: std::collections::HashMap<libc::c_int, PathBuf>,
+    overflowed: bool,
+}
+
+#[cfg(target_os = "linux")]
+impl Watcher {
+    const EVENTS: u32 = libc::IN_CREATE
+        | libc::IN_DELETE
+        | libc::IN_CLOSE_WRITE
+        | libc::IN_MOVED_FROM
+        | libc::IN_MOVED_TO
+        | libc::IN_ATTRIB
+        | libc::IN_DELETE_SELF
+        | libc::IN_MOVE_SELF
+        | libc::IN_ONLYDIR;
+
+    /// Creates a watcher that doesn't watch anything yet.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if inotify can't be set up, e.g. because of
+    /// `fs.inotify.max_user_instances`.
+    pub fn new() -> io::Result<Self> {
+        // SAFETY: `inotify_init1` has no preconditions
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            // SAFETY: `fd` was just opened and nothing else owns it
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            dirs: std::collections::HashMap::new(),
+            overflowed: false,
+        })
+    }
+
+    /// Watches the entries of the directory `dir`, and the directory itself
+    /// being removed or moved. Watching it again does nothing.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `dir` is not a directory or can't be watched.
+    pub fn watch(&mut self, dir: &Path) -> io::Result<()> {
+        let path = c_name(dir.as_os_str())?;
+        // SAFETY: `path` is terminated and the descriptor stays open for the
+        // duration of the call
+        let wd =
+            unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), path.as_ptr(), Self::EVENTS) };
+        if wd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        self.dirs.insert(wd, dir.to_path_buf());
+        Ok(())
+    }
+
+    /// Waits up to `timeout`, or without one until something happens, for
+    /// watched directories to change, and returns the paths that changed.
+    /// Empty if nothing changed in time. When the kernel drops changes
+    /// because too many happened, every watched directory is returned and
+    /// [`overflowed`][Self::overflowed] tells so.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if waiting for or reading the changes fails.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> io::Result<Vec<PathBuf>> {
+        let mut pollfd = libc::pollfd {
+            fd: self.fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = timeout.map_or(-1, |x| {
+            libc::c_int::try_from(x.as_millis()).unwrap_or(libc::c_int::MAX)
+        });
+        // SAFETY: `pollfd` is valid for the duration of the call
+        if unsafe { libc::poll(&raw mut pollfd, 1, timeout) } == -1 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                return Ok(Vec::new());
+            }
+            return Err(err);
+        }
+
+        let mut changed = Vec::new();
+        let mut buf = vec![0_u8; 64 * 1024];
+        loop {
+            // SAFETY: `buf` is valid for writes of `buf.len()` bytes
+            let len =
+                unsafe { libc::read(self.fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+            let Ok(len) = usize::try_from(len) else {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::WouldBlock {
+                    return Ok(changed);
+                }
+                return Err(err);
+            };
+            let mut offset = 0;
+            while offset + size_of::<libc::inotify_event>() <= len {
+                // SAFETY: the kernel only writes whole events, and this one
+                // starts at `offset`
+                let event = unsafe {
+                    buf.as_ptr()
+                        .add(offset)
+                        .cast::<libc::inotify_event>()
+                        .read_unaligned()
+                };
+                let start = offset + size_of::<libc::inotify_event>();
+                offset = start + usize::try_from(event.len).unwrap_or_default();
+                // The name is padded with NUL bytes
+                let name = buf[start..offset.min(len)]
+                    .split(|x| *x == 0)
+                    .next()
+                    .unwrap_or_default();
+                if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                    self.overflowed = true;
+                    changed.extend(self.dirs.values().cloned());
+                    continue;
+                }
+                let Some(dir) = self.dirs.get(&event.wd) else {
+                    continue;
+                };
+                changed.push(if name.is_empty() {
+                    dir.clone()
+                } else {
+                    dir.join(OsStr::from_bytes(name))
+                });
+                if event.mask & libc::IN_IGNORED != 0 {
+                    self.dirs.remove(&event.wd);
+                }
+            }
+        }
+    }
+
+    /// Returns whether changes were dropped since the last call, so that
+    /// what [`wait`][Self::wait] returned is incomplete.
+    pub const fn overflowed(&mut self) -> bool {
+        mem::replace(&mut self.overflowed, false)
+    }
+}
+
+/// Stands in for the inotify watcher, which only exists on Linux.
+#[cfg(not(target_os = "linux"))]
+#[derive(Debug)]
+pub struct Watcher;
+
+#[cfg(not(target_os = "linux"))]
+impl Watcher {
+    /// Watching is only supported on Linux.
+    ///
+    /// # Errors
+    ///
+    /// Always returns [`io::ErrorKind::Unsupported`].
+    pub fn new() -> io::Result<Self> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Never called, as [`new`][Self::new] always fails.
+    ///
+    /// # Errors
+    ///
+    /// Never returns an error.
+    #[allow(clippy::unused_self)]
+    pub fn watch(&mut self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Never called, as [`new`][Self::new] always fails.
+    ///
+    /// # Errors
+    ///
+    /// Never returns an error.
+    #[allow(clippy::unused_self)]
+    pub fn wait(&mut self, _timeout: Option<Duration>) -> io::Result<Vec<PathBuf>> {
+        Ok(Vec::new())
+    }
+
+    /// Never called, as [`new`][Self::new] always fails.
+    #[allow(clippy::unused_self)]
+    pub const fn overflowed(&mut self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,589 @@
+use super::{
+    Node,
+    Resolve,
+    User,
+};
+use log::warn;
+use std::{
+    env,
+    ffi::{
+        OsStr,
+        OsString,
+    },
+    fs::{
+        self,
+        Metadata,
+    },
+    io,
+    os::windows::fs::{
+        symlink_dir,
+        symlink_file,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    process::Command,
+    time::{
+        Duration,
+        SystemTime,
+    },
+};
+
+/// Creates a symlink at `target` pointing to `source`. Windows distinguishes
+/// between file and directory symlinks, so `source` must exist. Requires
+/// developer mode or the `SeCreateSymbolicLinkPrivilege` privilege.
+///
+/// # Errors
+///
+/// Returns an error if `source` cannot be accessed or symlink creation fails.
+pub fn symlink(source: &Path, target: &Path) -> io::Result<()> {
+    if fs::metadata(source)?.is_dir() {
+        symlink_dir(source, target)
+    } else {
+        symlink_file(source, target)
+    }
+}
+
+/// A directory whose entries are changed by path, as Windows has no `*at`
+/// system calls.
+#[derive(Debug)]
+pub struct Dir(PathBuf);
+
+impl Dir {
+    /// Checks that `path` is a directory.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `path` is not a directory or can't be accessed,
+    /// or with [`Resolve::NoSymlinks`], which is not supported.
+    pub fn open(path: &Path, resolve: Resolve) -> io::Result<Self> {
+        if resolve == Resolve::NoSymlinks {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "refusing symlinks requires openat2, which is only available on Linux",
+            ));
+        }
+        if fs::metadata(path)?.is_dir() {
+            Ok(Self(path.to_path_buf()))
+        } else {
+            Err(io::ErrorKind::NotADirectory.into())
+        }
+    }
+
+    /// Opens the parent directory of `path` like [`open`][Self::open],
+    /// returning it and the file name of `path` within it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `path` has no file name, or its parent is not a
+    /// directory.
+    pub fn open_parent(path: &Path, resolve: Resolve) -> io::Result<(Self, OsString)> {
+        let name = path.file_name().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("'{}' has no file name", path.display()),
+            )
+        })?;
+        let parent = path
+            .parent()
+            .filter(|x| !x.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        Ok((Self::open(parent, resolve)?, name.to_owned()))
+    }
+
+    /// See [`set_mode`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if accessing or setting permissions fails.
+    pub fn set_mode(&self, name: &OsStr, mode: u32) -> io::Result<()> {
+        set_mode(&self.0.join(name), mode)
+    }
+
+    /// Ownership is ACL-based on Windows and is not managed by smfh.
+    ///
+    /// # Errors
+    ///
+    /// Never returns an error.
+    #[allow(clippy::unnecessary_wraps, clippy::unused_self)]
+    pub const fn chown(
+        &self,
+        _name: &OsStr,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// See [`set_mtime`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if opening `name` or setting the time fails.
+    pub fn set_mtime(&self, name: &OsStr, mtime: SystemTime) -> io::Result<()> {
+        set_mtime(&self.0.join(name), mtime)
+    }
+
+    /// See [`symlink`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `source` cannot be accessed or symlink creation
+    /// fails.
+    pub fn symlink(&self, source: &Path, name: &OsStr) -> io::Result<()> {
+        symlink(source, &self.0.join(name))
+    }
+
+    /// Creates `name` as a hard link of `source`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if linking fails.
+    pub fn hard_link(&self, source: &Path, name: &OsStr) -> io::Result<()> {
+        fs::hard_link(source, self.0.join(name))
+    }
+
+    /// Renames `from` to `to`, replacing `to` if it exists.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if renaming fails.
+    pub fn rename(&self, from: &OsStr, to: &OsStr) -> io::Result<()> {
+        fs::rename(self.0.join(from), self.0.join(to))
+    }
+
+    /// Special files are not supported on Windows.
+    ///
+    /// # Errors
+    ///
+    /// Always returns [`io::ErrorKind::Unsupported`].
+    #[allow(clippy::unused_self)]
+    pub fn mknod(&self, _name: &OsStr, _node: Node, _mode: u32) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Creates a new file `name` for writing. It inherits the ACL of this
+    /// directory, as there are no modes on Windows.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `name` exists or can't be created.
+    pub fn create(&self, name: &OsStr, _mode: u32) -> io::Result<fs::File> {
+        fs::File::options()
+            .write(true)
+            .create_new(true)
+            .open(self.0.join(name))
+    }
+
+    /// Anonymous files are not supported on Windows.
+    ///
+    /// # Errors
+    ///
+    /// Always returns [`io::ErrorKind::Unsupported`].
+    #[allow(clippy::unused_self)]
+    pub fn create_anonymous(&self, _mode: u32) -> io::Result<fs::File> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Anonymous files are not supported on Windows.
+    ///
+    /// # Errors
+    ///
+    /// Always returns [`io::ErrorKind::Unsupported`].
+    #[allow(clippy::unused_self)]
+    pub fn link_anonymous(&self, _file: &fs::File, _name: &OsStr) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Removes `name`, which must not be a directory.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if removing fails.
+    pub fn remove_file(&self, name: &OsStr) -> io::Result<()> {
+        fs::remove_file(self.0.join(name))
+    }
+
+    /// Removes the empty directory `name`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `name` is not an empty directory or removing
+    /// fails.
+    pub fn remove_dir(&self, name: &OsStr) -> io::Result<()> {
+        fs::remove_dir(self.0.join(name))
+    }
+
+    /// Removes the directory `name` and everything in it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `name` is not a directory, or anything in it
+    /// can't be removed.
+    pub fn remove_dir_all(&self, name: &OsStr) -> io::Result<()> {
+        fs::remove_dir_all(self.0.join(name))
+    }
+
+    /// No-op, as directories can't be flushed on Windows.
+    ///
+    /// # Errors
+    ///
+    /// Never returns an error.
+    #[allow(clippy::unnecessary_wraps, clippy::unused_self)]
+    pub const fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Approximates unix permission bits from the read-only attribute, the only
+/// permission Windows exposes without going through ACLs.
+#[must_use]
+pub fn mode(metadata: &Metadata) -> u32 {
+    if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o666
+    }
+}
+
+/// Returns `true` if the read-only attribute of `metadata` agrees with the
+/// write bits of `mode`.
+#[must_use]
+pub fn mode_matches(metadata: &Metadata, mode: u32) -> bool {
+    metadata.permissions().readonly() == (mode & 0o222 == 0)
+}
+
+/// Flushes the contents and metadata of the file at `path` to disk, which
+/// needs it opened for writing on Windows.
+///
+/// # Errors
+///
+/// Returns an error if opening `path` or flushing fails.
+pub fn sync_file(path: &Path) -> io::Result<()> {
+    fs::OpenOptions::new().write(true).open(path)?.sync_all()
+}
+
+/// Sets the read-only attribute of `path` if `mode` has no write bits.
+///
+/// # Errors
+///
+/// Returns an error if accessing or setting permissions fails.
+pub fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(mode & 0o222 == 0);
+    fs::set_permissions(path, permissions)
+}
+
+/// Sets the read-only attribute of the open `file` if `mode` has no write
+/// bits.
+///
+/// # Errors
+///
+/// Returns an error if accessing or setting permissions fails.
+pub fn set_file_mode(file: &fs::File, mode: u32) -> io::Result<()> {
+    let mut permissions = file.metadata()?.permissions();
+    permissions.set_readonly(mode & 0o222 == 0);
+    file.set_permissions(permissions)
+}
+
+/// Ownership is ACL-based on Windows and is not managed by smfh.
+///
+/// # Errors
+///
+/// Never returns an error.
+#[allow(clippy::unnecessary_wraps)]
+pub const fn chown_file(_file: &fs::File, _uid: Option<u32>, _gid: Option<u32>) -> io::Result<()> {
+    Ok(())
+}
+
+/// Special files are not supported on Windows.
+#[must_use]
+pub const fn node(_metadata: &Metadata) -> Option<Node> {
+    None
+}
+
+/// Approximates whether both metadata describe the same file. File indices
+/// are not exposed on stable Rust, so this compares size and modification
+/// time, which hard links share.
+#[must_use]
+pub fn same_file(left: &Metadata, right: &Metadata) -> bool {
+    left.len() == right.len()
+        && left
+            .modified()
+            .ok()
+            .is_some_and(|x| right.modified().ok() == Some(x))
+}
+
+/// Returns `true` if `err` is from a path that can't be resolved, as with a
+/// symlink loop.
+#[must_use]
+pub fn is_loop(err: &io::Error) -> bool {
+    // ERROR_CANT_RESOLVE_FILENAME
+    err.raw_os_error() == Some(1921)
+}
+
+/// Volume serial numbers are not exposed on stable Rust, so every file is
+/// assumed to be on the same volume.
+#[must_use]
+pub const fn same_device(_left: &Metadata, _right: &Metadata) -> bool {
+    true
+}
+
+/// Ownership is ACL-based on Windows and is not managed by smfh.
+#[must_use]
+pub const fn owner(_metadata: &Metadata) -> Option<(u32, u32)> {
+    None
+}
+
+/// Extended attributes are not supported on Windows.
+///
+/// # Errors
+///
+/// Always returns [`io::ErrorKind::Unsupported`].
+pub fn xattr(_path: &Path, _name: &str) -> io::Result<Option<Vec<u8>>> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Extended attributes are not supported on Windows.
+///
+/// # Errors
+///
+/// Always returns [`io::ErrorKind::Unsupported`].
+pub fn set_xattr(_path: &Path, _name: &str, _value: &[u8]) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// There is no umask on Windows, so this does nothing.
+///
+/// # Errors
+///
+/// Never returns an error.
+pub const fn set_umask(_mask: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// The immutable flag is not supported on Windows.
+///
+/// # Errors
+///
+/// Always returns [`io::ErrorKind::Unsupported`].
+pub fn immutable(_path: &Path) -> io::Result<bool> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// The immutable flag is not supported on Windows.
+///
+/// # Errors
+///
+/// Always returns [`io::ErrorKind::Unsupported`].
+pub fn set_immutable(_path: &Path, _immutable: bool) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Reflinks are not supported on Windows.
+///
+/// # Errors
+///
+/// Always returns [`io::ErrorKind::Unsupported`].
+pub fn reflink(_source: &Path, _target: &fs::File) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Copies the contents and permissions of `source` to the empty `target`.
+///
+/// # Errors
+///
+/// Returns an error if the copy fails.
+pub fn copy(source: &Path, mut target: &fs::File) -> io::Result<()> {
+    let mut source = fs::File::open(source)?;
+    io::copy(&mut source, &mut target)?;
+    target.set_permissions(source.metadata()?.permissions())
+}
+
+/// Holes are not detected on Windows.
+#[must_use]
+pub const fn is_sparse(_metadata: &Metadata) -> bool {
+    false
+}
+
+/// Holes are not detected on Windows.
+///
+/// # Errors
+///
+/// Always returns [`io::ErrorKind::Unsupported`].
+pub fn copy_sparse(_source: &Path, _target: &fs::File) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Sets the modification time of `path`, leaving its access time alone.
+///
+/// # Errors
+///
+/// Returns an error if opening `path` or setting the time fails.
+pub fn set_mtime(path: &Path, mtime: SystemTime) -> io::Result<()> {
+    fs::File::options()
+        .write(true)
+        .open(path)?
+        .set_modified(mtime)
+}
+
+/// Returns a command that runs `command` with `cmd`.
+#[must_use]
+pub fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+/// Landlock is only available on Linux.
+///
+/// # Errors
+///
+/// Always returns [`io::ErrorKind::Unsupported`].
+pub fn landlock(_dirs: &[PathBuf]) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Running as another user is not supported on Windows.
+///
+/// # Errors
+///
+/// Always returns [`io::ErrorKind::Unsupported`].
+pub fn run_as(_command: &mut Command, _user: &User) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Returns the name of this machine, from `COMPUTERNAME`.
+///
+/// # Errors
+///
+/// Returns [`io::ErrorKind::NotFound`] if `COMPUTERNAME` is unset.
+pub fn hostname() -> io::Result<String> {
+    env::var("COMPUTERNAME").map_err(|_| io::ErrorKind::NotFound.into())
+}
+
+/// There is no root user on Windows.
+#[must_use]
+pub const fn is_root() -> bool {
+    false
+}
+
+/// Returns the name of the current user, from `USERNAME`.
+///
+/// # Errors
+///
+/// Never fails.
+pub fn user_name() -> io::Result<Option<String>> {
+    Ok(env::var("USERNAME").ok())
+}
+
+/// Stands in for the guard of [`impersonate`], which never succeeds.
+#[derive(Debug)]
+pub struct Impersonation;
+
+/// Impersonation is not supported on Windows.
+///
+/// # Errors
+///
+/// Always returns [`io::ErrorKind::Unsupported`].
+pub fn impersonate(_uid: Option<u32>, _gid: Option<u32>) -> io::Result<Impersonation> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// There is no NSS database on Windows.
+///
+/// # Errors
+///
+/// Always returns [`io::ErrorKind::Unsupported`].
+pub fn user_id(_name: &str) -> io::Result<Option<u32>> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// There is no NSS database on Windows.
+///
+/// # Errors
+///
+/// Always returns [`io::ErrorKind::Unsupported`].
+pub fn user(_name: &str) -> io::Result<Option<User>> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// There is no NSS database on Windows.
+///
+/// # Errors
+///
+/// Always returns [`io::ErrorKind::Unsupported`].
+pub fn group_id(_name: &str) -> io::Result<Option<u32>> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Ownership is ACL-based on Windows, so this is a no-op.
+///
+/// # Errors
+///
+/// Never returns an error.
+pub fn chown(
+    path: &Path,
+    _metadata: &Metadata,
+    uid: Option<u32>,
+    gid: Option<u32>,
+) -> io::Result<()> {
+    if uid.is_some() || gid.is_some() {
+        warn!(
+            "Ignoring uid/gid of '{}', ownership is not supported on Windows",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Stands in for the inotify watcher, which only exists on Linux.
+#[derive(Debug)]
+pub struct Watcher;
+
+impl Watcher {
+    /// Watching is not supported on Windows.
+    ///
+    /// # Errors
+    ///
+    /// Always returns [`io::ErrorKind::Unsupported`].
+    pub fn new() -> io::Result<Self> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Never called, as [`new`][Self::new] always fails.
+    ///
+    /// # Errors
+    ///
+    /// Never returns an error.
+    #[allow(clippy::unused_self)]
+    pub fn watch(&mut self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Never called, as [`new`][Self::new] always fails.
+    ///
+    /// # Errors
+    ///
+    /// Never returns an error.
+    #[allow(clippy::unused_self)]
+    pub fn wait(&mut self, _timeout: Option<Duration>) -> io::Result<Vec<PathBuf>> {
+        Ok(Vec::new())
+    }
+
+    /// Never called, as [`new`][Self::new] always fails.
+    #[allow(clippy::unused_self)]
+    pub const fn overflowed(&mut self) -> bool {
+        false
+    }
+}
+
+/// seccomp is only available on Linux.
+///
+/// # Errors
+///
+/// Always returns [`io::ErrorKind::Unsupported`].
+pub fn seccomp() -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
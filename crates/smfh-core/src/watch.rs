@@ -0,0 +1,111 @@
+//! What `smfh watch` watches for a manifest, and which of its entries a
+//! change affects, so that only those are activated again.
+
+use crate::{
+    fetch,
+    manifest::{
+        File,
+        Manifest,
+    },
+};
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+/// Returns the directories holding the local sources of `manifest`: the
+/// parent of every source, and every directory under a directory source, so
+/// that files added to it are noticed too.
+#[must_use]
+pub fn watched_dirs(manifest: &Manifest) -> Vec<PathBuf> {
+    fn walk(dir: &Path, dirs: &mut BTreeSet<PathBuf>) {
+        if !dirs.insert(dir.to_path_buf()) {
+            return;
+        }
+        for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+            if entry.file_type().is_ok_and(|x| x.is_dir()) {
+                walk(&entry.path(), dirs);
+            }
+        }
+    }
+
+    let mut dirs = BTreeSet::new();
+    let sources = manifest
+        .files
+        .iter()
+        .filter_map(|file| file.source.as_deref())
+        .filter(|source| !fetch::is_url(source));
+    for source in sources {
+        if let Some(parent) = source.parent() {
+            dirs.insert(parent.to_path_buf());
+        }
+        if source.is_dir() {
+            walk(source, &mut dirs);
+        }
+    }
+    dirs.into_iter().collect()
+}
+
+/// Whether one of the `changed` paths is the source of `file`, or under it.
+#[must_use]
+pub fn affects(file: &File, changed: &[PathBuf]) -> bool {
+    file.source
+        .as_deref()
+        .is_some_and(|source| changed.iter().any(|x| x.starts_with(source)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        manifest::FileKind,
+        sys,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn watched_dirs_cover_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = dir.path().join("tree");
+        fs::create_dir_all(tree.join("sub")).unwrap();
+        let file = dir.path().join("file");
+        fs::write(&file, "").unwrap();
+
+        let mut copy = File::new(FileKind::Copy, PathBuf::from("/target/file"));
+        copy.source = Some(file.clone());
+        let mut tree_copy = File::new(FileKind::RecursiveCopy, PathBuf::from("/target/tree"));
+        tree_copy.source = Some(tree.clone());
+        let manifest = Manifest::new(vec![copy.clone(), tree_copy.clone()]);
+        assert_eq!(
+            watched_dirs(&manifest),
+            vec![dir.path().to_path_buf(), tree.clone(), tree.join("sub")]
+        );
+
+        let changed = [tree.join("sub/new")];
+        assert!(!affects(&copy, &changed));
+        assert!(affects(&tree_copy, &changed));
+        assert!(affects(&copy, &[file]));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn watcher_reports_changed_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut watcher = sys::Watcher::new().unwrap();
+        watcher.watch(dir.path()).unwrap();
+        assert!(
+            watcher
+                .wait(Some(Duration::from_millis(10)))
+                .unwrap()
+                .is_empty()
+        );
+
+        fs::write(dir.path().join("file"), "").unwrap();
+        let changed = watcher.wait(Some(Duration::from_secs(5))).unwrap();
+        assert!(changed.contains(&dir.path().join("file")));
+    }
+}